@@ -0,0 +1,75 @@
+//! Derive macro for `yamabiko::model::YamabikoModel`, enabled with the `derive` feature
+//! of yamabiko and re-exported from there.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::ext::IdentExt;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, LitStr};
+
+/// Implements `YamabikoModel` with an index for every field marked with
+/// `#[yamabiko(index = "numeric")]` (or `"sequential"`, `"collection"`).
+/// Indexes are named after the fields.
+#[proc_macro_derive(YamabikoModel, attributes(yamabiko))]
+pub fn derive_yamabiko_model(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match model_impl(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn model_impl(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "YamabikoModel can only be derived for structs",
+        ));
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "YamabikoModel can only be derived for structs with named fields",
+        ));
+    };
+    let mut indexes = Vec::new();
+    for field in fields.named.iter() {
+        // unwrap: named fields always have an ident
+        let name = field.ident.as_ref().unwrap().unraw().to_string();
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("yamabiko")) {
+            attr.parse_nested_meta(|meta| {
+                if !meta.path.is_ident("index") {
+                    return Err(meta.error("expected `index = \"<type>\"`"));
+                }
+                let kind: LitStr = meta.value()?.parse()?;
+                let variant = match kind.value().as_str() {
+                    "numeric" => quote!(Numeric),
+                    "sequential" => quote!(Sequential),
+                    "collection" => quote!(Collection),
+                    _ => {
+                        return Err(syn::Error::new_spanned(
+                            &kind,
+                            "expected one of `numeric`, `sequential` or `collection`",
+                        ))
+                    }
+                };
+                indexes.push(quote! {
+                    (
+                        ::std::string::String::from(#name),
+                        ::yamabiko::index::IndexType::#variant,
+                    )
+                });
+                Ok(())
+            })?;
+        }
+    }
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::yamabiko::model::YamabikoModel for #ident #ty_generics #where_clause {
+            fn indexes() -> ::std::vec::Vec<(::std::string::String, ::yamabiko::index::IndexType)> {
+                ::std::vec![#(#indexes),*]
+            }
+        }
+    })
+}
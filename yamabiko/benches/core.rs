@@ -0,0 +1,148 @@
+use std::cmp::Ordering::*;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use yamabiko::{
+    index::IndexType,
+    query::{q, QueryBuilder},
+    serialization::DataFormat,
+    test::{create_db, seed_db, seed_records, ComplexDbStruct},
+    ConflictResolution, OperationTarget,
+};
+
+// `--features quick-bench` runs a smaller subset, e.g. on CI
+#[cfg(not(feature = "quick-bench"))]
+const FORMATS: &[DataFormat] = &[DataFormat::Json, DataFormat::Yaml, DataFormat::Pot];
+#[cfg(feature = "quick-bench")]
+const FORMATS: &[DataFormat] = &[DataFormat::Json];
+#[cfg(not(feature = "quick-bench"))]
+const BATCH_SIZES: &[usize] = &[1_000, 10_000];
+#[cfg(feature = "quick-bench")]
+const BATCH_SIZES: &[usize] = &[1_000];
+const DB_SIZE: usize = if cfg!(feature = "quick-bench") {
+    1_000
+} else {
+    10_000
+};
+const TRANSACTION_COMMITS: usize = 1_000;
+
+fn bench_writes(bench: &mut Criterion) {
+    for &data_format in FORMATS {
+        bench.bench_function(format!("single set ({})", data_format).as_str(), |b| {
+            let (db, _td) = create_db(data_format);
+            let records = seed_records(1);
+            let (key, record) = &records[0];
+            let mut i = 0;
+            b.iter(|| {
+                db.set(
+                    format!("{}-{}", key, i).as_str(),
+                    record.clone(),
+                    OperationTarget::Main,
+                )
+                .unwrap();
+                i += 1;
+            })
+        });
+        for &size in BATCH_SIZES {
+            bench.bench_function(
+                format!("set_batch of {} items ({})", size, data_format).as_str(),
+                |b| {
+                    b.iter_batched(
+                        || (create_db(data_format), seed_records(size)),
+                        |((db, _td), records)| {
+                            db.set_batch(records, OperationTarget::Main).unwrap();
+                        },
+                        BatchSize::PerIteration,
+                    )
+                },
+            );
+        }
+    }
+}
+
+fn bench_reads(bench: &mut Criterion) {
+    for &data_format in FORMATS {
+        let (db, _td) = create_db(data_format);
+        let records = seed_db(&db, DB_SIZE);
+        bench.bench_function(format!("get cold ({})", data_format).as_str(), |b| {
+            let mut keys = records.iter().map(|(key, _)| key).cycle();
+            b.iter(|| {
+                db.get::<ComplexDbStruct>(keys.next().unwrap(), OperationTarget::Main)
+                    .unwrap()
+                    .unwrap()
+            })
+        });
+        let db = db.with_read_cache(16);
+        bench.bench_function(format!("get hot ({})", data_format).as_str(), |b| {
+            b.iter(|| {
+                db.get::<ComplexDbStruct>(&records[0].0, OperationTarget::Main)
+                    .unwrap()
+                    .unwrap()
+            })
+        });
+    }
+}
+
+fn bench_indexed_range_query(bench: &mut Criterion) {
+    for &data_format in FORMATS {
+        let (db, _td) = create_db(data_format);
+        db.add_index("usize_val", IndexType::Numeric);
+        let records = seed_db(&db, DB_SIZE);
+        let expected = records
+            .iter()
+            .filter(|(_, record)| (100..200).contains(&record.usize_val))
+            .count();
+        bench.bench_function(
+            format!(
+                "indexed range query over {} documents ({})",
+                DB_SIZE, data_format
+            )
+            .as_str(),
+            |b| {
+                b.iter(|| {
+                    let result = QueryBuilder::query(
+                        q("usize_val", Greater, 99) & q("usize_val", Less, 200),
+                    )
+                    .execute(&db)
+                    .unwrap();
+                    assert_eq!(result.count, expected);
+                })
+            },
+        );
+    }
+}
+
+fn bench_apply_transaction(bench: &mut Criterion) {
+    for &data_format in FORMATS {
+        bench.bench_function(
+            format!(
+                "apply_transaction with {} commits ({})",
+                TRANSACTION_COMMITS, data_format
+            )
+            .as_str(),
+            |b| {
+                b.iter_batched(
+                    || {
+                        let (db, td) = create_db(data_format);
+                        let t = db.new_transaction(None).unwrap();
+                        for (key, record) in seed_records(TRANSACTION_COMMITS) {
+                            db.set(&key, record, OperationTarget::Transaction(&t))
+                                .unwrap();
+                        }
+                        (db, td, t)
+                    },
+                    |(db, _td, t)| {
+                        db.apply_transaction(&t, ConflictResolution::Overwrite)
+                            .unwrap();
+                    },
+                    BatchSize::PerIteration,
+                )
+            },
+        );
+    }
+}
+
+criterion_group! {
+name = benches;
+config = Criterion::default().sample_size(10);
+targets = bench_writes, bench_reads, bench_indexed_range_query, bench_apply_transaction}
+criterion_main!(benches);
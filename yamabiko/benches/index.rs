@@ -0,0 +1,41 @@
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use git2::{ObjectType, Oid};
+use yamabiko::{field::Field, index::IndexType, serialization::DataFormat, test::create_db};
+
+// The entries of the key are found with a binary search over the keys file, reading and
+// rewriting the files is what grows with the size of the index
+fn bench_delete_entry(bench: &mut Criterion) {
+    let mut group = bench.benchmark_group("delete an index entry");
+    for size in [1_000u32, 10_000, 100_000] {
+        let (db, _td) = create_db(DataFormat::Json);
+        let repo = db.repository();
+        let index = db.add_index("num_val", IndexType::Numeric);
+        let oid = |n: u32| Oid::hash_object(ObjectType::Blob, &n.to_be_bytes()).unwrap();
+        // a few values shared by many keys, like a status field
+        let entries: Vec<(Field, Oid)> = (0..size)
+            .map(|n| (Field::Int((n % 16) as i64), oid(n)))
+            .collect();
+        index.add_entries(repo, &entries).unwrap();
+        let (field, last) = entries.last().unwrap();
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, _| {
+            b.iter(|| {
+                assert!(index.delete_entry(repo, *last).unwrap());
+                // put it back so that every iteration deletes from an index of the same size
+                index.create_entry(repo, *last, field).unwrap();
+            })
+        });
+        // nothing to remove, so the file isn't rewritten
+        group.bench_with_input(BenchmarkId::new("missing", size), &size, |b, _| {
+            b.iter(|| assert!(!index.delete_entry(repo, oid(size)).unwrap()))
+        });
+    }
+    group.finish();
+}
+
+criterion_group! {
+name = benches;
+config = Criterion::default().sample_size(20).warm_up_time(Duration::new(1, 0)).measurement_time(Duration::new(5, 0));
+targets = bench_delete_entry}
+criterion_main!(benches);
@@ -0,0 +1,51 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use yamabiko::{
+    serialization::DataFormat, test::create_db, transaction::Transaction, ConflictResolution,
+};
+
+const WRITES: usize = 5_000;
+
+fn bench_transaction(bench: &mut Criterion) {
+    bench.bench_function("5k sets in a transaction and apply", |b| {
+        b.iter_with_setup(
+            || create_db(DataFormat::Json),
+            |(db, _td)| {
+                let t = db.new_transaction(None).unwrap();
+                for i in 0..WRITES {
+                    db.set(
+                        format!("key-{}", i).as_str(),
+                        yamabiko::test::SampleDbStruct::new(String::from("test value")),
+                        yamabiko::OperationTarget::Transaction(&t),
+                    )
+                    .unwrap();
+                }
+                db.apply_transaction(&t, ConflictResolution::Overwrite)
+                    .unwrap();
+            },
+        )
+    });
+    bench.bench_function("5k staged writes in a transaction and apply", |b| {
+        b.iter_with_setup(
+            || create_db(DataFormat::Json),
+            |(db, _td)| {
+                let t = Transaction::begin(&db, None).unwrap();
+                for i in 0..WRITES {
+                    t.stage(
+                        format!("key-{}", i).as_str(),
+                        yamabiko::test::SampleDbStruct::new(String::from("test value")),
+                    )
+                    .unwrap();
+                }
+                t.commit_staged(&db).unwrap();
+                db.apply_transaction(t.name(), ConflictResolution::Overwrite)
+                    .unwrap();
+            },
+        )
+    });
+}
+
+criterion_group! {
+name = benches;
+config = Criterion::default().sample_size(10);
+targets = bench_transaction}
+criterion_main!(benches);
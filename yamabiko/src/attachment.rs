@@ -0,0 +1,229 @@
+use git2::{ObjectType, TreeEntry};
+
+use crate::error;
+
+/// Attachments of a key are kept in a tree next to its document, named `<key>.attachments`,
+/// so they are part of the branch like the document itself - they are replicated and
+/// written by transactions the same way. Keys with a segment ending like this are reserved.
+pub const ATTACHMENTS_SUFFIX: &str = ".attachments";
+
+/// Whether a segment of the path ends with `.attachments` - for the root given by `Tree::walk`
+/// this means it's inside attachments, a key like that would clash with them
+pub(crate) fn in_attachments(path: &str) -> bool {
+    path.split('/')
+        .any(|segment| segment.ends_with(ATTACHMENTS_SUFFIX))
+}
+
+/// Whether the entry is a tree holding attachments - to be skipped when walking documents
+pub(crate) fn is_attachments_tree(entry: &TreeEntry) -> bool {
+    entry.kind() == Some(ObjectType::Tree)
+        && entry
+            .name()
+            .is_some_and(|name| name.ends_with(ATTACHMENTS_SUFFIX))
+}
+
+/// Path of the tree holding the attachments, given the path of the document
+pub(crate) fn attachments_tree_path(document_path: &str) -> String {
+    format!("{}{}", document_path, ATTACHMENTS_SUFFIX)
+}
+
+pub(crate) fn attachment_path(document_path: &str, name: &str) -> Result<String, error::KeyError> {
+    if name.is_empty() || name.contains('/') {
+        return Err(error::KeyError::InvalidAttachmentName(name.to_string()));
+    }
+    Ok(format!("{}/{}", attachments_tree_path(document_path), name))
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use crate::{
+        error::{KeyError, SetObjectError},
+        query::QueryBuilder,
+        serialization::DataFormat,
+        test::*,
+        ChangeKind, OperationTarget,
+    };
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_attachments(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("a")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let before = db
+            .repository()
+            .head()
+            .unwrap()
+            .peel_to_commit()
+            .unwrap()
+            .id();
+        let png: &[u8] = &[0x89, b'P', b'N', b'G', 0, 0xff];
+        db.put_attachment("a", "image.png", png, OperationTarget::Main)
+            .unwrap();
+        db.put_attachment(
+            "a",
+            "document.pdf",
+            "%PDF-1.7".as_bytes(),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        assert_eq!(
+            db.list_attachments("a", OperationTarget::Main).unwrap(),
+            vec!["document.pdf", "image.png"]
+        );
+        assert_eq!(
+            db.get_attachment("a", "image.png", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            png
+        );
+        assert_eq!(
+            db.get_attachment("a", "missing", OperationTarget::Main)
+                .unwrap(),
+            None
+        );
+        assert!(db
+            .list_attachments("b", OperationTarget::Main)
+            .unwrap()
+            .is_empty());
+        // attachments are not documents
+        assert_eq!(QueryBuilder::all().execute(&db).unwrap().count, 1);
+        assert!(db
+            .changes_since(before, OperationTarget::Main)
+            .unwrap()
+            .is_empty());
+        assert!(db.check(Default::default()).unwrap().is_ok());
+
+        assert!(db
+            .delete_attachment("a", "image.png", OperationTarget::Main)
+            .unwrap());
+        assert!(!db
+            .delete_attachment("a", "image.png", OperationTarget::Main)
+            .unwrap());
+        assert_eq!(
+            db.list_attachments("a", OperationTarget::Main).unwrap(),
+            vec!["document.pdf"]
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_delete_with_attachments(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        for key in ["a", "nested/b"] {
+            db.set(
+                key,
+                SampleDbStruct::new(key.to_string()),
+                OperationTarget::Main,
+            )
+            .unwrap();
+            db.put_attachment(key, "one.bin", [1_u8].as_slice(), OperationTarget::Main)
+                .unwrap();
+            db.put_attachment(key, "two.bin", [2_u8].as_slice(), OperationTarget::Main)
+                .unwrap();
+        }
+        // a plain delete leaves the attachments alone
+        assert!(db.delete("nested/b", OperationTarget::Main).unwrap());
+        assert_eq!(
+            db.list_attachments("nested/b", OperationTarget::Main)
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(db
+            .delete_with_attachments("a", OperationTarget::Main)
+            .unwrap());
+        assert!(db
+            .get::<SampleDbStruct>("a", OperationTarget::Main)
+            .unwrap()
+            .is_none());
+        assert!(db
+            .list_attachments("a", OperationTarget::Main)
+            .unwrap()
+            .is_empty());
+        assert!(db
+            .delete_with_attachments("nested/b", OperationTarget::Main)
+            .unwrap());
+        assert!(db
+            .list_attachments("nested/b", OperationTarget::Main)
+            .unwrap()
+            .is_empty());
+        assert!(!db
+            .delete_with_attachments("nested/b", OperationTarget::Main)
+            .unwrap());
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_attachments_in_transaction(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let t = db.new_transaction(None).unwrap();
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("a")),
+            OperationTarget::Transaction(&t),
+        )
+        .unwrap();
+        db.put_attachment(
+            "a",
+            "note.txt",
+            "hello".as_bytes(),
+            OperationTarget::Transaction(&t),
+        )
+        .unwrap();
+        assert!(db
+            .get_attachment("a", "note.txt", OperationTarget::Main)
+            .unwrap()
+            .is_none());
+        let preview = db.preview_transaction(&t).unwrap();
+        assert_eq!(preview.changes.len(), 1);
+        assert_eq!(preview.changes[0].kind, ChangeKind::Added);
+        db.apply_transaction(&t, crate::ConflictResolution::Overwrite)
+            .unwrap();
+        assert_eq!(
+            db.get_attachment("a", "note.txt", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            b"hello"
+        );
+    }
+
+    #[test]
+    fn test_reserved_names() {
+        let (db, _td) = create_db(DataFormat::Json);
+        assert!(matches!(
+            db.set(
+                "a.attachments",
+                SampleDbStruct::new(String::from("a")),
+                OperationTarget::Main
+            ),
+            Err(SetObjectError::InvalidKey(KeyError::Reserved(_)))
+        ));
+        assert!(matches!(
+            db.set(
+                "x.attachments/a",
+                SampleDbStruct::new(String::from("a")),
+                OperationTarget::Main
+            ),
+            Err(SetObjectError::InvalidKey(KeyError::Reserved(_)))
+        ));
+        assert!(matches!(
+            db.put_attachment("a", "nested/name", [0_u8].as_slice(), OperationTarget::Main),
+            Err(crate::error::StreamError::Set(SetObjectError::InvalidKey(
+                KeyError::InvalidAttachmentName(_)
+            )))
+        ));
+    }
+}
@@ -0,0 +1,403 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{debug, error, Collection, OperationTarget};
+
+struct PendingWrite {
+    branch: String,
+    key: String,
+    value: Vec<u8>,
+}
+
+struct WriteBuffer {
+    writes: Vec<PendingWrite>,
+    wal: Option<File>,
+}
+
+/// Collection wrapper which buffers writes instead of committing each of them separately.
+///
+/// `set` only appends to the buffer (and to the write-ahead log file, if there is one),
+/// while `commit` folds everything buffered so far into a single commit per branch.
+/// This amortizes the cost of building trees when there are many small writes.
+/// Reads go through the buffer first, so buffered-but-uncommitted writes are visible.
+///
+/// Durability: without a WAL, buffered writes live only in memory and are lost if the process
+/// dies before `commit`. With a WAL, they survive a crash once `flush` returns and are replayed
+/// into the buffer the next time the BufferedCollection is created with the same WAL path.
+pub struct BufferedCollection {
+    collection: Collection,
+    buffer: Mutex<WriteBuffer>,
+}
+
+impl BufferedCollection {
+    /// Buffer the writes in memory only
+    pub fn new(collection: Collection) -> Self {
+        Self {
+            collection,
+            buffer: Mutex::new(WriteBuffer {
+                writes: Vec::new(),
+                wal: None,
+            }),
+        }
+    }
+
+    /// Buffer the writes in memory and append them to the WAL file at the given path.
+    /// Writes left in the WAL by a previous run are loaded back into the buffer.
+    pub fn with_wal(collection: Collection, wal_path: &Path) -> Result<Self, std::io::Error> {
+        let mut wal = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(wal_path)?;
+        let writes = Self::replay_wal(&mut wal)?;
+        debug!("Replayed {} writes from {:?}", writes.len(), wal_path);
+        Ok(Self {
+            collection,
+            buffer: Mutex::new(WriteBuffer {
+                writes,
+                wal: Some(wal),
+            }),
+        })
+    }
+
+    pub fn collection(&self) -> &Collection {
+        &self.collection
+    }
+
+    /// Number of writes waiting for a commit
+    pub fn pending(&self) -> usize {
+        self.lock().writes.len()
+    }
+
+    pub fn set<S>(
+        &self,
+        key: &str,
+        value: S,
+        target: OperationTarget,
+    ) -> Result<(), error::BufferedWriteError>
+    where
+        S: Serialize,
+    {
+        let data = self
+            .collection
+            .data_format
+            .serialize_with_indexes(value, &mut HashMap::new());
+        self.set_raw(key, &data, target)
+    }
+
+    pub fn set_raw(
+        &self,
+        key: &str,
+        value: &[u8],
+        target: OperationTarget,
+    ) -> Result<(), error::BufferedWriteError> {
+        let branch = target.writable_branch()?;
+        // a write which can't be committed would block every later commit of its branch
+        self.collection.check_raw_write(key, value)?;
+        let write = PendingWrite {
+            branch: branch.to_string(),
+            key: key.to_string(),
+            value: value.to_vec(),
+        };
+        let mut buffer = self.lock();
+        if let Some(wal) = buffer.wal.as_mut() {
+            wal.write_all(&Self::encode(&write))?;
+        }
+        buffer.writes.push(write);
+        Ok(())
+    }
+
+    pub fn get_raw(
+        &self,
+        key: &str,
+        target: OperationTarget,
+    ) -> Result<Option<String>, error::GetObjectError> {
+        if let Some(value) = self.buffered_value(key, &target) {
+            return Ok(Some(String::from_utf8(value)?));
+        }
+        self.collection.get_raw(key, target)
+    }
+
+    pub fn get<D>(
+        &self,
+        key: &str,
+        target: OperationTarget,
+    ) -> Result<Option<D>, error::GetObjectError>
+    where
+        D: DeserializeOwned,
+    {
+        if let Some(value) = self.buffered_value(key, &target) {
+            return Ok(Some(self.collection.data_format.deserialize(&value)));
+        }
+        self.collection.get(key, target)
+    }
+
+    /// Make sure the buffered writes are persisted in the WAL.
+    /// Does nothing if there is no WAL.
+    pub fn flush(&self) -> Result<(), std::io::Error> {
+        let mut buffer = self.lock();
+        if let Some(wal) = buffer.wal.as_mut() {
+            wal.flush()?;
+            wal.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Commit all of the buffered writes - one commit per each branch written to.
+    /// Committed writes are removed from the buffer and the WAL, even if committing
+    /// to one of the other branches fails.
+    /// Returns the number of writes committed.
+    pub fn commit(&self) -> Result<usize, error::BufferedWriteError> {
+        let mut buffer = self.lock();
+        let pending_before = buffer.writes.len();
+        let result = self.commit_branches(&mut buffer.writes);
+        let committed = pending_before - buffer.writes.len();
+        if committed > 0 {
+            let WriteBuffer { writes, wal } = &mut *buffer;
+            if let Some(wal) = wal.as_mut() {
+                wal.set_len(0)?;
+                for write in writes.iter() {
+                    wal.write_all(&Self::encode(write))?;
+                }
+                wal.sync_data()?;
+            }
+        }
+        debug!("Committed {} buffered writes", committed);
+        result?;
+        Ok(committed)
+    }
+
+    fn commit_branches(&self, writes: &mut Vec<PendingWrite>) -> Result<(), error::SetObjectError> {
+        while let Some(first) = writes.first() {
+            let branch = first.branch.clone();
+            let items = writes
+                .iter()
+                .filter(|w| w.branch == branch)
+                .map(|w| (w.key.as_str(), w.value.as_slice()));
+            self.collection
+                .set_batch_raw(items, OperationTarget::Branch(&branch))?;
+            writes.retain(|w| w.branch != branch);
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, WriteBuffer> {
+        // unwrap: only poisoned if another thread panicked while holding the lock
+        self.buffer.lock().unwrap()
+    }
+
+    fn buffered_value(&self, key: &str, target: &OperationTarget) -> Option<Vec<u8>> {
+        let branch = target.to_git_branch()?;
+        self.lock()
+            .writes
+            .iter()
+            .rev()
+            .find(|w| w.key == key && w.branch == branch)
+            .map(|w| w.value.clone())
+    }
+
+    fn encode(write: &PendingWrite) -> Vec<u8> {
+        let mut record = Vec::new();
+        for part in [write.branch.as_bytes(), write.key.as_bytes(), &write.value] {
+            record.extend_from_slice(&(part.len() as u64).to_le_bytes());
+            record.extend_from_slice(part);
+        }
+        record
+    }
+
+    fn replay_wal(wal: &mut File) -> Result<Vec<PendingWrite>, std::io::Error> {
+        let mut content = Vec::new();
+        wal.read_to_end(&mut content)?;
+        let mut writes = Vec::new();
+        let mut rest = content.as_slice();
+        while !rest.is_empty() {
+            let mut parts: Vec<Vec<u8>> = Vec::with_capacity(3);
+            for _ in 0..3 {
+                let Some((len, tail)) = rest.split_first_chunk::<8>() else {
+                    // a torn record at the end of the log - the write never completed
+                    return Ok(writes);
+                };
+                let len = u64::from_le_bytes(*len) as usize;
+                if tail.len() < len {
+                    return Ok(writes);
+                }
+                parts.push(tail[..len].to_vec());
+                rest = &tail[len..];
+            }
+            let value = parts.pop().unwrap();
+            let key = String::from_utf8(parts.pop().unwrap())
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            let branch = String::from_utf8(parts.pop().unwrap())
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            writes.push(PendingWrite { branch, key, value });
+        }
+        Ok(writes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use crate::{
+        buffered::BufferedCollection, error, serialization::DataFormat, test::*, Collection,
+        OperationTarget,
+    };
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_buffered_writes_single_commit(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let commits_before = commit_count(&db, "main");
+        let buffered = BufferedCollection::new(db);
+        for i in 0..10 {
+            buffered
+                .set(
+                    &format!("key-{}", i),
+                    SampleDbStruct::new(format!("value {}", i)),
+                    OperationTarget::Main,
+                )
+                .unwrap();
+        }
+        buffered
+            .set(
+                "key-0",
+                SampleDbStruct::new(String::from("overwritten")),
+                OperationTarget::Main,
+            )
+            .unwrap();
+        assert_eq!(
+            buffered
+                .get::<SampleDbStruct>("key-0", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct::new(String::from("overwritten"))
+        );
+        assert_eq!(
+            buffered
+                .collection()
+                .get::<SampleDbStruct>("key-0", OperationTarget::Main)
+                .unwrap(),
+            None
+        );
+        assert_eq!(buffered.commit().unwrap(), 11);
+        assert_eq!(buffered.pending(), 0);
+        assert_eq!(
+            commit_count(buffered.collection(), "main"),
+            commits_before + 1
+        );
+        assert_eq!(
+            buffered
+                .collection()
+                .get::<SampleDbStruct>("key-0", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct::new(String::from("overwritten"))
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_wal_replay(#[case] data_format: DataFormat) {
+        let (db, td) = create_db(data_format);
+        let wal_path = td.path().join("pending.wal");
+        let buffered = BufferedCollection::with_wal(db, &wal_path).unwrap();
+        buffered
+            .set(
+                "a",
+                SampleDbStruct::new(String::from("a value")),
+                OperationTarget::Main,
+            )
+            .unwrap();
+        buffered.flush().unwrap();
+        drop(buffered);
+
+        let db = Collection::initialize(td.path(), data_format).unwrap();
+        let buffered = BufferedCollection::with_wal(db, &wal_path).unwrap();
+        assert_eq!(buffered.pending(), 1);
+        assert_eq!(
+            buffered
+                .get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct::new(String::from("a value"))
+        );
+        buffered.commit().unwrap();
+        drop(buffered);
+
+        let db = Collection::initialize(td.path(), data_format).unwrap();
+        let buffered = BufferedCollection::with_wal(db, &wal_path).unwrap();
+        assert_eq!(buffered.pending(), 0);
+        assert_eq!(
+            buffered
+                .get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct::new(String::from("a value"))
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_invalid_writes_rejected_before_buffering(#[case] data_format: DataFormat) {
+        let (db, td) = create_db(data_format);
+        db.set_value_limit(64).unwrap();
+        let wal_path = td.path().join("pending.wal");
+        let buffered = BufferedCollection::with_wal(db, &wal_path).unwrap();
+        assert!(matches!(
+            buffered.set(
+                ".metadata",
+                SampleDbStruct::new(String::from("a")),
+                OperationTarget::Main,
+            ),
+            Err(error::BufferedWriteError::Set(
+                error::SetObjectError::InvalidKey(error::KeyError::Reserved(_))
+            ))
+        ));
+        assert!(matches!(
+            buffered.set(
+                "large",
+                SampleDbStruct::new("x".repeat(100)),
+                OperationTarget::Main,
+            ),
+            Err(error::BufferedWriteError::Set(
+                error::SetObjectError::ValueTooLarge { limit: 64, .. }
+            ))
+        ));
+        assert_eq!(buffered.pending(), 0);
+        buffered
+            .set(
+                "a",
+                SampleDbStruct::new(String::from("a")),
+                OperationTarget::Main,
+            )
+            .unwrap();
+        buffered.flush().unwrap();
+        drop(buffered);
+
+        // nothing invalid made it into the WAL either
+        let db = Collection::initialize(td.path(), data_format).unwrap();
+        let buffered = BufferedCollection::with_wal(db, &wal_path).unwrap();
+        assert_eq!(buffered.pending(), 1);
+        assert_eq!(buffered.commit().unwrap(), 1);
+        assert_eq!(
+            buffered
+                .collection()
+                .get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap(),
+            Some(SampleDbStruct::new(String::from("a")))
+        );
+    }
+}
@@ -0,0 +1,236 @@
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use git2::{ErrorCode, Repository};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    error,
+    metrics::Metrics,
+    replica::optional,
+    serialization::DataFormat,
+    signing::CommitSigner,
+    Collection,
+};
+
+/// Keys in the config of the repository under which the options of the builder are kept
+const DATA_FORMAT_CONFIG: &str = "yamabiko.dataformat";
+const READ_CACHE_CONFIG: &str = "yamabiko.readcache";
+
+/// One place to configure a Collection, finished with `create` for a new repository
+/// or `load` for an existing one.
+///
+/// The data format, the value limit and the capacity of the read cache are persisted in
+/// the config of the repository, so `load` restores them - options set on the builder
+/// take precedence and are persisted again. The Metrics, CommitSigner and Clock only
+/// live as long as the Collection.
+#[derive(Default)]
+pub struct CollectionBuilder {
+    data_format: Option<DataFormat>,
+    value_limit: Option<u64>,
+    read_cache: Option<usize>,
+    metrics: Option<Arc<dyn Metrics>>,
+    signer: Option<Arc<dyn CommitSigner>>,
+    clock: Option<Arc<dyn Clock>>,
+}
+
+impl CollectionBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Format the documents are stored in, JSON if not set. It can't be changed
+    /// once the collection is created.
+    pub fn data_format(mut self, data_format: DataFormat) -> Self {
+        self.data_format = Some(data_format);
+        self
+    }
+
+    /// See `Collection::set_value_limit`
+    pub fn value_limit(mut self, bytes: u64) -> Self {
+        self.value_limit = Some(bytes);
+        self
+    }
+
+    /// See `Collection::with_read_cache`
+    pub fn read_cache(mut self, capacity: usize) -> Self {
+        self.read_cache = Some(capacity);
+        self
+    }
+
+    /// See `Collection::with_metrics`
+    pub fn metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// See `Collection::with_signer`
+    pub fn signer(mut self, signer: Arc<dyn CommitSigner>) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    /// See `Collection::initialize_with_clock`
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Create a new repository for the collection at `path`
+    pub fn create(self, path: &Path) -> Result<Collection, error::InitializationError> {
+        if Repository::open_bare(path).is_ok() {
+            return Err(error::InitializationError::AlreadyExists);
+        }
+        let data_format = self.data_format.unwrap_or(DataFormat::Json);
+        self.build(path, data_format)
+    }
+
+    /// Open the collection in the existing repository at `path`, with the options persisted
+    /// when it was created. Repositories created without the builder are assumed to be
+    /// in JSON, unless a data format is given.
+    pub fn load(self, path: &Path) -> Result<Collection, error::InitializationError> {
+        let repo = Repository::open_bare(path).map_err(|err| match err.code() {
+            ErrorCode::NotFound => error::InitializationError::RepositoryNotFound,
+            _ => err.into(),
+        })?;
+        let config = repo.config()?;
+        let configured = optional(config.get_string(DATA_FORMAT_CONFIG))?
+            .map(|format| {
+                DataFormat::from_str(&format).map_err(|_| {
+                    error::InitializationError::InvalidConfiguration(String::from(
+                        DATA_FORMAT_CONFIG,
+                    ))
+                })
+            })
+            .transpose()?;
+        let data_format = match (configured, self.data_format) {
+            (Some(configured), Some(requested))
+                if configured.to_string() != requested.to_string() =>
+            {
+                return Err(error::InitializationError::DataFormatMismatch(
+                    configured.to_string(),
+                    requested.to_string(),
+                ));
+            }
+            (Some(data_format), _) | (None, Some(data_format)) => data_format,
+            (None, None) => DataFormat::Json,
+        };
+        let read_cache = match self.read_cache {
+            Some(capacity) => Some(capacity),
+            None => optional(config.get_i64(READ_CACHE_CONFIG))?
+                .map(|capacity| capacity.max(0) as usize),
+        };
+        Self { read_cache, ..self }.build(path, data_format)
+    }
+
+    fn build(
+        self,
+        path: &Path,
+        data_format: DataFormat,
+    ) -> Result<Collection, error::InitializationError> {
+        let clock = self.clock.unwrap_or_else(|| Arc::new(SystemClock));
+        let mut collection = Collection::initialize_with_clock(path, data_format, clock)?;
+        let mut config = collection.repository().config()?;
+        config.set_str(DATA_FORMAT_CONFIG, &data_format.to_string())?;
+        if let Some(bytes) = self.value_limit {
+            collection.set_value_limit(bytes)?;
+        }
+        if let Some(capacity) = self.read_cache {
+            config.set_i64(READ_CACHE_CONFIG, capacity.min(i64::MAX as usize) as i64)?;
+            collection = collection.with_read_cache(capacity);
+        }
+        if let Some(metrics) = self.metrics {
+            collection = collection.with_metrics(metrics);
+        }
+        if let Some(signer) = self.signer {
+            collection = collection.with_signer(signer);
+        }
+        Ok(collection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use crate::{
+        builder::CollectionBuilder, error::InitializationError, serialization::DataFormat, test::*,
+        Collection, OperationTarget,
+    };
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_load_restores_options(#[case] data_format: DataFormat) {
+        let td = tempfile::tempdir().unwrap();
+        let db = CollectionBuilder::new()
+            .data_format(data_format)
+            .value_limit(1024)
+            .read_cache(8)
+            .create(td.path())
+            .unwrap();
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("a")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        drop(db);
+
+        let db = Collection::builder().load(td.path()).unwrap();
+        assert_eq!(db.data_format().to_string(), data_format.to_string());
+        assert_eq!(db.value_limit().unwrap(), Some(1024));
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct::new(String::from("a"))
+        );
+        assert_eq!(db.read_cache_stats().unwrap().misses, 1);
+    }
+
+    #[test]
+    fn test_create_and_load_errors() {
+        let td = tempfile::tempdir().unwrap();
+        assert_eq!(
+            CollectionBuilder::new().load(td.path()).err(),
+            Some(InitializationError::RepositoryNotFound)
+        );
+        CollectionBuilder::new()
+            .data_format(DataFormat::Yaml)
+            .create(td.path())
+            .unwrap();
+        assert_eq!(
+            CollectionBuilder::new().create(td.path()).err(),
+            Some(InitializationError::AlreadyExists)
+        );
+        assert_eq!(
+            CollectionBuilder::new()
+                .data_format(DataFormat::Json)
+                .load(td.path())
+                .err(),
+            Some(InitializationError::DataFormatMismatch(
+                String::from("yaml"),
+                String::from("json")
+            ))
+        );
+    }
+
+    #[test]
+    fn test_load_without_persisted_options() {
+        let (db, td) = create_db(DataFormat::Pot);
+        drop(db);
+        let db = CollectionBuilder::new()
+            .data_format(DataFormat::Pot)
+            .load(td.path())
+            .unwrap();
+        assert_eq!(db.read_cache_stats(), None);
+        assert_eq!(db.value_limit().unwrap(), None);
+        // the data format is persisted from now on
+        drop(db);
+        let db = CollectionBuilder::new().load(td.path()).unwrap();
+        assert_eq!(db.data_format().to_string(), "pot");
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::replica::Replicator;
+use crate::{debug, error, Collection, OperationTarget};
+
+/// Default number of items after which a BulkLoader commits
+pub const DEFAULT_MAX_ITEMS: usize = 10_000;
+/// Default size of the serialized items (in bytes) after which a BulkLoader commits
+pub const DEFAULT_MAX_BYTES: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct BulkLoadStats {
+    /// Number of items inserted.
+    pub items: usize,
+    /// Total size of the serialized items in bytes.
+    pub bytes: usize,
+    /// Number of commits created.
+    pub commits: usize,
+}
+
+/// Loads large amounts of data onto main, committing every `max_items` items or `max_bytes`
+/// bytes, whichever comes first. Indexes are updated with every commit.
+///
+/// Replication is not triggered by the intermediate commits - if a Replicator is attached,
+/// it replicates once, after `finish`. Items inserted after the last commit are lost
+/// if the loader is dropped without calling `finish`.
+pub struct BulkLoader<'c> {
+    collection: &'c Collection,
+    replicator: Option<&'c Replicator>,
+    max_items: usize,
+    max_bytes: usize,
+    pending: Vec<(String, Vec<u8>)>,
+    pending_bytes: usize,
+    stats: BulkLoadStats,
+}
+
+impl<'c> BulkLoader<'c> {
+    pub(crate) fn new(collection: &'c Collection) -> Self {
+        Self {
+            collection,
+            replicator: None,
+            max_items: DEFAULT_MAX_ITEMS,
+            max_bytes: DEFAULT_MAX_BYTES,
+            pending: Vec::new(),
+            pending_bytes: 0,
+            stats: BulkLoadStats::default(),
+        }
+    }
+
+    /// Commit after this many items (at least 1)
+    pub fn with_max_items(mut self, max_items: usize) -> Self {
+        self.max_items = max_items.max(1);
+        self
+    }
+
+    /// Commit once the serialized items take this many bytes
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Replicate with this Replicator once the load is finished
+    pub fn with_replicator(mut self, replicator: &'c Replicator) -> Self {
+        self.replicator = Some(replicator);
+        self
+    }
+
+    pub fn insert<S>(&mut self, key: &str, value: S) -> Result<(), error::SetObjectError>
+    where
+        S: Serialize,
+    {
+        let data = self
+            .collection
+            .data_format
+            .serialize_with_indexes(value, &mut HashMap::new());
+        self.insert_raw(key, data)
+    }
+
+    pub fn insert_raw(&mut self, key: &str, value: Vec<u8>) -> Result<(), error::SetObjectError> {
+        self.pending_bytes += value.len();
+        self.pending.push((key.to_string(), value));
+        if self.pending.len() >= self.max_items || self.pending_bytes >= self.max_bytes {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Statistics of the items committed so far
+    pub fn stats(&self) -> BulkLoadStats {
+        self.stats
+    }
+
+    /// Commit the remaining items and replicate, if there is a Replicator attached
+    pub fn finish(mut self) -> Result<BulkLoadStats, error::BulkLoadError> {
+        self.commit()?;
+        debug!(
+            "Bulk load finished: {} items in {} commits",
+            self.stats.items, self.stats.commits
+        );
+        if let Some(replicator) = self.replicator {
+            replicator.replicate()?;
+        }
+        Ok(self.stats)
+    }
+
+    fn commit(&mut self) -> Result<(), error::SetObjectError> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        self.collection.set_batch_raw(
+            self.pending.iter().map(|(k, v)| (k, v.as_slice())),
+            OperationTarget::Main,
+        )?;
+        self.stats.items += self.pending.len();
+        self.stats.bytes += self.pending_bytes;
+        self.stats.commits += 1;
+        self.pending.clear();
+        self.pending_bytes = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use crate::{
+        bulk::BulkLoadStats,
+        field::Field,
+        index::IndexType,
+        replica::{ReplicationMethod, Replicator},
+        serialization::DataFormat,
+        test::*,
+        OperationTarget,
+    };
+
+    // the loader does not depend on the data format, so a single one is enough for the big load
+    #[test]
+    fn test_bulk_load() {
+        let (db, _td) = create_db(DataFormat::Json);
+        let mut loader = db.bulk_load().with_max_items(10_000);
+        for i in 0..50_000 {
+            loader
+                .insert(&format!("key-{}", i), SampleDbStruct::new(i.to_string()))
+                .unwrap();
+        }
+        assert_eq!(loader.stats().commits, 5);
+        let stats = loader.finish().unwrap();
+        assert_eq!(stats.items, 50_000);
+        assert_eq!(stats.commits, 5);
+        assert_eq!(
+            db.get::<SampleDbStruct>("key-31337", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct::new(String::from("31337"))
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_bulk_load_max_bytes_and_indexes(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let index = db.add_index("num_val", IndexType::Numeric);
+        let mut loader = db.bulk_load().with_max_bytes(1);
+        for i in 0..5 {
+            loader
+                .insert(&format!("key-{}", i), InterigentDbStruct { num_val: i % 2 })
+                .unwrap();
+        }
+        let stats = loader.finish().unwrap();
+        assert_eq!(stats.commits, 5);
+        assert_eq!(stats.items, 5);
+        let git_index = index.git_index(db.repository());
+        assert_eq!(git_index.len(), 5);
+        let odd = Field::Int(1).to_index_value();
+        assert_eq!(
+            git_index
+                .iter()
+                .filter(|e| e.path.starts_with(odd.as_bytes()))
+                .count(),
+            2
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_bulk_load_empty(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let stats = db.bulk_load().finish().unwrap();
+        assert_eq!(stats, BulkLoadStats::default());
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_bulk_load_replicates_once_finished(#[case] data_format: DataFormat) {
+        let (db, td) = create_db(data_format);
+        let (db_backup, td_backup) = create_db(data_format);
+        let repl = Replicator::initialize(
+            td.path(),
+            "test",
+            td_backup.path().to_str().unwrap(),
+            ReplicationMethod::All,
+            None,
+        )
+        .unwrap();
+        let mut loader = db.bulk_load().with_max_items(2).with_replicator(&repl);
+        for i in 0..5 {
+            loader
+                .insert(&format!("key-{}", i), SampleDbStruct::new(i.to_string()))
+                .unwrap();
+        }
+        assert!(db_backup
+            .get::<SampleDbStruct>("key-0", OperationTarget::Main)
+            .unwrap()
+            .is_none());
+        loader.finish().unwrap();
+        assert_eq!(
+            db_backup
+                .get::<SampleDbStruct>("key-4", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct::new(String::from("4"))
+        );
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::{BTreeMap, HashMap};
+
+use git2::Oid;
+
+/// Root tree the value was read from and the path of the key in it
+type CacheKey = (Oid, String);
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct ReadCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Number of values held by the cache at the moment.
+    pub entries: usize,
+}
+
+/// Bounded LRU cache of the values read with `Collection::get` and `Collection::get_raw`,
+/// enabled with `Collection::with_read_cache`.
+///
+/// Values are cached by the root tree they were read from rather than by branch, so a commit
+/// never has to invalidate anything - reads of the branch after it simply look up
+/// the new tree, while the entries of the old one age out. Absent keys are cached as well.
+pub(crate) struct ReadCache {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<CacheKey, (u64, Option<Vec<u8>>)>,
+    /// Keys of the entries by the tick they were last used at, least recently used first
+    recency: BTreeMap<u64, CacheKey>,
+    hits: u64,
+    misses: u64,
+}
+
+impl ReadCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// `Some(None)` means the key was cached as absent from the tree
+    pub(crate) fn get(&mut self, tree: Oid, key: &str) -> Option<Option<Vec<u8>>> {
+        self.tick += 1;
+        let cache_key = (tree, key.to_string());
+        let Some((used, value)) = self.entries.get_mut(&cache_key) else {
+            self.misses += 1;
+            return None;
+        };
+        self.recency.remove(used);
+        *used = self.tick;
+        self.recency.insert(self.tick, cache_key);
+        self.hits += 1;
+        Some(value.clone())
+    }
+
+    pub(crate) fn insert(&mut self, tree: Oid, key: &str, value: Option<Vec<u8>>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        let cache_key = (tree, key.to_string());
+        if let Some((used, _)) = self.entries.remove(&cache_key) {
+            self.recency.remove(&used);
+        } else if self.entries.len() >= self.capacity {
+            if let Some((_, evicted)) = self.recency.pop_first() {
+                self.entries.remove(&evicted);
+            }
+        }
+        self.recency.insert(self.tick, cache_key.clone());
+        self.entries.insert(cache_key, (self.tick, value));
+    }
+
+    pub(crate) fn stats(&self) -> ReadCacheStats {
+        ReadCacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            entries: self.entries.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use git2::{ObjectType, Oid};
+    use rstest::rstest;
+
+    use crate::{cache::ReadCache, serialization::DataFormat, test::*, OperationTarget};
+
+    #[test]
+    fn test_read_cache_evicts_least_recently_used() {
+        let tree = Oid::hash_object(ObjectType::Tree, b"").unwrap();
+        let mut cache = ReadCache::new(2);
+        cache.insert(tree, "a", Some(b"a".to_vec()));
+        cache.insert(tree, "b", None);
+        assert_eq!(cache.get(tree, "a"), Some(Some(b"a".to_vec())));
+        cache.insert(tree, "c", Some(b"c".to_vec()));
+        assert_eq!(cache.get(tree, "b"), None);
+        assert_eq!(cache.get(tree, "a"), Some(Some(b"a".to_vec())));
+        assert_eq!(cache.get(tree, "c"), Some(Some(b"c".to_vec())));
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (3, 1, 2));
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_cached_reads_follow_commits(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        assert_eq!(db.read_cache_stats(), None);
+        let db = db.with_read_cache(16);
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("old")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        for _ in 0..3 {
+            assert_eq!(
+                db.get::<SampleDbStruct>("a", OperationTarget::Main)
+                    .unwrap()
+                    .unwrap(),
+                SampleDbStruct::new(String::from("old"))
+            );
+        }
+        assert!(db
+            .get_raw("missing", OperationTarget::Main)
+            .unwrap()
+            .is_none());
+        assert!(db
+            .get_raw("missing", OperationTarget::Main)
+            .unwrap()
+            .is_none());
+        let stats = db.read_cache_stats().unwrap();
+        assert_eq!((stats.hits, stats.misses), (3, 2));
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("new")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct::new(String::from("new"))
+        );
+        let t = db.new_transaction(None).unwrap();
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("transaction")),
+            OperationTarget::Transaction(&t),
+        )
+        .unwrap();
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Transaction(&t))
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct::new(String::from("transaction"))
+        );
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct::new(String::from("new"))
+        );
+        assert_eq!(db.read_cache_stats().unwrap().misses, 4);
+    }
+}
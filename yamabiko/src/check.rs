@@ -0,0 +1,396 @@
+use std::collections::HashSet;
+
+use git2::{BranchType, Index as GitIndex, ObjectType, Oid, Repository, Tree, TreeWalkResult};
+
+use crate::field::Field;
+use crate::index::Index;
+use crate::{debug, error, namespace, record, Collection, RepositoryAbstraction};
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CheckOptions {
+    /// Also make sure every value can be parsed in the DataFormat of the collection.
+    /// This reads the entire content of the collection, so it's expensive.
+    pub deserialize: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ProblemLocation {
+    /// A branch, by name.
+    Branch(String),
+    /// A tree in the data layout of main, by its path.
+    Tree(String),
+    /// A value stored on main, by its key.
+    Key(String),
+    /// An index, by its name.
+    Index(String),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ProblemKind {
+    /// The object cannot be read from the object database. Contains the error from git.
+    Unreadable(String),
+    /// A tree entry is neither a blob nor a tree.
+    UnexpectedObject,
+    /// The value cannot be parsed in the DataFormat of the collection. Contains the parser error.
+    Undeserializable(String),
+    /// The index name doesn't follow the `<field>#<kind>.index` pattern.
+    InvalidIndexName,
+    /// The file backing the index cannot be parsed.
+    InvalidIndexFile(String),
+    /// The index entry with this path cannot be decoded into a value.
+    InvalidIndexEntry(String),
+    /// The index entry with this path points at neither a key nor an object.
+    DanglingIndexEntry(String),
+    /// The index holds numbers in an older encoding and has to be rebuilt
+    /// with Collection::rebuild_index. Its entries aren't checked.
+    OutdatedIndex,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Problem {
+    pub location: ProblemLocation,
+    pub kind: ProblemKind,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct CheckReport {
+    /// Number of values found on main.
+    pub keys_checked: usize,
+    /// Number of index entries verified.
+    pub index_entries_checked: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    fn problem(&mut self, location: ProblemLocation, kind: ProblemKind) {
+        debug!("check found a problem: {:?} at {:?}", kind, location);
+        self.problems.push(Problem { location, kind });
+    }
+}
+
+pub(crate) fn check(
+    collection: &Collection,
+    opts: CheckOptions,
+) -> Result<CheckReport, error::CheckError> {
+    let repo = collection.repository();
+    let mut report = CheckReport::default();
+    let mut key_hashes = HashSet::new();
+    match Collection::current_commit(repo, "main").and_then(|c| c.tree()) {
+        Ok(tree) => {
+            check_tree(collection, &tree, "", opts, &mut report, &mut key_hashes);
+            check_indexes(repo, &tree, &mut report, &mut key_hashes)?;
+        }
+        Err(err) => report.problem(
+            ProblemLocation::Branch(String::from("main")),
+            ProblemKind::Unreadable(err.message().to_string()),
+        ),
+    }
+    record!("problems", report.problems.len());
+    Ok(report)
+}
+
+fn check_tree(
+    collection: &Collection,
+    tree: &Tree,
+    path: &str,
+    opts: CheckOptions,
+    report: &mut CheckReport,
+    key_hashes: &mut HashSet<Oid>,
+) {
+    let repo = collection.repository();
+    for entry in tree.iter() {
+        let name = String::from_utf8_lossy(entry.name_bytes());
+        if (path.is_empty() && name.ends_with(".index")) || namespace::is_reserved_tree(&entry) {
+            continue;
+        }
+        match entry.kind() {
+            Some(ObjectType::Tree) => {
+                let tree_path = format!("{}{}/", path, name);
+                match repo.find_tree(entry.id()) {
+                    Ok(subtree) => {
+                        check_tree(collection, &subtree, &tree_path, opts, report, key_hashes)
+                    }
+                    Err(err) => report.problem(
+                        ProblemLocation::Tree(tree_path),
+                        ProblemKind::Unreadable(err.message().to_string()),
+                    ),
+                }
+            }
+            Some(ObjectType::Blob) => {
+                report.keys_checked += 1;
+                let key = Collection::key_from_path(path, &name)
+                    .unwrap_or_else(|_| format!("{}{}", path, name));
+                if let Ok(hash) = Oid::hash_object(ObjectType::Blob, key.as_bytes()) {
+                    key_hashes.insert(hash);
+                }
+                match repo.find_blob(entry.id()) {
+                    Ok(blob) if opts.deserialize => {
+                        let validated = match collection.open_value(blob.content()) {
+                            Ok(content) => collection.data_format.validate(&content),
+                            Err(err) => Err(err.to_string()),
+                        };
+                        if let Err(err) = validated {
+                            report.problem(
+                                ProblemLocation::Key(key),
+                                ProblemKind::Undeserializable(err),
+                            );
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(err) => report.problem(
+                        ProblemLocation::Key(key),
+                        ProblemKind::Unreadable(err.message().to_string()),
+                    ),
+                }
+            }
+            _ => report.problem(
+                ProblemLocation::Tree(format!("{}{}", path, name)),
+                ProblemKind::UnexpectedObject,
+            ),
+        }
+    }
+}
+
+/// Hashes of the keys present on the branches, except for the `skip`ped one - indexes are shared
+/// between all the branches, so their entries may point at keys which only exist in transactions
+pub(crate) fn branch_key_hashes(
+    repo: &Repository,
+    skip: Option<&str>,
+) -> Result<HashSet<Oid>, git2::Error> {
+    let mut key_hashes = HashSet::new();
+    for branch in repo.branches(Some(BranchType::Local))? {
+        let (branch, _) = branch?;
+        if skip.is_some() && branch.name().ok().flatten() == skip {
+            continue;
+        }
+        let Ok(tree) = branch.get().peel_to_tree() else {
+            continue;
+        };
+        // problems with these trees are not reported here
+        let _ = tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+            if namespace::is_reserved_tree(entry) {
+                return TreeWalkResult::Skip;
+            }
+            if entry.kind() == Some(ObjectType::Blob) {
+                let key = Collection::key_from_path(root, entry.name().unwrap_or_default());
+                if let Ok(hash) = key.and_then(|k| Oid::hash_object(ObjectType::Blob, k.as_bytes()))
+                {
+                    key_hashes.insert(hash);
+                }
+            }
+            TreeWalkResult::Ok
+        });
+    }
+    Ok(key_hashes)
+}
+
+fn check_indexes(
+    repo: &Repository,
+    tree: &Tree,
+    report: &mut CheckReport,
+    key_hashes: &mut HashSet<Oid>,
+) -> Result<(), error::CheckError> {
+    let odb = repo.odb()?;
+    let mut transaction_keys_added = false;
+    for entry in tree.iter() {
+        let Some(name) = entry.name().filter(|n| n.ends_with(".index")) else {
+            continue;
+        };
+        let location = || ProblemLocation::Index(name.to_string());
+        let Ok(index) = Index::from_name(name) else {
+            report.problem(location(), ProblemKind::InvalidIndexName);
+            continue;
+        };
+        // the file doesn't exist until the first entry is written - that's just an empty index
+        let index_path = repo.path().join(".index").join(name);
+        let git_index = match GitIndex::open(&index_path) {
+            Ok(git_index) => git_index,
+            Err(err) => {
+                report.problem(
+                    location(),
+                    ProblemKind::InvalidIndexFile(err.message().to_string()),
+                );
+                continue;
+            }
+        };
+        if index.is_outdated(repo) {
+            report.problem(location(), ProblemKind::OutdatedIndex);
+            continue;
+        }
+        for index_entry in git_index.iter() {
+            report.index_entries_checked += 1;
+            let entry_path = String::from_utf8_lossy(&index_entry.path).to_string();
+            if !entry_path.contains('/') || Field::from_index_entry(&index_entry).is_none() {
+                report.problem(location(), ProblemKind::InvalidIndexEntry(entry_path));
+                continue;
+            }
+            if key_hashes.contains(&index_entry.id) || odb.exists(index_entry.id) {
+                continue;
+            }
+            if !transaction_keys_added {
+                key_hashes.extend(branch_key_hashes(repo, Some("main"))?);
+                transaction_keys_added = true;
+                if key_hashes.contains(&index_entry.id) {
+                    continue;
+                }
+            }
+            report.problem(location(), ProblemKind::DanglingIndexEntry(entry_path));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use rstest::rstest;
+
+    use crate::{
+        check::{CheckOptions, ProblemKind, ProblemLocation},
+        index::IndexType,
+        serialization::DataFormat,
+        test::*,
+        Collection, OperationTarget,
+    };
+
+    fn overwrite_loose_object(db: &Collection, oid: git2::Oid, content: &[u8]) {
+        let hex = oid.to_string();
+        let path = db
+            .repository()
+            .path()
+            .join("objects")
+            .join(&hex[..2])
+            .join(&hex[2..]);
+        let mut permissions = fs::metadata(&path).unwrap().permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        fs::set_permissions(&path, permissions).unwrap();
+        fs::write(&path, content).unwrap();
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_check_healthy(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.add_index("num_val", IndexType::Numeric);
+        db.set_batch(
+            [
+                ("a", InterigentDbStruct { num_val: 1 }),
+                ("nested/b", InterigentDbStruct { num_val: 2 }),
+            ],
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let report = db.check(CheckOptions { deserialize: true }).unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.keys_checked, 2);
+        assert_eq!(report.index_entries_checked, 2);
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_check_corrupted_object(#[case] data_format: DataFormat) {
+        let (db, td) = create_db(data_format);
+        db.set_batch(
+            [
+                ("a", SampleDbStruct::new(String::from("a"))),
+                ("b", SampleDbStruct::new(String::from("b"))),
+            ],
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let blob = db
+            .repository()
+            .head()
+            .unwrap()
+            .peel_to_tree()
+            .unwrap()
+            .get_path(std::path::Path::new(
+                &Collection::construct_path_to_key("a").unwrap(),
+            ))
+            .unwrap()
+            .id();
+        overwrite_loose_object(&db, blob, b"definitely not zlib");
+        drop(db);
+        let db = Collection::initialize(td.path(), data_format).unwrap();
+        let report = db.check(CheckOptions::default()).unwrap();
+        assert_eq!(report.keys_checked, 2);
+        assert_eq!(report.problems.len(), 1);
+        assert_eq!(
+            report.problems[0].location,
+            ProblemLocation::Key(String::from("a"))
+        );
+        assert!(matches!(
+            report.problems[0].kind,
+            ProblemKind::Unreadable(_)
+        ));
+    }
+
+    #[test]
+    fn test_check_undeserializable_value() {
+        let (db, _td) = create_db(DataFormat::Json);
+        let blob = db.repository().blob(b"{not json").unwrap();
+        let tree = db.repository().head().unwrap().peel_to_tree().unwrap();
+        let new_tree =
+            Collection::insert_into_tree(db.repository(), Some(&tree), &[("broken/value", blob)])
+                .unwrap();
+        let commit = db.repository().head().unwrap().peel_to_commit().unwrap();
+        db.commit_to_branch(
+            "main",
+            &commit,
+            &db.repository().find_tree(new_tree).unwrap(),
+            "corrupt",
+        )
+        .unwrap();
+        assert!(db.check(CheckOptions::default()).unwrap().is_ok());
+        let report = db.check(CheckOptions { deserialize: true }).unwrap();
+        assert_eq!(report.problems.len(), 1);
+        assert_eq!(
+            report.problems[0].location,
+            ProblemLocation::Key(String::from("broken/value"))
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_check_dangling_index_entry(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let index = db.add_index("num_val", IndexType::Numeric);
+        let t = db.new_transaction(None).unwrap();
+        db.set(
+            "in_transaction",
+            InterigentDbStruct { num_val: 1 },
+            OperationTarget::Transaction(&t),
+        )
+        .unwrap();
+        assert!(db.check(CheckOptions::default()).unwrap().is_ok());
+        index
+            .create_entry(
+                db.repository(),
+                git2::Oid::hash_object(git2::ObjectType::Blob, b"gone").unwrap(),
+                &crate::field::Field::Int(5),
+            )
+            .unwrap();
+        let report = db.check(CheckOptions::default()).unwrap();
+        assert_eq!(report.problems.len(), 1);
+        assert_eq!(
+            report.problems[0].location,
+            ProblemLocation::Index(index.name().to_string())
+        );
+        assert!(matches!(
+            report.problems[0].kind,
+            ProblemKind::DanglingIndexEntry(_)
+        ));
+    }
+}
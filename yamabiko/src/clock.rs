@@ -0,0 +1,131 @@
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
+
+/// Source of the timestamps (seconds since the Unix epoch) yamabiko writes into the repository -
+/// the signatures of commits and the names of history tags.
+/// Set with `Collection::initialize_with_clock` or `Collection::with_clock`.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> i64;
+
+    /// Wait for the duration, e.g. before retrying a failed push (see `RetryPolicy`)
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+/// The time of the system, used by default
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> i64 {
+        chrono::Utc::now().timestamp()
+    }
+}
+
+/// Clock which only moves when told to, so the commits (and their Oids)
+/// don't depend on when the operations were run.
+#[derive(Debug, Default)]
+pub struct MockClock {
+    time: AtomicI64,
+}
+
+impl MockClock {
+    pub fn new(time: i64) -> Self {
+        Self {
+            time: AtomicI64::new(time),
+        }
+    }
+
+    pub fn set(&self, time: i64) {
+        self.time.store(time, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, seconds: i64) {
+        self.time.fetch_add(seconds, Ordering::SeqCst);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> i64 {
+        self.time.load(Ordering::SeqCst)
+    }
+
+    /// Moves the clock by the whole seconds of the duration instead of waiting
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration.as_secs().min(i64::MAX as u64) as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rstest::rstest;
+
+    use crate::{
+        clock::{Clock, MockClock},
+        serialization::DataFormat,
+        test::*,
+        Collection, ConflictResolution, OperationTarget,
+    };
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new(100);
+        assert_eq!(clock.now(), 100);
+        clock.advance(5);
+        assert_eq!(clock.now(), 105);
+        clock.set(7);
+        assert_eq!(clock.now(), 7);
+    }
+
+    fn build_history(data_format: DataFormat) -> (Collection, tempfile::TempDir) {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let clock = Arc::new(MockClock::new(1_700_000_000));
+        let db =
+            Collection::initialize_with_clock(tmpdir.path(), data_format, clock.clone()).unwrap();
+        db.set_batch(
+            [
+                ("a", SampleDbStruct::new(String::from("a"))),
+                ("nested/b", SampleDbStruct::new(String::from("b"))),
+            ],
+            OperationTarget::Main,
+        )
+        .unwrap();
+        clock.advance(60);
+        let t = db.new_transaction(Some("t")).unwrap();
+        db.set(
+            "c",
+            SampleDbStruct::new(String::from("c")),
+            OperationTarget::Transaction(&t),
+        )
+        .unwrap();
+        clock.advance(60);
+        db.apply_transaction(&t, ConflictResolution::Overwrite)
+            .unwrap();
+        clock.advance(60);
+        db.delete("a", OperationTarget::Main).unwrap();
+        (db, tmpdir)
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_fixed_clock_gives_identical_commits(#[case] data_format: DataFormat) {
+        let (first, _first_td) = build_history(data_format);
+        let (second, _second_td) = build_history(data_format);
+        let main = |db: &Collection| {
+            db.repository()
+                .find_branch("main", git2::BranchType::Local)
+                .unwrap()
+                .get()
+                .target()
+                .unwrap()
+        };
+        assert_eq!(main(&first), main(&second));
+        let commit = first.repository().find_commit(main(&first)).unwrap();
+        assert_eq!(commit.time().seconds(), 1_700_000_180);
+    }
+}
@@ -0,0 +1,81 @@
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+use crate::error::DecryptionError;
+
+/// Marks the encrypted values, followed by the version of the format
+const MAGIC: &[u8] = b"ymbkenc";
+const VERSION: u8 = 1;
+const NONCE_LEN: usize = 24;
+const HEADER_LEN: usize = MAGIC.len() + 1 + NONCE_LEN;
+
+/// Encryption of the stored values with XChaCha20-Poly1305, see `Collection::with_encryption`.
+///
+/// Every value is stored as `ymbkenc`, the version of the format (1), a random 24-byte
+/// nonce and the ciphertext with its tag. Only the values are encrypted - keys, the layout
+/// of the trees and the commits (their messages and authors) are visible to anyone
+/// with access to the repository.
+pub struct Encryption {
+    cipher: XChaCha20Poly1305,
+}
+
+impl Encryption {
+    /// Encrypt with the given 256-bit key. Keeping the key safe is up to the caller.
+    pub fn new(key: &[u8; 32]) -> Self {
+        Self {
+            cipher: XChaCha20Poly1305::new(key.into()),
+        }
+    }
+
+    pub(crate) fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        // unwrap: encryption only fails for plaintexts longer than 256 GiB
+        let ciphertext = self.cipher.encrypt(&nonce, plaintext).unwrap();
+        let mut sealed = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+        sealed.extend_from_slice(MAGIC);
+        sealed.push(VERSION);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// Fails for values encrypted with another key, tampered with or not encrypted at all
+    pub(crate) fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, DecryptionError> {
+        if sealed.len() < HEADER_LEN || !sealed.starts_with(MAGIC) || sealed[MAGIC.len()] != VERSION
+        {
+            return Err(DecryptionError);
+        }
+        let nonce = XNonce::from_slice(&sealed[MAGIC.len() + 1..HEADER_LEN]);
+        self.cipher
+            .decrypt(nonce, &sealed[HEADER_LEN..])
+            .map_err(|_| DecryptionError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_and_open() {
+        let encryption = Encryption::new(&[7; 32]);
+        let sealed = encryption.seal(b"value");
+        assert!(sealed.starts_with(b"ymbkenc\x01"));
+        assert!(!sealed.windows(5).any(|window| window == b"value"));
+        // a fresh nonce every time
+        assert_ne!(encryption.seal(b"value"), sealed);
+        assert_eq!(encryption.open(&sealed).unwrap(), b"value");
+
+        assert_eq!(
+            Encryption::new(&[8; 32]).open(&sealed),
+            Err(DecryptionError)
+        );
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(encryption.open(&tampered), Err(DecryptionError));
+        assert_eq!(encryption.open(b"value"), Err(DecryptionError));
+        let mut future = sealed;
+        future[MAGIC.len()] = 2;
+        assert_eq!(encryption.open(&future), Err(DecryptionError));
+    }
+}
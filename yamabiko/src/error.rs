@@ -1,56 +1,288 @@
+use std::fmt;
 use std::str::Utf8Error;
 use std::string::FromUtf8Error;
+use std::time::Duration;
 
 use git2::Error as GitErr;
 use git2::Oid;
+use thiserror::Error;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Error)]
 pub enum InitializationError {
+    /// There is no replica with this name in the configuration of the repository.
+    #[error("there is no replica named {0:?} in the configuration")]
+    ReplicaNotConfigured(String),
+    /// The value of this configuration key of a replica cannot be understood.
+    #[error("invalid value of the replica configuration key {0}")]
+    InvalidReplicaConfiguration(String),
+    /// The url given to `Replicator::initialize` can't point at a git repository.
+    #[error("{0:?} is not a url of a git repository")]
+    InvalidReplicaUrl(String),
+    /// The repository already has a git remote for this replica, which wasn't set up by
+    /// `Replicator::initialize` and points at the contained url.
+    #[error("a git remote for this replica already exists and points at {0}")]
+    RemoteConflict(String),
+    /// `CollectionBuilder::create` was given a path which already holds a repository.
+    #[error("a repository already exists at this path")]
+    AlreadyExists,
+    /// `CollectionBuilder::load` was given a path without a repository.
+    #[error("there is no repository at this path")]
+    RepositoryNotFound,
+    /// The collection is stored in the first data format, but the builder was given the second.
+    #[error("the collection is stored as {0}, not {1}")]
+    DataFormatMismatch(String, String),
+    /// The value of this configuration key of the collection cannot be understood.
+    #[error("invalid value of the configuration key {0}")]
+    InvalidConfiguration(String),
+    /// The repository given to `Collection::clone_from` doesn't hold a collection
+    /// (in the requested data format), for the contained reason.
+    #[error("the repository is not a collection: {0}")]
+    NotACollection(String),
     /// Unknown error caused by git.
-    InternalGitError(GitErr),
+    #[error("git error")]
+    InternalGitError(#[source] GitErr),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Error)]
 pub enum RevertError {
     /// Unable to execute the revert operation - one of the commits in history
     /// has multiple parents and yamabiko doesn't know which one to pick.
     /// Contains the said commit as an argument.
+    #[error("commit {0} has multiple parents")]
     BranchingHistory(Oid),
     /// There is no such commit with specified Oid.
+    #[error("there is no commit {0}")]
     TargetCommitNotFound(Oid),
-    /// OperationTarget the function was invoked with does not exist.
-    InvalidOperationTarget,
+    /// OperationTarget the function was invoked with does not exist. Contains the target.
+    #[error("the operation target {0} does not exist")]
+    InvalidOperationTarget(String),
+    /// The write lock of the repository couldn't be taken, nothing was reverted.
+    #[error("the repository is locked")]
+    Locked(#[from] LockError),
     /// Unknown error caused by git.
-    InternalGitError(GitErr),
+    #[error("git error")]
+    InternalGitError(#[source] GitErr),
 }
 
-#[derive(Debug, PartialEq)]
+/// The lock taken by the writes to coordinate with the ones of other processes
+/// (see `Collection::with_lock_timeout`) couldn't be acquired
+#[derive(Debug, PartialEq, Eq, Clone, Error)]
+pub enum LockError {
+    /// Someone else held the lock for longer than the timeout, which is contained.
+    #[error("the lock was held by another writer for more than {0:?}")]
+    Timeout(Duration),
+    /// The lock file can't be opened or locked. Contains the reason.
+    #[error("the lock file can't be used: {0}")]
+    Io(String),
+}
+
+/// For the operations which report only git errors, like `Squasher::squash`
+impl From<LockError> for git2::Error {
+    fn from(err: LockError) -> Self {
+        git2::Error::new(
+            git2::ErrorCode::Locked,
+            git2::ErrorClass::Os,
+            err.to_string(),
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Error)]
 pub enum SetObjectError {
-    /// OperationTarget the function was invoked with does not exist.
-    InvalidOperationTarget,
+    /// OperationTarget the function was invoked with does not exist. Contains the target.
+    #[error("the operation target {0} does not exist")]
+    InvalidOperationTarget(String),
+    /// OperationTarget the function was invoked with is a commit, which can't be written to.
+    #[error("the operation target is a commit, which can't be written to")]
+    ReadOnlyTarget,
+    /// The key can't be written to - it's reserved (e.g. for attachments or the metadata
+    /// of the documents) or in an invalid namespace. Nothing was written.
+    #[error("invalid key")]
+    InvalidKey(#[from] KeyError),
+    /// The serialized value is larger than the limit set with Collection::set_value_limit.
+    /// Streamed values are not read past the limit, so their size is reported as `limit + 1`.
+    #[error("the value has {size} bytes, more than the limit of {limit}")]
+    ValueTooLarge { size: u64, limit: u64 },
+    /// The configured CommitSigner failed to sign the commit. Contains the reason it gave.
+    #[error("signing the commit failed: {0}")]
+    SigningFailed(String),
+    /// A pre-commit hook (see `Collection::on_pre_commit`) rejected the write,
+    /// nothing was written.
+    #[error("the write was vetoed by a pre-commit hook")]
+    Vetoed(#[source] HookError),
+    /// The write would take the namespace over its quota (see `Namespace::set_quota`),
+    /// nothing was written.
+    #[error("the write would exceed the {which} quota of namespace {namespace}")]
+    QuotaExceeded {
+        namespace: String,
+        which: QuotaLimit,
+    },
+    /// The write lock of the repository couldn't be taken, nothing was written.
+    #[error("the repository is locked")]
+    Locked(#[from] LockError),
+    /// The storage of the repository can't be used (see StorageError).
+    #[error("the storage of the repository can't be used")]
+    Storage(#[source] StorageError),
+    /// Unknown error caused by git.
+    #[error("git error")]
+    InternalGitError(#[source] GitErr),
+}
+
+/// Limit of a namespace quota which a write would exceed
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum QuotaLimit {
+    /// The number of documents
+    Keys,
+    /// The total size of the stored values
+    Bytes,
+}
+
+impl fmt::Display for QuotaLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Keys => write!(f, "keys"),
+            Self::Bytes => write!(f, "bytes"),
+        }
+    }
+}
+
+/// The repository or the disk it's on can't be used - unlike other git errors
+/// these are usually fixed outside of yamabiko and retried.
+#[derive(Debug, PartialEq, Eq, Clone, Error)]
+pub enum StorageError {
+    /// The directory of the repository doesn't exist (anymore).
+    #[error("the repository does not exist")]
+    RepositoryMissing,
+    /// There is no space left on the device or the quota was exceeded. Contains the message from git.
+    #[error("out of space: {0}")]
+    OutOfSpace(String),
+    /// The files of the repository can't be accessed or the file system is read-only.
+    /// Contains the message from git.
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+    /// The storage can't be used for any other reason, e.g. an I/O error of the disk
+    /// (only reported by `Collection::ping`). Contains the message of the error.
+    #[error("the storage is unavailable: {0}")]
+    Unavailable(String),
+}
+
+impl StorageError {
+    /// Tells apart the git errors caused by the storage, `None` for any other error.
+    /// git only passes on the message of the OS error, so that's what it's matched on.
+    pub fn from_git(err: &GitErr) -> Option<Self> {
+        let message = err.message();
+        if err.class() == git2::ErrorClass::Repository
+            && message.starts_with("could not find repository")
+        {
+            Some(Self::RepositoryMissing)
+        } else if message.contains("No space left on device")
+            || message.contains("Disk quota exceeded")
+        {
+            Some(Self::OutOfSpace(message.to_string()))
+        } else if message.contains("Permission denied")
+            || message.contains("Read-only file system")
+            || message.contains("Operation not permitted")
+        {
+            Some(Self::PermissionDenied(message.to_string()))
+        } else {
+            None
+        }
+    }
+
+    /// Classifies the error of accessing a file of the repository
+    pub fn from_io(err: &std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::NotFound => Self::RepositoryMissing,
+            std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded => {
+                Self::OutOfSpace(err.to_string())
+            }
+            std::io::ErrorKind::PermissionDenied | std::io::ErrorKind::ReadOnlyFilesystem => {
+                Self::PermissionDenied(err.to_string())
+            }
+            _ => Self::Unavailable(err.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Error)]
+pub enum SigningError {
+    /// There is no signer configured on the collection (see Collection::with_signer).
+    #[error("no signer configured")]
+    NoSigner,
+    /// The signing program couldn't be run or failed. Contains the reason it gave.
+    #[error("the signer failed: {0}")]
+    SignerFailed(String),
     /// Unknown error caused by git.
-    InternalGitError(GitErr),
+    #[error("git error")]
+    InternalGitError(#[source] GitErr),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Error)]
 pub enum GetObjectError {
-    InvalidOperationTarget,
+    #[error("the operation target {0} does not exist")]
+    InvalidOperationTarget(String),
+    #[error("the stored object is corrupted")]
     CorruptedObject,
-    ValueIsNotValidUTF8(Utf8Error),
-    InvalidKey(KeyError),
+    #[error("the value is not valid UTF-8")]
+    ValueIsNotValidUTF8(#[from] Utf8Error),
+    #[error("invalid key")]
+    InvalidKey(#[from] KeyError),
+    /// The value can't be decrypted with the key of the collection (see DecryptionError).
+    #[error("the value can't be decrypted")]
+    DecryptionFailed,
+    /// The value was stored with a different type tag than the one it was read as.
+    #[error("the value is a {found}, not a {expected}")]
+    TypeMismatch { expected: String, found: String },
+    /// The storage of the repository can't be used (see StorageError).
+    #[error("the storage of the repository can't be used")]
+    Storage(#[source] StorageError),
     /// Unknown error caused by git.
-    InternalGitError(GitErr),
+    #[error("git error")]
+    InternalGitError(#[source] GitErr),
+}
+
+/// The stored value was encrypted with another key, was tampered with or isn't encrypted
+/// at all, see `Collection::with_encryption`
+#[derive(Debug, PartialEq, Eq, Clone, Error)]
+#[error("the value can't be decrypted")]
+pub struct DecryptionError;
+
+impl From<DecryptionError> for GetObjectError {
+    fn from(_: DecryptionError) -> Self {
+        Self::DecryptionFailed
+    }
+}
+
+impl From<DecryptionError> for QueryError {
+    fn from(_: DecryptionError) -> Self {
+        Self::DecryptionFailed
+    }
+}
+
+/// For the operations which only report git errors
+impl From<DecryptionError> for GitErr {
+    fn from(err: DecryptionError) -> Self {
+        GitErr::from_str(&err.to_string())
+    }
 }
 
-impl From<KeyError> for GetObjectError {
-    fn from(err: KeyError) -> Self {
-        Self::InvalidKey(err)
+impl From<SigningError> for SetObjectError {
+    fn from(err: SigningError) -> Self {
+        match err {
+            SigningError::InternalGitError(err) => err.into(),
+            SigningError::SignerFailed(reason) => Self::SigningFailed(reason),
+            SigningError::NoSigner => Self::SigningFailed(String::from("no signer configured")),
+        }
     }
 }
 
-impl From<Utf8Error> for GetObjectError {
-    fn from(err: Utf8Error) -> Self {
-        Self::ValueIsNotValidUTF8(err)
+impl From<SigningError> for TransactionError {
+    fn from(err: SigningError) -> Self {
+        match err {
+            SigningError::InternalGitError(err) => err.into(),
+            SigningError::SignerFailed(reason) => Self::SigningFailed(reason),
+            SigningError::NoSigner => Self::SigningFailed(String::from("no signer configured")),
+        }
     }
 }
 
@@ -60,34 +292,345 @@ impl From<FromUtf8Error> for GetObjectError {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Error)]
+pub enum PatchError {
+    /// There is no document stored under the key.
+    #[error("there is no document under the key {0:?}")]
+    KeyNotFound(String),
+    /// The document stored under the key is not an object, so its fields cannot be patched.
+    #[error("the document under the key {0:?} is not an object")]
+    NotAnObject(String),
+    /// Reading the document to patch failed.
+    #[error("reading the document to patch failed")]
+    Get(#[from] GetObjectError),
+    /// Writing the patched document failed.
+    #[error("writing the patched document failed")]
+    Set(#[from] SetObjectError),
+}
+
+#[derive(Debug, Error)]
+pub enum BufferedWriteError {
+    /// Writing to or truncating the write-ahead log failed.
+    #[error("writing the write-ahead log failed")]
+    Io(#[from] std::io::Error),
+    /// Committing the buffered writes failed.
+    #[error("committing the buffered writes failed")]
+    Set(#[from] SetObjectError),
+}
+
+#[derive(Debug, PartialEq, Error)]
+pub enum BulkLoadError {
+    /// Committing the loaded items failed.
+    #[error("committing the loaded items failed")]
+    Set(#[from] SetObjectError),
+    /// The items were committed, but replicating them afterwards failed.
+    #[error("the items were committed, but replicating them failed")]
+    Replication(#[from] ReplicationError),
+}
+
+#[derive(Debug, PartialEq, Error)]
+pub enum PipelineError {
+    /// The writer thread is gone (it panicked), so the write was not performed.
+    #[error("the writer thread is gone")]
+    WriterStopped,
+    /// Committing the write failed.
+    #[error("committing the write failed")]
+    Set(#[from] SetObjectError),
+}
+
+#[derive(Debug, Error)]
+pub enum StreamError {
+    /// Reading the value from the provided reader failed.
+    #[error("reading the value failed")]
+    Io(#[from] std::io::Error),
+    /// Storing the value failed.
+    #[error("storing the value failed")]
+    Set(#[from] SetObjectError),
+}
+
+#[derive(Debug, PartialEq, Error)]
+pub enum NewTransactionError {
+    /// The name is not a valid git branch name, contains a slash or is reserved (`main`).
+    #[error("{0:?} is not a valid transaction name")]
+    InvalidTransactionName(String),
+    /// A transaction (or another branch) with that name exists already.
+    #[error("transaction {0:?} already exists")]
+    TransactionExists(String),
+    /// The storage of the repository can't be used (see StorageError).
+    #[error("the storage of the repository can't be used")]
+    Storage(#[source] StorageError),
+    /// Unknown error caused by git.
+    #[error("git error")]
+    InternalGitError(#[source] GitErr),
+}
+
+#[derive(Debug, PartialEq, Error)]
 pub enum TransactionError {
     /// Transaction was aborted - only applicable when using ConflictResolution::Abort.
+    #[error("the transaction was aborted")]
     Aborted,
     /// Transaction (more specifically, a branch with that name) wasn't found among git objects.
+    #[error("the transaction does not exist")]
     TransactionNotFound,
+    /// Branch the transaction was supposed to be merged into does not exist.
+    /// Contains the name of the branch.
+    #[error("the target branch {0} does not exist")]
+    InvalidOperationTarget(String),
+    /// Value returned by the conflict resolver is not a valid document,
+    /// or the conflicting key can't be stored.
+    #[error("invalid resolution of the conflict: {0}")]
+    InvalidResolution(String),
+    /// The MergeDriver couldn't merge the values of the key (or the key was deleted on one
+    /// of the sides) - only applicable when using ConflictResolution::Custom.
+    #[error("the values of {key} can't be merged")]
+    MergeConflict { key: String },
+    /// ConflictResolution::Custom was used without setting a MergeDriver on the collection.
+    #[error("no merge driver was set")]
+    NoMergeDriver,
+    /// The MergeDriver failed (see MergeError).
+    #[error("the merge driver failed")]
+    MergeDriverFailed(#[from] MergeError),
+    /// These keys were read with `Transaction::get_tracked` and changed on main since.
+    #[error("keys read by the transaction changed since: {keys:?}")]
+    ReadSetConflict { keys: Vec<String> },
+    /// Main (or the target branch of `merge`) was moved by another writer while the transaction
+    /// was being applied. Nothing was written - the transaction can be applied again.
+    #[error("the target branch was moved by another writer")]
+    MainMoved,
+    /// The configured CommitSigner failed to sign the merge commit. Contains the reason it gave.
+    #[error("signing the commit failed: {0}")]
+    SigningFailed(String),
+    /// A pre-commit hook (see `Collection::on_pre_commit`) rejected the merge,
+    /// the target branch wasn't moved.
+    #[error("the merge was vetoed by a pre-commit hook")]
+    Vetoed(#[source] HookError),
+    /// The name of the savepoint is empty, contains a slash or can't be used in a git reference.
+    #[error("{0:?} is not a valid savepoint name")]
+    InvalidSavepointName(String),
+    /// The transaction has no savepoint with that name - it was never created,
+    /// was released or was rolled back past.
+    #[error("savepoint {0:?} does not exist")]
+    SavepointNotFound(String),
+    /// The write lock of the repository couldn't be taken, the branch wasn't moved.
+    #[error("the repository is locked")]
+    Locked(#[from] LockError),
+    /// The storage of the repository can't be used (see StorageError).
+    #[error("the storage of the repository can't be used")]
+    Storage(#[source] StorageError),
     /// Unknown error caused by git.
-    InternalGitError(GitErr),
+    #[error("git error")]
+    InternalGitError(#[source] GitErr),
+}
+
+/// Returned by a MergeDriver which can't handle the values it was given. Contains the reason.
+#[derive(Debug, PartialEq, Eq, Clone, Error)]
+#[error("{0}")]
+pub struct MergeError(pub String);
+
+/// Returned by a pre-commit hook to reject a write. Contains the reason.
+#[derive(Debug, PartialEq, Eq, Clone, Error)]
+#[error("{0}")]
+pub struct HookError(pub String);
+
+/// String couldn't be parsed into the requested variant of `Field`
+#[derive(Debug, PartialEq, Eq, Clone, Error)]
+pub enum ParseFieldError {
+    /// Not an integer that fits in an `i64`. Contains the string.
+    #[error("{0:?} is not an integer")]
+    NotAnInt(String),
+    /// Not a finite floating point number. Contains the string.
+    #[error("{0:?} is not a finite number")]
+    NotAFloat(String),
 }
 
-#[derive(Debug, PartialEq)]
+/// Index name doesn't follow the `<field>#<type>.<suffix>` scheme
+#[derive(Debug, PartialEq, Eq, Clone, Error)]
+pub enum IndexNameError {
+    /// There is no `.` before the suffix.
+    #[error("the index name has no suffix")]
+    MissingSuffix,
+    /// There is no `#` between the field and the type.
+    #[error("the index name has no type")]
+    MissingType,
+    /// The indexed field is empty.
+    #[error("the indexed field is empty")]
+    EmptyField,
+    /// The type is not one of `numeric`, `sequential` or `collection`.
+    #[error("unknown index type {0:?}")]
+    UnknownType(String),
+}
+
+#[derive(Debug, PartialEq, Error)]
 pub enum KeyError {
-    NotHashable(GitErr),
+    #[error("the key can't be hashed")]
+    NotHashable(#[source] GitErr),
+    /// A segment of the key ends with `.attachments`, which is where attachments are stored.
+    #[error("{0:?} is reserved for attachments")]
+    Reserved(String),
+    /// Names of attachments can't be empty or contain a slash.
+    #[error("{0:?} is not a valid attachment name")]
+    InvalidAttachmentName(String),
+    /// Names of namespaces can't be empty, contain a slash or end like a reserved tree.
+    #[error("{0:?} is not a valid namespace name")]
+    InvalidNamespace(String),
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Error)]
+#[error("unknown data format")]
 pub struct InvalidDataFormatError;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Error)]
 pub enum ReplicationError {
+    /// The commit to replicate is not in the history of main (anymore).
+    #[error("commit {0} is not in the history of main")]
+    CommitNotOnMain(Oid),
+    /// Recording the outcome of the push (see `Replicator::status`) failed.
+    #[error("recording the status of the replica failed")]
+    Metadata(#[from] MetaError),
     /// Unknown error caused by git.
-    InternalGitError(GitErr),
+    #[error("git error")]
+    InternalGitError(#[source] GitErr),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Error)]
+pub enum ChangesError {
+    /// OperationTarget the function was invoked with does not exist. Contains the target.
+    #[error("the operation target {0} does not exist")]
+    InvalidOperationTarget(String),
+    /// There is no such commit with specified Oid.
+    #[error("there is no commit {0}")]
+    CommitNotFound(Oid),
+    /// The commit is not in the history of the branch (for example because the history was
+    /// reverted or squashed in the meantime) - the caller has to do a full resync.
+    #[error("commit {0} is not in the history of the branch")]
+    NotAnAncestor(Oid),
+    /// Unknown error caused by git.
+    #[error("git error")]
+    InternalGitError(#[source] GitErr),
+}
+
+#[derive(Debug, PartialEq, Error)]
+pub enum MigrationError {
+    /// Returned by a migration function which cannot upgrade the document.
+    #[error("the migration failed: {0}")]
+    Failed(String),
+    /// The document is not an object, so its schema version cannot be stored in it.
+    #[error("the document is not an object")]
+    NotAnObject,
+    /// OperationTarget the function was invoked with does not exist. Contains the target.
+    #[error("the operation target {0} does not exist")]
+    InvalidOperationTarget(String),
+    /// A document can't be decrypted with the key of the collection (see DecryptionError).
+    #[error("a document can't be decrypted")]
+    DecryptionFailed,
+    /// Writing the migrated documents failed.
+    #[error("writing the migrated documents failed")]
+    Set(#[from] SetObjectError),
+    /// Unknown error caused by git.
+    #[error("git error")]
+    InternalGitError(#[source] GitErr),
+}
+
+#[derive(Debug, PartialEq, Error)]
+pub enum CheckError {
+    /// Unknown error caused by git, not related to a specific object.
+    #[error("git error")]
+    InternalGitError(#[source] GitErr),
+}
+
+#[derive(Debug, PartialEq, Error)]
+pub enum VerifyError {
+    /// Unknown error caused by git, not related to a specific object.
+    #[error("git error")]
+    InternalGitError(#[source] GitErr),
+}
+
+#[derive(Debug, PartialEq, Error)]
+pub enum LogError {
+    /// OperationTarget the function was invoked with does not exist. Contains the target.
+    #[error("the operation target {0} does not exist")]
+    InvalidOperationTarget(String),
+    /// There is no such commit with specified Oid.
+    #[error("there is no commit {0}")]
+    CommitNotFound(Oid),
+    /// The commit passed as the cursor is not in the history of the branch.
+    #[error("commit {0} is not in the history of the branch")]
+    NotAnAncestor(Oid),
+    /// Unknown error caused by git.
+    #[error("git error")]
+    InternalGitError(#[source] GitErr),
+}
+
+#[derive(Debug, Error)]
+pub enum StatsError {
+    /// Measuring the size of the repository on disk failed.
+    #[error("measuring the size of the repository failed")]
+    Io(#[from] std::io::Error),
+    /// Unknown error caused by git.
+    #[error("git error")]
+    InternalGitError(#[source] GitErr),
+}
+
+#[derive(Debug, PartialEq, Error)]
+pub enum MetaError {
+    /// The key is empty, `.`, `..` or contains a slash.
+    #[error("{0:?} is not a valid metadata key")]
+    InvalidKey(String),
+    /// The stored value can't be read as the requested type (or the value can't be stored).
+    #[error("invalid metadata value: {0}")]
+    InvalidValue(String),
+    /// Unknown error caused by git.
+    #[error("git error")]
+    InternalGitError(#[source] GitErr),
+}
+
+impl From<MetaError> for git2::Error {
+    fn from(err: MetaError) -> Self {
+        match err {
+            MetaError::InternalGitError(err) => err,
+            err => git2::Error::from_str(&err.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Error)]
 pub enum QueryError {
+    /// OperationTarget the function was invoked with does not exist. Contains the target.
+    #[error("the operation target {0} does not exist")]
+    InvalidOperationTarget(String),
+    /// A document can't be decrypted with the key of the collection (see DecryptionError).
+    #[error("a document can't be decrypted")]
+    DecryptionFailed,
+    /// Unknown error caused by git.
+    #[error("git error")]
+    InternalGitError(#[source] GitErr),
+}
+
+#[derive(Debug, PartialEq, Error)]
+pub enum IndexError {
+    /// The configured CommitSigner failed to sign the commit adding the index.
+    /// Contains the reason it gave.
+    #[error("signing the commit failed: {0}")]
+    SigningFailed(String),
+    /// The write lock of the repository couldn't be taken, the index wasn't added.
+    #[error("the repository is locked")]
+    Locked(#[from] LockError),
+    /// The storage of the repository can't be used (see StorageError).
+    #[error("the storage of the repository can't be used")]
+    Storage(#[source] StorageError),
     /// Unknown error caused by git.
-    InternalGitError(GitErr),
+    #[error("git error")]
+    InternalGitError(#[source] GitErr),
+}
+
+impl From<SigningError> for IndexError {
+    fn from(err: SigningError) -> Self {
+        match err {
+            SigningError::InternalGitError(err) => err.into(),
+            SigningError::SignerFailed(reason) => Self::SigningFailed(reason),
+            SigningError::NoSigner => Self::SigningFailed(String::from("no signer configured")),
+        }
+    }
 }
 
 macro_rules! impl_GitErr {
@@ -100,12 +643,118 @@ macro_rules! impl_GitErr {
     }
 }
 
-impl_GitErr!(
-    InitializationError,
-    RevertError,
+/// Like impl_GitErr, but errors caused by the storage get their own variant
+macro_rules! impl_StorageErr {
+    ($($t:ty),+) => {
+        $(impl From<GitErr> for $t {
+            fn from(err: GitErr) -> Self {
+                match StorageError::from_git(&err) {
+                    Some(storage) => Self::Storage(storage),
+                    None => Self::InternalGitError(err),
+                }
+            }
+        }
+
+        impl From<StorageError> for $t {
+            fn from(err: StorageError) -> Self {
+                Self::Storage(err)
+            }
+        })*
+    }
+}
+
+impl_StorageErr!(
     SetObjectError,
     GetObjectError,
     TransactionError,
+    NewTransactionError,
+    IndexError
+);
+
+impl_GitErr!(
+    InitializationError,
+    RevertError,
     ReplicationError,
+    ChangesError,
+    LogError,
+    MigrationError,
+    CheckError,
+    VerifyError,
+    SigningError,
+    StatsError,
+    MetaError,
     QueryError
 );
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use crate::{serialization::DataFormat, test::*, Collection, OperationTarget};
+
+    use super::*;
+
+    #[test]
+    fn test_source_is_git_error() {
+        let td = tempfile::tempdir().unwrap();
+        let file = td.path().join("file");
+        std::fs::write(&file, "not a repository").unwrap();
+        let err = Collection::initialize(&file, DataFormat::Json)
+            .err()
+            .unwrap();
+        let InitializationError::InternalGitError(inner) = &err else {
+            panic!("unexpected error {:?}", err);
+        };
+        let source = err.source().unwrap().downcast_ref::<GitErr>().unwrap();
+        assert_eq!(source, inner);
+        assert_eq!(err.to_string(), "git error");
+    }
+
+    #[test]
+    fn test_missing_target_is_named() {
+        let (db, _td) = create_db(DataFormat::Json);
+        let err = db
+            .get::<serde_json::Value>("a", OperationTarget::Branch("missing"))
+            .unwrap_err();
+        assert_eq!(
+            err,
+            GetObjectError::InvalidOperationTarget(String::from("missing"))
+        );
+        assert_eq!(
+            err.to_string(),
+            "the operation target missing does not exist"
+        );
+    }
+
+    #[test]
+    fn test_source_chain() {
+        let (db, td) = create_db(DataFormat::Json);
+        std::fs::remove_dir_all(td.path()).unwrap();
+        let err = db
+            .patch(
+                "a",
+                &serde_json::json!({"str_val": "a"}),
+                OperationTarget::Main,
+            )
+            .unwrap_err();
+        assert_eq!(err.to_string(), "reading the document to patch failed");
+        let get = err.source().unwrap();
+        assert_eq!(
+            get.downcast_ref::<GetObjectError>(),
+            Some(&GetObjectError::Storage(StorageError::RepositoryMissing))
+        );
+        assert_eq!(
+            get.source().unwrap().to_string(),
+            "the repository does not exist"
+        );
+        let boxed: Box<dyn Error + Send + Sync> = Box::new(err);
+        assert!(boxed.source().is_some());
+    }
+
+    #[test]
+    fn test_veto_reason_is_source() {
+        let err = SetObjectError::Vetoed(HookError(String::from("no writes today")));
+        assert_eq!(err.to_string(), "the write was vetoed by a pre-commit hook");
+        assert_eq!(err.source().unwrap().to_string(), "no writes today");
+    }
+}
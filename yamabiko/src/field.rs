@@ -1,16 +1,54 @@
 use std::cmp::Ordering;
+use std::fmt::Display;
+use std::str::FromStr;
 
 use git2::IndexEntry;
 
+use crate::error::ParseFieldError;
 use crate::index::Index;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Field {
     Int(i64),
     Float(f64),
     String(String),
 }
 
+/// Variant of `Field` a string should be parsed into, see `Field::parse`
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum FieldType {
+    Int,
+    Float,
+    String,
+}
+
+impl FromStr for FieldType {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, String> {
+        match name {
+            "int" => Ok(Self::Int),
+            "float" => Ok(Self::Float),
+            "string" => Ok(Self::String),
+            _ => Err(String::from("No such field type")),
+        }
+    }
+}
+
+impl Display for FieldType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Int => "int",
+                Self::Float => "float",
+                Self::String => "string",
+            }
+        )
+    }
+}
+
 impl From<f64> for Field {
     fn from(number: f64) -> Self {
         Self::Float(number)
@@ -138,12 +176,12 @@ impl PartialOrd<Self> for Field {
     }
 }
 
-impl ToString for Field {
-    fn to_string(&self) -> String {
+impl Display for Field {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::Int(v) => v.to_string(),
-            Self::String(v) => v.to_string(),
-            Self::Float(v) => v.to_string(),
+            Self::Int(v) => write!(f, "{}", v),
+            Self::String(v) => write!(f, "{}", v),
+            Self::Float(v) => write!(f, "{}", v),
         }
     }
 }
@@ -157,35 +195,92 @@ impl Field {
     pub fn from_index_entry(index_entry: &IndexEntry) -> Option<Self> {
         let val = String::from_utf8_lossy(Index::extract_value(index_entry));
         match index_entry.ino {
-            0 => Some(Self::from(
-                f64::from_bits(u64::from_str_radix(&val, 16).ok()?) as i64,
-            )),
+            0 => Some(Self::from(Self::decode_number(&val)? as i64)),
             1 => Some(Self::from(val.to_string())),
-            2 => Some(Self::from(f64::from_bits(
-                u64::from_str_radix(&val, 16).ok()?,
-            ))),
+            2 => Some(Self::from(Self::decode_number(&val)?)),
             _ => None,
         }
     }
 
+    /// Numbers are indexed as the bits of their `f64`, with the sign bit flipped for positive
+    /// numbers and all the bits flipped for negative ones, in fixed-width hex - so that
+    /// the order of the strings is the order of the numbers.
+    fn encode_number(number: f64) -> String {
+        // -0.0 would sort right before 0.0 while being equal to it
+        let number = if number == 0.0 { 0.0 } else { number };
+        let bits = number.to_bits();
+        let ordered = match number.is_sign_negative() {
+            true => !bits,
+            false => bits | 1 << 63,
+        };
+        format!("{:016x}", ordered)
+    }
+
+    fn decode_number(value: &str) -> Option<f64> {
+        if value.len() != 16 {
+            return None;
+        }
+        let ordered = u64::from_str_radix(value, 16).ok()?;
+        let bits = match ordered & 1 << 63 {
+            0 => !ordered,
+            _ => ordered & !(1 << 63),
+        };
+        Some(f64::from_bits(bits))
+    }
+
+    /// Turn a JSON value into a `Field`
+    ///
+    /// Integral numbers become `Int` (or `Float`, if they don't fit in an `i64`),
+    /// numbers with a fractional part or an exponent become `Float`, strings become `String`
+    ///
+    /// Returns `None` for nulls, booleans, arrays and objects
+    pub fn from_serde(value: &serde_json::Value) -> Option<Self> {
+        Self::try_from(value).ok()
+    }
+
+    /// Parse a string (e.g. an argument from the command line) into the given variant
+    ///
+    /// Ints and floats are parsed as by `str::parse`, so `"007"` is `Int(7)`
+    /// and `"1e5"` is only a valid float. Floats have to be finite.
+    pub fn parse(s: &str, hint: FieldType) -> Result<Self, ParseFieldError> {
+        match hint {
+            FieldType::Int => s
+                .parse()
+                .map(Self::Int)
+                .map_err(|_| ParseFieldError::NotAnInt(s.to_string())),
+            FieldType::Float => s
+                .parse::<f64>()
+                .ok()
+                .filter(|f| f.is_finite())
+                .map(Self::Float)
+                .ok_or_else(|| ParseFieldError::NotAFloat(s.to_string())),
+            FieldType::String => Ok(Self::String(s.to_string())),
+        }
+    }
+
+    /// Best-effort guess of the variant a string was meant to be
+    ///
+    /// Integers become `Int` (or `Float`, if they don't fit in an `i64`), other finite numbers
+    /// like `"3.14"` or `"1e5"` become `Float` and everything else is a `String`.
+    /// Numbers with leading zeros like `"007"` are kept as strings, since they are usually codes.
+    pub fn infer(s: &str) -> Self {
+        let digits = s.trim_start_matches(['-', '+']);
+        let leading_zero = digits.len() > 1
+            && digits.starts_with('0')
+            && digits[1..].starts_with(|c: char| c.is_ascii_digit());
+        if leading_zero {
+            return Self::String(s.to_string());
+        }
+        Self::parse(s, FieldType::Int)
+            .or_else(|_| Self::parse(s, FieldType::Float))
+            .unwrap_or_else(|_| Self::String(s.to_string()))
+    }
+
+    /// Value as it's stored in the index, see `encode_number` for numbers
     pub fn to_index_value(&self) -> String {
         match self {
-            Field::Int(v) => format!(
-                "{}/{:16x}",
-                match v.is_positive() {
-                    true => "1",
-                    false => "0",
-                },
-                (*v as f64).to_bits()
-            ),
-            Field::Float(v) => format!(
-                "{}/{:16x}",
-                match v.is_sign_positive() {
-                    true => "1",
-                    false => "0",
-                },
-                v.to_bits()
-            ),
+            Field::Int(v) => Self::encode_number(*v as f64),
+            Field::Float(v) => Self::encode_number(*v),
             Field::String(v) => v.to_owned(),
         }
     }
@@ -204,16 +299,16 @@ impl TryFrom<&serde_json::Value> for Field {
 
     fn try_from(value: &serde_json::Value) -> Result<Self, Self::Error> {
         match value {
-            serde_json::Value::Null => todo!(),
-            serde_json::Value::Bool(_) => todo!(),
+            serde_json::Value::Null => Err(()),
+            serde_json::Value::Bool(_) => Err(()),
             serde_json::Value::Number(v) => v
                 .as_i64()
                 .map(Self::Int)
                 .or_else(|| v.as_f64().map(Self::Float))
                 .ok_or(()),
             serde_json::Value::String(v) => Ok(Self::String(v.as_str().to_string())),
-            serde_json::Value::Array(_) => todo!(),
-            serde_json::Value::Object(_) => todo!(),
+            serde_json::Value::Array(_) => Err(()),
+            serde_json::Value::Object(_) => Err(()),
         }
     }
 }
@@ -224,17 +319,17 @@ impl TryFrom<&serde_yml::Value> for Field {
 
     fn try_from(value: &serde_yml::Value) -> Result<Self, Self::Error> {
         match value {
-            serde_yml::Value::Null => todo!(),
-            serde_yml::Value::Bool(_) => todo!(),
+            serde_yml::Value::Null => Err(()),
+            serde_yml::Value::Bool(_) => Err(()),
             serde_yml::Value::Number(v) => v
                 .as_i64()
                 .map(Self::Int)
                 .or_else(|| v.as_f64().map(Self::Float))
                 .ok_or(()),
             serde_yml::Value::String(v) => Ok(Self::String(v.as_str().to_string())),
-            serde_yml::Value::Sequence(_vec) => todo!(),
-            serde_yml::Value::Mapping(_mapping) => todo!(),
-            serde_yml::Value::Tagged(_tagged_value) => todo!(),
+            serde_yml::Value::Sequence(_vec) => Err(()),
+            serde_yml::Value::Mapping(_mapping) => Err(()),
+            serde_yml::Value::Tagged(_tagged_value) => Err(()),
         }
     }
 }
@@ -245,15 +340,198 @@ impl<'a> TryFrom<&pot::Value<'a>> for Field {
 
     fn try_from(value: &pot::Value) -> Result<Self, Self::Error> {
         match value {
-            pot::Value::None => todo!(),
-            pot::Value::Unit => todo!(),
-            pot::Value::Bool(_) => todo!(),
+            pot::Value::None => Err(()),
+            pot::Value::Unit => Err(()),
+            pot::Value::Bool(_) => Err(()),
             pot::Value::Integer(i) => i.as_i64().map(Self::Int).map_err(|_| ()),
             pot::Value::Float(f) => Ok(Self::Float(f.as_f64())),
-            pot::Value::Bytes(_cow) => todo!(),
+            pot::Value::Bytes(_cow) => Err(()),
             pot::Value::String(s) => Ok(Self::String(s.to_string())),
-            pot::Value::Sequence(_vec) => todo!(),
-            pot::Value::Mappings(_vec) => todo!(),
+            pot::Value::Sequence(_vec) => Err(()),
+            pot::Value::Mappings(_vec) => Err(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use git2::{IndexEntry, IndexTime, Oid};
+    use rand::Rng;
+
+    use crate::error::ParseFieldError;
+    use crate::field::{Field, FieldType};
+
+    fn index_entry(field: &Field) -> IndexEntry {
+        IndexEntry {
+            ctime: IndexTime::new(0, 0),
+            mtime: IndexTime::new(0, 0),
+            dev: 0,
+            ino: field.to_ino_number(),
+            mode: 0o100644,
+            uid: 0,
+            gid: 0,
+            file_size: 0,
+            id: Oid::zero(),
+            flags: 0,
+            flags_extended: 0,
+            path: format!("{}/{:16x}", field.to_index_value(), u64::MAX).into_bytes(),
+        }
+    }
+
+    fn assert_encoded_order(a: Field, b: Field) {
+        assert_eq!(
+            a.to_index_value().cmp(&b.to_index_value()),
+            // partial_cmp of Field compares the other way around
+            b.partial_cmp(&a).unwrap(),
+            "{:?} and {:?}",
+            a,
+            b
+        );
+    }
+
+    #[test]
+    fn test_from_serde_numbers() {
+        assert_eq!(Field::from_serde(&json!(42)), Some(Field::Int(42)));
+        assert_eq!(Field::from_serde(&json!(-7)), Some(Field::Int(-7)));
+        assert_eq!(Field::from_serde(&json!(42.0)), Some(Field::Float(42.0)));
+        assert_eq!(Field::from_serde(&json!(-0.5)), Some(Field::Float(-0.5)));
+        assert_eq!(
+            Field::from_serde(&serde_json::from_str("1e3").unwrap()),
+            Some(Field::Float(1000.0))
+        );
+        assert_eq!(
+            Field::from_serde(&json!(u64::MAX)),
+            Some(Field::Float(u64::MAX as f64))
+        );
+    }
+
+    #[test]
+    fn test_from_serde_string() {
+        assert_eq!(
+            Field::from_serde(&json!("yamabiko")),
+            Some(Field::String(String::from("yamabiko")))
+        );
+        assert_eq!(
+            Field::from_serde(&json!("")),
+            Some(Field::String(String::new()))
+        );
+    }
+
+    #[test]
+    fn test_from_serde_unsupported() {
+        assert_eq!(Field::from_serde(&json!(null)), None);
+        assert_eq!(Field::from_serde(&json!(true)), None);
+        assert_eq!(Field::from_serde(&json!([1, 2])), None);
+        assert_eq!(Field::from_serde(&json!({"a": 1})), None);
+    }
+
+    #[test]
+    fn test_parse_with_hint() {
+        assert_eq!(Field::parse("42", FieldType::Int), Ok(Field::Int(42)));
+        assert_eq!(Field::parse("-3", FieldType::Int), Ok(Field::Int(-3)));
+        assert_eq!(Field::parse("007", FieldType::Int), Ok(Field::Int(7)));
+        assert_eq!(
+            Field::parse("1e5", FieldType::Int),
+            Err(ParseFieldError::NotAnInt(String::from("1e5")))
+        );
+        assert_eq!(Field::parse("2.5", FieldType::Float), Ok(Field::Float(2.5)));
+        assert_eq!(
+            Field::parse("1e5", FieldType::Float),
+            Ok(Field::Float(100000.0))
+        );
+        assert_eq!(Field::parse("42", FieldType::Float), Ok(Field::Float(42.0)));
+        assert_eq!(
+            Field::parse("NaN", FieldType::Float),
+            Err(ParseFieldError::NotAFloat(String::from("NaN")))
+        );
+        assert_eq!(
+            Field::parse("hello", FieldType::Int),
+            Err(ParseFieldError::NotAnInt(String::from("hello")))
+        );
+        assert_eq!(
+            Field::parse("007", FieldType::String),
+            Ok(Field::String(String::from("007")))
+        );
+        assert_eq!("float".parse(), Ok(FieldType::Float));
+        assert_eq!(FieldType::Int.to_string(), "int");
+    }
+
+    #[test]
+    fn test_infer() {
+        assert_eq!(Field::infer("42"), Field::Int(42));
+        assert_eq!(Field::infer("-42"), Field::Int(-42));
+        assert_eq!(Field::infer("0"), Field::Int(0));
+        assert_eq!(Field::infer("2.5"), Field::Float(2.5));
+        assert_eq!(Field::infer("0.5"), Field::Float(0.5));
+        assert_eq!(Field::infer("1e5"), Field::Float(100000.0));
+        assert_eq!(
+            Field::infer("99999999999999999999"),
+            Field::Float(99999999999999999999.0)
+        );
+        assert_eq!(Field::infer("007"), Field::String(String::from("007")));
+        assert_eq!(Field::infer("-01"), Field::String(String::from("-01")));
+        assert_eq!(Field::infer("inf"), Field::String(String::from("inf")));
+        assert_eq!(Field::infer("hello"), Field::String(String::from("hello")));
+        assert_eq!(Field::infer(""), Field::String(String::new()));
+    }
+
+    #[test]
+    fn test_index_value_order_across_zero() {
+        let ordered = [
+            Field::Float(f64::NEG_INFINITY),
+            Field::Int(i64::MIN),
+            Field::Float(-1e10),
+            Field::Int(-5),
+            Field::Float(-0.5),
+            Field::Float(-f64::MIN_POSITIVE),
+            Field::Int(0),
+            Field::Float(f64::MIN_POSITIVE),
+            Field::Float(0.5),
+            Field::Int(1),
+            Field::Int(i64::MAX),
+            Field::Float(f64::INFINITY),
+        ];
+        for pair in ordered.windows(2) {
+            assert!(pair[0].to_index_value() < pair[1].to_index_value());
+        }
+        assert_eq!(
+            Field::Float(-0.0).to_index_value(),
+            Field::Int(0).to_index_value()
+        );
+    }
+
+    #[test]
+    fn test_index_value_order_random_pairs() {
+        let mut rng = rand::thread_rng();
+        // integers which survive the trip through f64
+        let int = |rng: &mut rand::rngs::ThreadRng| rng.gen_range(-(1_i64 << 53)..=1 << 53);
+        let float = |rng: &mut rand::rngs::ThreadRng| {
+            let magnitude = 10_f64.powi(rng.gen_range(-20..20));
+            rng.gen_range(-1.0..1.0) * magnitude
+        };
+        for _ in 0..10_000 {
+            assert_encoded_order(Field::Int(int(&mut rng)), Field::Int(int(&mut rng)));
+            assert_encoded_order(Field::Float(float(&mut rng)), Field::Float(float(&mut rng)));
+            assert_encoded_order(Field::Int(int(&mut rng)), Field::Float(float(&mut rng)));
+            let small = rng.gen_range(-3..=3);
+            assert_encoded_order(Field::Int(small), Field::Int(rng.gen_range(-3..=3)));
+        }
+    }
+
+    #[test]
+    fn test_index_value_round_trip() {
+        for field in [
+            Field::Int(0),
+            Field::Int(-42),
+            Field::Int(1 << 53),
+            Field::Float(-2.5),
+            Field::Float(1e-300),
+            Field::Float(f64::NEG_INFINITY),
+            Field::String(String::from("a/b")),
+        ] {
+            assert_eq!(Field::from_index_entry(&index_entry(&field)), Some(field));
         }
     }
 }
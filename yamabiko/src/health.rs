@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+
+use git2::{BranchType, Repository};
+use serde::Serialize;
+
+use crate::replica::{self, ReplicaStatus};
+use crate::{transaction, warn, Collection};
+
+/// Cheap summary of the state of a collection, see `Collection::health`.
+/// Times are in seconds since the Unix epoch.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct HealthSnapshot {
+    /// Commit main points at, None if main couldn't be read.
+    pub last_commit: Option<String>,
+    /// When the commit main points at was written.
+    pub last_commit_time: Option<i64>,
+    /// Status of the replicas configured in the repository, by name. A replica whose
+    /// status couldn't be read is left out.
+    pub replicas: BTreeMap<String, ReplicaStatus>,
+    /// Number of transactions which weren't written to for longer than the threshold
+    /// (see `Collection::with_stale_transaction_age`).
+    pub stale_transactions: usize,
+    /// HEAD points at main and main points at a commit present in the repository.
+    pub refs_ok: bool,
+}
+
+pub(crate) fn health(collection: &Collection) -> HealthSnapshot {
+    let repo = collection.repository();
+    let max_age = collection
+        .stale_transaction_age
+        .as_secs()
+        .min(i64::MAX as u64) as i64;
+    let threshold = collection.clock.now().saturating_sub(max_age);
+    let main = repo
+        .find_branch("main", BranchType::Local)
+        .and_then(|branch| branch.get().peel_to_commit());
+    let (last_commit, last_commit_time) = match &main {
+        Ok(commit) => (Some(commit.id().to_string()), Some(commit.time().seconds())),
+        Err(_) => (None, None),
+    };
+    let replicas = match replica::configured_in(repo) {
+        Ok(names) => names
+            .into_iter()
+            .filter_map(|name| {
+                let status = replica::read_status(repo, &name).ok()?;
+                Some((name, status))
+            })
+            .collect(),
+        Err(_err) => {
+            warn!(
+                "Couldn't read the replicas from the configuration: {}",
+                _err
+            );
+            BTreeMap::new()
+        }
+    };
+    HealthSnapshot {
+        last_commit,
+        last_commit_time,
+        replicas,
+        stale_transactions: stale_transactions(repo, threshold),
+        refs_ok: main.is_ok() && refs_ok(repo),
+    }
+}
+
+/// Transactions whose tip was written before the threshold
+fn stale_transactions(repo: &Repository, threshold: i64) -> usize {
+    let Ok(branches) = repo.branches(Some(BranchType::Local)) else {
+        return 0;
+    };
+    branches
+        .flatten()
+        .filter(|(branch, _)| {
+            branch
+                .name()
+                .ok()
+                .flatten()
+                .is_some_and(|name| transaction::is_transaction(repo, name))
+        })
+        .filter_map(|(branch, _)| branch.get().peel_to_commit().ok())
+        .filter(|tip| tip.time().seconds() < threshold)
+        .count()
+}
+
+fn refs_ok(repo: &Repository) -> bool {
+    let head = repo.find_reference("HEAD");
+    let head_on_main = head
+        .as_ref()
+        .is_ok_and(|head| head.symbolic_target() == Some("refs/heads/main"));
+    let main_exists = repo
+        .refname_to_id("refs/heads/main")
+        .is_ok_and(|id| repo.odb().is_ok_and(|odb| odb.exists(id)));
+    head_on_main && main_exists
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use rstest::rstest;
+
+    use crate::{
+        clock::{Clock, MockClock, SystemClock},
+        serialization::DataFormat,
+        test::*,
+        OperationTarget,
+    };
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_health(#[case] data_format: DataFormat) {
+        let pair = create_linked_pair(data_format);
+        let db = pair
+            .primary
+            .with_stale_transaction_age(Duration::from_secs(0));
+        let health = db.health();
+        assert!(health.refs_ok);
+        assert_eq!(health.stale_transactions, 0);
+        assert_eq!(health.replicas.len(), 1);
+        assert_eq!(health.replicas["test"].last_success, None);
+
+        let commit = db
+            .set(
+                "a",
+                SampleDbStruct::new(String::from("a")),
+                OperationTarget::Main,
+            )
+            .unwrap();
+        let t = db.new_transaction(None).unwrap();
+        db.set(
+            "b",
+            SampleDbStruct::new(String::from("b")),
+            OperationTarget::Transaction(&t),
+        )
+        .unwrap();
+        // branches which aren't transactions don't count
+        let repo = db.repository();
+        repo.branch(
+            "archive",
+            &repo.head().unwrap().peel_to_commit().unwrap(),
+            false,
+        )
+        .unwrap();
+        pair.replicator.replicate().unwrap();
+        // the transaction is a second old now
+        let db = db.with_clock(Arc::new(MockClock::new(SystemClock.now() + 1)));
+
+        let health = db.health();
+        assert_eq!(health.last_commit, Some(commit.to_string()));
+        assert_eq!(
+            health.last_commit_time,
+            Some(
+                db.repository()
+                    .find_commit(commit)
+                    .unwrap()
+                    .time()
+                    .seconds()
+            )
+        );
+        assert!(health.refs_ok);
+        assert_eq!(health.stale_transactions, 1);
+        let status = &health.replicas["test"];
+        assert!(status.last_success.is_some());
+        assert_eq!(status.last_error, None);
+        assert_eq!(status, &pair.replicator.status().unwrap());
+
+        let json = serde_json::to_value(&health).unwrap();
+        assert_eq!(json["last_commit"], commit.to_string());
+        assert_eq!(json["stale_transactions"], 1);
+    }
+}
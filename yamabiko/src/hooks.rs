@@ -0,0 +1,102 @@
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+use git2::Oid;
+
+use crate::error::HookError;
+use crate::{warn, ChangeKind};
+
+/// Key changed by a write
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct WrittenKey {
+    pub key: String,
+    pub kind: ChangeKind,
+    /// Size of the stored value in bytes, None if the key was deleted.
+    pub size: Option<u64>,
+}
+
+/// Write about to be committed, given to the hooks registered with `Collection::on_pre_commit`
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct PendingWrite {
+    /// Branch the commit is going to be written to.
+    pub branch: String,
+    /// Keys changed by the write, compared to the current tip of the branch. Documents
+    /// of namespaces and attachments are not listed.
+    pub keys: Vec<WrittenKey>,
+}
+
+/// Write which has just been committed, given to the hooks registered
+/// with `Collection::on_post_commit`
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CommittedWrite {
+    /// Branch the commit was written to.
+    pub branch: String,
+    /// Keys changed by the write, see `PendingWrite::keys`.
+    pub keys: Vec<WrittenKey>,
+    /// Commit the branch points at now.
+    pub commit: Oid,
+}
+
+type PreCommitHook = Arc<dyn Fn(&PendingWrite) -> Result<(), HookError> + Send + Sync>;
+type PostCommitHook = Arc<dyn Fn(&CommittedWrite) + Send + Sync>;
+
+/// Hooks of a Collection, run in the order they were registered in
+#[derive(Clone, Default)]
+pub(crate) struct Hooks {
+    pre_commit: Vec<PreCommitHook>,
+    post_commit: Vec<PostCommitHook>,
+}
+
+impl Hooks {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pre_commit.is_empty() && self.post_commit.is_empty()
+    }
+
+    pub(crate) fn add_pre_commit(&mut self, hook: PreCommitHook) {
+        self.pre_commit.push(hook);
+    }
+
+    pub(crate) fn add_post_commit(&mut self, hook: PostCommitHook) {
+        self.post_commit.push(hook);
+    }
+
+    /// Stops at the first hook which fails (or panics)
+    pub(crate) fn pre_commit(&self, write: &PendingWrite) -> Result<(), HookError> {
+        for hook in self.pre_commit.iter() {
+            match panic::catch_unwind(AssertUnwindSafe(|| hook(write))) {
+                Ok(result) => result?,
+                Err(panic) => {
+                    return Err(HookError(format!(
+                        "the hook panicked: {}",
+                        panic_message(panic.as_ref())
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The write is done already, so a hook which panics is only logged
+    pub(crate) fn post_commit(&self, write: &CommittedWrite) {
+        for hook in self.post_commit.iter() {
+            if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(|| hook(write))) {
+                warn!(
+                    "Post-commit hook panicked on {}: {}",
+                    write.commit,
+                    panic_message(panic.as_ref())
+                );
+            }
+        }
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => match panic.downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => String::from("unknown panic"),
+        },
+    }
+}
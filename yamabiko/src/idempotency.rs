@@ -0,0 +1,99 @@
+use std::collections::BTreeMap;
+
+use git2::{ErrorCode, Oid, Repository, Signature};
+use serde::{Deserialize, Serialize};
+
+use crate::meta;
+
+/// Key in the metadata (see `Collection::metadata`) under which the tokens
+/// of the recent idempotent writes are kept
+pub(crate) const TOKENS_KEY: &str = "idempotency.tokens";
+
+/// Reference the tokens were kept under before they moved to the metadata,
+/// still read until the first token is recorded
+const LEGACY_TOKENS_REF: &str = "refs/yamabiko/idempotency";
+
+/// Key in the config of the repository under which the retention of the tokens is kept
+pub(crate) const RETENTION_CONFIG: &str = "yamabiko.idempotencyretention";
+
+/// How long the tokens are kept if the retention wasn't set, in seconds (one day)
+pub const DEFAULT_RETENTION: u64 = 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize)]
+struct Token {
+    commit: String,
+    time: i64,
+}
+
+fn parse(content: &[u8]) -> Result<BTreeMap<String, Token>, git2::Error> {
+    serde_json::from_slice(content)
+        .map_err(|err| git2::Error::from_str(&format!("invalid idempotency tokens: {}", err)))
+}
+
+fn legacy_tokens(repo: &Repository) -> Result<BTreeMap<String, Token>, git2::Error> {
+    match repo.find_reference(LEGACY_TOKENS_REF) {
+        Ok(reference) => parse(reference.peel_to_blob()?.content()),
+        Err(err) if err.code() == ErrorCode::NotFound => Ok(BTreeMap::new()),
+        Err(err) => Err(err),
+    }
+}
+
+fn tokens(
+    repo: &Repository,
+    current: Option<&[u8]>,
+) -> Result<BTreeMap<String, Token>, git2::Error> {
+    match current {
+        Some(content) => parse(content),
+        None => legacy_tokens(repo),
+    }
+}
+
+/// Commit of the write made with the token, unless it's older than `retention`
+pub(crate) fn recorded(
+    repo: &Repository,
+    token: &str,
+    now: i64,
+    retention: u64,
+) -> Result<Option<Oid>, git2::Error> {
+    let current = meta::get(repo, TOKENS_KEY)?;
+    match tokens(repo, current.as_deref())?.get(token) {
+        Some(recorded) if !expired(recorded, now, retention) => {
+            Ok(Some(Oid::from_str(&recorded.commit)?))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Remember the commit of the write made with the token, dropping the expired tokens.
+/// Recorded with a compare-and-swap, so concurrent writers don't drop each other's tokens.
+pub(crate) fn record(
+    repo: &Repository,
+    signature: &Signature,
+    token: &str,
+    commit: Oid,
+    now: i64,
+    retention: u64,
+) -> Result<(), git2::Error> {
+    meta::update(repo, signature, TOKENS_KEY, |current| {
+        let mut tokens = tokens(repo, current)?;
+        tokens.retain(|_, recorded| !expired(recorded, now, retention));
+        tokens.insert(
+            token.to_string(),
+            Token {
+                commit: commit.to_string(),
+                time: now,
+            },
+        );
+        // unwrap: a map of strings and numbers always serializes
+        Ok(Some(serde_json::to_vec(&tokens).unwrap()))
+    })?;
+    match repo.find_reference(LEGACY_TOKENS_REF) {
+        Ok(mut reference) => reference.delete(),
+        Err(err) if err.code() == ErrorCode::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+fn expired(token: &Token, now: i64, retention: u64) -> bool {
+    now.saturating_sub(token.time) >= retention.min(i64::MAX as u64) as i64
+}
@@ -1,10 +1,169 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::{fmt::Display, path::Path};
 
 use git2::{Index as GitIndex, IndexEntry, IndexTime, Oid, Repository};
 
-use crate::debug;
+use crate::error::IndexNameError;
 use crate::field::Field;
+use crate::{debug, RepositoryAbstraction};
+
+/// Suffix of the files in `.index` locked while an index is written.
+/// git uses `.lock` for its own lock files already.
+const LOCK_SUFFIX: &str = ".flock";
+/// Refs holding the snapshots of the index files, which are pushed to the replicas along with main
+pub(crate) const SNAPSHOT_REFS: &str = "refs/yamabiko/indexes/";
+/// Refs to the snapshots the local index files are at (or past), not pushed anywhere
+const SEEN_SNAPSHOT_REFS: &str = "refs/yamabiko/indexes-seen/";
+/// Suffix of the files with the entries of a `KeyedIndex` ordered by the oids they point at
+const KEYS_SUFFIX: &str = ".keys";
+
+/// git index whose entries can be looked up by the oids they point at (the hashes of the keys),
+/// not only by their paths. Every entry `<path>` has a counterpart `<oid>/<path>` in a second
+/// file next to it (`<name>.keys`), so the entries of a key are found with a binary search
+/// instead of a scan. The second file also holds the checksum git writes at the end of the
+/// first one, and is rebuilt from it if the checksum doesn't match - e.g. if it's missing or
+/// the first file was replaced. Both are written at once, with the lock of the first one held
+/// (see `lock_file`).
+pub(crate) struct KeyedIndex {
+    path: PathBuf,
+    entries: GitIndex,
+    keys: GitIndex,
+}
+
+impl KeyedIndex {
+    /// Prefix of the path of the entry with the checksum, which sorts after all the oids
+    const CHECKSUM_PREFIX: &str = "~checksum/";
+
+    pub(crate) fn open(path: &Path) -> Result<Self, git2::Error> {
+        let entries = GitIndex::open(path)?;
+        let mut keys = GitIndex::open(&Self::keys_path(path))?;
+        let checksum = Self::checksum(path)?;
+        if Self::stored_checksum(&keys).as_deref() != Some(checksum.as_str())
+            || keys.len() != entries.len() + 1
+        {
+            debug!("rebuilding the keys of {}", path.display());
+            keys.clear()?;
+            for entry in entries.iter() {
+                keys.add(&Self::key_entry(entry.id, &entry.path))?;
+            }
+            Self::store_checksum(&mut keys, &checksum)?;
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            entries,
+            keys,
+        })
+    }
+
+    fn keys_path(path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(KEYS_SUFFIX);
+        name.into()
+    }
+
+    /// The trailing checksum of the index file, empty if there is no file
+    fn checksum(path: &Path) -> Result<String, git2::Error> {
+        let io_error = |err: std::io::Error| git2::Error::from_str(&err.to_string());
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(String::new()),
+            Err(err) => return Err(io_error(err)),
+        };
+        let mut checksum = [0; 20];
+        file.seek(SeekFrom::End(-(checksum.len() as i64)))
+            .and_then(|_| file.read_exact(&mut checksum))
+            .map_err(io_error)?;
+        Ok(checksum.iter().map(|b| format!("{:02x}", b)).collect())
+    }
+
+    fn stored_checksum(keys: &GitIndex) -> Option<String> {
+        let position = keys.find_prefix(Self::CHECKSUM_PREFIX).ok()?;
+        let path = keys.get(position)?.path;
+        Some(String::from_utf8_lossy(&path[Self::CHECKSUM_PREFIX.len()..]).to_string())
+    }
+
+    fn store_checksum(keys: &mut GitIndex, checksum: &str) -> Result<(), git2::Error> {
+        if let Some(stored) = Self::stored_checksum(keys) {
+            keys.remove_path(Path::new(&format!("{}{}", Self::CHECKSUM_PREFIX, stored)))?;
+        }
+        let path = format!("{}{}", Self::CHECKSUM_PREFIX, checksum);
+        keys.add(&Self::key_entry(Oid::zero(), path.as_bytes()))
+    }
+
+    /// Entry `<oid>/<path>` of the keys, or just `path` for the zero oid
+    fn key_entry(oid: Oid, path: &[u8]) -> IndexEntry {
+        let path = match oid.is_zero() {
+            true => path.to_vec(),
+            false => [format!("{}/", oid).as_bytes(), path].concat(),
+        };
+        IndexEntry {
+            ctime: IndexTime::new(0, 0),
+            mtime: IndexTime::new(0, 0),
+            dev: 0,
+            ino: 0,
+            mode: 0o100644,
+            uid: 0,
+            gid: 0,
+            file_size: 0,
+            id: oid,
+            flags: 0,
+            flags_extended: 0,
+            path,
+        }
+    }
+
+    pub(crate) fn entries(&self) -> &GitIndex {
+        &self.entries
+    }
+
+    /// Add the entry, replacing the one with the same path
+    pub(crate) fn add(&mut self, entry: &IndexEntry) -> Result<(), git2::Error> {
+        let path = String::from_utf8_lossy(&entry.path).to_string();
+        if let Some(replaced) = self.entries.get_path(Path::new(&path), 0) {
+            self.keys
+                .remove_path(Path::new(&format!("{}/{}", replaced.id, path)))?;
+        }
+        self.entries.add(entry)?;
+        self.keys.add(&Self::key_entry(entry.id, &entry.path))
+    }
+
+    /// Remove the entries pointing at the oid, returning how many there were
+    pub(crate) fn remove(&mut self, oid: Oid) -> Result<usize, git2::Error> {
+        let prefix = format!("{}/", oid);
+        let Ok(mut position) = self.keys.find_prefix(&prefix) else {
+            return Ok(0);
+        };
+        let mut paths = Vec::new();
+        while let Some(entry) = self.keys.get(position) {
+            if !entry.path.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            paths.push(String::from_utf8_lossy(&entry.path).to_string());
+            position += 1;
+        }
+        for path in paths.iter() {
+            debug!("removing an entry: {}", path);
+            self.keys.remove_path(Path::new(path))?;
+            self.entries.remove_path(Path::new(&path[prefix.len()..]))?;
+        }
+        Ok(paths.len())
+    }
+
+    pub(crate) fn clear(&mut self) -> Result<(), git2::Error> {
+        self.entries.clear()?;
+        self.keys.clear()
+    }
+
+    pub(crate) fn write(&mut self) -> Result<(), git2::Error> {
+        self.entries.write()?;
+        Self::store_checksum(&mut self.keys, &Self::checksum(&self.path)?)?;
+        self.keys.write()
+    }
+}
 
 #[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
 pub enum IndexType {
@@ -40,39 +199,390 @@ impl Display for IndexType {
     }
 }
 
+/// How the values are normalized before they are put in the index,
+/// see `Collection::create_index_with`
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy, Default)]
+pub struct IndexOptions {
+    /// Index strings in lowercase (as given by `str::to_lowercase`, which isn't locale-aware),
+    /// so that `Banana` sorts after `apple`. Everything answered with the index works on
+    /// the lowercased values: scans yield them instead of the values stored in the documents,
+    /// and queries compare the (lowercased) value they're given case-insensitively -
+    /// `q("name", Equal, "APPLE")` matches `Apple` and `apple`, while ranges treat `Banana`
+    /// as greater than `apple`. Queries on fields without an index still compare
+    /// the values as they are. Numbers are indexed as usual.
+    pub case_insensitive: bool,
+}
+
+impl IndexOptions {
+    /// Marker following the type in the name of the index file
+    const CASE_INSENSITIVE: &str = "-ci";
+
+    fn name_suffix(&self) -> &'static str {
+        match self.case_insensitive {
+            true => Self::CASE_INSENSITIVE,
+            false => "",
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Order {
+    Ascending,
+    Descending,
+}
+
+/// Position in an index, right after the entry it was taken at
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct IndexCursor {
+    path: Vec<u8>,
+}
+
+impl IndexCursor {
+    /// Opaque textual form of the cursor, to be handed out to clients
+    pub fn to_token(&self) -> String {
+        self.path.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Parse a cursor from `to_token`. Returns `None` if the token is malformed.
+    pub fn from_token(token: &str) -> Option<Self> {
+        if !token.len().is_multiple_of(2) {
+            return None;
+        }
+        let path = (0..token.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(token.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        Some(Self { path })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct IndexPage {
+    pub entries: Vec<(Field, Oid)>,
+    /// Cursor to pass to get the next page. `None` if this is the last one.
+    pub next: Option<IndexCursor>,
+}
+
+/// Lazy iterator over an index, returned by `Index::scan`
+pub struct IndexScan {
+    git_index: GitIndex,
+    order: Order,
+    /// Positions of the rest of the current group of entries sharing a value, in order
+    group: VecDeque<usize>,
+    /// Where the next group starts (ascending) or ends, exclusively (descending)
+    frontier: usize,
+    last_cursor: Option<IndexCursor>,
+}
+
+impl IndexScan {
+    fn new(git_index: GitIndex, order: Order, cursor: Option<&IndexCursor>) -> Self {
+        let mut scan = Self {
+            frontier: match order {
+                Order::Ascending => 0,
+                Order::Descending => git_index.len(),
+            },
+            git_index,
+            order,
+            group: VecDeque::new(),
+            last_cursor: cursor.cloned(),
+        };
+        if let Some(cursor) = cursor {
+            scan.seek(&cursor.path);
+        }
+        scan
+    }
+
+    /// Cursor pointing right after the last entry returned so far
+    pub fn cursor(&self) -> Option<IndexCursor> {
+        self.last_cursor.clone()
+    }
+
+    fn path(&self, position: usize) -> Option<Vec<u8>> {
+        self.git_index.get(position).map(|e| e.path)
+    }
+
+    fn value(path: &[u8]) -> &[u8] {
+        path.rsplitn(2, |b| *b == b'/').nth(1).unwrap_or(path)
+    }
+
+    fn same_value(&self, position: usize, value: &[u8]) -> bool {
+        self.path(position)
+            .is_some_and(|path| Self::value(&path) == value)
+    }
+
+    /// First position with a path not lower than the given one
+    fn lower_bound(&self, path: &[u8]) -> usize {
+        let (mut low, mut high) = (0, self.git_index.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match self.path(mid) {
+                Some(p) if p.as_slice() < path => low = mid + 1,
+                _ => high = mid,
+            }
+        }
+        low
+    }
+
+    fn seek(&mut self, path: &[u8]) {
+        let value = Self::value(path);
+        let position = self.lower_bound(path);
+        let mut group_start = position;
+        while group_start > 0 && self.same_value(group_start - 1, value) {
+            group_start -= 1;
+        }
+        let mut group_end = position;
+        while self.same_value(group_end, value) {
+            group_end += 1;
+        }
+        let past_cursor = match self.path(position) {
+            Some(p) if p == path => position + 1,
+            _ => position,
+        };
+        // within a value, newer entries get lower counters and so come first in the index
+        match self.order {
+            Order::Ascending => {
+                self.group = (group_start..position).rev().collect();
+                self.frontier = group_end;
+            }
+            Order::Descending => {
+                self.group = (past_cursor..group_end).collect();
+                self.frontier = group_start;
+            }
+        }
+    }
+
+    fn load_next_group(&mut self) -> bool {
+        match self.order {
+            Order::Ascending => {
+                let start = self.frontier;
+                let Some(path) = self.path(start) else {
+                    return false;
+                };
+                let value = Self::value(&path).to_vec();
+                let mut end = start + 1;
+                while self.same_value(end, &value) {
+                    end += 1;
+                }
+                self.group = (start..end).rev().collect();
+                self.frontier = end;
+            }
+            Order::Descending => {
+                if self.frontier == 0 {
+                    return false;
+                }
+                let end = self.frontier;
+                // unwrap: frontier never goes past the length of the index
+                let path = self.path(end - 1).unwrap();
+                let value = Self::value(&path).to_vec();
+                let mut start = end - 1;
+                while start > 0 && self.same_value(start - 1, &value) {
+                    start -= 1;
+                }
+                self.group = (start..end).collect();
+                self.frontier = start;
+            }
+        }
+        true
+    }
+}
+
+impl Iterator for IndexScan {
+    type Item = (Field, Oid);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            while let Some(position) = self.group.pop_front() {
+                let Some(entry) = self.git_index.get(position) else {
+                    continue;
+                };
+                let Some(field) = Field::from_index_entry(&entry) else {
+                    continue;
+                };
+                self.last_cursor = Some(IndexCursor { path: entry.path });
+                return Some((field, entry.id));
+            }
+            if !self.load_next_group() {
+                return None;
+            }
+        }
+    }
+}
+
+/// Document which couldn't be added to an index when it was filled from the documents
+/// already on main, see `Collection::create_index`
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SkippedDocument {
+    pub key: String,
+    /// Why the document couldn't be parsed
+    pub reason: String,
+}
+
+/// Indexed values along with the hashes of the keys they belong to
+pub(crate) type Entries = Vec<(Field, Oid)>;
+
+/// Entries to replace in several indexes, so that each index file is written once
+/// instead of once per entry
+#[derive(Default)]
+pub(crate) struct IndexUpdates<'i> {
+    updates: HashMap<&'i Index, IndexUpdate>,
+}
+
+#[derive(Default)]
+struct IndexUpdate {
+    removed: Vec<Oid>,
+    added: Vec<(Field, Oid)>,
+}
+
+impl<'i> IndexUpdates<'i> {
+    /// Drop the entries of the key from the index, replacing them with the new values
+    pub(crate) fn replace(&mut self, index: &'i Index, hash: Oid, values: Vec<Field>) {
+        let update = self.updates.entry(index).or_default();
+        update.removed.push(hash);
+        update
+            .added
+            .extend(values.into_iter().map(|value| (value, hash)));
+    }
+
+    pub(crate) fn apply(self, repo: &Repository) -> Result<(), git2::Error> {
+        for (index, update) in self.updates {
+            index.delete_entries(repo, &update.removed)?;
+            index.add_entries(repo, &update.added)?;
+        }
+        Ok(())
+    }
+}
+
+/// Exclusive lock of the file with the name in `.index`, see `Index::lock`.
+/// The name may point into a subdirectory, like the indexes of a namespace do.
+pub(crate) fn lock_file(repo: &Repository, name: &str) -> Result<File, std::io::Error> {
+    let path = repo
+        .path()
+        .join(".index")
+        .join(format!("{}{}", name, LOCK_SUFFIX));
+    // unwrap: the path is always inside `.index`
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+    file.lock()?;
+    Ok(file)
+}
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub struct Index {
     name: String,
     indexed_field: String,
     kind: IndexType,
+    options: IndexOptions,
 }
 
 impl Index {
     pub fn new(name: &str, indexed_field: &str, kind: IndexType) -> Self {
+        Self::new_with(name, indexed_field, kind, IndexOptions::default())
+    }
+
+    pub fn new_with(
+        name: &str,
+        indexed_field: &str,
+        kind: IndexType,
+        options: IndexOptions,
+    ) -> Self {
         Self {
             name: name.to_string(),
             indexed_field: indexed_field.to_string(),
             kind,
+            options,
         }
     }
 
-    pub fn from_name(name: &str) -> Result<Self, String> {
-        let token_list = name.rsplit_once(".").unwrap().0.rsplit_once("#");
-        if let Some(tokens) = token_list {
-            return Ok(Self::new(name, tokens.0, IndexType::from_str(tokens.1)?));
+    /// Name of the index file of the field, see `from_name`
+    pub(crate) fn file_name(field: &str, kind: IndexType, options: IndexOptions) -> String {
+        format!("{}#{}{}.index", field, kind, options.name_suffix())
+    }
+
+    /// Parse the name of an index file: `<field>#<type>.<suffix>`, e.g. `age#numeric.index`.
+    /// The suffix follows the last `.` and the type (`numeric`, `sequential` or `collection`)
+    /// follows the last `#` before it, so the field itself may contain both characters.
+    /// A type ending with `-ci` marks a case-insensitive index (see `IndexOptions`),
+    /// e.g. `name#sequential-ci.index`.
+    pub fn from_name(name: &str) -> Result<Self, IndexNameError> {
+        let (stem, _suffix) = name.rsplit_once(".").ok_or(IndexNameError::MissingSuffix)?;
+        let (field, kind) = stem.rsplit_once("#").ok_or(IndexNameError::MissingType)?;
+        if field.is_empty() {
+            return Err(IndexNameError::EmptyField);
         }
-        Err(String::from("No such index"))
+        let (kind, case_insensitive) = match kind.strip_suffix(IndexOptions::CASE_INSENSITIVE) {
+            Some(kind) => (kind, true),
+            None => (kind, false),
+        };
+        let kind =
+            IndexType::from_str(kind).map_err(|_| IndexNameError::UnknownType(kind.to_string()))?;
+        Ok(Self::new_with(
+            name,
+            field,
+            kind,
+            IndexOptions { case_insensitive },
+        ))
     }
 
     pub fn name(&self) -> &str {
         self.name.as_str()
     }
 
+    /// Index with the file at the path in `.index` - a name like for `from_name`,
+    /// possibly in the directory of a namespace (see `in_namespace`)
+    fn from_path(path: &str) -> Result<Self, IndexNameError> {
+        match path.rsplit_once('/') {
+            Some((tree_path, name)) => Ok(Self::from_name(name)?.in_namespace(tree_path)),
+            None => Self::from_name(path),
+        }
+    }
+
+    /// Name of the file, without the directory of the namespace
+    fn base_name(&self) -> &str {
+        self.name.rsplit('/').next().unwrap_or(&self.name)
+    }
+
+    /// The same index over the documents of the namespace kept in the tree at the path
+    /// (see `Namespace`). Its file is in a directory of `.index` named after the tree,
+    /// so the entries of a namespace never mix with the ones of the collection.
+    pub(crate) fn in_namespace(&self, tree_path: &str) -> Self {
+        Self {
+            name: format!("{}/{}", tree_path, self.base_name()),
+            ..self.clone()
+        }
+    }
+
     pub fn indexed_field(&self) -> &str {
         self.indexed_field.as_str()
     }
 
+    pub fn kind(&self) -> IndexType {
+        self.kind
+    }
+
+    pub fn options(&self) -> IndexOptions {
+        self.options
+    }
+
+    /// The value as it's kept in the index, see `IndexOptions`
+    pub fn normalize(&self, field: &Field) -> Field {
+        match field {
+            Field::String(value) if self.options.case_insensitive => {
+                Field::String(value.to_lowercase())
+            }
+            _ => field.clone(),
+        }
+    }
+
+    /// Whether the index can hold the value. `Collection` indexes hold the elements
+    /// of array fields, which can be of any type.
     pub fn indexes_given_field(&self, field: &Field) -> bool {
+        if self.kind == IndexType::Collection {
+            return true;
+        }
         match field {
             Field::Int(_) => self.kind == IndexType::Numeric,
             Field::Float(_) => self.kind == IndexType::Numeric,
@@ -80,19 +590,52 @@ impl Index {
         }
     }
 
-    pub fn create_entry(&self, repo: &Repository, oid: Oid, field: &Field) {
+    pub fn create_entry(
+        &self,
+        repo: &Repository,
+        oid: Oid,
+        field: &Field,
+    ) -> Result<(), git2::Error> {
+        let _lock = self.lock(repo)?;
+        let mut keyed = self.keyed_index(repo)?;
+        Self::add_to(&mut keyed, oid, &self.normalize(field))?;
+        keyed.write()
+    }
+
+    /// Same as `create_entry` for each of the entries, but the index file is written just once
+    pub fn add_entries(
+        &self,
+        repo: &Repository,
+        entries: &[(Field, Oid)],
+    ) -> Result<(), git2::Error> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let _lock = self.lock(repo)?;
+        let mut keyed = self.keyed_index(repo)?;
+        for (field, oid) in entries {
+            Self::add_to(&mut keyed, *oid, &self.normalize(field))?;
+        }
+        keyed.write()
+    }
+
+    /// Entries sharing a value are told apart by a counter going down from `u64::MAX`,
+    /// so that the newest one comes first. The counter is read from the entry of the value
+    /// that comes first, which is only safe with the index locked (see `lock`).
+    fn add_to(keyed: &mut KeyedIndex, oid: Oid, field: &Field) -> Result<(), git2::Error> {
         let value = field.to_index_value();
-        let mut git_index = self.git_index(repo);
-        let last_entry = git_index.find_prefix(&value);
+        // the separator keeps values which are prefixes of other values apart
+        let last_entry = keyed.entries().find_prefix(format!("{}/", value));
         let next_value = match last_entry {
             Ok(v) => {
-                let path = git_index.get(v).unwrap().path;
+                let path = keyed.entries().get(v).unwrap().path;
                 let num = u64::from_str_radix(
                     core::str::from_utf8(path.split_at(path.len() - 16).1).unwrap(),
                     16,
                 )
                 .unwrap();
-                num - 1
+                // running out would take 2^64 entries of a single value
+                num.saturating_sub(1)
             }
             Err(_) => u64::MAX,
         };
@@ -112,40 +655,672 @@ impl Index {
             path: path.as_bytes().to_vec(),
         };
         debug!("creating a new entry: {:?}", entry);
-        git_index.add(&entry).unwrap();
-        git_index.write().unwrap();
+        keyed.add(&entry)
     }
 
-    pub fn delete_entry(&self, repo: &Repository, oid: Oid) -> bool {
-        // this method is going to be terribly slow on large indexes but it works for now
-        let mut git_index = self.git_index(repo);
-        debug!("removing an entry with oid: {}", oid);
-        if let Some(entry) = git_index.iter().find(|x| x.id == oid) {
-            git_index
-                .remove(Path::new(&String::from_utf8(entry.path).unwrap()), 0)
-                .unwrap();
-            git_index.write().unwrap();
-            return true;
+    /// Remove the entries pointing at the oid (a key has one per element in a `Collection`
+    /// index), returning whether there were any. They're found with a binary search over
+    /// the entries ordered by their oids (see `KeyedIndex`), and the files are only written
+    /// if an entry was removed.
+    pub fn delete_entry(&self, repo: &Repository, oid: Oid) -> Result<bool, git2::Error> {
+        debug!("removing the entries with oid: {}", oid);
+        Ok(self.delete_entries(repo, &[oid])? > 0)
+    }
+
+    /// Remove the entries pointing at any of the oids, writing the index files once
+    /// (or not at all, if there are none). Returns the number of entries removed.
+    pub fn delete_entries(&self, repo: &Repository, oids: &[Oid]) -> Result<usize, git2::Error> {
+        if oids.is_empty() {
+            return Ok(0);
         }
-        git_index.write().unwrap();
-        false
+        let _lock = self.lock(repo)?;
+        let mut keyed = self.keyed_index(repo)?;
+        let mut removed = 0;
+        for oid in oids.iter().collect::<HashSet<_>>() {
+            removed += keyed.remove(*oid)?;
+        }
+        if removed > 0 {
+            keyed.write()?;
+        }
+        Ok(removed)
+    }
+
+    /// Iterate over the indexed values along with the oids they point at, in the given order.
+    ///
+    /// Entries sharing a value come in the order they were indexed - oldest first when
+    /// ascending, newest first when descending, so the descending scan yields
+    /// "largest, most recent first".
+    pub fn scan(&self, repo: &Repository, order: Order) -> IndexScan {
+        self.scan_from(repo, order, None)
+    }
+
+    /// Same as `scan`, but resumes right after the entry the cursor was taken at.
+    /// Finding the position of the cursor is a binary search, not a re-scan of the entries
+    /// before it.
+    pub fn scan_from(
+        &self,
+        repo: &Repository,
+        order: Order,
+        cursor: Option<&IndexCursor>,
+    ) -> IndexScan {
+        IndexScan::new(self.git_index(repo), order, cursor)
+    }
+
+    /// Up to `limit` entries following the cursor (or from the start, if there is none),
+    /// along with the cursor to the next page, if there is one.
+    ///
+    /// Cursors are best-effort across concurrent writes: entries added or removed before
+    /// the cursor position are not seen by the following pages, entries after it are.
+    /// An entry whose value is updated in between two pages may be seen twice or not at all.
+    pub fn page(
+        &self,
+        repo: &Repository,
+        order: Order,
+        cursor: Option<&IndexCursor>,
+        limit: usize,
+    ) -> IndexPage {
+        let mut scan = self.scan_from(repo, order, cursor);
+        let entries: Vec<(Field, Oid)> = scan.by_ref().take(limit).collect();
+        let cursor = scan.cursor();
+        let next = scan.next().and(cursor);
+        IndexPage { entries, next }
+    }
+
+    /// Entry with the lowest value - the oldest one, if several share it.
+    /// Only the entries sharing the value are read, not the whole index.
+    pub fn min(&self, repo: &Repository) -> Option<(Field, Oid)> {
+        self.scan(repo, Order::Ascending).next()
+    }
+
+    /// Entry with the highest value - the most recent one, if several share it.
+    /// Only the entries sharing the value are read, not the whole index.
+    pub fn max(&self, repo: &Repository) -> Option<(Field, Oid)> {
+        self.scan(repo, Order::Descending).next()
+    }
+
+    /// Remove all the entries
+    pub(crate) fn clear(&self, repo: &Repository) -> Result<(), git2::Error> {
+        let _lock = self.lock(repo)?;
+        let mut keyed = KeyedIndex::open(&self.path(repo))?;
+        keyed.clear()?;
+        keyed.write()
+    }
+
+    /// Exclusive lock of the index file, held from reading the entries until the changed ones
+    /// are written, so that writers don't overwrite each other's entries or take the same
+    /// counter. It's a file lock, as Collections can't be shared between threads and each
+    /// thread (or process) writing to the repository has one of its own.
+    fn lock(&self, repo: &Repository) -> Result<File, git2::Error> {
+        lock_file(repo, self.name()).map_err(|err| git2::Error::from_str(&err.to_string()))
     }
 
     pub fn git_index(&self, repo: &Repository) -> GitIndex {
-        GitIndex::open(
-            Path::new(repo.path())
-                .join(".index")
-                .join(self.name())
-                .as_path(),
-        )
-        .unwrap()
+        GitIndex::open(&self.path(repo)).unwrap()
+    }
+
+    fn path(&self, repo: &Repository) -> PathBuf {
+        Path::new(repo.path()).join(".index").join(self.name())
+    }
+
+    /// Whether the index has a file yet, see `Collection::index_list`
+    pub(crate) fn has_file(&self, repo: &Repository) -> bool {
+        self.path(repo).exists()
+    }
+
+    /// The index file along with its entries ordered by the keys, for the writes
+    fn keyed_index(&self, repo: &Repository) -> Result<KeyedIndex, git2::Error> {
+        KeyedIndex::open(&self.path(repo))
     }
 
     pub fn extract_value(entry: &IndexEntry) -> &[u8] {
-        let n = match entry.ino {
-            1 => 2,
-            _ => 3,
+        entry.path.rsplitn(2, |b| *b == b'/').nth(1).unwrap()
+    }
+
+    /// Store the current content of the index file in the repository, as a commit of a tree
+    /// with the file in it under `refs/yamabiko/indexes/<name>`, so that it can be pushed
+    /// like any other ref. The parent of the commit is the commit of main the index is at.
+    /// Returns the name of the ref, or None if there is no file yet (or the name of the index
+    /// can't be a part of a ref name).
+    pub(crate) fn snapshot(
+        &self,
+        repo: &Repository,
+        main: Oid,
+    ) -> Result<Option<String>, git2::Error> {
+        let ref_name = format!("{}{}", SNAPSHOT_REFS, self.name());
+        if !git2::Reference::is_valid_name(&ref_name) {
+            debug!("index '{}' can't be replicated", self.name());
+            return Ok(None);
+        }
+        let path = repo.path().join(".index").join(self.name());
+        let content = {
+            let _lock = self.lock(repo)?;
+            match std::fs::read(&path) {
+                Ok(content) => content,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(err) => return Err(git2::Error::from_str(&err.to_string())),
+            }
         };
-        entry.path.rsplitn(n, |b| *b == b'/').nth(1).unwrap()
+        let blob = repo.blob(&content)?;
+        let mut tb = repo.treebuilder(None)?;
+        tb.insert(self.base_name(), blob, 0o100644)?;
+        let tree = repo.find_tree(tb.write()?)?;
+        // git only pushes commits, the fixed time keeps snapshots of the same content the same
+        let signature = crate::Collection::signature_at(0);
+        let parent = repo.find_commit(main)?;
+        let commit = repo.commit(
+            None,
+            &signature,
+            &signature,
+            "index snapshot",
+            &tree,
+            &[&parent],
+        )?;
+        repo.reference(&ref_name, commit, true, "index snapshot")?;
+        // the local file is already at the snapshot, it's not to be restored from it
+        let seen_ref = format!("{}{}", SEEN_SNAPSHOT_REFS, self.name());
+        repo.reference(&seen_ref, commit, true, "index snapshot")?;
+        Ok(Some(ref_name))
+    }
+
+    /// Snapshots of all the index files in the `.index` directory (including the ones
+    /// of the namespaces, in its subdirectories) at the commit of main, see `snapshot`
+    pub(crate) fn snapshot_all(repo: &Repository, main: Oid) -> Result<Vec<String>, git2::Error> {
+        let mut paths = Vec::new();
+        Self::index_files(&repo.path().join(".index"), "", &mut paths);
+        let mut refs = Vec::new();
+        for index in paths.iter().filter_map(|path| Index::from_path(path).ok()) {
+            refs.extend(index.snapshot(repo, main)?);
+        }
+        Ok(refs)
+    }
+
+    /// Paths (relative to `.index`, prefixed with `prefix`) of the index files in the directory
+    /// and its subdirectories. Directories which can't be read are skipped.
+    fn index_files(dir: &Path, prefix: &str, paths: &mut Vec<String>) {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            let path = format!("{}{}", prefix, name);
+            if entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+                Self::index_files(&entry.path(), &format!("{}/", path), paths);
+            } else if name.ends_with(".index") {
+                paths.push(path);
+            }
+        }
+    }
+
+    /// Write out the index files from the snapshots received from another repository
+    /// (see `snapshot`), e.g. on a replica taking over - so that it doesn't have to rebuild
+    /// them. A file is replaced whenever its snapshot has moved since it was last restored
+    /// (or taken locally).
+    pub(crate) fn restore_snapshots(repo: &Repository) -> Result<(), git2::Error> {
+        for reference in repo.references_glob(&format!("{}*", SNAPSHOT_REFS))? {
+            let reference = reference?;
+            let Some(index) = reference
+                .name()
+                .and_then(|name| name.strip_prefix(SNAPSHOT_REFS))
+                .and_then(|name| Index::from_path(name).ok())
+            else {
+                continue;
+            };
+            let path = repo.path().join(".index").join(index.name());
+            let seen_ref = format!("{}{}", SEEN_SNAPSHOT_REFS, index.name());
+            let snapshot = reference.peel_to_commit()?.id();
+            match repo.refname_to_id(&seen_ref) {
+                Ok(seen) if seen == snapshot && path.exists() => continue,
+                Ok(_) => {}
+                // a file from before the snapshots were tracked, nothing tells if it's older
+                Err(err) if err.code() == git2::ErrorCode::NotFound && path.exists() => {
+                    repo.reference(&seen_ref, snapshot, true, "index snapshot")?;
+                    continue;
+                }
+                Err(err) if err.code() == git2::ErrorCode::NotFound => {}
+                Err(err) => return Err(err),
+            }
+            let tree = reference.peel_to_tree()?;
+            let Some(entry) = tree.get_name(index.base_name()) else {
+                continue;
+            };
+            let blob = entry.to_object(repo)?.peel_to_blob()?;
+            debug!("restoring index '{}' from its snapshot", index.name());
+            {
+                let _lock = index.lock(repo)?;
+                std::fs::write(&path, blob.content())
+                    .map_err(|err| git2::Error::from_str(&err.to_string()))?;
+            }
+            repo.reference(&seen_ref, snapshot, true, "index snapshot")?;
+        }
+        Ok(())
+    }
+
+    /// Whether the index holds numbers in the encoding used before it was made to sort
+    /// like the numbers themselves (`<sign>/<bits>/<counter>`). Such an index returns wrong
+    /// results for ranges and has to be rebuilt with `Collection::rebuild_index`.
+    pub fn is_outdated(&self, repo: &Repository) -> bool {
+        self.git_index(repo)
+            .iter()
+            .any(|entry| entry.ino != 1 && entry.path.iter().filter(|b| **b == b'/').count() > 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use std::cmp::Ordering::*;
+    use std::collections::HashSet;
+
+    use git2::{IndexEntry, IndexTime, ObjectType, Oid};
+
+    use crate::error::IndexNameError;
+    use crate::field::Field;
+    use crate::index::{Index, IndexOptions, IndexType, Order};
+    use crate::query::{q, QueryBuilder};
+    use crate::serialization::DataFormat;
+    use crate::test::*;
+    use crate::OperationTarget;
+
+    fn set_numbers(db: &crate::Collection) {
+        for num_val in [-100, -5, -1, 0, 3, 10] {
+            db.set(
+                &format!("n{}", num_val),
+                InterigentDbStruct { num_val },
+                OperationTarget::Main,
+            )
+            .unwrap();
+        }
+    }
+
+    fn sorted_keys(query: QueryBuilder, db: &crate::Collection) -> Vec<String> {
+        let mut keys = query.execute(db).unwrap().keys().unwrap();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn test_from_name() {
+        assert_eq!(
+            Index::from_name("age#numeric.index"),
+            Ok(Index::new("age#numeric.index", "age", IndexType::Numeric))
+        );
+        assert_eq!(
+            Index::from_name("tag#1.v2#sequential.index"),
+            Ok(Index::new(
+                "tag#1.v2#sequential.index",
+                "tag#1.v2",
+                IndexType::Sequential
+            ))
+        );
+    }
+
+    #[rstest]
+    #[case("age_numeric.index", IndexNameError::MissingType)]
+    #[case("age#numeric", IndexNameError::MissingSuffix)]
+    #[case("", IndexNameError::MissingSuffix)]
+    #[case("#numeric.index", IndexNameError::EmptyField)]
+    #[case(
+        "age#fulltext.index",
+        IndexNameError::UnknownType(String::from("fulltext"))
+    )]
+    #[case("age#.index", IndexNameError::UnknownType(String::new()))]
+    // there is no single-value index type, such names are reported instead of guessed at
+    #[case("age#single.idx", IndexNameError::UnknownType(String::from("single")))]
+    fn test_from_name_malformed(#[case] name: &str, #[case] error: IndexNameError) {
+        assert_eq!(Index::from_name(name), Err(error));
+    }
+
+    #[test]
+    fn test_from_name_case_insensitive() {
+        let index = Index::from_name("name#sequential-ci.index").unwrap();
+        assert_eq!(index.kind(), IndexType::Sequential);
+        assert!(index.options().case_insensitive);
+        assert_eq!(
+            Index::file_name(
+                "name",
+                IndexType::Sequential,
+                IndexOptions {
+                    case_insensitive: true
+                }
+            ),
+            "name#sequential-ci.index"
+        );
+        assert!(
+            !Index::from_name("name#sequential.index")
+                .unwrap()
+                .options()
+                .case_insensitive
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_case_insensitive_ordering(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        for name in ["banana", "Apple", "cherry", "Banana", "apple"] {
+            db.set(
+                name,
+                SampleDbStruct::new(name.to_string()),
+                OperationTarget::Main,
+            )
+            .unwrap();
+        }
+        let options = IndexOptions {
+            case_insensitive: true,
+        };
+        let (index, _) = db
+            .create_index_with("str_val", IndexType::Sequential, options)
+            .unwrap();
+        assert_eq!(index.name(), "str_val#sequential-ci.index");
+        db.set(
+            "Durian",
+            SampleDbStruct::new(String::from("Durian")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let values: Vec<Field> = index
+            .scan(db.repository(), Order::Ascending)
+            .map(|(field, _)| field)
+            .collect();
+        assert_eq!(
+            values,
+            ["apple", "apple", "banana", "banana", "cherry", "durian"]
+                .map(|value| Field::String(value.to_string()))
+                .into_iter()
+                .collect::<Vec<_>>()
+        );
+        // the entries still point at the documents, which keep their case
+        let (_, oid) = index.max(db.repository()).unwrap();
+        assert_eq!(oid, Oid::hash_object(ObjectType::Blob, b"Durian").unwrap());
+        assert_eq!(
+            db.get::<SampleDbStruct>("Durian", OperationTarget::Main)
+                .unwrap()
+                .unwrap()
+                .str_val,
+            "Durian"
+        );
+
+        assert_eq!(
+            sorted_keys(QueryBuilder::query(q("str_val", Equal, "APPLE")), &db),
+            vec!["Apple", "apple"]
+        );
+        // Banana sorts after apple, unlike in a byte-ordered index
+        assert_eq!(
+            sorted_keys(QueryBuilder::query(q("str_val", Less, "b")), &db),
+            vec!["Apple", "apple"]
+        );
+        assert_eq!(
+            sorted_keys(QueryBuilder::query(q("str_val", Greater, "Banana")), &db),
+            vec!["Durian", "cherry"]
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_numeric_ranges_across_zero(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let index = db.add_index("num_val", IndexType::Numeric);
+        set_numbers(&db);
+        let values: Vec<Field> = index
+            .scan(db.repository(), Order::Ascending)
+            .map(|(field, _)| field)
+            .collect();
+        assert_eq!(
+            values,
+            [-100, -5, -1, 0, 3, 10]
+                .map(Field::Int)
+                .into_iter()
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            sorted_keys(QueryBuilder::query(q("num_val", Less, 1)), &db),
+            vec!["n-1", "n-100", "n-5", "n0"]
+        );
+        assert_eq!(
+            sorted_keys(QueryBuilder::query(q("num_val", Greater, -2)), &db),
+            vec!["n-1", "n0", "n10", "n3"]
+        );
+        assert_eq!(
+            sorted_keys(QueryBuilder::query(q("num_val", Equal, 0)), &db),
+            vec!["n0"]
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_rebuild_outdated_index(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let index = db.add_index("num_val", IndexType::Numeric);
+        set_numbers(&db);
+        assert!(!index.is_outdated(db.repository()));
+        // an entry like the ones written before numbers were encoded to sort correctly
+        let mut git_index = index.git_index(db.repository());
+        git_index
+            .add(&IndexEntry {
+                ctime: IndexTime::new(0, 0),
+                mtime: IndexTime::new(0, 0),
+                dev: 0,
+                ino: 0,
+                mode: 0o100644,
+                uid: 0,
+                gid: 0,
+                file_size: 0,
+                id: Oid::hash_object(ObjectType::Blob, b"n3").unwrap(),
+                flags: 0,
+                flags_extended: 0,
+                path: format!("1/{:16x}/{:16x}", 3_f64.to_bits(), u64::MAX).into_bytes(),
+            })
+            .unwrap();
+        git_index.write().unwrap();
+        assert!(index.is_outdated(db.repository()));
+        let report = db.check(Default::default()).unwrap();
+        assert_eq!(
+            report.problems[0].kind,
+            crate::check::ProblemKind::OutdatedIndex
+        );
+
+        db.rebuild_index(&index).unwrap();
+        assert!(!index.is_outdated(db.repository()));
+        assert_eq!(index.git_index(db.repository()).len(), 6);
+        assert!(db.check(Default::default()).unwrap().is_ok());
+        assert_eq!(
+            sorted_keys(QueryBuilder::query(q("num_val", Greater, 0)), &db),
+            vec!["n10", "n3"]
+        );
+    }
+
+    #[test]
+    fn test_add_and_delete_entries() {
+        let (db, _td) = create_db(DataFormat::Json);
+        let repo = db.repository();
+        let one_by_one = db.add_index("a", IndexType::Numeric);
+        let batched = db.add_index("b", IndexType::Numeric);
+        let oid = |n: u8| Oid::hash_object(ObjectType::Blob, &[n]).unwrap();
+        let entries = vec![
+            (Field::Int(5), oid(1)),
+            (Field::Int(-5), oid(2)),
+            (Field::Int(5), oid(3)),
+            (Field::Float(0.5), oid(4)),
+        ];
+        for (field, oid) in entries.iter() {
+            one_by_one.create_entry(repo, *oid, field).unwrap();
+        }
+        batched.add_entries(repo, &entries).unwrap();
+        let paths = |index: &Index| {
+            index
+                .git_index(repo)
+                .iter()
+                .map(|entry| (entry.path, entry.id))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(paths(&one_by_one), paths(&batched));
+        assert_eq!(batched.min(repo), Some((Field::Int(-5), oid(2))));
+
+        assert_eq!(
+            batched
+                .delete_entries(repo, &[oid(1), oid(4), oid(9)])
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            batched
+                .scan(repo, Order::Ascending)
+                .map(|(_, oid)| oid)
+                .collect::<Vec<_>>(),
+            vec![oid(2), oid(3)]
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_delete_one_of_entries_sharing_a_value(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let index = db.add_index("num_val", IndexType::Numeric);
+        for key in ["a", "b", "c", "d"] {
+            db.set(
+                key,
+                InterigentDbStruct { num_val: 7 },
+                OperationTarget::Main,
+            )
+            .unwrap();
+        }
+        db.set(
+            "e",
+            InterigentDbStruct { num_val: 8 },
+            OperationTarget::Main,
+        )
+        .unwrap();
+        db.delete("b", OperationTarget::Main).unwrap();
+        db.set(
+            "c",
+            InterigentDbStruct { num_val: 9 },
+            OperationTarget::Main,
+        )
+        .unwrap();
+        assert_eq!(
+            sorted_keys(QueryBuilder::query(q("num_val", Equal, 7)), &db),
+            vec!["a", "d"]
+        );
+        assert_eq!(
+            sorted_keys(QueryBuilder::query(q("num_val", Greater, 7)), &db),
+            vec!["c", "e"]
+        );
+        let repo = db.repository();
+        let hash = |key: &str| Oid::hash_object(ObjectType::Blob, key.as_bytes()).unwrap();
+        assert!(index.delete_entry(repo, hash("a")).unwrap());
+        assert!(!index.delete_entry(repo, hash("a")).unwrap());
+        assert!(!index.delete_entry(repo, hash("b")).unwrap());
+        assert_eq!(
+            index
+                .scan(repo, Order::Ascending)
+                .map(|(_, oid)| oid)
+                .collect::<Vec<_>>(),
+            vec![hash("d"), hash("e"), hash("c")]
+        );
+        assert!(db.check(Default::default()).unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_index_file_errors_are_returned() {
+        let (db, _td) = create_db(DataFormat::Json);
+        db.set(
+            "b",
+            SampleDbStruct::new(String::from("b")),
+            crate::OperationTarget::Main,
+        )
+        .unwrap();
+        let index = db.add_index("str_val", IndexType::Sequential);
+        // the lock file can't be opened if there's a directory in its place
+        let lock = db.repository().path().join(".index").join(format!(
+            "{}{}",
+            index.name(),
+            super::LOCK_SUFFIX
+        ));
+        std::fs::remove_file(&lock).unwrap();
+        std::fs::create_dir(&lock).unwrap();
+        assert!(matches!(
+            db.set(
+                "a",
+                SampleDbStruct::new(String::from("a")),
+                crate::OperationTarget::Main,
+            ),
+            Err(crate::error::SetObjectError::InternalGitError(_))
+        ));
+        assert!(db.rebuild_index(&index).is_err());
+        std::fs::remove_dir(&lock).unwrap();
+        db.rebuild_index(&index).unwrap();
+        assert_eq!(index.git_index(db.repository()).len(), 2);
+    }
+
+    #[test]
+    fn test_keys_follow_a_replaced_index_file() {
+        let (db, _td) = create_db(DataFormat::Json);
+        let repo = db.repository();
+        let index = db.add_index("a", IndexType::Numeric);
+        let oid = |n: u8| Oid::hash_object(ObjectType::Blob, &[n]).unwrap();
+        index
+            .add_entries(repo, &[(Field::Int(1), oid(1)), (Field::Int(2), oid(2))])
+            .unwrap();
+        let path = repo.path().join(".index").join(index.name());
+        let saved = std::fs::read(&path).unwrap();
+        assert!(index.delete_entry(repo, oid(1)).unwrap());
+        index.create_entry(repo, oid(3), &Field::Int(3)).unwrap();
+        // as many entries as before, but not the same ones - e.g. a restored snapshot
+        std::fs::write(&path, saved).unwrap();
+        assert!(!index.delete_entry(repo, oid(3)).unwrap());
+        assert!(index.delete_entry(repo, oid(1)).unwrap());
+        assert_eq!(
+            index
+                .scan(repo, Order::Ascending)
+                .map(|(_, oid)| oid)
+                .collect::<Vec<_>>(),
+            vec![oid(2)]
+        );
+        // the file with the keys can be lost as well
+        std::fs::remove_file(path.with_extension("index.keys")).unwrap();
+        assert!(index.delete_entry(repo, oid(2)).unwrap());
+        assert_eq!(index.git_index(repo).len(), 0);
+    }
+
+    #[test]
+    fn test_concurrent_inserts_of_the_same_value() {
+        let (db, td) = create_db(DataFormat::Json);
+        let index = db.add_index("num_val", IndexType::Numeric);
+        let transactions: Vec<String> = (0..4).map(|_| db.new_transaction(None).unwrap()).collect();
+        std::thread::scope(|scope| {
+            for (thread, transaction) in transactions.iter().enumerate() {
+                let path = td.path();
+                scope.spawn(move || {
+                    // every thread writes with a Collection of its own
+                    let db = crate::Collection::initialize(path, DataFormat::Json).unwrap();
+                    for i in 0..25 {
+                        db.set(
+                            &format!("{}-{}", thread, i),
+                            InterigentDbStruct { num_val: 7 },
+                            OperationTarget::Transaction(transaction),
+                        )
+                        .unwrap();
+                    }
+                });
+            }
+        });
+        let paths: HashSet<Vec<u8>> = index
+            .git_index(db.repository())
+            .iter()
+            .map(|entry| entry.path)
+            .collect();
+        assert_eq!(paths.len(), 100);
+        assert_eq!(index.scan(db.repository(), Order::Ascending).count(), 100);
     }
 }
@@ -0,0 +1,157 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use git2::{
+    ErrorClass, ErrorCode, Index as GitIndex, IndexEntry, IndexTime, ObjectType, Oid, Repository,
+};
+
+use crate::index::{lock_file, KeyedIndex, Order};
+use crate::{debug, ChangeKind, KeyChange};
+
+/// File in `.index` with the keys on main in the order they were inserted in, see
+/// `Collection::keys_by_insertion`. It's a git index like the ones of the fields, with
+/// an entry `<sequence number>/<key>` pointing at the hash of the key for every key.
+/// The name doesn't end with `.index`, so it's never taken for the index of a field.
+/// The entries of a key are found through the keys of a `KeyedIndex`.
+const LOG_NAME: &str = "insertions.log";
+
+fn log_path(repo: &Repository) -> PathBuf {
+    repo.path().join(".index").join(LOG_NAME)
+}
+
+/// Whether the log was built (see `rebuild`) and has to be maintained
+pub(crate) fn exists(repo: &Repository) -> bool {
+    log_path(repo).exists()
+}
+
+/// Drop the log (e.g. once it missed a change of main), it has to be rebuilt to be used again
+pub(crate) fn discard(repo: &Repository) -> Result<(), git2::Error> {
+    let _lock = lock_file(repo, LOG_NAME).map_err(|err| git2::Error::from_str(&err.to_string()))?;
+    match std::fs::remove_file(log_path(repo)) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+            Err(git2::Error::from_str(&err.to_string()))
+        }
+        _ => Ok(()),
+    }
+}
+
+struct InsertionLog {
+    log: KeyedIndex,
+}
+
+impl InsertionLog {
+    fn open(repo: &Repository) -> Result<Self, git2::Error> {
+        Ok(Self {
+            log: KeyedIndex::open(&log_path(repo))?,
+        })
+    }
+
+    fn sequence(path: &[u8]) -> Option<u64> {
+        let (sequence, _) = std::str::from_utf8(path).ok()?.split_once('/')?;
+        u64::from_str_radix(sequence, 16).ok()
+    }
+
+    /// Keys added since are appended (after dropping their previous entries, if the log
+    /// missed their deletion), deleted keys are dropped. Changed values don't count.
+    fn apply(&mut self, changes: &[KeyChange]) -> Result<(), git2::Error> {
+        let mut hashes = HashSet::new();
+        for change in changes.iter().filter(|c| c.kind != ChangeKind::Modified) {
+            hashes.insert(Oid::hash_object(ObjectType::Blob, change.key.as_bytes())?);
+        }
+        if hashes.is_empty() {
+            return Ok(());
+        }
+        for hash in hashes {
+            self.log.remove(hash)?;
+        }
+        let entries = self.log.entries();
+        let next = match entries.len() {
+            0 => 0,
+            len => entries
+                .get(len - 1)
+                .and_then(|entry| Self::sequence(&entry.path))
+                .map_or(0, |sequence| sequence + 1),
+        };
+        let added = changes.iter().filter(|c| c.kind == ChangeKind::Added);
+        for (sequence, change) in (next..).zip(added) {
+            let path = format!("{:016x}/{}", sequence, change.key);
+            self.log.add(&IndexEntry {
+                ctime: IndexTime::new(0, 0),
+                mtime: IndexTime::new(0, 0),
+                dev: 0,
+                ino: 0,
+                mode: 0o100644,
+                uid: 0,
+                gid: 0,
+                file_size: 0,
+                id: Oid::hash_object(ObjectType::Blob, change.key.as_bytes())?,
+                flags: 0,
+                flags_extended: 0,
+                path: path.into_bytes(),
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Update the log with the changes of main
+pub(crate) fn record(repo: &Repository, changes: &[KeyChange]) -> Result<(), git2::Error> {
+    if changes.iter().all(|c| c.kind == ChangeKind::Modified) {
+        return Ok(());
+    }
+    let _lock = lock_file(repo, LOG_NAME).map_err(|err| git2::Error::from_str(&err.to_string()))?;
+    let mut log = InsertionLog::open(repo)?;
+    log.apply(changes)?;
+    log.log.write()
+}
+
+/// Replace the log with one built from the changes of the commits of main, oldest first.
+/// Returns the number of keys in it.
+pub(crate) fn rebuild<I>(repo: &Repository, history: I) -> Result<usize, git2::Error>
+where
+    I: IntoIterator<Item = Result<Vec<KeyChange>, git2::Error>>,
+{
+    let _lock = lock_file(repo, LOG_NAME).map_err(|err| git2::Error::from_str(&err.to_string()))?;
+    let mut log = InsertionLog::open(repo)?;
+    log.log.clear()?;
+    for changes in history {
+        log.apply(&changes?)?;
+    }
+    log.log.write()?;
+    let len = log.log.entries().len();
+    debug!("rebuilt the insertion log of {} keys", len);
+    Ok(len)
+}
+
+/// Up to `limit` keys of the log in the order, skipping the first `offset` of them.
+/// Fails with NotFound if the log wasn't built.
+pub(crate) fn keys(
+    repo: &Repository,
+    order: Order,
+    limit: usize,
+    offset: usize,
+) -> Result<Vec<String>, git2::Error> {
+    if !exists(repo) {
+        return Err(git2::Error::new(
+            ErrorCode::NotFound,
+            ErrorClass::Index,
+            "the insertion log has to be built with Collection::rebuild_insertion_log",
+        ));
+    }
+    // only the entries are read, their keys don't matter
+    let log = GitIndex::open(&log_path(repo))?;
+    let len = log.len();
+    let positions: Box<dyn Iterator<Item = usize>> = match order {
+        Order::Ascending => Box::new(0..len),
+        Order::Descending => Box::new((0..len).rev()),
+    };
+    Ok(positions
+        .skip(offset)
+        .take(limit)
+        .filter_map(|position| log.get(position))
+        .filter_map(|entry| {
+            let path = String::from_utf8(entry.path).ok()?;
+            path.split_once('/').map(|(_, key)| key.to_string())
+        })
+        .collect())
+}
@@ -0,0 +1,124 @@
+use git2::{ObjectType, Tree};
+
+use crate::{error, namespace, Collection};
+
+/// Lazy iterator over the documents of a collection, returned by `Collection::iter`.
+///
+/// It walks the tree of the commit the target pointed at when it was created, reading
+/// one value at a time - writes made in the meantime (to any branch) are not seen, and
+/// every document is yielded exactly once. Documents come in the order of their paths,
+/// which for keys without a slash is the order of their hashes rather than of the keys.
+pub struct Documents<'c> {
+    collection: &'c Collection,
+    /// Trees being walked, along with their path and the position of their next entry
+    stack: Vec<(Tree<'c>, String, usize)>,
+}
+
+impl<'c> Documents<'c> {
+    pub(crate) fn new(collection: &'c Collection, root: Tree<'c>) -> Self {
+        Self {
+            collection,
+            stack: vec![(root, String::new(), 0)],
+        }
+    }
+}
+
+impl Iterator for Documents<'_> {
+    type Item = Result<(String, Vec<u8>), error::GetObjectError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let repo = self.collection.repository();
+        loop {
+            let (tree, root, position) = self.stack.last_mut()?;
+            let Some(entry) = tree.get(*position).map(|entry| entry.to_owned()) else {
+                self.stack.pop();
+                continue;
+            };
+            *position += 1;
+            let root = root.clone();
+            let Some(name) = entry.name() else {
+                continue;
+            };
+            if (root.is_empty() && name.ends_with(".index")) || namespace::is_reserved_tree(&entry)
+            {
+                continue;
+            }
+            match entry.kind() {
+                Some(ObjectType::Tree) => {
+                    let path = format!("{}{}/", root, name);
+                    match repo.find_tree(entry.id()) {
+                        Ok(subtree) => self.stack.push((subtree, path, 0)),
+                        Err(err) => return Some(Err(err.into())),
+                    }
+                }
+                Some(ObjectType::Blob) => {
+                    let key = match Collection::key_from_path(&root, name) {
+                        Ok(key) => key,
+                        Err(err) => return Some(Err(err.into())),
+                    };
+                    return Some(
+                        self.collection
+                            .entry_content(&entry)
+                            .map(|value| (key, value)),
+                    );
+                }
+                _ => continue,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use rstest::rstest;
+
+    use crate::{index::IndexType, serialization::DataFormat, test::*, OperationTarget};
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_iter(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.add_index("str_val", IndexType::Sequential);
+        let keys = ["a", "b", "nested/c", "nested/deeper/d"];
+        db.set_batch(
+            keys.map(|key| (key, SampleDbStruct::new(key.to_string()))),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        db.namespace("users")
+            .unwrap()
+            .set(
+                "e",
+                SampleDbStruct::new(String::from("e")),
+                OperationTarget::Main,
+            )
+            .unwrap();
+
+        let documents = db.iter(OperationTarget::Main).unwrap();
+        // written after the iterator was created, not seen by it
+        db.set(
+            "f",
+            SampleDbStruct::new(String::from("f")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let documents: BTreeMap<String, SampleDbStruct> = documents
+            .map(|document| {
+                let (key, value) = document.unwrap();
+                (key, data_format.deserialize(&value))
+            })
+            .collect();
+        assert_eq!(
+            documents,
+            keys.map(|key| (key.to_string(), SampleDbStruct::new(key.to_string())))
+                .into_iter()
+                .collect()
+        );
+        assert_eq!(db.iter(OperationTarget::Main).unwrap().count(), 5);
+        assert!(db.iter(OperationTarget::Branch("missing")).is_err());
+    }
+}
@@ -1,50 +1,377 @@
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use core::str;
 use git2::build::CheckoutBuilder;
 use git2::{
-    BranchType, Commit, ErrorCode, FileFavor, Index, MergeOptions, ObjectType, Oid, RebaseOptions,
-    Repository, RepositoryInitOptions, Signature, Time, Tree, TreeBuilder, TreeWalkResult,
+    Blob, BranchType, Commit, Delta, ErrorCode, FileFavor, Index, MergeOptions, ObjectType, Oid,
+    RebaseOptions, Repository, RepositoryInitOptions, Signature, Time, Tree, TreeBuilder,
+    TreeWalkResult,
 };
 use rand::distributions::Alphanumeric;
 use rand::prelude::*;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use serialization::DataFormat;
-use std::{collections::HashMap, path::Path};
+use std::borrow::Cow;
+use std::fmt::{self, Display};
+use std::io::Read;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
 
 use crate::field::Field;
 
+// lets the code generated by the derive macros refer to `::yamabiko` inside this crate too
+extern crate self as yamabiko;
+
+pub mod attachment;
+pub mod buffered;
+pub mod builder;
+pub mod bulk;
+pub mod cache;
+pub mod check;
+pub mod clock;
+#[cfg(any(feature = "encryption", feature = "full"))]
+pub mod encryption;
 pub mod error;
 pub mod field;
+pub mod health;
+pub mod hooks;
+pub mod idempotency;
 pub mod index;
+mod insertions;
+pub mod iter;
+mod lock;
 pub mod logging;
+pub mod merge;
+pub mod meta;
+pub mod metadata;
+pub mod metrics;
+pub mod migration;
+pub mod model;
+pub mod namespace;
+pub mod pipeline;
 pub mod query;
 pub mod replica;
 pub mod serialization;
+pub mod signing;
 pub mod squash;
+pub mod stats;
+pub mod transaction;
+pub mod verify;
 
+#[derive(Debug, Clone, Copy)]
 pub enum OperationTarget<'a> {
     Main,
     Transaction(&'a str),
+    /// Any local branch of the repository, for those who manage their own branches.
+    /// Writes go straight to the branch - it's not a transaction, so `apply_transaction`
+    /// doesn't apply to it (`merge` can still combine it with other branches).
+    Branch(&'a str),
+    /// Read-only view of the collection as of the given commit. Writes to it are rejected.
+    Commit(Oid),
+    /// Read-only view of a branch of a git remote as of its last fetch
+    /// (`refs/remotes/<remote>/<branch>`, see `Collection::refresh_remote`),
+    /// e.g. for serving reads from a replica without touching its own main.
+    /// Writes to it are rejected.
+    Remote {
+        remote: &'a str,
+        branch: &'a str,
+    },
 }
 
 impl<'a> OperationTarget<'a> {
-    pub fn to_git_branch(&self) -> &str {
+    /// Name of the local branch behind the target, None if it's a commit or a remote branch
+    pub fn to_git_branch(&self) -> Option<&str> {
+        match self {
+            OperationTarget::Main => Some("main"),
+            OperationTarget::Transaction(t) | OperationTarget::Branch(t) => Some(t),
+            OperationTarget::Commit(_) | OperationTarget::Remote { .. } => None,
+        }
+    }
+
+    /// Whether the target can be resolved: branches need valid git branch names
+    /// and transactions also can't be named `main` (or contain slashes)
+    pub fn is_valid(&self) -> bool {
+        match self {
+            OperationTarget::Main | OperationTarget::Commit(_) => true,
+            OperationTarget::Transaction(name) => is_valid_transaction_name(name),
+            OperationTarget::Branch(name) => git2::Branch::name_is_valid(name).unwrap_or(false),
+            OperationTarget::Remote { remote, branch } => {
+                !remote.is_empty()
+                    && !remote.contains('/')
+                    && git2::Reference::is_valid_name(&remote_reference(remote, branch))
+            }
+        }
+    }
+
+    /// Name of the branch writes to the target go to
+    pub(crate) fn writable_branch(&self) -> Result<&str, error::SetObjectError> {
+        match self {
+            OperationTarget::Commit(_) | OperationTarget::Remote { .. } => {
+                Err(error::SetObjectError::ReadOnlyTarget)
+            }
+            _ if !self.is_valid() => Err(error::SetObjectError::InvalidOperationTarget(
+                self.to_string(),
+            )),
+            OperationTarget::Main => Ok("main"),
+            OperationTarget::Transaction(name) | OperationTarget::Branch(name) => Ok(name),
+        }
+    }
+}
+
+fn remote_reference(remote: &str, branch: &str) -> String {
+    format!("refs/remotes/{}/{}", remote, branch)
+}
+
+fn is_valid_transaction_name(name: &str) -> bool {
+    !["main", "HEAD"].contains(&name)
+        && !name.contains('/')
+        && git2::Branch::name_is_valid(name).unwrap_or(false)
+}
+
+impl Display for OperationTarget<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            OperationTarget::Main => "main",
-            OperationTarget::Transaction(t) => t,
+            OperationTarget::Main => write!(f, "main"),
+            OperationTarget::Transaction(name) | OperationTarget::Branch(name) => {
+                write!(f, "{}", name)
+            }
+            OperationTarget::Commit(oid) => write!(f, "{}", oid),
+            OperationTarget::Remote { remote, branch } => write!(f, "{}/{}", remote, branch),
         }
     }
 }
 
+/// How keys changed on both sides of a merge are resolved. Values in a binary data format
+/// (see `DataFormat::is_binary`) or encrypted ones are never merged line by line - a key
+/// changed on both sides is a conflict of the whole values, even if the changes don't overlap.
 pub enum ConflictResolution {
     Overwrite,
     DiscardChanges,
     Abort,
+    /// Merge the values of the conflicting keys with the MergeDriver of the collection
+    /// (see `Collection::with_merge_driver`). Fails with a MergeConflict if it can't.
+    Custom,
+    /// Let the callback decide what to do with every conflicting key. `TakeMain` keeps
+    /// the value of the target branch, `TakeTransaction` the one of the source branch.
+    Callback(Box<dyn Fn(&ConflictInfo) -> Resolution>),
+}
+
+impl fmt::Debug for ConflictResolution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConflictResolution::Overwrite => write!(f, "Overwrite"),
+            ConflictResolution::DiscardChanges => write!(f, "DiscardChanges"),
+            ConflictResolution::Abort => write!(f, "Abort"),
+            ConflictResolution::Custom => write!(f, "Custom"),
+            ConflictResolution::Callback(_) => write!(f, "Callback(..)"),
+        }
+    }
+}
+
+/// Key conflicting while rebasing a commit, given to `ConflictResolution::Callback`
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ConflictInfo {
+    pub key: String,
+    /// Value from the common ancestor, None if the key didn't exist there.
+    pub base: Option<Vec<u8>>,
+    /// Value on the target branch, None if the key was deleted there.
+    pub ours: Option<Vec<u8>>,
+    /// Value in the commit being rebased, None if the key was deleted there.
+    pub theirs: Option<Vec<u8>>,
+    /// When (seconds since the Unix epoch) the commit the target branch is at was authored.
+    pub ours_time: i64,
+    /// When the commit being rebased was authored, rebasing doesn't change it.
+    pub theirs_time: i64,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct TransactionOutcome {
+    /// Commit the target branch points at after the merge.
+    pub head: Oid,
+    /// Number of commits from the source branch that were rebased onto the target.
+    pub commits_applied: usize,
+}
+
+/// Key changed in different ways on main and in a transaction
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct KeyConflict {
+    pub key: String,
+    /// Value from the commit the transaction started at, None if the key didn't exist there.
+    pub base: Option<Vec<u8>>,
+    /// Value on main, None if the key was deleted there.
+    pub ours: Option<Vec<u8>>,
+    /// Value in the transaction, None if the key was deleted there.
+    pub theirs: Option<Vec<u8>>,
+}
+
+/// How to resolve a KeyConflict
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Resolution {
+    TakeMain,
+    TakeTransaction,
+    /// Store this value under the key instead of either side.
+    Replace(Vec<u8>),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct TransactionPreview {
+    pub conflicts: Vec<KeyConflict>,
+    /// Changes made in the transaction which can be applied without conflicts.
+    pub changes: Vec<KeyChange>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ChangeKind {
+    Added,
+    Modified,
+    Deleted,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct KeyChange {
+    pub key: String,
+    pub kind: ChangeKind,
+}
+
+/// Key in the config of the repository under which the value size limit is kept
+const VALUE_LIMIT_CONFIG: &str = "yamabiko.valuelimit";
+
+/// Bits of the flags of an index entry holding its stage
+const INDEX_STAGE_SHIFT: u16 = 12;
+const INDEX_STAGE_MASK: u16 = 0x3000;
+
+/// Transactions not written to for longer than this are stale, see `Collection::health`
+const DEFAULT_STALE_TRANSACTION_AGE: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Copy)]
+pub struct LogOptions<'a> {
+    /// Maximum number of commits to return.
+    pub limit: usize,
+    /// Only return commits older than this one - pass the last commit of the previous page
+    /// to get the next one.
+    pub before: Option<Oid>,
+    pub branch: OperationTarget<'a>,
+}
+
+impl Default for LogOptions<'_> {
+    fn default() -> Self {
+        Self {
+            limit: 20,
+            before: None,
+            branch: OperationTarget::Main,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CommitInfo {
+    pub oid: Oid,
+    pub timestamp: DateTime<Utc>,
+    pub author: String,
+    pub message: String,
+    /// Keys touched by the commit (compared to its first parent) and how they changed.
+    pub keys: Vec<KeyChange>,
+}
+
+/// When, by whom and in which commit the value under a key was last changed,
+/// see `Collection::get_with_meta`
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct RecordMeta {
+    pub last_modified: DateTime<Utc>,
+    pub author: String,
+    pub commit: Oid,
+    /// Metadata stored along with the document, see `Collection::document_meta`.
+    pub document: Option<metadata::DocumentMeta>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct WriteResult {
+    /// Commit created by the write, or the tip of the branch if nothing changed.
+    pub commit: Oid,
+    /// False if the write didn't change anything (every key already had its value),
+    /// in which case no commit was created and the branch wasn't moved.
+    pub committed: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct DeleteResult {
+    /// Number of keys removed.
+    pub deleted: usize,
+    /// Commit created by the delete, or the tip of the branch if nothing was removed.
+    pub commit: Oid,
+}
+
+/// Reads the content of a blob in place, without copying it into a separate buffer first -
+/// unless the collection is encrypted, in which case the value is decrypted upfront.
+pub struct BlobReader<'r> {
+    content: BlobContent<'r>,
+    position: usize,
+}
+
+enum BlobContent<'r> {
+    Blob(Blob<'r>),
+    Decrypted(Vec<u8>),
+}
+
+impl BlobReader<'_> {
+    fn content(&self) -> &[u8] {
+        match &self.content {
+            BlobContent::Blob(blob) => blob.content(),
+            BlobContent::Decrypted(content) => content,
+        }
+    }
+
+    /// Total size of the value in bytes
+    pub fn len(&self) -> usize {
+        self.content().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.content().is_empty()
+    }
+}
+
+impl Read for BlobReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = &self.content()[self.position..];
+        let n = remaining.len().min(buf.len());
+        buf[..n].copy_from_slice(&remaining[..n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+fn json_merge_patch(document: &mut serde_json::Value, patch: &serde_json::Value) {
+    let serde_json::Value::Object(patch_fields) = patch else {
+        *document = patch.clone();
+        return;
+    };
+    if !document.is_object() {
+        *document = serde_json::Value::Object(serde_json::Map::new());
+    }
+    // unwrap: the document has been made an object above
+    let document_fields = document.as_object_mut().unwrap();
+    for (field, value) in patch_fields {
+        if value.is_null() {
+            document_fields.remove(field);
+        } else {
+            json_merge_patch(
+                document_fields
+                    .entry(field)
+                    .or_insert(serde_json::Value::Null),
+                value,
+            );
+        }
+    }
 }
 
 trait RepositoryAbstraction {
-    fn init_new_repo(path: &Path) -> Result<Repository, git2::Error> {
+    fn init_new_repo(path: &Path, time: i64) -> Result<Repository, git2::Error> {
         let repo = Repository::init_opts(
             path,
             RepositoryInitOptions::new().bare(true).initial_head("main"),
@@ -56,7 +383,7 @@ trait RepositoryAbstraction {
             let index = &mut repo.index()?;
             let id = index.write_tree()?;
             let tree = repo.find_tree(id)?;
-            let sig = repo.signature()?;
+            let sig = Self::signature_at(time);
             repo.commit(Some("HEAD"), &sig, &sig, "init", &tree, &[])?;
             // HEAD has to exist and point at something
             let head = repo.head().unwrap().target().unwrap();
@@ -70,11 +397,11 @@ trait RepositoryAbstraction {
         Repository::open_bare(path)
     }
 
-    fn load_or_create_repo(path: &Path) -> Result<Repository, git2::Error> {
+    fn load_or_create_repo(path: &Path, time: i64) -> Result<Repository, git2::Error> {
         match Self::load_existing_repo(path) {
             Ok(repo) => Ok(repo),
             Err(error) => match error.code() {
-                ErrorCode::NotFound => Self::init_new_repo(path),
+                ErrorCode::NotFound => Self::init_new_repo(path, time),
                 _ => Err(error),
             },
         }
@@ -82,14 +409,53 @@ trait RepositoryAbstraction {
 
     fn current_commit<'a>(repo: &'a Repository, branch: &str) -> Result<Commit<'a>, git2::Error> {
         let reference = repo
-            .find_branch(branch.as_ref(), BranchType::Local)?
+            .find_branch(branch.as_ref(), BranchType::Local)
+            .map_err(|err| Self::repository_missing(repo).unwrap_or(err))?
             .into_reference();
         let commit = reference.peel_to_commit()?;
         Ok(commit)
     }
 
-    fn signature<'a>() -> Signature<'a> {
-        let current_time = &Time::new(chrono::Utc::now().timestamp(), 0);
+    /// git reports a deleted repository as a missing branch, this tells the two apart.
+    /// The error is worded like the one from opening a repository at a wrong path.
+    fn repository_missing(repo: &Repository) -> Option<git2::Error> {
+        if repo.path().exists() {
+            return None;
+        }
+        Some(git2::Error::new(
+            ErrorCode::GenericError,
+            git2::ErrorClass::Repository,
+            format!("could not find repository at '{}'", repo.path().display()),
+        ))
+    }
+
+    /// Commit at the tip of the target branch or the target commit itself
+    fn target_commit<'a>(
+        repo: &'a Repository,
+        target: OperationTarget,
+    ) -> Result<Commit<'a>, git2::Error> {
+        if !target.is_valid() {
+            // reported as NotFound so that callers turn it into InvalidOperationTarget
+            return Err(git2::Error::new(
+                ErrorCode::NotFound,
+                git2::ErrorClass::Reference,
+                format!("invalid operation target '{}'", target),
+            ));
+        }
+        match target {
+            OperationTarget::Main => Self::current_commit(repo, "main"),
+            OperationTarget::Transaction(branch) | OperationTarget::Branch(branch) => {
+                Self::current_commit(repo, branch)
+            }
+            OperationTarget::Commit(oid) => repo.find_commit(oid),
+            OperationTarget::Remote { remote, branch } => repo
+                .find_reference(&remote_reference(remote, branch))?
+                .peel_to_commit(),
+        }
+    }
+
+    fn signature_at<'a>(time: i64) -> Signature<'a> {
+        let current_time = &Time::new(time, 0);
         // unwrap: this signature has to be valid
         Signature::new("yamabiko", "yamabiko@localhost", current_time).unwrap()
     }
@@ -98,63 +464,503 @@ trait RepositoryAbstraction {
 pub struct Collection {
     repository: Repository,
     data_format: serialization::DataFormat,
+    migrations: BTreeMap<u32, migration::Migration>,
+    metrics: Arc<dyn metrics::Metrics>,
+    signer: Option<Arc<dyn signing::CommitSigner>>,
+    merge_driver: Option<Arc<dyn merge::MergeDriver>>,
+    hooks: hooks::Hooks,
+    read_cache: Option<Mutex<cache::ReadCache>>,
+    clock: Arc<dyn clock::Clock>,
+    stale_transaction_age: Duration,
+    lock_timeout: Duration,
+    lock: lock::CollectionLock,
+    #[cfg(any(feature = "encryption", feature = "full"))]
+    encryption: Option<Arc<encryption::Encryption>>,
+    // declared last so that it's removed only after the repository is closed
+    scratch_dir: Option<tempfile::TempDir>,
 }
 
 impl RepositoryAbstraction for Collection {}
 
 impl Collection {
+    /// Configure the collection with a CollectionBuilder, which persists the options
+    pub fn builder() -> builder::CollectionBuilder {
+        builder::CollectionBuilder::new()
+    }
+
+    /// Open the collection at `path`, creating the repository if there is none.
+    /// Indexes pushed by a Replicator but without a file here yet are written out.
     pub fn initialize(
         path: &Path,
         data_format: serialization::DataFormat,
     ) -> Result<Self, error::InitializationError> {
-        let repo = Self::load_or_create_repo(path)?;
+        Self::initialize_with_clock(path, data_format, Arc::new(clock::SystemClock))
+    }
+
+    /// Clone the collection at `url` (another Collection, most likely one some Replicator
+    /// pushes to) into a new repository at `path`, which can't exist or has to be empty.
+    /// Along with the documents come the indexes the remote has snapshots of (see
+    /// `Index::snapshot`), but not its transactions, configuration or metadata - so the data
+    /// format has to be given. The remote is set up as the replica named `origin`, with
+    /// `ReplicationMethod::All` and the credentials (which are also used for the clone).
+    ///
+    /// Fails with `NotACollection` if the remote has no main branch or its documents aren't
+    /// in the data format. Nothing is left at `path` if the clone fails.
+    pub fn clone_from(
+        url: &str,
+        path: &Path,
+        data_format: serialization::DataFormat,
+        credentials: Option<replica::RemoteCredentials>,
+    ) -> Result<Self, error::InitializationError> {
+        let existed = path.exists();
+        if existed && std::fs::read_dir(path).map_or(true, |mut entries| entries.next().is_some()) {
+            return Err(error::InitializationError::AlreadyExists);
+        }
+        let result = Self::clone_into(url, path, data_format, credentials);
+        if result.is_err() {
+            let _cleanup = if existed {
+                std::fs::read_dir(path).and_then(|entries| {
+                    entries
+                        .flatten()
+                        .try_for_each(|entry| match entry.file_type() {
+                            Ok(kind) if kind.is_dir() => std::fs::remove_dir_all(entry.path()),
+                            _ => std::fs::remove_file(entry.path()),
+                        })
+                })
+            } else {
+                std::fs::remove_dir_all(path)
+            };
+            if let Err(_err) = _cleanup {
+                warn!(
+                    "Couldn't clean up {} after a failed clone: {}",
+                    path.display(),
+                    _err
+                );
+            }
+        }
+        result
+    }
+
+    fn clone_into(
+        url: &str,
+        path: &Path,
+        data_format: serialization::DataFormat,
+        credentials: Option<replica::RemoteCredentials>,
+    ) -> Result<Self, error::InitializationError> {
+        let repo = replica::clone_repository(url, path, credentials.as_ref())?;
+        if repo.find_branch("main", BranchType::Local).is_err() {
+            return Err(error::InitializationError::NotACollection(String::from(
+                "there is no main branch",
+            )));
+        }
+        drop(repo);
+        replica::Replicator::initialize(
+            path,
+            "origin",
+            url,
+            replica::ReplicationMethod::All,
+            credentials,
+        )?;
+        let collection = Self::builder().data_format(data_format).load(path)?;
+        let first = collection
+            .iter(OperationTarget::Main)
+            .map_err(|err| error::InitializationError::NotACollection(err.to_string()))?
+            .next();
+        if let Some(document) = first {
+            let (key, value) = document
+                .map_err(|err| error::InitializationError::NotACollection(err.to_string()))?;
+            data_format.validate(&value).map_err(|err| {
+                error::InitializationError::NotACollection(format!(
+                    "{} is not {}: {}",
+                    key, data_format, err
+                ))
+            })?;
+        }
+        Ok(collection)
+    }
+
+    /// Like `initialize`, with the timestamps of all commits (including the initial one,
+    /// if the repository is created) taken from the given Clock.
+    /// With a `MockClock`, the same operations always produce the same commits.
+    pub fn initialize_with_clock(
+        path: &Path,
+        data_format: serialization::DataFormat,
+        clock: Arc<dyn clock::Clock>,
+    ) -> Result<Self, error::InitializationError> {
+        let repo = Self::load_or_create_repo(path, clock.now())?;
+        index::Index::restore_snapshots(&repo)?;
+        transaction::mark_legacy_transactions(&repo)?;
         Ok(Self {
             repository: repo,
             data_format,
+            migrations: BTreeMap::new(),
+            metrics: Arc::new(metrics::NoopMetrics),
+            signer: None,
+            merge_driver: None,
+            hooks: hooks::Hooks::default(),
+            read_cache: None,
+            clock,
+            stale_transaction_age: DEFAULT_STALE_TRANSACTION_AGE,
+            lock_timeout: lock::DEFAULT_LOCK_TIMEOUT,
+            lock: lock::CollectionLock::default(),
+            #[cfg(any(feature = "encryption", feature = "full"))]
+            encryption: None,
+            scratch_dir: None,
+        })
+    }
+
+    /// Create a collection which lives only as long as the returned Collection, meant for
+    /// tests of code built on top of it. The API is the same as for `initialize`.
+    ///
+    /// libgit2 can't keep references, the config and the index files anywhere but on disk,
+    /// so the repository is created in a temporary directory - under `/dev/shm` where it's
+    /// available, which keeps it in memory - and removed on drop. Anything which needs to
+    /// open the collection by path (`Replicator`, `write_pipeline`) works only until then.
+    pub fn create_in_memory(
+        data_format: serialization::DataFormat,
+    ) -> Result<Self, error::InitializationError> {
+        let shm = Path::new("/dev/shm");
+        let scratch_dir = if shm.is_dir() {
+            tempfile::Builder::new().prefix("yamabiko").tempdir_in(shm)
+        } else {
+            tempfile::Builder::new().prefix("yamabiko").tempdir()
+        }
+        .map_err(|err| git2::Error::from_str(&err.to_string()))?;
+        let mut collection = Self::initialize(scratch_dir.path(), data_format)?;
+        collection.scratch_dir = Some(scratch_dir);
+        Ok(collection)
+    }
+
+    /// Another handle to the repository configured like this one, e.g. for a writer thread
+    /// (a Collection can't be shared between threads). The migrations and the read cache
+    /// are left out - they only matter for reads.
+    pub(crate) fn reopen(&self) -> Result<Self, error::InitializationError> {
+        Ok(Self {
+            repository: Self::load_existing_repo(self.repository.path())?,
+            data_format: self.data_format,
+            migrations: BTreeMap::new(),
+            metrics: self.metrics.clone(),
+            signer: self.signer.clone(),
+            merge_driver: self.merge_driver.clone(),
+            hooks: self.hooks.clone(),
+            read_cache: None,
+            clock: self.clock.clone(),
+            stale_transaction_age: self.stale_transaction_age,
+            lock_timeout: self.lock_timeout,
+            lock: lock::CollectionLock::default(),
+            #[cfg(any(feature = "encryption", feature = "full"))]
+            encryption: self.encryption.clone(),
+            scratch_dir: None,
         })
     }
 
+    /// Count the transactions not written to for longer than this as stale in `health`
+    /// (an hour by default)
+    pub fn with_stale_transaction_age(mut self, age: Duration) -> Self {
+        self.stale_transaction_age = age;
+        self
+    }
+
+    /// Wait up to `timeout` (10 seconds by default) for the write lock of the repository before
+    /// failing with `Locked`. Writes hold the lock (a file in the repository) from reading the tip
+    /// of the branch until moving it, so that writers in other processes sharing the repository
+    /// don't drop each other's commits. Merges take it only to move the branch, as they notice
+    /// it moved in the meantime on their own (see `TransactionError::MainMoved`), so conflict
+    /// resolvers are free to write. Reads don't take it. Hooks run while it's held,
+    /// so they can't write to the collection themselves.
+    pub fn with_lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    fn write_lock(&self) -> Result<lock::LockGuard<'_>, error::LockError> {
+        self.lock.acquire(&self.repository, self.lock_timeout)
+    }
+
+    /// Take the timestamps of the commits written from now on from the given Clock
+    pub fn with_clock(mut self, clock: Arc<dyn clock::Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn signature<'a>(&self) -> Signature<'a> {
+        Self::signature_at(self.clock.now())
+    }
+
+    /// Report the measurements of the operations to the given Metrics
+    pub fn with_metrics(mut self, metrics: Arc<dyn metrics::Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Sign the commits written by `set`, `set_batch`, `add_index` and by merging transactions
+    /// with the given CommitSigner. Without a signer, commits are written without a signature.
+    /// Commits created by rebasing transactions (`apply_transaction`), squashing and
+    /// the initial commit are never signed.
+    pub fn with_signer(mut self, signer: Arc<dyn signing::CommitSigner>) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    /// Merge the keys changed on both sides with the given MergeDriver when merging
+    /// with `ConflictResolution::Custom`.
+    pub fn with_merge_driver(mut self, driver: Arc<dyn merge::MergeDriver>) -> Self {
+        self.merge_driver = Some(driver);
+        self
+    }
+
+    /// Run the hook before every commit written to a branch of the collection - by `set`,
+    /// `set_batch`, `delete_batch` and the other writes, and by merging transactions.
+    /// A hook returning an error (or panicking) vetoes the write, which then fails with
+    /// `SetObjectError::Vetoed` (`TransactionError::Vetoed` for merges) before any reference
+    /// or index is changed. Hooks run in the order they were registered in, until one fails.
+    pub fn on_pre_commit<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&hooks::PendingWrite) -> Result<(), error::HookError> + Send + Sync + 'static,
+    {
+        self.hooks.add_pre_commit(Arc::new(hook));
+        self
+    }
+
+    /// Run the hook after every commit written to a branch of the collection, once the branch
+    /// points at it. Hooks run in the order they were registered in; a hook which panics
+    /// is logged and doesn't stop the others.
+    pub fn on_post_commit<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&hooks::CommittedWrite) + Send + Sync + 'static,
+    {
+        self.hooks.add_post_commit(Arc::new(hook));
+        self
+    }
+
+    /// Encrypt the values written from now on and decrypt the values read, for repositories
+    /// replicated to places which can't be trusted with the documents (see `Encryption`).
+    /// Values written without encryption (or with another key) can't be read anymore.
+    ///
+    /// The values of indexed fields would be visible in the index files, so an encrypted
+    /// collection neither maintains nor uses indexes - queries always scan the documents.
+    /// A fresh nonce is used for every write, so setting the same value again always
+    /// creates a commit.
+    #[cfg(any(feature = "encryption", feature = "full"))]
+    pub fn with_encryption(mut self, encryption: encryption::Encryption) -> Self {
+        self.encryption = Some(Arc::new(encryption));
+        self
+    }
+
+    fn is_encrypted(&self) -> bool {
+        #[cfg(any(feature = "encryption", feature = "full"))]
+        if self.encryption.is_some() {
+            return true;
+        }
+        false
+    }
+
+    /// The value as it's stored, encrypted if the collection is
+    fn seal_value(&self, data: Vec<u8>) -> Vec<u8> {
+        #[cfg(any(feature = "encryption", feature = "full"))]
+        if let Some(encryption) = &self.encryption {
+            return encryption.seal(&data);
+        }
+        data
+    }
+
+    /// Reverse of `seal_value`, for everything which reads the stored values
+    pub(crate) fn open_value<'b>(
+        &self,
+        content: &'b [u8],
+    ) -> Result<Cow<'b, [u8]>, error::DecryptionError> {
+        #[cfg(any(feature = "encryption", feature = "full"))]
+        if let Some(encryption) = &self.encryption {
+            return encryption.open(content).map(Cow::Owned);
+        }
+        Ok(Cow::Borrowed(content))
+    }
+
+    /// Keep up to `capacity` values read with `get` and `get_raw` in memory, dropping the least
+    /// recently used ones first. Values are cached along with the tree they were read from,
+    /// so writes (from this Collection or anywhere else) are seen right away.
+    pub fn with_read_cache(mut self, capacity: usize) -> Self {
+        self.read_cache = Some(Mutex::new(cache::ReadCache::new(capacity)));
+        self
+    }
+
+    /// Hits and misses of the cache enabled with `with_read_cache`, None if it's disabled
+    pub fn read_cache_stats(&self) -> Option<cache::ReadCacheStats> {
+        self.read_cache
+            .as_ref()
+            .map(|cache| cache.lock().unwrap().stats())
+    }
+
+    /// The git repository the collection is stored in, for the operations yamabiko doesn't
+    /// wrap (custom refs, notes, inspecting the odb).
+    ///
+    /// **Warning:** yamabiko expects to be the only one writing the branches and the `.index`
+    /// directory. Don't move `main` or the transaction branches, don't write documents
+    /// to the trees directly and don't touch the index files - the indexes would no longer
+    /// match the documents. Reading anything and managing refs outside of `refs/heads`
+    /// and `refs/yamabiko` is fine.
     pub fn repository(&self) -> &Repository {
         &self.repository
     }
 
+    /// Store for operational state which isn't part of the documents, meant for extensions
+    /// built on top of the collection (see `MetaStore`)
+    pub fn metadata(&self) -> meta::MetaStore<'_> {
+        meta::MetaStore::new(self)
+    }
+
+    /// Run the closure with the repository, see `repository` for what it may not do.
+    /// A Collection isn't shared between threads (each has its own repository handle),
+    /// so there is no lock to take - but writing documents through git2 leaves the indexes
+    /// out of date until `rebuild_index` is called.
+    pub fn with_repository<R>(&self, f: impl FnOnce(&Repository) -> R) -> R {
+        f(&self.repository)
+    }
+
+    /// Run the closure with mutable access to the repository (e.g. to change its settings
+    /// with `set_odb` or `set_namespace`), see `repository` for what it may not do
+    pub fn with_repository_mut<R>(&mut self, f: impl FnOnce(&mut Repository) -> R) -> R {
+        f(&mut self.repository)
+    }
+
+    /// View of the documents stored in the namespace `name`, separate from the documents
+    /// of the collection and of the other namespaces (see `namespace::Namespace`).
+    /// The name can't be empty, contain a slash or end like a reserved tree.
+    pub fn namespace(&self, name: &str) -> Result<namespace::Namespace<'_>, error::KeyError> {
+        namespace::Namespace::new(self, name)
+    }
+
+    /// Fetch the branches of the git remote (configured in the repository, e.g. with
+    /// `git remote add`) into `refs/remotes/<remote>/`, to be read with
+    /// `OperationTarget::Remote`. The local branches are left as they are.
+    pub fn refresh_remote(&self, remote: &str) -> Result<(), git2::Error> {
+        debug!("fetching {}", remote);
+        self.repository
+            .find_remote(remote)?
+            .fetch(&[] as &[&str], None, None)
+    }
+
+    /// Path of the bare repository the collection is stored in
+    pub fn path(&self) -> &Path {
+        self.repository.path()
+    }
+
+    pub fn data_format(&self) -> serialization::DataFormat {
+        self.data_format
+    }
+
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(
+            level = "debug",
+            name = "collection.get",
+            skip_all,
+            fields(key = key, branch = target.to_string())
+        )
+    )]
     fn get_tree_key(
         &self,
         key: &str,
         target: OperationTarget,
     ) -> Result<Option<git2::TreeEntry<'_>>, error::GetObjectError> {
         let path = Self::construct_path_to_key(key)?;
-        let branch = match target {
-            OperationTarget::Main => "main",
-            OperationTarget::Transaction(t) => t,
-        };
         let repo = &self.repository;
-        let tree_path = Collection::current_commit(repo, branch)
+        let tree_path = Collection::target_commit(repo, target)
             .map_err(|e| match e.code() {
-                ErrorCode::NotFound => error::GetObjectError::InvalidOperationTarget,
+                ErrorCode::NotFound => {
+                    error::GetObjectError::InvalidOperationTarget(target.to_string())
+                }
                 _ => e.into(),
             })?
             .tree()?
             .get_path(Path::new(&path))
             .ok();
+        self.metrics
+            .record_get(&target.to_string(), tree_path.is_some());
         Ok(tree_path)
     }
 
+    /// Content of the blob stored under the key, through the read cache if it's enabled
+    fn get_content(
+        &self,
+        key: &str,
+        target: OperationTarget,
+    ) -> Result<Option<Vec<u8>>, error::GetObjectError> {
+        let Some(cache) = &self.read_cache else {
+            let Some(tree_entry) = self.get_tree_key(key, target)? else {
+                return Ok(None);
+            };
+            return Ok(Some(self.entry_content(&tree_entry)?));
+        };
+        let path = Self::construct_path_to_key(key)?;
+        let commit =
+            Collection::target_commit(&self.repository, target).map_err(|e| match e.code() {
+                ErrorCode::NotFound => {
+                    error::GetObjectError::InvalidOperationTarget(target.to_string())
+                }
+                _ => e.into(),
+            })?;
+        if let Some(content) = cache.lock().unwrap().get(commit.tree_id(), &path) {
+            self.metrics
+                .record_get(&target.to_string(), content.is_some());
+            return Ok(content);
+        }
+        // read from the tree the commit points at, the branch might have moved on already
+        let tree = commit.tree()?;
+        let content = match tree.get_path(Path::new(&path)) {
+            Ok(tree_entry) => Some(self.entry_content(&tree_entry)?),
+            Err(_) => None,
+        };
+        self.metrics
+            .record_get(&target.to_string(), content.is_some());
+        cache
+            .lock()
+            .unwrap()
+            .insert(tree.id(), &path, content.clone());
+        Ok(content)
+    }
+
+    fn entry_content(
+        &self,
+        tree_entry: &git2::TreeEntry,
+    ) -> Result<Vec<u8>, error::GetObjectError> {
+        let obj = tree_entry.to_object(&self.repository)?;
+        let blob = obj
+            .as_blob()
+            .ok_or(error::GetObjectError::CorruptedObject)?;
+        Ok(self.open_value(blob.content())?.into_owned())
+    }
+
     pub fn get_raw(
         &self,
         key: &str,
         target: OperationTarget,
     ) -> Result<Option<String>, error::GetObjectError> {
-        if let Some(tree_entry) = self.get_tree_key(key, target)? {
-            let obj = tree_entry.to_object(&self.repository)?;
-            let blob = obj
-                .as_blob()
-                .ok_or_else(|| error::GetObjectError::CorruptedObject)?;
-            let blob_content = blob.content().to_owned();
-            let parsed = String::from_utf8(blob_content)?;
-            return Ok(Some(parsed));
+        match self.get_content(key, target)? {
+            Some(blob_content) => Ok(Some(String::from_utf8(blob_content)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Bytes `range` of the value stored under the key (as it's stored, i.e. serialized
+    /// in the data format of the collection). The range is clamped to the length of the
+    /// value: a range past its end gets the bytes up to the end (or none), an empty
+    /// or reversed range gets no bytes. None if there is no such key.
+    /// The value is still read whole, only the requested bytes are copied.
+    pub fn get_range(
+        &self,
+        key: &str,
+        range: Range<usize>,
+        target: OperationTarget,
+    ) -> Result<Option<Vec<u8>>, error::GetObjectError> {
+        let Some(tree_entry) = self.get_tree_key(key, target)? else {
+            return Ok(None);
         };
-        Ok(None)
+        let blob = self.repository.find_blob(tree_entry.id())?;
+        let value = self.open_value(blob.content())?;
+        let end = range.end.min(value.len());
+        let start = range.start.min(end);
+        Ok(Some(value[start..end].to_vec()))
     }
 
     pub fn get<D>(
@@ -165,102 +971,592 @@ impl Collection {
     where
         D: DeserializeOwned,
     {
-        if let Some(tree_entry) = self.get_tree_key(key, target)? {
-            let obj = tree_entry.to_object(&self.repository)?;
-            let blob = obj
-                .as_blob()
-                .ok_or_else(|| error::GetObjectError::CorruptedObject)?;
-            let blob_content = blob.content().to_owned();
-            return Ok(Some(self.data_format.deserialize(&blob_content)));
-        };
-        Ok(None)
+        Ok(self
+            .get_content(key, target)?
+            .map(|blob_content| self.data_format.deserialize(&blob_content)))
     }
 
-    /// Beware that this method only works on the main branch
-    /// Should be faster than the normal get by key if the blob is in cache
-    pub fn get_by_oid<D>(&self, oid: Oid) -> Result<Option<D>, error::GetObjectError>
+    /// Type tag the value was stored with by `set_typed` or `set_tagged`,
+    /// None for values stored without one (or if there is no such key)
+    pub fn type_of(
+        &self,
+        key: &str,
+        target: OperationTarget,
+    ) -> Result<Option<String>, error::GetObjectError> {
+        Ok(self
+            .document_meta(key, target)?
+            .and_then(|meta| meta.type_tag))
+    }
+
+    /// `get` which fails with a TypeMismatch if the value was stored by `set_typed`
+    /// as another type. Values stored without a type tag are deserialized as usual.
+    pub fn get_as<D>(
+        &self,
+        key: &str,
+        target: OperationTarget,
+    ) -> Result<Option<D>, error::GetObjectError>
     where
         D: DeserializeOwned,
     {
-        debug!("Looking up oid {}", oid);
-        let repo = &self.repository;
-        let blob = repo.find_blob(oid);
-        if let Ok(blob) = blob {
-            let blob_content = blob.content().to_owned();
-            return Ok(Some(self.data_format.deserialize(&blob_content)));
-        };
-        Ok(None)
+        self.get_tagged(key, std::any::type_name::<D>(), target)
     }
 
-    fn set_batch_with_indexing_fn<S, I, T, F>(
+    /// `get` which fails with a TypeMismatch if the value was stored with another type tag
+    /// (see `set_tagged`). Values stored without a type tag are deserialized as usual.
+    pub fn get_tagged<D>(
         &self,
-        items: I,
+        key: &str,
+        type_tag: &str,
         target: OperationTarget,
-        mut indexing_fn: F,
-    ) -> Result<(), error::SetObjectError>
+    ) -> Result<Option<D>, error::GetObjectError>
     where
-        S: Serialize,
-        I: IntoIterator<Item = (T, S)>,
-        T: AsRef<str>,
-        F: FnMut(&DataFormat, S, &mut HashMap<&crate::index::Index, Option<Field>>) -> Vec<u8>,
+        D: DeserializeOwned,
     {
-        let indexes = self.index_list();
-        let repo = &self.repository;
-        let branch = match target {
-            OperationTarget::Main => "main",
-            OperationTarget::Transaction(t) => t,
+        let path = Self::construct_path_to_key(key)?;
+        let tree = self.target_tree(target)?;
+        let Ok(tree_entry) = tree.get_path(Path::new(&path)) else {
+            self.metrics.record_get(&target.to_string(), false);
+            return Ok(None);
         };
-        let commit = Collection::current_commit(repo, branch)?;
+        self.metrics.record_get(&target.to_string(), true);
+        let found = metadata::read(&self.repository, &tree, &path)?.and_then(|meta| meta.type_tag);
+        if let Some(found) = found.filter(|found| found != type_tag) {
+            return Err(error::GetObjectError::TypeMismatch {
+                expected: type_tag.to_string(),
+                found,
+            });
+        }
+        let content = self.entry_content(&tree_entry)?;
+        Ok(Some(self.data_format.deserialize(&content)))
+    }
 
-        let mut root_tree = commit.tree()?;
-        let mut counter = 0;
-        for (key, value) in items {
-            counter += 1;
-            debug!("set #{} key '{}'", counter, key.as_ref());
-            let mut index_values = HashMap::new();
-            for index in indexes.iter() {
-                index_values.insert(index, None);
-            }
-            let blob =
-                repo.blob(indexing_fn(&self.data_format, value, &mut index_values).as_slice())?;
+    /// The value stored under the key together with when, by whom and in which commit
+    /// it was last changed. History is followed along first parents only, until the first
+    /// commit whose parent holds a different value (or none) under the key.
+    ///
+    /// A key removed with `soft_delete` comes back with an empty value and the tombstone
+    /// in the metadata of the document, from the commit which removed it.
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(
+            level = "debug",
+            name = "collection.get_with_meta",
+            skip_all,
+            fields(key = key, branch = target.to_string())
+        )
+    )]
+    pub fn get_with_meta(
+        &self,
+        key: &str,
+        target: OperationTarget,
+    ) -> Result<Option<(Vec<u8>, RecordMeta)>, error::GetObjectError> {
+        let path_str = Self::construct_path_to_key(key)?;
+        let path = Path::new(&path_str);
+        let repo = &self.repository;
+        let mut commit = Collection::target_commit(repo, target).map_err(|e| match e.code() {
+            ErrorCode::NotFound => {
+                error::GetObjectError::InvalidOperationTarget(target.to_string())
+            }
+            _ => e.into(),
+        })?;
+        let document = metadata::read(repo, &commit.tree()?, &path_str)?;
+        // a tombstone is followed through the history by its metadata instead
+        let meta_path = metadata::metadata_path(&path_str);
+        let (path, tree_entry, content) = match commit.tree()?.get_path(path) {
+            Ok(tree_entry) => {
+                let content = self.entry_content(&tree_entry)?;
+                (path, tree_entry, content)
+            }
+            Err(_)
+                if document
+                    .as_ref()
+                    .is_some_and(|meta| meta.tombstone.is_some()) =>
+            {
+                let path = Path::new(&meta_path);
+                (path, commit.tree()?.get_path(path)?, Vec::new())
+            }
+            Err(_) => {
+                self.metrics.record_get(&target.to_string(), false);
+                return Ok(None);
+            }
+        };
+        self.metrics.record_get(&target.to_string(), true);
+        while let Ok(parent) = commit.parent(0) {
+            let unchanged = parent
+                .tree()?
+                .get_path(path)
+                .is_ok_and(|entry| entry.id() == tree_entry.id());
+            if !unchanged {
+                break;
+            }
+            commit = parent;
+        }
+        let meta = RecordMeta {
+            last_modified: DateTime::from_timestamp(commit.time().seconds(), 0).unwrap_or_default(),
+            author: String::from_utf8_lossy(commit.author().name_bytes()).to_string(),
+            commit: commit.id(),
+            document,
+        };
+        Ok(Some((content, meta)))
+    }
+
+    /// Creation and update times along with the revision of the document, stored next to it
+    /// whenever it's written - unlike `get_with_meta`, this doesn't walk the history.
+    /// None if there is no such key, or if it was written before the metadata was introduced.
+    /// The times come from the Clock of the collection at the time of the write, so they
+    /// are kept when a transaction is applied, even though its commits are rebased.
+    pub fn document_meta(
+        &self,
+        key: &str,
+        target: OperationTarget,
+    ) -> Result<Option<metadata::DocumentMeta>, error::GetObjectError> {
+        let path = Self::construct_path_to_key(key)?;
+        let tree = self.target_tree(target)?;
+        if tree.get_path(Path::new(&path)).is_err() {
+            return Ok(None);
+        }
+        Ok(metadata::read(&self.repository, &tree, &path)?)
+    }
+
+    /// Stream the value stored under the key instead of copying it into memory at once
+    pub fn get_reader(
+        &self,
+        key: &str,
+        target: OperationTarget,
+    ) -> Result<Option<BlobReader<'_>>, error::GetObjectError> {
+        if let Some(tree_entry) = self.get_tree_key(key, target)? {
+            let blob = self
+                .repository
+                .find_blob(tree_entry.id())
+                .map_err(|_| error::GetObjectError::CorruptedObject)?;
+            let content = match self.is_encrypted() {
+                true => BlobContent::Decrypted(self.open_value(blob.content())?.into_owned()),
+                false => BlobContent::Blob(blob),
+            };
+            return Ok(Some(BlobReader {
+                content,
+                position: 0,
+            }));
+        };
+        Ok(None)
+    }
+
+    /// Beware that this method only works on the main branch
+    /// Should be faster than the normal get by key if the blob is in cache
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(level = "debug", name = "collection.get_by_oid", skip(self))
+    )]
+    pub fn get_by_oid<D>(&self, oid: Oid) -> Result<Option<D>, error::GetObjectError>
+    where
+        D: DeserializeOwned,
+    {
+        debug!("Looking up oid {}", oid);
+        let repo = &self.repository;
+        let blob = repo.find_blob(oid);
+        if let Ok(blob) = blob {
+            let blob_content = self.open_value(blob.content())?;
+            return Ok(Some(self.data_format.deserialize(&blob_content)));
+        };
+        Ok(None)
+    }
+
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(
+            name = "collection.set_batch",
+            skip_all,
+            fields(
+                branch = target.to_string(),
+                items = tracing::field::Empty,
+                commit = tracing::field::Empty
+            )
+        )
+    )]
+    fn set_batch_with_indexing_fn<S, I, T, F>(
+        &self,
+        items: I,
+        target: OperationTarget,
+        mut indexing_fn: F,
+        type_tag: Option<&str>,
+        namespace: Option<&namespace::Namespace>,
+    ) -> Result<WriteResult, error::SetObjectError>
+    where
+        S: Serialize,
+        I: IntoIterator<Item = (T, S)>,
+        T: AsRef<str>,
+        F: FnMut(&DataFormat, S, &mut HashMap<&crate::index::Index, Vec<Field>>) -> Vec<u8>,
+    {
+        let start = Instant::now();
+        let indexes = self.indexes()?;
+        let repo = &self.repository;
+        let branch = target.writable_branch()?;
+        let _lock = self.write_lock()?;
+        let commit = Self::branch_commit(repo, branch)?;
+
+        let full_root = commit.tree()?;
+        // the documents of a namespace are written into its tree like into a root of their own
+        let (root_tree, indexes) = match namespace {
+            Some(namespace) => {
+                let tree = namespace.tree(&full_root)?;
+                let indexes = namespace.indexes(&indexes, &tree)?;
+                (tree, indexes)
+            }
+            None => (full_root.clone(), indexes),
+        };
+        let value_limit = self.value_limit()?;
+        let mut counter = 0;
+        let mut bytes = 0;
+        // everything is serialized upfront so that nothing is written if any value is too large
+        let mut serialized = Vec::new();
+        for (key, value) in items {
+            counter += 1;
+            debug!("set #{} key '{}'", counter, key.as_ref());
+            let mut index_values = HashMap::new();
+            for index in indexes.iter() {
+                index_values.insert(index, Vec::new());
+            }
+            let data = self.seal_value(indexing_fn(&self.data_format, value, &mut index_values));
+            Self::check_value_size(data.len() as u64, value_limit)?;
+            bytes += data.len();
+            serialized.push((key, data, index_values));
+        }
+        // applied in the order of the keys, so that the outcome doesn't depend on the order
+        // of the iterator (the sort is stable - the last of the duplicated keys wins)
+        serialized.sort_by(|(a, _, _), (b, _, _)| a.as_ref().cmp(b.as_ref()));
+        // only the last value of a duplicated key matters, the earlier ones would only churn indexes
+        serialized.reverse();
+        serialized.dedup_by(|(a, _, _), (b, _, _)| a.as_ref() == b.as_ref());
+        serialized.reverse();
+        let mut blobs = Vec::new();
+        let mut index_updates = index::IndexUpdates::default();
+        // every item of the batch gets the same timestamp
+        let now = self.clock.now();
+        for (key, data, index_values) in serialized {
+            let blob = repo.blob(data.as_slice())?;
             let hash = Oid::hash_object(ObjectType::Blob, key.as_ref().as_bytes())?;
-            let trees =
-                Collection::make_tree(repo, hash.as_bytes(), &root_tree, key.as_ref(), blob)?;
-            root_tree = repo.find_tree(trees)?;
+            let path = Self::construct_path_to_key(key.as_ref())?;
+            let unchanged = root_tree
+                .get_path(Path::new(&path))
+                .is_ok_and(|entry| entry.id() == blob)
+                && metadata::read(repo, &root_tree, &path)?
+                    .and_then(|meta| meta.type_tag)
+                    .as_deref()
+                    == type_tag;
+            if !unchanged {
+                blobs.push(self.stamp(&root_tree, &path, now, type_tag)?);
+            }
+            blobs.push((path, blob));
+            // rewriting the same value would only move its index entries to the newest position
+            if unchanged {
+                debug!("key '{}' already has this value", key.as_ref());
+                continue;
+            }
             for (index, value) in index_values {
-                if let Some(val) = value {
-                    index.create_entry(repo, hash, &val);
-                } else {
-                    index.delete_entry(repo, hash);
-                }
+                // the previous value of the indexed field (if any) is stale either way
+                index_updates.replace(index, hash, value);
+            }
+        }
+        let blobs: Vec<(&str, Oid)> = blobs.iter().map(|(p, b)| (p.as_str(), *b)).collect();
+        let new_root = Self::insert_into_tree(repo, Some(&root_tree), &blobs)?;
+        let new_root = match namespace {
+            Some(namespace) => namespace.graft(&full_root, new_root)?,
+            None => new_root,
+        };
+        let result = if new_root == full_root.id() {
+            debug!("nothing changed on {}, skipping the commit", branch);
+            record!("commit", commit.id().to_string());
+            WriteResult {
+                commit: commit.id(),
+                committed: false,
+            }
+        } else {
+            let root_tree = repo.find_tree(new_root)?;
+            let (commit_msg, usage) = match namespace {
+                Some(namespace) => (
+                    format!(
+                        "set {} items in {} on {}",
+                        counter,
+                        namespace.name(),
+                        branch
+                    ),
+                    Some(namespace.usage_after(&full_root, &root_tree)?),
+                ),
+                None => (format!("set {} items on {}", counter, branch), None),
+            };
+            let commit =
+                self.commit_to_branch_then(branch, &commit, &root_tree, &commit_msg, || {
+                    // only once the commit went through, a vetoed write leaves the indexes untouched
+                    index_updates.apply(repo)?;
+                    if let (Some(namespace), Some(usage)) = (namespace, usage) {
+                        namespace.store_usage(branch, &root_tree, usage)?;
+                    }
+                    Ok(())
+                })?;
+            WriteResult {
+                commit,
+                committed: true,
+            }
+        };
+        record!("items", counter);
+        self.metrics
+            .record_set(branch, counter, bytes, start.elapsed());
+
+        Ok(result)
+    }
+
+    /// Reject values larger than `bytes` in all the subsequent writes.
+    /// The limit is persisted in the config of the repository.
+    pub fn set_value_limit(&self, bytes: u64) -> Result<(), git2::Error> {
+        self.repository
+            .config()?
+            .set_i64(VALUE_LIMIT_CONFIG, bytes.min(i64::MAX as u64) as i64)
+    }
+
+    /// Lift the limit set with `set_value_limit`
+    pub fn remove_value_limit(&self) -> Result<(), git2::Error> {
+        match self.repository.config()?.remove(VALUE_LIMIT_CONFIG) {
+            Err(err) if err.code() == ErrorCode::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    pub fn value_limit(&self) -> Result<Option<u64>, git2::Error> {
+        match self.repository.config()?.get_i64(VALUE_LIMIT_CONFIG) {
+            Ok(limit) => Ok(Some(limit.max(0) as u64)),
+            Err(err) if err.code() == ErrorCode::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Keep the tokens of idempotent writes (see `set_batch_idempotent`) for `retention`,
+    /// with a precision of seconds. The retention is persisted in the config of the repository.
+    /// It should be longer than the time a client may keep retrying a write - a token replayed
+    /// after it expired is written again. Every token costs a few dozen bytes, rewritten
+    /// on every idempotent write, so the retention shouldn't be much longer than that either.
+    pub fn set_idempotency_retention(&self, retention: Duration) -> Result<(), git2::Error> {
+        self.repository.config()?.set_i64(
+            idempotency::RETENTION_CONFIG,
+            retention.as_secs().min(i64::MAX as u64) as i64,
+        )
+    }
+
+    /// Retention set with `set_idempotency_retention`, one day if it wasn't set
+    pub fn idempotency_retention(&self) -> Result<Duration, git2::Error> {
+        let seconds = match self
+            .repository
+            .config()?
+            .get_i64(idempotency::RETENTION_CONFIG)
+        {
+            Ok(seconds) => seconds.max(0) as u64,
+            Err(err) if err.code() == ErrorCode::NotFound => idempotency::DEFAULT_RETENTION,
+            Err(err) => return Err(err),
+        };
+        Ok(Duration::from_secs(seconds))
+    }
+
+    /// Tip of the branch about to be written to
+    fn branch_commit<'r>(
+        repo: &'r Repository,
+        branch: &str,
+    ) -> Result<Commit<'r>, error::SetObjectError> {
+        Self::current_commit(repo, branch).map_err(|e| match e.code() {
+            ErrorCode::NotFound => {
+                error::SetObjectError::InvalidOperationTarget(branch.to_string())
+            }
+            _ => e.into(),
+        })
+    }
+
+    fn check_value_size(size: u64, limit: Option<u64>) -> Result<(), error::SetObjectError> {
+        match limit {
+            Some(limit) if size > limit => {
+                Err(error::SetObjectError::ValueTooLarge { size, limit })
             }
+            _ => Ok(()),
+        }
+    }
+
+    /// The checks `set_raw` makes of the key and the value before writing anything,
+    /// for the writes which are held somewhere else until they're committed
+    pub(crate) fn check_raw_write(
+        &self,
+        key: &str,
+        value: &[u8],
+    ) -> Result<(), error::SetObjectError> {
+        Self::construct_path_to_key(key)?;
+        let data = self.seal_value(
+            self.data_format
+                .serialize_with_indexes_raw(value, &mut HashMap::new()),
+        );
+        Self::check_value_size(data.len() as u64, self.value_limit()?)
+    }
+
+    fn commit_to_branch(
+        &self,
+        branch: &str,
+        parent: &Commit,
+        tree: &Tree,
+        message: &str,
+    ) -> Result<Oid, error::SetObjectError> {
+        self.commit_to_branch_then(branch, parent, tree, message, || Ok(()))
+    }
+
+    /// `commit_to_branch` which runs `update` (of the indexes, for example) once the branch
+    /// has moved, before the post-commit hooks - so that the hooks see the whole write done.
+    /// The hooks are called even if the update fails, the commit is there either way.
+    fn commit_to_branch_then<U>(
+        &self,
+        branch: &str,
+        parent: &Commit,
+        tree: &Tree,
+        message: &str,
+        update: U,
+    ) -> Result<Oid, error::SetObjectError>
+    where
+        U: FnOnce() -> Result<(), error::SetObjectError>,
+    {
+        let repo = &self.repository;
+        let parent_tree = parent.tree()?;
+        let pending = self.pending_write(branch, Some(&parent_tree), tree)?;
+        if let Some(pending) = &pending {
+            self.hooks
+                .pre_commit(pending)
+                .map_err(error::SetObjectError::Vetoed)?;
         }
-        let signature = Self::signature();
-        let commit_msg = format!("set {} items on {}", counter, branch);
+        let signature = self.signature();
         let new_commit =
-            repo.commit_create_buffer(&signature, &signature, &commit_msg, &root_tree, &[&commit])?;
-        // unwrap: commit_create_buffer should never create an invalid UTF-8
-        let commit_obj = repo.commit_signed(str::from_utf8(&new_commit).unwrap(), "", None)?;
+            repo.commit_create_buffer(&signature, &signature, message, tree, &[parent])?;
+        let commit_obj = self.write_commit(&new_commit)?;
         let mut branch_ref = repo
             .find_branch(branch, BranchType::Local)
-            .map_err(|_| error::SetObjectError::InvalidOperationTarget)?;
-        branch_ref.get_mut().set_target(commit_obj, &commit_msg)?;
+            .map_err(|_| error::SetObjectError::InvalidOperationTarget(branch.to_string()))?;
+        branch_ref.get_mut().set_target(commit_obj, message)?;
+        record!("commit", commit_obj.to_string());
+        self.log_insertions(branch, Some(&parent_tree), tree);
+        let updated = update();
+        if let Some(pending) = pending {
+            self.committed(pending, commit_obj);
+        }
+        updated.map(|()| commit_obj)
+    }
 
-        Ok(())
+    /// Keep the insertion log (see `keys_by_insertion`) in line with main moving
+    /// from `old` to `new`. Main has already moved, so a failure doesn't fail the write -
+    /// the log is dropped instead, to be rebuilt rather than quietly missing the change.
+    fn log_insertions(&self, branch: &str, old: Option<&Tree>, new: &Tree) {
+        if branch != "main" || !insertions::exists(&self.repository) {
+            return;
+        }
+        let logged = self
+            .changed_keys(old, new)
+            .and_then(|changes| insertions::record(&self.repository, &changes));
+        if let Err(_err) = logged {
+            warn!(
+                "dropping the insertion log, which missed a change: {}",
+                _err
+            );
+            if let Err(_err) = insertions::discard(&self.repository) {
+                warn!("the insertion log couldn't be dropped: {}", _err);
+            }
+        }
+    }
+
+    /// The write from `old` to `new` given to the hooks, None if there are no hooks
+    fn pending_write(
+        &self,
+        branch: &str,
+        old: Option<&Tree>,
+        new: &Tree,
+    ) -> Result<Option<hooks::PendingWrite>, git2::Error> {
+        if self.hooks.is_empty() {
+            return Ok(None);
+        }
+        let odb = self.repository.odb()?;
+        let mut keys = Vec::new();
+        for change in self.changed_keys(old, new)? {
+            let size = match change.kind {
+                ChangeKind::Deleted => None,
+                _ => Self::construct_full_path(&change.key)
+                    .ok()
+                    .and_then(|path| new.get_path(Path::new(&path)).ok())
+                    .map(|entry| odb.read_header(entry.id()))
+                    .transpose()?
+                    .map(|(size, _)| size as u64),
+            };
+            keys.push(hooks::WrittenKey {
+                key: change.key,
+                kind: change.kind,
+                size,
+            });
+        }
+        Ok(Some(hooks::PendingWrite {
+            branch: branch.to_string(),
+            keys,
+        }))
+    }
+
+    fn committed(&self, pending: hooks::PendingWrite, commit: Oid) {
+        self.hooks.post_commit(&hooks::CommittedWrite {
+            branch: pending.branch,
+            keys: pending.keys,
+            commit,
+        });
+    }
+
+    /// Writes the commit created with `commit_create_buffer`, signed if there is a signer.
+    /// Unsigned commits don't get a `gpgsig` header at all.
+    fn write_commit(&self, buffer: &[u8]) -> Result<Oid, error::SigningError> {
+        let repo = &self.repository;
+        match &self.signer {
+            Some(signer) => {
+                // unwrap: commit_create_buffer should never create an invalid UTF-8
+                let content = str::from_utf8(buffer).unwrap();
+                let signature = signer.sign(content)?;
+                Ok(repo.commit_signed(content, &signature, None)?)
+            }
+            None => Ok(repo.odb()?.write(ObjectType::Commit, buffer)?),
+        }
     }
 
+    /// Returns the commit the items were written in.
+    /// Items are applied in the order of their keys, so the resulting tree is the same
+    /// no matter the order the iterator yields them in. If a key is given more than once,
+    /// the value which comes last wins.
+    /// If every key already has the given value, no commit is created and the current tip
+    /// of the target is returned instead (see `set_batch_with_result` to tell the two apart).
     pub fn set_batch<S, I, T>(
         &self,
         items: I,
         target: OperationTarget,
-    ) -> Result<(), error::SetObjectError>
+    ) -> Result<Oid, error::SetObjectError>
     where
         S: Serialize,
         I: IntoIterator<Item = (T, S)>,
         T: AsRef<str>,
     {
-        self.set_batch_with_indexing_fn(items, target, DataFormat::serialize_with_indexes)?;
-        Ok(())
+        Ok(self.set_batch_with_result(items, target)?.commit)
+    }
+
+    /// `set_batch` which also reports whether a commit was created - a write which
+    /// changes nothing doesn't need to be replicated, for example
+    pub fn set_batch_with_result<S, I, T>(
+        &self,
+        items: I,
+        target: OperationTarget,
+    ) -> Result<WriteResult, error::SetObjectError>
+    where
+        S: Serialize,
+        I: IntoIterator<Item = (T, S)>,
+        T: AsRef<str>,
+    {
+        self.set_batch_with_indexing_fn(
+            items,
+            target,
+            DataFormat::serialize_with_indexes,
+            None,
+            None,
+        )
     }
 
     pub fn set<S>(
@@ -268,7 +1564,7 @@ impl Collection {
         key: &str,
         value: S,
         target: OperationTarget,
-    ) -> Result<(), error::SetObjectError>
+    ) -> Result<Oid, error::SetObjectError>
     where
         S: Serialize,
     {
@@ -279,13 +1575,20 @@ impl Collection {
         &self,
         items: I,
         target: OperationTarget,
-    ) -> Result<(), error::SetObjectError>
+    ) -> Result<Oid, error::SetObjectError>
     where
         I: IntoIterator<Item = (T, &'a [u8])>,
         T: AsRef<str>,
     {
-        self.set_batch_with_indexing_fn(items, target, DataFormat::serialize_with_indexes_raw)?;
-        Ok(())
+        Ok(self
+            .set_batch_with_indexing_fn(
+                items,
+                target,
+                DataFormat::serialize_with_indexes_raw,
+                None,
+                None,
+            )?
+            .commit)
     }
 
     pub fn set_raw(
@@ -293,360 +1596,2803 @@ impl Collection {
         key: &str,
         value: &[u8],
         target: OperationTarget,
-    ) -> Result<(), error::SetObjectError> {
+    ) -> Result<Oid, error::SetObjectError> {
         self.set_batch_raw([(key, value)], target)
     }
 
-    pub fn new_transaction(&self, name: Option<&str>) -> Result<String, git2::Error> {
-        let repo = &self.repository;
-        // unwrap: HEAD has to exist and point at something
-        let head = repo.head().unwrap().target().unwrap();
-        let head_commit = repo.find_commit(head)?;
-        let transaction_name = name.map(|n| n.to_string()).unwrap_or_else(|| {
-            format!(
-                "t-{}",
-                rand::thread_rng()
-                    .sample_iter(&Alphanumeric)
-                    .take(8)
-                    .map(char::from)
-                    .collect::<String>()
-            )
-        });
-        repo.branch(&transaction_name, &head_commit, false)?;
-        Ok(transaction_name)
+    /// Store the value along with the name of its type (see `std::any::type_name`), so that
+    /// reading it with `get_as` as another type fails instead of producing garbage.
+    /// The type name isn't guaranteed to be stable across compiler versions - use `set_tagged`
+    /// with your own schema id if the collection has to outlive the build.
+    pub fn set_typed<S>(
+        &self,
+        key: &str,
+        value: &S,
+        target: OperationTarget,
+    ) -> Result<Oid, error::SetObjectError>
+    where
+        S: Serialize,
+    {
+        self.set_tagged(key, value, std::any::type_name::<S>(), target)
     }
 
-    pub fn apply_transaction(
+    /// Store the value along with a type tag of your choosing (see `type_of` and `get_tagged`).
+    /// The tag is kept in the metadata of the document, so writing the key in any other way
+    /// (`set`, `patch`, staged changes...) removes it.
+    pub fn set_tagged<S>(
         &self,
-        name: &str,
-        conflict_resolution: ConflictResolution,
-    ) -> Result<(), error::TransactionError> {
-        let repo = &self.repository;
-        let main_branch = repo
-            .find_annotated_commit(Collection::current_commit(repo, "main")?.id())
-            .unwrap();
-        let transaction =
-            Collection::current_commit(repo, name).map_err(|err| match err.code() {
-                ErrorCode::NotFound => error::TransactionError::TransactionNotFound,
-                _ => err.into(),
-            })?;
-        let target_branch = repo.find_annotated_commit(transaction.id())?;
-        let mut checkout_options = CheckoutBuilder::new();
-        checkout_options.force();
-        checkout_options.allow_conflicts(true);
-        let mut merge_options = MergeOptions::new();
-        match conflict_resolution {
-            ConflictResolution::DiscardChanges => {
-                checkout_options.use_ours(true);
-                merge_options.file_favor(FileFavor::Ours);
-            }
-            ConflictResolution::Overwrite => {
-                checkout_options.use_theirs(true);
-                merge_options.file_favor(FileFavor::Theirs);
-            }
-            ConflictResolution::Abort => {
-                // merge_options.fail_on_conflict(true);
-            }
-        }
-        let mut rebase_options = RebaseOptions::new();
-        let mut rebase_opts = rebase_options
-            .inmemory(true)
-            .checkout_options(checkout_options)
-            .merge_options(merge_options);
-        let mut rebase = repo
-            .rebase(
-                Some(&target_branch),
-                Some(&main_branch),
+        key: &str,
+        value: S,
+        type_tag: &str,
+        target: OperationTarget,
+    ) -> Result<Oid, error::SetObjectError>
+    where
+        S: Serialize,
+    {
+        Ok(self
+            .set_batch_with_indexing_fn(
+                [(key, value)],
+                target,
+                DataFormat::serialize_with_indexes,
+                Some(type_tag),
                 None,
-                Some(&mut rebase_opts),
-            )
-            .unwrap();
-        let mut current_commit: Option<Oid> = None;
-        loop {
-            let change = rebase.next();
-            if change.is_none() {
-                rebase.finish(None).unwrap();
-                if let Some(commit) = current_commit {
-                    let mut branch_ref = repo.find_branch("main", BranchType::Local).unwrap();
-                    branch_ref
-                        .get_mut()
-                        .set_target(commit, format!("apply transaction {}", name).as_str())
-                        .unwrap();
-                };
-                break;
-            }
-            match rebase.commit(None, &Self::signature(), None) {
-                Ok(com) => current_commit = Some(com),
-                Err(err) => match err.code() {
-                    ErrorCode::Applied => {}
-                    ErrorCode::MergeConflict | ErrorCode::Unmerged => match conflict_resolution {
-                        ConflictResolution::Abort => {
-                            rebase.abort()?;
-                            return Err(error::TransactionError::Aborted);
-                        }
-                        _ => return Err(err.into()),
-                    },
-                    _ => return Err(err.into()),
-                },
-            }
-        }
-        repo.find_branch(name, BranchType::Local)
-            .unwrap()
-            .delete()
-            .unwrap();
-        Ok(())
+            )?
+            .commit)
     }
 
-    pub fn add_index(&self, field: &str, kind: index::IndexType) -> index::Index {
-        let branch = "main";
+    /// `set_batch` which is written only once for the given token, for clients which may
+    /// send the same write again (e.g. retrying after a timeout). A write with a token seen
+    /// within the retention (see `set_idempotency_retention`) writes nothing and returns
+    /// the commit of the original write - even if the items or the target are different.
+    /// Tokens are recorded only for successful writes, so a failed write can be retried
+    /// with the same token.
+    pub fn set_batch_idempotent<S, I, T>(
+        &self,
+        token: &str,
+        items: I,
+        target: OperationTarget,
+    ) -> Result<Oid, error::SetObjectError>
+    where
+        S: Serialize,
+        I: IntoIterator<Item = (T, S)>,
+        T: AsRef<str>,
+    {
         let repo = &self.repository;
-        let commit = Collection::current_commit(repo, branch).unwrap();
-        let index_tree = commit.tree().unwrap();
-        let index_name = format!("{}#{}.index", &field, kind);
-        let existing_index = index_tree.get_name(&index_name);
-        let index_obj = index::Index::from_name(&index_name).unwrap();
-        if existing_index.is_none() {
-            {
-                let mut tb = repo.treebuilder(Some(&index_tree)).unwrap();
-                Self::ensure_index_dir_exists(repo);
-                let mut index =
-                    Index::open(Path::new(&repo.path().join(".index").join(&index_name))).unwrap();
-                let obj = index.write_tree_to(repo).unwrap();
-                tb.insert(&index_name, obj, 0o040000).unwrap();
-                let new_root = tb.write().unwrap();
-                let root_tree = repo.find_tree(new_root).unwrap();
-                let signature = Self::signature();
-                let new_commit = repo
-                    .commit_create_buffer(
-                        &signature,
-                        &signature,
-                        format!("add index: {}", index_name).as_str(),
-                        &root_tree,
-                        &[&commit],
-                    )
-                    .unwrap();
-                let commit_obj = repo
-                    .commit_signed(str::from_utf8(&new_commit).unwrap(), "", None)
-                    .unwrap();
-                let mut branch_ref = repo.find_branch(branch, BranchType::Local).unwrap();
-                branch_ref
-                    .get_mut()
-                    .set_target(commit_obj, format!("add index: {}", index_name).as_str())
-                    .unwrap();
-            }
+        let retention = self.idempotency_retention()?.as_secs();
+        let now = self.clock.now();
+        // held from checking the token until it's recorded, so that a concurrent replay
+        // of the same write waits and finds the token instead of writing again
+        let _lock = self.write_lock()?;
+        if let Some(commit) = idempotency::recorded(repo, token, now, retention)? {
+            debug!("Write with token {} was already made in {}", token, commit);
+            return Ok(commit);
         }
-        self.populate_index(repo, &index_obj);
-        index_obj
+        let commit = self.set_batch(items, target)?;
+        idempotency::record(repo, &self.signature(), token, commit, now, retention)?;
+        Ok(commit)
     }
 
-    fn populate_index(&self, repo: &Repository, index: &index::Index) {
-        let current_commit = Collection::current_commit(repo, "main").unwrap();
-        current_commit
-            .tree()
-            .unwrap()
-            .walk(git2::TreeWalkMode::PostOrder, |_, entry| {
-                if entry.kind() != Some(ObjectType::Blob)
-                    || entry.name().unwrap().ends_with(".index")
-                {
-                    return TreeWalkResult::Skip;
-                }
-                let mut index_values: HashMap<&index::Index, Option<Field>> = HashMap::new();
-                index_values.insert(index, None);
-                let oid = entry.id();
-                let blob = entry.to_object(repo).unwrap();
-                let blob_content = blob.as_blob().unwrap().content();
-                self.data_format
-                    .serialize_with_indexes_raw(blob_content, &mut index_values);
-                if let Some(v) = index_values.get(index).unwrap() {
-                    index.create_entry(repo, oid, v);
-                }
-                TreeWalkResult::Ok
-            })
-            .unwrap();
+    pub fn set_idempotent<S>(
+        &self,
+        token: &str,
+        key: &str,
+        value: S,
+        target: OperationTarget,
+    ) -> Result<Oid, error::SetObjectError>
+    where
+        S: Serialize,
+    {
+        self.set_batch_idempotent(token, [(key, value)], target)
     }
 
-    pub fn index_list(&self) -> Vec<index::Index> {
+    /// Start a writer thread which coalesces the writes submitted through the returned
+    /// WritePipeline within `window` of each other into single commits
+    pub fn write_pipeline(
+        &self,
+        window: std::time::Duration,
+    ) -> Result<pipeline::WritePipeline, error::InitializationError> {
+        pipeline::WritePipeline::start(self, window)
+    }
+
+    /// Store the values and remove the keys mapped to None, all in a single commit.
+    /// Removing keys that don't exist is ignored. Returns None if nothing changed.
+    pub(crate) fn write_changes<'a, I>(
+        &self,
+        changes: I,
+        target: OperationTarget,
+    ) -> Result<Option<Oid>, error::SetObjectError>
+    where
+        I: IntoIterator<Item = (&'a str, Option<&'a [u8]>)>,
+    {
+        let start = Instant::now();
+        let indexes = self.indexes()?;
         let repo = &self.repository;
-        let index_tree = Self::current_commit(repo, "main").unwrap().tree().unwrap();
-        let mut indexes = Vec::new();
-        for index in index_tree.iter() {
-            if index.name().unwrap().ends_with(".index") {
-                indexes.push(index::Index::from_name(index.name().unwrap()).unwrap());
+        let branch = target.writable_branch()?;
+        let _lock = self.write_lock()?;
+        let commit = Self::branch_commit(repo, branch)?;
+        let value_limit = self.value_limit()?;
+        let mut root_tree = commit.tree()?;
+        let mut serialized = Vec::new();
+        let mut deleted = Vec::new();
+        let mut bytes = 0;
+        // every value is checked before anything is removed from the tree or the indexes
+        for (key, value) in changes {
+            let path = Self::construct_path_to_key(key)?;
+            let hash = Oid::hash_object(ObjectType::Blob, key.as_bytes())?;
+            match value {
+                Some(value) => {
+                    let mut index_values = HashMap::new();
+                    for index in indexes.iter() {
+                        index_values.insert(index, Vec::new());
+                    }
+                    let data = self
+                        .data_format
+                        .serialize_with_indexes_raw(value, &mut index_values);
+                    let data = self.seal_value(data);
+                    Self::check_value_size(data.len() as u64, value_limit)?;
+                    bytes += data.len();
+                    serialized.push((path, hash, data, index_values));
+                }
+                None => deleted.push((path, hash)),
             }
         }
-        indexes
-    }
-
-    fn index_field_map(repo: &Repository) -> HashMap<String, index::Index> {
-        let index_tree = Self::current_commit(repo, "main").unwrap().tree().unwrap();
-        let mut indexes = HashMap::new();
-        for index in index_tree.iter() {
-            if index.name().unwrap().ends_with(".index") {
-                let ind = index::Index::from_name(index.name().unwrap()).unwrap();
-                indexes.insert(ind.indexed_field().to_string(), ind);
+        let mut index_updates = index::IndexUpdates::default();
+        let mut removed = 0;
+        for (path, hash) in deleted {
+            let Some(new_root) = Self::remove_document(repo, &root_tree, &path)? else {
+                continue;
+            };
+            removed += 1;
+            root_tree = repo.find_tree(new_root)?;
+            for index in indexes.iter() {
+                index_updates.replace(index, hash, Vec::new());
             }
         }
-        indexes
+        if serialized.is_empty() && removed == 0 {
+            return Ok(None);
+        }
+        let mut blobs = Vec::new();
+        let now = self.clock.now();
+        for (path, hash, data, index_values) in serialized.iter_mut() {
+            let blob = repo.blob(data)?;
+            if !root_tree
+                .get_path(Path::new(path))
+                .is_ok_and(|entry| entry.id() == blob)
+            {
+                blobs.push(self.stamp(&root_tree, path, now, None)?);
+            }
+            blobs.push((path.clone(), blob));
+            for (index, value) in index_values.drain() {
+                index_updates.replace(index, *hash, value);
+            }
+        }
+        let blobs: Vec<(&str, Oid)> = blobs.iter().map(|(p, b)| (p.as_str(), *b)).collect();
+        let root_tree = repo.find_tree(Self::insert_into_tree(repo, Some(&root_tree), &blobs)?)?;
+        let commit_msg = format!(
+            "set {} items and delete {} items on {}",
+            serialized.len(),
+            removed,
+            branch
+        );
+        let commit_obj =
+            self.commit_to_branch_then(branch, &commit, &root_tree, &commit_msg, || {
+                index_updates.apply(repo)?;
+                Ok(())
+            })?;
+        if !serialized.is_empty() {
+            self.metrics
+                .record_set(branch, serialized.len(), bytes, start.elapsed());
+        }
+        if removed > 0 {
+            self.metrics.record_delete(branch, removed, start.elapsed());
+        }
+        Ok(Some(commit_obj))
     }
 
-    fn ensure_index_dir_exists(repo: &Repository) {
-        std::fs::create_dir_all(repo.path().join(".index")).unwrap();
+    /// Load a large number of items onto main in a few big commits instead of one per item
+    pub fn bulk_load(&self) -> bulk::BulkLoader<'_> {
+        bulk::BulkLoader::new(self)
     }
 
-    fn make_tree<'a>(
-        repo: &'a Repository,
-        oid: &[u8],
-        root_tree: &'a Tree,
-        key: &str,
-        blob: Oid,
-    ) -> Result<Oid, git2::Error> {
-        let mut trees: Vec<(String, TreeBuilder)> =
-            vec![("".to_string(), repo.treebuilder(Some(root_tree))?)];
-        let natural_tree = key.contains("/");
-        if natural_tree {
-            let mut iterator = key.split("/").peekable();
-            while let Some(part) = iterator.next() {
-                let (parent_name, mut parent_tree) = trees.pop().unwrap();
-                if iterator.peek().is_none() {
-                    parent_tree.insert(part, blob, 0o100644)?;
-                    trees.push((parent_name, parent_tree));
-                } else {
-                    let tree_builder = parent_tree
-                        .get(part)
-                        .unwrap()
-                        .map(|x| {
-                            repo.treebuilder(Some(&x.to_object(repo).unwrap().into_tree().unwrap()))
-                        })
-                        .unwrap_or_else(|| repo.treebuilder(None))?;
-                    trees.push((parent_name, parent_tree));
-                    trees.push((part.to_string(), tree_builder));
+    /// Compute an aggregate of a numeric field over the documents on main matching the filter
+    /// (or all of them). Uses a numeric index on the field if there is one.
+    pub fn aggregate(
+        &self,
+        field: &str,
+        aggregate: query::Aggregate,
+        filter: Option<query::QueryGroup>,
+    ) -> Result<query::AggregateResult, error::QueryError> {
+        query::aggregate(self, field, aggregate, filter)
+    }
+
+    /// Remove the keys along with their index entries in a single commit.
+    /// Keys that don't exist are ignored. Returns the number of keys actually removed.
+    pub fn delete_batch<I, T>(
+        &self,
+        keys: I,
+        target: OperationTarget,
+    ) -> Result<usize, error::SetObjectError>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<str>,
+    {
+        Ok(self.delete_batch_with_result(keys, target)?.deleted)
+    }
+
+    /// `delete_batch` which also returns the commit the keys were removed in
+    pub fn delete_batch_with_result<I, T>(
+        &self,
+        keys: I,
+        target: OperationTarget,
+    ) -> Result<DeleteResult, error::SetObjectError>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<str>,
+    {
+        self.delete_batch_in(None, keys, target)
+    }
+
+    /// `delete_batch_with_result` of the keys of the namespace, or of the collection itself
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(
+            name = "collection.delete_batch",
+            skip_all,
+            fields(
+                branch = target.to_string(),
+                items = tracing::field::Empty,
+                commit = tracing::field::Empty
+            )
+        )
+    )]
+    fn delete_batch_in<I, T>(
+        &self,
+        namespace: Option<&namespace::Namespace>,
+        keys: I,
+        target: OperationTarget,
+    ) -> Result<DeleteResult, error::SetObjectError>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<str>,
+    {
+        let start = Instant::now();
+        let indexes = self.indexes()?;
+        let repo = &self.repository;
+        let branch = target.writable_branch()?;
+        let _lock = self.write_lock()?;
+        let commit = Self::branch_commit(repo, branch)?;
+        let full_root = commit.tree()?;
+        let (mut root_tree, indexes) = match namespace {
+            Some(namespace) => {
+                let tree = namespace.tree(&full_root)?;
+                let indexes = namespace.indexes(&indexes, &tree)?;
+                (tree, indexes)
+            }
+            None => (full_root.clone(), indexes),
+        };
+        let mut removed_hashes = Vec::new();
+        for key in keys {
+            let path = Self::construct_path_to_key(key.as_ref())?;
+            let Some(new_root) = Self::remove_document(repo, &root_tree, &path)? else {
+                debug!("key '{}' not found, nothing to delete", key.as_ref());
+                continue;
+            };
+            root_tree = repo.find_tree(new_root)?;
+            removed_hashes.push(Oid::hash_object(ObjectType::Blob, key.as_ref().as_bytes())?);
+        }
+        let removed = removed_hashes.len();
+        record!("items", removed);
+        if removed == 0 {
+            return Ok(DeleteResult {
+                deleted: 0,
+                commit: commit.id(),
+            });
+        }
+        let (root_tree, commit_msg, usage) = match namespace {
+            Some(namespace) => {
+                let root_tree = repo.find_tree(namespace.graft(&full_root, root_tree.id())?)?;
+                let usage = namespace.usage_after(&full_root, &root_tree)?;
+                let commit_msg = format!(
+                    "delete {} items in {} on {}",
+                    removed,
+                    namespace.name(),
+                    branch
+                );
+                (root_tree, commit_msg, Some(usage))
+            }
+            None => (
+                root_tree,
+                format!("delete {} items on {}", removed, branch),
+                None,
+            ),
+        };
+        let new_commit =
+            self.commit_to_branch_then(branch, &commit, &root_tree, &commit_msg, || {
+                for index in indexes.iter() {
+                    index.delete_entries(repo, &removed_hashes)?;
+                }
+                if let (Some(namespace), Some(usage)) = (namespace, usage) {
+                    namespace.store_usage(branch, &root_tree, usage)?;
                 }
+                Ok(())
+            })?;
+        self.metrics.record_delete(branch, removed, start.elapsed());
+        Ok(DeleteResult {
+            deleted: removed,
+            commit: new_commit,
+        })
+    }
+
+    /// Remove every key starting with the prefix along with their index entries
+    /// in a single commit (see `delete_batch`), e.g. to purge the documents of one tenant.
+    /// Returns the number of keys removed.
+    ///
+    /// Keys without a slash are spread over the tree by their hash rather than by name,
+    /// so the whole tree is walked. Documents of namespaces are not removed.
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(
+            name = "collection.delete_prefix",
+            skip_all,
+            fields(
+                prefix = prefix,
+                branch = target.to_string(),
+                items = tracing::field::Empty
+            )
+        )
+    )]
+    pub fn delete_prefix(
+        &self,
+        prefix: &str,
+        target: OperationTarget,
+    ) -> Result<usize, error::SetObjectError> {
+        let repo = &self.repository;
+        let branch = target.writable_branch()?;
+        let root_tree = Self::branch_commit(repo, branch)?.tree()?;
+        let mut keys = Vec::new();
+        let mut walk_error = None;
+        root_tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+            let Some(name) = entry.name() else {
+                return TreeWalkResult::Skip;
+            };
+            if (root.is_empty() && name.ends_with(".index")) || namespace::is_reserved_tree(entry) {
+                return TreeWalkResult::Skip;
             }
-        } else {
-            for part in 0..2 {
-                let (parent_name, parent_tree) = trees.pop().unwrap();
-                let hex_part = oid[part];
-                let name = format!("{hex_part:x}");
-                let mut tree_builder = parent_tree
-                    .get(&name)
-                    .unwrap()
-                    .map(|x| {
-                        repo.treebuilder(Some(&x.to_object(repo).unwrap().into_tree().unwrap()))
-                    })
-                    .unwrap_or_else(|| repo.treebuilder(None))?;
-                if part == 1 {
-                    tree_builder.insert(key, blob, 0o100644)?;
+            if entry.kind() != Some(ObjectType::Blob) {
+                return TreeWalkResult::Ok;
+            }
+            match Self::key_from_path(root, name) {
+                Ok(key) => {
+                    if key.starts_with(prefix) {
+                        keys.push(key);
+                    }
+                    TreeWalkResult::Ok
+                }
+                Err(err) => {
+                    walk_error = Some(err);
+                    TreeWalkResult::Abort
                 }
-                trees.push((parent_name, parent_tree));
-                trees.push((name, tree_builder));
             }
+        })?;
+        if let Some(err) = walk_error {
+            return Err(err.into());
         }
+        debug!("{} keys start with '{}'", keys.len(), prefix);
+        let removed = self.delete_batch(keys, target)?;
+        record!("items", removed);
+        Ok(removed)
+    }
 
-        loop {
-            let (self_name, self_tree) = trees.pop().unwrap();
-            if let Some((parent_name, mut parent_tree)) = trees.pop() {
-                let tree_id = self_tree.write()?;
-                parent_tree.insert(&self_name, tree_id, 0o040000)?;
-                trees.push((parent_name, parent_tree));
-            } else {
-                return self_tree.write();
+    /// Remove the key. Returns false if there was nothing to remove.
+    pub fn delete(
+        &self,
+        key: &str,
+        target: OperationTarget,
+    ) -> Result<bool, error::SetObjectError> {
+        Ok(self.delete_batch([key], target)? > 0)
+    }
+
+    /// Remove the key, leaving a tombstone in its metadata from which `restore` can bring
+    /// the value back until the tombstone is purged (see `purge_tombstones`). The key is gone
+    /// for `get`, iteration and the indexes, only `get_with_meta` reveals the tombstone.
+    /// Writing the key again replaces the tombstone. Returns false if there was nothing to remove.
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(
+            name = "collection.soft_delete",
+            skip_all,
+            fields(key = key, branch = target.to_string())
+        )
+    )]
+    pub fn soft_delete(
+        &self,
+        key: &str,
+        target: OperationTarget,
+    ) -> Result<bool, error::SetObjectError> {
+        let start = Instant::now();
+        let indexes = self.indexes()?;
+        let repo = &self.repository;
+        let branch = target.writable_branch()?;
+        let _lock = self.write_lock()?;
+        let commit = Self::branch_commit(repo, branch)?;
+        let root_tree = commit.tree()?;
+        let path = Self::construct_path_to_key(key)?;
+        let Ok(entry) = root_tree.get_path(Path::new(&path)) else {
+            debug!("key '{}' not found, nothing to delete", key);
+            return Ok(false);
+        };
+        let previous = metadata::read(repo, &root_tree, &path)?;
+        let meta = metadata::DocumentMeta::buried(previous, entry.id(), self.clock.now());
+        // unwrap: the document was just found at the path
+        let new_root = Self::remove_from_tree(repo, &root_tree, &path)?.unwrap();
+        let meta_path = metadata::metadata_path(&path);
+        let tombstone_path = metadata::tombstone_path(&path);
+        let new_root = Self::insert_into_tree(
+            repo,
+            Some(&repo.find_tree(new_root)?),
+            &[
+                (meta_path.as_str(), meta.write(repo)?),
+                (tombstone_path.as_str(), entry.id()),
+            ],
+        )?;
+        let commit_msg = format!("soft delete {} on {}", key, branch);
+        let hash = Oid::hash_object(ObjectType::Blob, key.as_bytes())?;
+        let new_root = repo.find_tree(new_root)?;
+        self.commit_to_branch_then(branch, &commit, &new_root, &commit_msg, || {
+            for index in indexes.iter() {
+                index.delete_entries(repo, &[hash])?;
             }
-        }
+            Ok(())
+        })?;
+        self.metrics.record_delete(branch, 1, start.elapsed());
+        Ok(true)
     }
 
-    fn prepare_history_tags(&self, head: Oid, target: Oid) -> Result<(), git2::Error> {
-        let remotes = self.repository.remotes()?;
-        let current_time = Utc::now();
-        let tag_name = format!(
-            "revert-{}-{}-{}",
-            &head.to_string()[0..7],
-            &target.to_string()[0..7],
-            current_time.timestamp()
-        );
-        self.repository
-            .reference(format!("refs/tags/{}", tag_name).as_str(), head, true, "")?;
-        for remote in remotes.iter().flatten() {
-            let ref_name = format!("refs/history_tags/{}/{}", remote, tag_name);
-            self.repository.reference(&ref_name, head, true, "")?;
-            debug!("Prepared {} for push", &ref_name);
-        }
-        Ok(())
+    /// Bring back the last value of a key removed with `soft_delete`, along with its index
+    /// entries. The metadata continues from the tombstone, as if the key was written again.
+    /// Returns false if the key has no tombstone (it was never soft-deleted, has been written
+    /// since or the tombstone was purged).
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(
+            name = "collection.restore",
+            skip_all,
+            fields(key = key, branch = target.to_string())
+        )
+    )]
+    pub fn restore(
+        &self,
+        key: &str,
+        target: OperationTarget,
+    ) -> Result<bool, error::SetObjectError> {
+        let start = Instant::now();
+        let repo = &self.repository;
+        let branch = target.writable_branch()?;
+        let _lock = self.write_lock()?;
+        let commit = Self::branch_commit(repo, branch)?;
+        let root_tree = commit.tree()?;
+        let path = Self::construct_path_to_key(key)?;
+        let tombstone = metadata::read(repo, &root_tree, &path)?
+            .and_then(|meta| meta.tombstone.clone().map(|tombstone| (meta, tombstone)));
+        let Some((meta, tombstone)) = tombstone else {
+            debug!("key '{}' has no tombstone, nothing to restore", key);
+            return Ok(false);
+        };
+        // tombstones written before the values were kept under .tombstones only have the oid
+        let tombstone_path = metadata::tombstone_path(&path);
+        let blob = match root_tree.get_path(Path::new(&tombstone_path)) {
+            Ok(entry) => repo.find_blob(entry.id())?,
+            Err(_) => repo.find_blob(Oid::from_str(&tombstone.blob)?)?,
+        };
+        let value = self
+            .open_value(blob.content())
+            .map_err(git2::Error::from)?
+            .into_owned();
+        let meta = metadata::DocumentMeta {
+            updated_at: self.clock.now(),
+            revision: meta.revision + 1,
+            tombstone: None,
+            ..meta
+        };
+        let meta_path = metadata::metadata_path(&path);
+        let new_root = Self::insert_into_tree(
+            repo,
+            Some(&root_tree),
+            &[
+                (path.as_str(), blob.id()),
+                (meta_path.as_str(), meta.write(repo)?),
+            ],
+        )?;
+        let new_root = repo.find_tree(new_root)?;
+        let new_root = match Self::remove_from_tree(repo, &new_root, &tombstone_path)? {
+            Some(without_value) => repo.find_tree(without_value)?,
+            None => new_root,
+        };
+        let commit_msg = format!("restore {} on {}", key, branch);
+        self.commit_to_branch_then(branch, &commit, &new_root, &commit_msg, || {
+            Ok(self.reindex(&[(key.to_string(), Some(value))])?)
+        })?;
+        self.metrics
+            .record_set(branch, 1, blob.size(), start.elapsed());
+        Ok(true)
     }
 
-    pub fn revert_main_to_commit(
+    /// Remove the tombstones left by `soft_delete` more than `older_than` ago (by the Clock
+    /// of the collection) in a single commit, after which the keys can't be restored.
+    /// Returns the number of tombstones removed.
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(
+            name = "collection.purge_tombstones",
+            skip_all,
+            fields(branch = target.to_string(), items = tracing::field::Empty)
+        )
+    )]
+    pub fn purge_tombstones(
         &self,
-        commit: Oid,
-        keep_history: bool,
-    ) -> Result<(), error::RevertError> {
+        older_than: Duration,
+        target: OperationTarget,
+    ) -> Result<usize, error::SetObjectError> {
         let repo = &self.repository;
-        let target_commit = repo
-            .find_commit(commit)
-            .map_err(|_| error::RevertError::TargetCommitNotFound(commit))?;
-        if keep_history {
-            let current_commit = Self::current_commit(repo, OperationTarget::Main.to_git_branch())
-                .map_err(|e| match e.code() {
-                    ErrorCode::NotFound => error::RevertError::InvalidOperationTarget,
-                    _ => e.into(),
+        let branch = target.writable_branch()?;
+        let _lock = self.write_lock()?;
+        let commit = Self::branch_commit(repo, branch)?;
+        let mut root_tree = commit.tree()?;
+        let max_age = older_than.as_secs().min(i64::MAX as u64) as i64;
+        let threshold = self.clock.now().saturating_sub(max_age);
+        let mut expired = Vec::new();
+        if let Some(entry) = root_tree.get_name(metadata::METADATA_TREE) {
+            let mut walk_error = None;
+            repo.find_tree(entry.id())?
+                .walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+                    if entry.kind() != Some(ObjectType::Blob) {
+                        return TreeWalkResult::Ok;
+                    }
+                    match metadata::DocumentMeta::from_blob(repo, entry.id()) {
+                        Ok(meta) => {
+                            if meta
+                                .tombstone
+                                .is_some_and(|tombstone| tombstone.deleted_at <= threshold)
+                            {
+                                let name = entry.name().unwrap_or_default();
+                                expired.push(format!("{}{}", root, name));
+                            }
+                            TreeWalkResult::Ok
+                        }
+                        Err(err) => {
+                            walk_error = Some(err);
+                            TreeWalkResult::Abort
+                        }
+                    }
                 })?;
-            self.prepare_history_tags(current_commit.id(), target_commit.id())?;
+            if let Some(err) = walk_error {
+                return Err(err.into());
+            }
+        }
+        let mut removed = Vec::new();
+        for path in &expired {
+            removed.push(metadata::metadata_path(path));
+            removed.push(metadata::tombstone_path(path));
+        }
+        // values of the keys written or restored since they were soft-deleted
+        // in a way which didn't remove them (e.g. by a merge)
+        if let Some(entry) = root_tree.get_name(metadata::TOMBSTONES_TREE) {
+            let mut walk_error = None;
+            repo.find_tree(entry.id())?
+                .walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+                    if entry.kind() != Some(ObjectType::Blob) {
+                        return TreeWalkResult::Ok;
+                    }
+                    let path = format!("{}{}", root, entry.name().unwrap_or_default());
+                    match metadata::read(repo, &root_tree, &path) {
+                        Ok(meta) => {
+                            if meta.is_none_or(|meta| meta.tombstone.is_none()) {
+                                removed.push(metadata::tombstone_path(&path));
+                            }
+                            TreeWalkResult::Ok
+                        }
+                        Err(err) => {
+                            walk_error = Some(err);
+                            TreeWalkResult::Abort
+                        }
+                    }
+                })?;
+            if let Some(err) = walk_error {
+                return Err(err.into());
+            }
+        }
+        let mut changed = false;
+        for path in &removed {
+            if let Some(new_root) = Self::remove_from_tree(repo, &root_tree, path)? {
+                root_tree = repo.find_tree(new_root)?;
+                changed = true;
+            }
+        }
+        record!("items", expired.len());
+        if changed {
+            let commit_msg = format!("purge {} tombstones on {}", expired.len(), branch);
+            self.commit_to_branch(branch, &commit, &root_tree, &commit_msg)?;
+        }
+        Ok(expired.len())
+    }
+
+    /// Remove every key (and attachment) of the branch in a single commit, leaving only the
+    /// index definitions and the namespaces. Clearing main also drops all the entries of the
+    /// indexes, which are left alone for the other branches since they describe main.
+    /// The previous commits stay in the history, so the documents can be brought back with
+    /// `revert_main_to_commit` (followed by `rebuild_index` for each index).
+    /// Nothing is committed if there are no keys.
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(
+            name = "collection.clear",
+            skip_all,
+            fields(branch = target.to_string(), commit = tracing::field::Empty)
+        )
+    )]
+    pub fn clear(&self, target: OperationTarget) -> Result<(), error::SetObjectError> {
+        let indexes = self.indexes()?;
+        let repo = &self.repository;
+        let branch = target.writable_branch()?;
+        let _lock = self.write_lock()?;
+        let commit = Self::branch_commit(repo, branch)?;
+        let root_tree = commit.tree()?;
+        let mut tb = repo.treebuilder(Some(&root_tree))?;
+        tb.filter(|entry| {
+            namespace::is_namespace_tree(entry)
+                || entry.name().is_some_and(|name| name.ends_with(".index"))
+        })?;
+        let clear_indexes = || {
+            if matches!(target, OperationTarget::Main) {
+                for index in indexes.iter() {
+                    index.clear(repo)?;
+                }
+            }
+            Ok(())
+        };
+        if tb.len() < root_tree.len() {
+            let new_root = repo.find_tree(tb.write()?)?;
+            let commit_msg = format!("clear {}", branch);
+            self.commit_to_branch_then(branch, &commit, &new_root, &commit_msg, clear_indexes)?;
+        } else {
+            clear_indexes()?;
         }
-        repo.reset(target_commit.as_object(), git2::ResetType::Soft, None)?;
         Ok(())
     }
 
-    pub fn revert_n_commits(
+    /// Stream the value into the repository and store it under the key.
+    /// The value is stored as-is, so indexes are not updated for it
+    /// (any index entries left from the previous value are removed).
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(
+            name = "collection.set_reader",
+            skip(self, reader),
+            fields(branch = target.to_string(), commit = tracing::field::Empty)
+        )
+    )]
+    pub fn set_reader<R>(
         &self,
-        n: usize,
+        key: &str,
+        reader: R,
         target: OperationTarget,
-        keep_history: bool,
-    ) -> Result<(), error::RevertError> {
-        debug!("Reverting {} commits", n);
-        if n == 0 {
-            return Ok(());
-        }
+    ) -> Result<WriteResult, error::StreamError>
+    where
+        R: Read,
+    {
+        let start = Instant::now();
         let repo = &self.repository;
-        let current_commit =
-            Self::current_commit(repo, target.to_git_branch()).map_err(|e| match e.code() {
-                ErrorCode::NotFound => error::RevertError::InvalidOperationTarget,
-                _ => e.into(),
-            })?;
-        let mut target_commit = current_commit.clone();
-        for _ in 0..n {
-            let parent_count = target_commit.parent_count();
-            if parent_count > 1 {
-                return Err(error::RevertError::BranchingHistory(target_commit.id()));
-            } else if parent_count == 0 {
-                debug!("No more parents to check");
-                break;
+        let (blob, bytes) = self.write_blob(reader)?;
+        let branch = target.writable_branch()?;
+        let _lock = self.write_lock().map_err(error::SetObjectError::from)?;
+        let commit = Self::branch_commit(repo, branch)?;
+        let hash = Oid::hash_object(ObjectType::Blob, key.as_bytes())
+            .map_err(error::SetObjectError::from)?;
+        let root_tree = commit.tree().map_err(error::SetObjectError::from)?;
+        let path = Self::construct_path_to_key(key).map_err(error::SetObjectError::from)?;
+        let (meta_path, meta) = self
+            .stamp(&root_tree, &path, self.clock.now(), None)
+            .map_err(error::SetObjectError::from)?;
+        let new_root = Collection::make_tree(repo, hash.as_bytes(), &root_tree, key, blob)
+            .and_then(|t| repo.find_tree(t))
+            .and_then(|t| Self::insert_into_tree(repo, Some(&t), &[(meta_path.as_str(), meta)]))
+            .and_then(|t| repo.find_tree(t))
+            .map_err(error::SetObjectError::from)?;
+        let commit_msg = format!("set 1 items on {}", branch);
+        let commit = self.commit_to_branch_then(branch, &commit, &new_root, &commit_msg, || {
+            for index in self.index_list() {
+                index.delete_entry(repo, hash)?;
             }
-            target_commit = target_commit.parent(0)?;
-            debug!("Current commit to revert: {:?}", target_commit.as_object());
-        }
-        if keep_history {
-            self.prepare_history_tags(current_commit.id(), target_commit.id())?;
-        }
-        repo.reset(target_commit.as_object(), git2::ResetType::Soft, None)?;
-        Ok(())
+            Ok(())
+        })?;
+        self.metrics
+            .record_set(branch, 1, bytes as usize, start.elapsed());
+        Ok(WriteResult {
+            commit,
+            committed: true,
+        })
     }
 
-    fn construct_path_to_key(key: &str) -> Result<String, error::KeyError> {
-        if key.contains("/") {
-            return Ok(key.to_string());
+    /// Stream the reader into a blob, unless it's larger than the value limit.
+    /// Returns the blob along with its size.
+    fn write_blob<R>(&self, mut reader: R) -> Result<(Oid, u64), error::StreamError>
+    where
+        R: Read,
+    {
+        let value_limit = self.value_limit().map_err(error::SetObjectError::from)?;
+        if self.is_encrypted() {
+            // the whole value is needed to encrypt it, the limit applies to the encrypted value
+            let mut data = Vec::new();
+            match value_limit {
+                Some(limit) => reader.by_ref().take(limit + 1).read_to_end(&mut data)?,
+                None => reader.read_to_end(&mut data)?,
+            };
+            let data = self.seal_value(data);
+            Self::check_value_size(data.len() as u64, value_limit)?;
+            let blob = self
+                .repository
+                .blob(&data)
+                .map_err(error::SetObjectError::from)?;
+            return Ok((blob, data.len() as u64));
         }
-        let hash = Oid::hash_object(ObjectType::Blob, key.as_bytes())
-            .map_err(error::KeyError::NotHashable)?;
-        let hash_bytes = hash.as_bytes();
-        let mut path = String::new();
-        (0..2).for_each(|x| {
-            let val = &hash_bytes[x];
-            path.push_str(format!("{val:x}").as_ref());
-            path.push('/');
-        });
-        path.push_str(key);
-        Ok(path)
+        let mut writer = self
+            .repository
+            .blob_writer(None)
+            .map_err(error::SetObjectError::from)?;
+        // the writer is dropped without committing the blob if the value turns out to be too large
+        let bytes = match value_limit {
+            Some(limit) => {
+                let bytes = std::io::copy(&mut reader.by_ref().take(limit + 1), &mut writer)?;
+                Self::check_value_size(bytes, value_limit)?;
+                bytes
+            }
+            None => std::io::copy(&mut reader, &mut writer)?,
+        };
+        let blob = writer.commit().map_err(error::SetObjectError::from)?;
+        Ok((blob, bytes))
     }
 
-    pub fn prefix_from_oid(oid: &Oid) -> String {
+    /// Store the content of the reader (a `&[u8]` will do) as the attachment `name` of the key,
+    /// replacing the attachment with that name, if there is one. The value limit applies
+    /// to attachments too. Attachments are stored as-is, next to the document, so they are not
+    /// indexed, queried nor migrated and the key doesn't need to have a document at all.
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(
+            name = "collection.put_attachment",
+            skip(self, reader),
+            fields(branch = target.to_string(), commit = tracing::field::Empty)
+        )
+    )]
+    pub fn put_attachment<R>(
+        &self,
+        key: &str,
+        name: &str,
+        reader: R,
+        target: OperationTarget,
+    ) -> Result<WriteResult, error::StreamError>
+    where
+        R: Read,
+    {
+        let repo = &self.repository;
+        let path = Self::construct_path_to_key(key)
+            .and_then(|document| attachment::attachment_path(&document, name))
+            .map_err(error::SetObjectError::from)?;
+        let branch = target.writable_branch()?;
+        let _lock = self.write_lock().map_err(error::SetObjectError::from)?;
+        let commit = Self::branch_commit(repo, branch)?;
+        let (blob, _) = self.write_blob(reader)?;
+        let root_tree = commit.tree().map_err(error::SetObjectError::from)?;
+        let new_root = Self::insert_into_tree(repo, Some(&root_tree), &[(path.as_str(), blob)])
+            .and_then(|t| repo.find_tree(t))
+            .map_err(error::SetObjectError::from)?;
+        let commit_msg = format!("attach {} to {} on {}", name, key, branch);
+        let commit = self.commit_to_branch(branch, &commit, &new_root, &commit_msg)?;
+        Ok(WriteResult {
+            commit,
+            committed: true,
+        })
+    }
+
+    /// Content of the attachment `name` of the key, None if there is no such attachment
+    pub fn get_attachment(
+        &self,
+        key: &str,
+        name: &str,
+        target: OperationTarget,
+    ) -> Result<Option<Vec<u8>>, error::GetObjectError> {
+        let path = attachment::attachment_path(&Self::construct_path_to_key(key)?, name)?;
+        let tree = self.target_tree(target)?;
+        let Ok(entry) = tree.get_path(Path::new(&path)) else {
+            return Ok(None);
+        };
+        Ok(Some(self.entry_content(&entry)?))
+    }
+
+    /// Names of the attachments of the key, sorted
+    pub fn list_attachments(
+        &self,
+        key: &str,
+        target: OperationTarget,
+    ) -> Result<Vec<String>, error::GetObjectError> {
+        let path = attachment::attachments_tree_path(&Self::construct_path_to_key(key)?);
+        let tree = self.target_tree(target)?;
+        let Ok(entry) = tree.get_path(Path::new(&path)) else {
+            return Ok(Vec::new());
+        };
+        let attachments = self.repository.find_tree(entry.id())?;
+        Ok(attachments
+            .iter()
+            .filter_map(|entry| entry.name().map(str::to_string))
+            .collect())
+    }
+
+    /// Remove the attachment `name` of the key. Returns false if there was nothing to remove.
+    pub fn delete_attachment(
+        &self,
+        key: &str,
+        name: &str,
+        target: OperationTarget,
+    ) -> Result<bool, error::SetObjectError> {
+        let path = attachment::attachment_path(&Self::construct_path_to_key(key)?, name)?;
+        self.remove_paths(&[path], target, &format!("detach {} from {}", name, key))
+    }
+
+    /// Remove the key along with all of its attachments in a single commit.
+    /// `delete` leaves the attachments in place. Returns false if there was nothing to remove.
+    pub fn delete_with_attachments(
+        &self,
+        key: &str,
+        target: OperationTarget,
+    ) -> Result<bool, error::SetObjectError> {
+        let path = Self::construct_path_to_key(key)?;
+        let attachments = attachment::attachments_tree_path(&path);
+        let repo = &self.repository;
+        let hash = Oid::hash_object(ObjectType::Blob, key.as_bytes())?;
+        let removed = self.remove_paths(
+            &[path, attachments],
+            target,
+            &format!("delete {} with attachments", key),
+        )?;
+        if removed {
+            for index in self.index_list() {
+                index.delete_entry(repo, hash)?;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Remove the paths (blobs or whole trees) in a single commit, if any of them exists
+    fn remove_paths(
+        &self,
+        paths: &[String],
+        target: OperationTarget,
+        message: &str,
+    ) -> Result<bool, error::SetObjectError> {
+        let repo = &self.repository;
+        let branch = target.writable_branch()?;
+        let _lock = self.write_lock()?;
+        let commit = Self::branch_commit(repo, branch)?;
+        let mut root_tree = commit.tree()?;
+        let mut removed = false;
+        for path in paths {
+            if let Some(new_root) = Self::remove_from_tree(repo, &root_tree, path)? {
+                root_tree = repo.find_tree(new_root)?;
+                removed = true;
+            }
+        }
+        if removed {
+            let commit_msg = format!("{} on {}", message, branch);
+            self.commit_to_branch(branch, &commit, &root_tree, &commit_msg)?;
+        }
+        Ok(removed)
+    }
+
+    fn target_tree(&self, target: OperationTarget) -> Result<Tree<'_>, error::GetObjectError> {
+        Ok(Collection::target_commit(&self.repository, target)
+            .map_err(|e| match e.code() {
+                ErrorCode::NotFound => {
+                    error::GetObjectError::InvalidOperationTarget(target.to_string())
+                }
+                _ => e.into(),
+            })?
+            .tree()?)
+    }
+
+    /// Lazily iterate over the keys and values (decrypted, but not deserialized) of all
+    /// the documents on the target, reading one document at a time. The iterator sees
+    /// the target as of when `iter` was called, see `iter::Documents`.
+    pub fn iter(
+        &self,
+        target: OperationTarget,
+    ) -> Result<iter::Documents<'_>, error::GetObjectError> {
+        Ok(iter::Documents::new(self, self.target_tree(target)?))
+    }
+
+    /// Apply a JSON merge patch (RFC 7386) to the document stored under the key.
+    /// Fields set to `null` in the patch are removed from the document.
+    pub fn patch(
+        &self,
+        key: &str,
+        patch: &serde_json::Value,
+        target: OperationTarget,
+    ) -> Result<WriteResult, error::PatchError> {
+        self.patch_batch([key], patch, target)
+    }
+
+    /// Apply the same JSON merge patch to every key and save the results in a single commit.
+    /// Fails without writing anything if any of the keys is missing.
+    pub fn patch_batch<I, T>(
+        &self,
+        keys: I,
+        patch: &serde_json::Value,
+        target: OperationTarget,
+    ) -> Result<WriteResult, error::PatchError>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<str>,
+    {
+        self.patch_batch_in(None, keys, patch, target)
+    }
+
+    /// `patch_batch` of the keys of the namespace, or of the collection itself
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(
+            name = "collection.patch_batch",
+            skip_all,
+            fields(
+                branch = target.to_string(),
+                items = tracing::field::Empty,
+                commit = tracing::field::Empty
+            )
+        )
+    )]
+    fn patch_batch_in<I, T>(
+        &self,
+        namespace: Option<&namespace::Namespace>,
+        keys: I,
+        patch: &serde_json::Value,
+        target: OperationTarget,
+    ) -> Result<WriteResult, error::PatchError>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<str>,
+    {
+        let mut patched = Vec::new();
+        for key in keys {
+            let document = match namespace {
+                Some(namespace) => namespace.get::<serde_json::Value>(key.as_ref(), target)?,
+                None => self.get::<serde_json::Value>(key.as_ref(), target)?,
+            };
+            let mut document =
+                document.ok_or_else(|| error::PatchError::KeyNotFound(key.as_ref().to_string()))?;
+            if !document.is_object() {
+                return Err(error::PatchError::NotAnObject(key.as_ref().to_string()));
+            }
+            json_merge_patch(&mut document, patch);
+            patched.push((key, document));
+        }
+        record!("items", patched.len());
+        Ok(self.set_batch_with_indexing_fn(
+            patched,
+            target,
+            DataFormat::serialize_with_indexes,
+            None,
+            namespace,
+        )?)
+    }
+
+    /// Register a migration upgrading documents to the given schema version (starting at 1).
+    /// Registering a version again replaces the previous migration.
+    pub fn register_migration<F>(&mut self, version: u32, f: F)
+    where
+        F: Fn(serde_json::Value) -> Result<serde_json::Value, error::MigrationError>
+            + Send
+            + 'static,
+    {
+        self.migrations.insert(version, Box::new(f));
+    }
+
+    /// Apply the pending registered migrations to every document on the target, in order
+    /// of their versions. The schema version of a document is kept in its
+    /// `migration::SCHEMA_VERSION_FIELD` field. Migrated documents are written in batches,
+    /// one commit each, so calling this again after an interruption resumes the migration.
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(
+            name = "collection.migrate_all",
+            skip_all,
+            fields(
+                branch = target.to_string(),
+                migrated = tracing::field::Empty,
+                failed = tracing::field::Empty
+            )
+        )
+    )]
+    pub fn migrate_all(
+        &self,
+        target: OperationTarget,
+    ) -> Result<migration::MigrationReport, error::MigrationError> {
+        migration::migrate_all(self, &self.migrations, target)
+    }
+
+    /// Verify that main and its indexes are readable. Problems with particular keys or indexes
+    /// end up in the report - corrupt objects are reported rather than causing a panic.
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(
+            level = "debug",
+            name = "collection.check",
+            skip(self),
+            fields(problems = tracing::field::Empty)
+        )
+    )]
+    pub fn check(
+        &self,
+        opts: check::CheckOptions,
+    ) -> Result<check::CheckReport, error::CheckError> {
+        check::check(self, opts)
+    }
+
+    /// Whether the collection is healthy: the last commit on main, how the replicas
+    /// are doing and how many transactions look abandoned. Only reads references
+    /// and a few commits (no trees) and takes no locks, so it's cheap to call often.
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(level = "debug", name = "collection.health", skip_all)
+    )]
+    pub fn health(&self) -> health::HealthSnapshot {
+        health::health(self)
+    }
+
+    /// Sizes of the collection and the health of its indexes, for monitoring
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(level = "debug", name = "collection.stats", skip_all)
+    )]
+    pub fn stats(&self) -> Result<stats::CollectionStats, error::StatsError> {
+        stats::stats(self)
+    }
+
+    /// How much space the values on main share thanks to identical values being stored
+    /// in the same blob
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(level = "debug", name = "collection.dedup_stats", skip_all)
+    )]
+    pub fn dedup_stats(&self) -> Result<stats::DedupStats, error::StatsError> {
+        stats::dedup_stats(self)
+    }
+
+    /// Rehash every object reachable from the references of the repository (including
+    /// the history) and make sure every index entry points at a key existing on some branch.
+    /// Unlike `check`, this reads the entire object database, so it's expensive.
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(
+            level = "debug",
+            name = "collection.verify",
+            skip_all,
+            fields(objects = tracing::field::Empty, problems = tracing::field::Empty)
+        )
+    )]
+    pub fn verify(&self) -> Result<verify::VerifyReport, error::VerifyError> {
+        verify::verify(self)
+    }
+
+    /// Check the signatures of every commit in the history of main with the signer
+    /// set with `with_signer`.
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(
+            level = "debug",
+            name = "collection.verify_signatures",
+            skip_all,
+            fields(valid = tracing::field::Empty, invalid = tracing::field::Empty)
+        )
+    )]
+    pub fn verify_signatures(&self) -> Result<signing::SignatureReport, error::SigningError> {
+        let signer = self.signer.as_ref().ok_or(error::SigningError::NoSigner)?;
+        signing::verify_signatures(self, signer.as_ref())
+    }
+
+    /// Checks that the repository is still there, main can be read and new objects can be
+    /// written, without writing anything to it. Errors which aren't about the repository
+    /// missing, space or permissions are reported as `Unavailable` with the message from git.
+    pub fn ping(&self) -> Result<(), error::StorageError> {
+        let repo = &self.repository;
+        let storage_error = |err: git2::Error| {
+            error::StorageError::from_git(&err)
+                .unwrap_or_else(|| error::StorageError::Unavailable(err.message().to_string()))
+        };
+        Self::current_commit(repo, "main")
+            .and_then(|commit| commit.tree())
+            .map_err(storage_error)?;
+        tempfile::tempfile_in(repo.path().join("objects"))
+            .map_err(|err| error::StorageError::from_io(&err))?;
+        Ok(())
+    }
+
+    /// Commit at the tip of the target (the commit itself for `OperationTarget::Commit`).
+    /// It changes with every write which changes something, so it can serve as the version
+    /// of the collection, like an ETag - the commits returned by the writes
+    /// (`WriteResult::commit`, `DeleteResult::commit`) are the heads they leave behind.
+    pub fn head(&self, target: OperationTarget) -> Result<Oid, error::QueryError> {
+        let commit = Self::target_commit(&self.repository, target).map_err(|e| match e.code() {
+            ErrorCode::NotFound => error::QueryError::InvalidOperationTarget(target.to_string()),
+            _ => e.into(),
+        })?;
+        Ok(commit.id())
+    }
+
+    /// Whether there is a branch (main or a transaction) with the name
+    pub fn branch_exists(&self, name: &str) -> bool {
+        self.repository.find_branch(name, BranchType::Local).is_ok()
+    }
+
+    /// Create a branch for a transaction, named `name` or a random one.
+    /// Names have to be valid git branch names without slashes and can't be `main`.
+    /// Fails with TransactionExists if the name is taken - the existing transaction
+    /// is left as it is.
+    pub fn new_transaction(
+        &self,
+        name: Option<&str>,
+    ) -> Result<String, error::NewTransactionError> {
+        if let Some(name) = name {
+            Self::validate_transaction_name(name)?;
+        }
+        let repo = &self.repository;
+        let head_commit = Self::current_commit(repo, "main")?;
+        let transaction_name = name.map(|n| n.to_string()).unwrap_or_else(|| {
+            format!(
+                "t-{}",
+                rand::thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(8)
+                    .map(char::from)
+                    .collect::<String>()
+            )
+        });
+        if self.branch_exists(&transaction_name) {
+            return Err(error::NewTransactionError::TransactionExists(
+                transaction_name,
+            ));
+        }
+        repo.branch(&transaction_name, &head_commit, false)
+            .map_err(|err| match err.code() {
+                // created by someone else in the meantime
+                ErrorCode::Exists => {
+                    error::NewTransactionError::TransactionExists(transaction_name.clone())
+                }
+                _ => err.into(),
+            })?;
+        if let Err(err) = transaction::mark(repo, &transaction_name, head_commit.id()) {
+            // without the marker it wouldn't be a transaction, nor collected by gc_transactions
+            repo.find_branch(&transaction_name, BranchType::Local)?
+                .delete()?;
+            return Err(err.into());
+        }
+        Ok(transaction_name)
+    }
+
+    /// Pick up a transaction created earlier, possibly by another process or before a restart -
+    /// its branch is kept in the repository until it's applied or discarded.
+    /// Returns the name to use with `OperationTarget::Transaction`.
+    pub fn resume_transaction(&self, name: &str) -> Result<String, error::TransactionError> {
+        if !is_valid_transaction_name(name) {
+            return Err(error::TransactionError::TransactionNotFound);
+        }
+        self.repository
+            .find_branch(name, BranchType::Local)
+            .map_err(|err| match err.code() {
+                ErrorCode::NotFound => error::TransactionError::TransactionNotFound,
+                _ => err.into(),
+            })?;
+        Ok(name.to_string())
+    }
+
+    /// Delete the transactions (branches created with `new_transaction`, other branches
+    /// are left alone) which haven't been written to for longer than `older_than` (by the time
+    /// of the commit at their tip, see `Clock`) and which have commits main doesn't,
+    /// i.e. were never applied.
+    /// Returns the number of transactions deleted.
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(
+            name = "collection.gc_transactions",
+            skip(self),
+            fields(removed = tracing::field::Empty)
+        )
+    )]
+    pub fn gc_transactions(&self, older_than: Duration) -> Result<usize, git2::Error> {
+        let repo = &self.repository;
+        let main = Self::current_commit(repo, "main")?.id();
+        let threshold = self
+            .clock
+            .now()
+            .saturating_sub(older_than.as_secs().min(i64::MAX as u64) as i64);
+        let mut removed = 0;
+        for branch in repo.branches(Some(BranchType::Local))? {
+            let (mut branch, _) = branch?;
+            let Some(name) = branch.name()?.map(String::from) else {
+                continue;
+            };
+            if !transaction::is_transaction(repo, &name) {
+                continue;
+            }
+            let tip = branch.get().peel_to_commit()?;
+            if tip.time().seconds() >= threshold
+                || tip.id() == main
+                || repo.graph_descendant_of(main, tip.id())?
+            {
+                continue;
+            }
+            debug!("deleting abandoned transaction '{}'", name);
+            branch.delete()?;
+            transaction::delete_refs(repo, &name)?;
+            removed += 1;
+        }
+        record!("removed", removed);
+        Ok(removed)
+    }
+
+    fn validate_transaction_name(name: &str) -> Result<(), error::NewTransactionError> {
+        if !is_valid_transaction_name(name) {
+            return Err(error::NewTransactionError::InvalidTransactionName(
+                name.to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Rebase the commits of the `source` branch onto the `target` branch and move `target`
+    /// to the resulting commit. Neither of the branches has to be main and the source branch
+    /// is left untouched - see `apply_transaction` for the variant that cleans it up.
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(
+            name = "collection.merge",
+            skip(self),
+            fields(commits_rebased = tracing::field::Empty, commit = tracing::field::Empty)
+        )
+    )]
+    pub fn merge(
+        &self,
+        source: &str,
+        target: &str,
+        conflict_resolution: ConflictResolution,
+    ) -> Result<TransactionOutcome, error::TransactionError> {
+        let repo = &self.repository;
+        let target_commit =
+            Collection::current_commit(repo, target).map_err(|err| match err.code() {
+                ErrorCode::NotFound => {
+                    error::TransactionError::InvalidOperationTarget(target.to_string())
+                }
+                _ => err.into(),
+            })?;
+        let upstream = repo.find_annotated_commit(target_commit.id())?;
+        let source_commit =
+            Collection::current_commit(repo, source).map_err(|err| match err.code() {
+                ErrorCode::NotFound => error::TransactionError::TransactionNotFound,
+                _ => err.into(),
+            })?;
+        let branch = repo.find_annotated_commit(source_commit.id())?;
+        let mut checkout_options = CheckoutBuilder::new();
+        checkout_options.force();
+        checkout_options.allow_conflicts(true);
+        let mut merge_options = MergeOptions::new();
+        match conflict_resolution {
+            ConflictResolution::DiscardChanges => {
+                checkout_options.use_ours(true);
+                merge_options.file_favor(FileFavor::Ours);
+            }
+            ConflictResolution::Overwrite => {
+                checkout_options.use_theirs(true);
+                merge_options.file_favor(FileFavor::Theirs);
+            }
+            ConflictResolution::Abort
+            | ConflictResolution::Custom
+            | ConflictResolution::Callback(_) => {
+                // merge_options.fail_on_conflict(true);
+            }
+        }
+        if matches!(conflict_resolution, ConflictResolution::Custom) && self.merge_driver.is_none()
+        {
+            return Err(error::TransactionError::NoMergeDriver);
+        }
+        let mut rebase_options = RebaseOptions::new();
+        let rebase_opts = rebase_options
+            .inmemory(true)
+            .checkout_options(checkout_options)
+            .merge_options(merge_options);
+        let mut rebase = repo.rebase(Some(&branch), Some(&upstream), None, Some(rebase_opts))?;
+        let mut outcome = TransactionOutcome {
+            head: target_commit.id(),
+            commits_applied: 0,
+        };
+        let mut driver_merged = Vec::new();
+        while let Some(operation) = rebase.next() {
+            let rebased = operation?.id();
+            let mut index = rebase.inmemory_index()?;
+            self.resolve_metadata_conflicts(&mut index)?;
+            let mut value_conflicts = Vec::new();
+            if self.merges_whole_values() {
+                let (ours, theirs) = (repo.find_commit(outcome.head)?, repo.find_commit(rebased)?);
+                let base = theirs.parent(0)?.tree()?;
+                value_conflicts = Self::restore_value_conflicts(
+                    &mut index,
+                    (&base, &ours.tree()?, &theirs.tree()?),
+                )?;
+            }
+            let resolved = match &conflict_resolution {
+                ConflictResolution::DiscardChanges | ConflictResolution::Overwrite
+                    if !value_conflicts.is_empty() =>
+                {
+                    let resolution = match conflict_resolution {
+                        ConflictResolution::DiscardChanges => Resolution::TakeMain,
+                        _ => Resolution::TakeTransaction,
+                    };
+                    self.resolve_value_conflicts(
+                        &mut index,
+                        &value_conflicts,
+                        resolution,
+                        (outcome.head, rebased),
+                        &mut driver_merged,
+                    )
+                }
+                ConflictResolution::Custom => {
+                    self.merge_with_driver(&mut index, &mut driver_merged)
+                }
+                ConflictResolution::Callback(callback) => self.merge_with_callback(
+                    &mut index,
+                    callback,
+                    (outcome.head, rebased),
+                    &mut driver_merged,
+                ),
+                _ => Ok(()),
+            };
+            if let Err(err) = resolved {
+                rebase.abort()?;
+                return Err(err);
+            }
+            match rebase.commit(None, &self.signature(), None) {
+                Ok(com) => {
+                    outcome.head = com;
+                    outcome.commits_applied += 1;
+                }
+                Err(err) => match err.code() {
+                    ErrorCode::Applied => {}
+                    ErrorCode::MergeConflict | ErrorCode::Unmerged => match conflict_resolution {
+                        ConflictResolution::Abort => {
+                            rebase.abort()?;
+                            return Err(error::TransactionError::Aborted);
+                        }
+                        _ => return Err(err.into()),
+                    },
+                    _ => return Err(err.into()),
+                },
+            }
+        }
+        rebase.finish(None)?;
+        record!("commits_rebased", outcome.commits_applied);
+        record!("commit", outcome.head.to_string());
+        if outcome.commits_applied > 0 {
+            self.advance_branch(
+                target,
+                target_commit.id(),
+                outcome.head,
+                &format!("merge {} into {}", source, target),
+            )?;
+            if target == "main" {
+                self.reindex(&driver_merged)?;
+            }
+        }
+        Ok(outcome)
+    }
+
+    /// Replace the conflicts in the index of a rebased commit with the values merged
+    /// by the MergeDriver, which are also added to `merged` for reindexing
+    fn merge_with_driver(
+        &self,
+        index: &mut git2::Index,
+        merged: &mut Vec<(String, Option<Vec<u8>>)>,
+    ) -> Result<(), error::TransactionError> {
+        if !index.has_conflicts() {
+            return Ok(());
+        }
+        let repo = &self.repository;
+        let driver = self
+            .merge_driver
+            .as_ref()
+            .ok_or(error::TransactionError::NoMergeDriver)?;
+        let read = |entry: &git2::IndexEntry| -> Result<Vec<u8>, git2::Error> {
+            let blob = repo.find_blob(entry.id)?;
+            Ok(self.open_value(blob.content())?.into_owned())
+        };
+        let conflicts = index.conflicts()?.collect::<Result<Vec<_>, _>>()?;
+        for conflict in conflicts {
+            // unwrap: a conflict always has at least two of the sides
+            let entry = [&conflict.ancestor, &conflict.our, &conflict.their]
+                .into_iter()
+                .flatten()
+                .next()
+                .unwrap();
+            let path = String::from_utf8_lossy(&entry.path).to_string();
+            if metadata::in_metadata(&path) || metadata::in_tombstones(&path) {
+                // deleted on one side, along with the document
+                continue;
+            }
+            let key = Self::key_from_full_path(&path).unwrap_or_else(|_| path.clone());
+            let (Some(ours), Some(theirs)) = (&conflict.our, &conflict.their) else {
+                return Err(error::TransactionError::MergeConflict { key });
+            };
+            let base = conflict.ancestor.as_ref().map(read).transpose()?;
+            let value = match driver.merge(&key, base.as_deref(), &read(ours)?, &read(theirs)?)? {
+                merge::MergeOutcome::Merged(value) => value,
+                merge::MergeOutcome::Conflict => {
+                    return Err(error::TransactionError::MergeConflict { key })
+                }
+            };
+            let data = self.seal_value(value.clone());
+            Self::replace_in_index(index, &path, Some((repo.blob(&data)?, data.len())))?;
+            // combined by resolve_metadata_conflicts already
+            let meta_path = metadata::metadata_path(&path);
+            let previous = match index.get_path(Path::new(&meta_path), 0) {
+                Some(entry) => Some(metadata::DocumentMeta::from_blob(repo, entry.id)?),
+                None => None,
+            };
+            let meta = metadata::DocumentMeta::next(previous, self.clock.now());
+            Self::replace_in_index(index, &meta_path, Some((meta.write(repo)?, 0)))?;
+            merged.push((key, Some(value)));
+        }
+        Ok(())
+    }
+
+    /// Replace the conflicts in the index of a rebased commit with the values chosen by
+    /// the callback, which are also added to `merged` for reindexing. `commits` are the
+    /// commit the rebased one is put on top of and the rebased commit itself.
+    fn merge_with_callback(
+        &self,
+        index: &mut git2::Index,
+        callback: &dyn Fn(&ConflictInfo) -> Resolution,
+        commits: (Oid, Oid),
+        merged: &mut Vec<(String, Option<Vec<u8>>)>,
+    ) -> Result<(), error::TransactionError> {
+        if !index.has_conflicts() {
+            return Ok(());
+        }
+        let repo = &self.repository;
+        let (ours, theirs) = (repo.find_commit(commits.0)?, repo.find_commit(commits.1)?);
+        let trees = (ours.tree()?, theirs.tree()?);
+        for conflict in self.key_conflicts(index)? {
+            let info = ConflictInfo {
+                key: conflict.key.clone(),
+                base: conflict.base.clone(),
+                ours: conflict.ours.clone(),
+                theirs: conflict.theirs.clone(),
+                ours_time: ours.author().when().seconds(),
+                theirs_time: theirs.author().when().seconds(),
+            };
+            let resolution = callback(&info);
+            merged.push(self.resolve_conflict(index, conflict, resolution, &trees)?);
+        }
+        Ok(())
+    }
+
+    /// Resolve the conflicts restored at the paths by `restore_value_conflicts` with the same
+    /// resolution, adding the values to `merged` for reindexing. `commits` are the commit the
+    /// rebased one is put on top of and the rebased commit itself.
+    fn resolve_value_conflicts(
+        &self,
+        index: &mut Index,
+        paths: &[String],
+        resolution: Resolution,
+        commits: (Oid, Oid),
+        merged: &mut Vec<(String, Option<Vec<u8>>)>,
+    ) -> Result<(), error::TransactionError> {
+        let repo = &self.repository;
+        let trees = (
+            repo.find_commit(commits.0)?.tree()?,
+            repo.find_commit(commits.1)?.tree()?,
+        );
+        let keys = paths
+            .iter()
+            .map(|path| Self::key_from_full_path(path))
+            .collect::<Result<Vec<_>, _>>()?;
+        for conflict in self.key_conflicts(index)? {
+            if keys.contains(&conflict.key) {
+                merged.push(self.resolve_conflict(index, conflict, resolution.clone(), &trees)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Turn the values git merged line by line back into conflicts of the whole values, as
+    /// interleaving the lines of binary (or encrypted) values corrupts them. `trees` are
+    /// the merge base and the ours and theirs sides. Returns the paths of the conflicts.
+    fn restore_value_conflicts(
+        index: &mut Index,
+        trees: (&Tree, &Tree, &Tree),
+    ) -> Result<Vec<String>, git2::Error> {
+        let id_at =
+            |tree: &Tree, path: &str| tree.get_path(Path::new(path)).ok().map(|entry| entry.id());
+        let mut conflicts = Vec::new();
+        for entry in index.iter() {
+            if entry.flags & INDEX_STAGE_MASK != 0 {
+                continue;
+            }
+            let path = String::from_utf8_lossy(&entry.path).to_string();
+            if namespace::in_reserved_tree(&path) {
+                continue;
+            }
+            let (ours, theirs) = (id_at(trees.1, &path), id_at(trees.2, &path));
+            // the value is on neither side, so it's the result of a line merge
+            if ours != Some(entry.id) && theirs != Some(entry.id) {
+                conflicts.push((path.clone(), [id_at(trees.0, &path), ours, theirs]));
+            }
+        }
+        for (path, sides) in &conflicts {
+            index.remove_path(Path::new(path))?;
+            // stages 1, 2 and 3 hold the ancestor, ours and theirs
+            for (stage, id) in (1..).zip(sides) {
+                if let Some(id) = id {
+                    Self::add_to_index(index, path, *id, 0, stage)?;
+                }
+            }
+        }
+        Ok(conflicts.into_iter().map(|(path, _)| path).collect())
+    }
+
+    /// Values in a binary data format or encrypted ones can't be merged line by line
+    fn merges_whole_values(&self) -> bool {
+        #[cfg(any(feature = "encryption", feature = "full"))]
+        if self.encryption.is_some() {
+            return true;
+        }
+        self.data_format.is_binary()
+    }
+
+    /// Put the value the resolution picked for the conflicting key (along with its metadata)
+    /// in the index, `trees` being the ones the ours and theirs sides come from
+    fn resolve_conflict(
+        &self,
+        index: &mut Index,
+        conflict: KeyConflict,
+        resolution: Resolution,
+        trees: &(Tree, Tree),
+    ) -> Result<(String, Option<Vec<u8>>), error::TransactionError> {
+        let repo = &self.repository;
+        let key = conflict.key;
+        let path = Self::construct_full_path(&key)
+            .map_err(|_| error::TransactionError::InvalidResolution(key.clone()))?;
+        let ours_meta = metadata::read(repo, &trees.0, &path)?;
+        let theirs_meta = metadata::read(repo, &trees.1, &path)?;
+        let (value, meta) = match resolution {
+            Resolution::TakeMain => (conflict.ours, ours_meta),
+            Resolution::TakeTransaction => (conflict.theirs, theirs_meta),
+            Resolution::Replace(value) => {
+                self.data_format
+                    .validate(&value)
+                    .map_err(error::TransactionError::InvalidResolution)?;
+                let previous = match (ours_meta, theirs_meta) {
+                    (Some(ours), Some(theirs)) => Some(ours.combine(theirs)),
+                    (ours, theirs) => ours.or(theirs),
+                };
+                let now = self.clock.now();
+                (
+                    Some(value),
+                    Some(metadata::DocumentMeta::next(previous, now)),
+                )
+            }
+        };
+        let blob = match &value {
+            Some(value) => {
+                let data = self.seal_value(value.clone());
+                Some((repo.blob(&data)?, data.len()))
+            }
+            None => None,
+        };
+        Self::replace_in_index(index, &path, blob)?;
+        let meta = match meta.filter(|_| value.is_some()) {
+            Some(meta) => Some((meta.write(repo)?, 0)),
+            None => None,
+        };
+        Self::replace_in_index(index, &metadata::metadata_path(&path), meta)?;
+        Ok((key, value))
+    }
+
+    /// Resolve the conflicts of the metadata of documents changed on both sides by combining
+    /// them (see `DocumentMeta::combine`), so that writing the same value on both sides isn't
+    /// a conflict. Metadata deleted on one side is left to be resolved along with its document.
+    fn resolve_metadata_conflicts(&self, index: &mut Index) -> Result<(), git2::Error> {
+        if !index.has_conflicts() {
+            return Ok(());
+        }
+        let repo = &self.repository;
+        let conflicts = index.conflicts()?.collect::<Result<Vec<_>, _>>()?;
+        for conflict in conflicts {
+            let (Some(ours), Some(theirs)) = (&conflict.our, &conflict.their) else {
+                continue;
+            };
+            let path = String::from_utf8_lossy(&ours.path).to_string();
+            if metadata::in_metadata(&path) {
+                let meta = metadata::DocumentMeta::from_blob(repo, ours.id)?
+                    .combine(metadata::DocumentMeta::from_blob(repo, theirs.id)?);
+                Self::replace_in_index(index, &path, Some((meta.write(repo)?, 0)))?;
+            } else if let Some(document_path) = path
+                .strip_prefix(metadata::TOMBSTONES_TREE)
+                .and_then(|rest| rest.strip_prefix('/'))
+            {
+                // both sides soft-deleted the document, the value follows the merged tombstone
+                // (.metadata sorts before .tombstones, so it's already resolved)
+                let meta_path = metadata::metadata_path(document_path);
+                let blob = match index.get_path(Path::new(&meta_path), 0) {
+                    Some(entry) => metadata::DocumentMeta::from_blob(repo, entry.id)?
+                        .tombstone
+                        .map(|tombstone| tombstone.blob),
+                    None => None,
+                };
+                let kept = [ours, theirs]
+                    .into_iter()
+                    .find(|side| Some(side.id.to_string()) == blob)
+                    .map(|side| (side.id, side.file_size as usize));
+                Self::replace_in_index(index, &path, kept)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Put the blob (along with its size) at the path of the index, replacing whatever was there
+    /// including conflicts, or just remove the path if there is no blob
+    fn replace_in_index(
+        index: &mut Index,
+        path: &str,
+        blob: Option<(Oid, usize)>,
+    ) -> Result<(), git2::Error> {
+        // also removes the conflicting entries
+        index.remove_path(Path::new(path))?;
+        if let Some((id, size)) = blob {
+            Self::add_to_index(index, path, id, size, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Add the blob at the path and stage (0 unless it's a side of a conflict) of the index
+    fn add_to_index(
+        index: &mut Index,
+        path: &str,
+        id: Oid,
+        size: usize,
+        stage: u16,
+    ) -> Result<(), git2::Error> {
+        index.add(&git2::IndexEntry {
+            ctime: git2::IndexTime::new(0, 0),
+            mtime: git2::IndexTime::new(0, 0),
+            dev: 0,
+            ino: 0,
+            mode: 0o100644,
+            uid: 0,
+            gid: 0,
+            file_size: size as u32,
+            id,
+            flags: path.len().min(0xfff) as u16 | (stage << INDEX_STAGE_SHIFT),
+            flags_extended: 0,
+            path: path.as_bytes().to_vec(),
+        })
+    }
+
+    /// Move the branch from `expected` to `new`. Fails with MainMoved if someone else moved
+    /// the branch away from `expected` in the meantime, instead of dropping their commits.
+    /// Runs the hooks of the collection around the update, just like `commit_to_branch`
+    fn advance_branch(
+        &self,
+        branch: &str,
+        expected: Oid,
+        new: Oid,
+        message: &str,
+    ) -> Result<(), error::TransactionError> {
+        let _lock = self.write_lock()?;
+        let repo = &self.repository;
+        let old_tree = repo.find_commit(expected)?.tree()?;
+        let new_tree = repo.find_commit(new)?.tree()?;
+        let pending = self.pending_write(branch, Some(&old_tree), &new_tree)?;
+        if let Some(pending) = &pending {
+            self.hooks
+                .pre_commit(pending)
+                .map_err(error::TransactionError::Vetoed)?;
+        }
+        let name = format!("refs/heads/{}", branch);
+        match repo.reference_matching(&name, new, true, expected, message) {
+            Ok(_) => {
+                self.log_insertions(branch, Some(&old_tree), &new_tree);
+                if let Some(pending) = pending {
+                    self.committed(pending, new);
+                }
+                Ok(())
+            }
+            Err(err) if err.code() == ErrorCode::Modified => {
+                debug!("{} moved away from {}, not updating it", branch, expected);
+                Err(error::TransactionError::MainMoved)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Merge the transaction into main and delete its branch afterwards
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(
+            name = "collection.apply_transaction",
+            skip(self),
+            fields(commits_rebased = tracing::field::Empty)
+        )
+    )]
+    pub fn apply_transaction(
+        &self,
+        name: &str,
+        conflict_resolution: ConflictResolution,
+    ) -> Result<TransactionOutcome, error::TransactionError> {
+        // held from checking the read set until main is moved, so that nothing the check
+        // didn't see can become the base of the merge
+        let _lock = self.write_lock()?;
+        self.check_read_set(name)?;
+        let outcome = self.merge(name, "main", conflict_resolution)?;
+        record!("commits_rebased", outcome.commits_applied);
+        self.repository
+            .find_branch(name, BranchType::Local)?
+            .delete()?;
+        transaction::delete_refs(&self.repository, name)?;
+        Ok(outcome)
+    }
+
+    /// Fail if main changed any of the keys the transaction read with `get_tracked`
+    fn check_read_set(&self, name: &str) -> Result<(), error::TransactionError> {
+        let repo = &self.repository;
+        let read_set = transaction::read_set(repo, name)?;
+        if read_set.is_empty() {
+            return Ok(());
+        }
+        let main = Self::current_commit(repo, "main").map_err(|err| match err.code() {
+            ErrorCode::NotFound => {
+                error::TransactionError::InvalidOperationTarget(String::from("main"))
+            }
+            _ => err.into(),
+        })?;
+        let tip = Self::current_commit(repo, name).map_err(|err| match err.code() {
+            ErrorCode::NotFound => error::TransactionError::TransactionNotFound,
+            _ => err.into(),
+        })?;
+        let base = repo
+            .find_commit(repo.merge_base(main.id(), tip.id())?)?
+            .tree()?;
+        let main = main.tree()?;
+        let blob_at = |tree: &Tree, key: &str| {
+            Self::construct_path_to_key(key)
+                .ok()
+                .and_then(|path| tree.get_path(Path::new(&path)).ok())
+                .map(|entry| entry.id())
+        };
+        let keys: Vec<String> = read_set
+            .into_iter()
+            // a different value than at the branching point was written by the transaction
+            .filter(|(key, observed)| blob_at(&base, key) == *observed)
+            .filter(|(key, observed)| blob_at(&main, key) != *observed)
+            .map(|(key, _)| key)
+            .collect();
+        if !keys.is_empty() {
+            debug!("{} read keys changed on main: {:?}", name, keys);
+            return Err(error::TransactionError::ReadSetConflict { keys });
+        }
+        Ok(())
+    }
+
+    /// Merge main and the transaction in memory (without updating any branches) and report
+    /// the conflicting keys along with the changes that would apply cleanly
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(
+            level = "debug",
+            name = "collection.preview_transaction",
+            skip(self),
+            fields(conflicts = tracing::field::Empty)
+        )
+    )]
+    pub fn preview_transaction(
+        &self,
+        name: &str,
+    ) -> Result<TransactionPreview, error::TransactionError> {
+        let (_, tip, base, merged) = self.merge_into_main(name)?;
+        let conflicts = self.key_conflicts(&merged)?;
+        let changes = self
+            .changed_keys(Some(&base), &tip.tree()?)?
+            .into_iter()
+            .filter(|change| !conflicts.iter().any(|c| c.key == change.key))
+            .collect();
+        record!("conflicts", conflicts.len());
+        Ok(TransactionPreview { conflicts, changes })
+    }
+
+    /// Merge the transaction into main, resolving every conflicting key with the resolver,
+    /// and delete its branch afterwards. Unlike `apply_transaction`, the commits of the
+    /// transaction are not rebased - main gets a single merge commit instead.
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(
+            name = "collection.apply_transaction_with",
+            skip(self, resolver),
+            fields(conflicts = tracing::field::Empty, commit = tracing::field::Empty)
+        )
+    )]
+    pub fn apply_transaction_with<F>(
+        &self,
+        name: &str,
+        mut resolver: F,
+    ) -> Result<TransactionOutcome, error::TransactionError>
+    where
+        F: FnMut(KeyConflict) -> Resolution,
+    {
+        // see apply_transaction
+        let _lock = self.write_lock()?;
+        self.check_read_set(name)?;
+        let repo = &self.repository;
+        let (main, tip, _, mut merged) = self.merge_into_main(name)?;
+        self.resolve_metadata_conflicts(&mut merged)?;
+        let conflicts = self.key_conflicts(&merged)?;
+        record!("conflicts", conflicts.len());
+        let trees = (main.tree()?, tip.tree()?);
+        let mut resolved = Vec::new();
+        for conflict in conflicts {
+            let resolution = resolver(conflict.clone());
+            resolved.push(self.resolve_conflict(&mut merged, conflict, resolution, &trees)?);
+        }
+        let tree = repo.find_tree(merged.write_tree_to(repo)?)?;
+
+        let mut revwalk = repo.revwalk()?;
+        revwalk.push(tip.id())?;
+        revwalk.hide(main.id())?;
+        let mut outcome = TransactionOutcome {
+            head: main.id(),
+            commits_applied: revwalk.count(),
+        };
+        if outcome.commits_applied > 0 {
+            let message = format!("merge {} into main", name);
+            let signature = self.signature();
+            let buffer =
+                repo.commit_create_buffer(&signature, &signature, &message, &tree, &[&main, &tip])?;
+            outcome.head = self.write_commit(&buffer)?;
+            self.advance_branch("main", main.id(), outcome.head, &message)?;
+            self.reindex(&resolved)?;
+        }
+        record!("commit", outcome.head.to_string());
+        repo.find_branch(name, BranchType::Local)?.delete()?;
+        transaction::delete_refs(repo, name)?;
+        Ok(outcome)
+    }
+
+    /// Returns the tips of main and the transaction, the tree of their merge base
+    /// and the result of merging the transaction into main
+    fn merge_into_main(
+        &self,
+        name: &str,
+    ) -> Result<(Commit<'_>, Commit<'_>, Tree<'_>, Index), error::TransactionError> {
+        let repo = &self.repository;
+        let main = Self::current_commit(repo, "main").map_err(|err| match err.code() {
+            ErrorCode::NotFound => {
+                error::TransactionError::InvalidOperationTarget(String::from("main"))
+            }
+            _ => err.into(),
+        })?;
+        let tip = Self::current_commit(repo, name).map_err(|err| match err.code() {
+            ErrorCode::NotFound => error::TransactionError::TransactionNotFound,
+            _ => err.into(),
+        })?;
+        let base = repo
+            .find_commit(repo.merge_base(main.id(), tip.id())?)?
+            .tree()?;
+        let mut merged = repo.merge_trees(&base, &main.tree()?, &tip.tree()?, None)?;
+        if self.merges_whole_values() {
+            Self::restore_value_conflicts(&mut merged, (&base, &main.tree()?, &tip.tree()?))?;
+        }
+        Ok((main, tip, base, merged))
+    }
+
+    fn key_conflicts(&self, merged: &Index) -> Result<Vec<KeyConflict>, git2::Error> {
+        let read = |entry: Option<git2::IndexEntry>| match entry {
+            Some(entry) => Ok::<_, git2::Error>(Some(
+                self.open_value(self.repository.find_blob(entry.id)?.content())?
+                    .into_owned(),
+            )),
+            None => Ok(None),
+        };
+        let mut conflicts = Vec::new();
+        for conflict in merged.conflicts()? {
+            let conflict = conflict?;
+            // unwrap: a conflict always has at least two of the sides
+            let path = [&conflict.ancestor, &conflict.our, &conflict.their]
+                .into_iter()
+                .flatten()
+                .next()
+                .map(|e| String::from_utf8_lossy(&e.path).to_string())
+                .unwrap();
+            if metadata::in_metadata(&path) || metadata::in_tombstones(&path) {
+                // resolved along with the document
+                continue;
+            }
+            conflicts.push(KeyConflict {
+                key: Self::key_from_full_path(&path)?,
+                base: read(conflict.ancestor)?,
+                ours: read(conflict.our)?,
+                theirs: read(conflict.their)?,
+            });
+        }
+        Ok(conflicts)
+    }
+
+    /// Bring the index entries of the keys in line with their new values. The keys
+    /// of namespaces (see `key_from_full_path`) go to the indexes of their namespace,
+    /// unless those weren't filled yet.
+    fn reindex(&self, values: &[(String, Option<Vec<u8>>)]) -> Result<(), git2::Error> {
+        let repo = &self.repository;
+        let indexes = self.index_list();
+        if indexes.is_empty() {
+            return Ok(());
+        }
+        let mut scoped: HashMap<&str, Vec<index::Index>> = HashMap::new();
+        for (tree, _) in values
+            .iter()
+            .filter_map(|(key, _)| namespace::split_path(key))
+        {
+            scoped.entry(tree).or_insert_with(|| {
+                indexes
+                    .iter()
+                    .map(|index| index.in_namespace(tree))
+                    .filter(|index| index.has_file(repo))
+                    .collect()
+            });
+        }
+        let mut index_updates = index::IndexUpdates::default();
+        for (key, value) in values {
+            let (indexes, key) = match namespace::split_path(key) {
+                Some((tree, key)) => (&scoped[tree], key),
+                None => (&indexes, key.as_str()),
+            };
+            let hash = Oid::hash_object(ObjectType::Blob, key.as_bytes())?;
+            let mut index_values = HashMap::new();
+            for index in indexes.iter() {
+                index_values.insert(index, Vec::new());
+            }
+            if let Some(value) = value {
+                self.data_format
+                    .serialize_with_indexes_raw(value, &mut index_values);
+            }
+            for (index, field) in index_values {
+                index_updates.replace(index, hash, field);
+            }
+        }
+        index_updates.apply(repo)?;
+        Ok(())
+    }
+
+    /// Add an index of the field, see `create_index`
+    ///
+    /// # Panics
+    ///
+    /// If `create_index` fails - e.g. the lock isn't released within the lock timeout,
+    /// or the index file can't be written. Use `create_index` to handle these.
+    pub fn add_index(&self, field: &str, kind: index::IndexType) -> index::Index {
+        self.create_index(field, kind).unwrap().0
+    }
+
+    /// Add an index of the field to main, unless it's there already, and fill it from the
+    /// documents on main with a single write of the index file. An index which has
+    /// a file already is left as it is. Documents which can't be parsed in the data format
+    /// of the collection (e.g. written with `set_reader`) are skipped and returned.
+    pub fn create_index(
+        &self,
+        field: &str,
+        kind: index::IndexType,
+    ) -> Result<(index::Index, Vec<index::SkippedDocument>), error::IndexError> {
+        self.create_index_with(field, kind, index::IndexOptions::default())
+    }
+
+    /// Same as `create_index`, with the values normalized as given by the options
+    /// (e.g. case-insensitive string ordering). Indexes of the same field with
+    /// different options are separate indexes, each with a file of its own.
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(
+            name = "collection.create_index",
+            skip(self),
+            fields(skipped = tracing::field::Empty)
+        )
+    )]
+    pub fn create_index_with(
+        &self,
+        field: &str,
+        kind: index::IndexType,
+        options: index::IndexOptions,
+    ) -> Result<(index::Index, Vec<index::SkippedDocument>), error::IndexError> {
+        let branch = "main";
+        let repo = &self.repository;
+        // held until the index is filled, so that no write to main slips in between
+        let _lock = self.write_lock()?;
+        let commit = Collection::current_commit(repo, branch)?;
+        let index_tree = commit.tree()?;
+        let index_name = index::Index::file_name(field, kind, options);
+        let index_obj = index::Index::new_with(&index_name, field, kind, options);
+        let index_path = repo.path().join(".index").join(&index_name);
+        let populated = index_path.exists();
+        if index_tree.get_name(&index_name).is_none() {
+            let mut tb = repo.treebuilder(Some(&index_tree))?;
+            Self::ensure_index_dir_exists(repo);
+            let mut index = Index::open(&index_path)?;
+            let obj = index.write_tree_to(repo)?;
+            tb.insert(&index_name, obj, 0o040000)?;
+            let new_root = tb.write()?;
+            let root_tree = repo.find_tree(new_root)?;
+            let signature = self.signature();
+            let message = format!("add index: {}", index_name);
+            let new_commit = repo.commit_create_buffer(
+                &signature,
+                &signature,
+                &message,
+                &root_tree,
+                &[&commit],
+            )?;
+            let commit_obj = self.write_commit(&new_commit)?;
+            repo.reference_matching(
+                &format!("refs/heads/{}", branch),
+                commit_obj,
+                true,
+                commit.id(),
+                &message,
+            )?;
+        }
+        let skipped = if populated {
+            Vec::new()
+        } else {
+            self.populate_index(repo, &index_obj)?
+        };
+        record!("skipped", skipped.len());
+        Ok((index_obj, skipped))
+    }
+
+    /// Drop all the entries of the index and fill it again from the documents on main,
+    /// e.g. when `Index::is_outdated` says it was written in an older format
+    pub fn rebuild_index(&self, index: &index::Index) -> Result<(), error::IndexError> {
+        let repo = &self.repository;
+        // like in create_index_with, a write to main in between would be missing from the index
+        let _lock = self.write_lock()?;
+        index.clear(repo)?;
+        self.populate_index(repo, index)?;
+        Ok(())
+    }
+
+    /// Add the indexes declared by the model which don't exist yet (filling them with
+    /// the documents already on main) and return all of the indexes of the model
+    ///
+    /// # Panics
+    ///
+    /// If adding one of the indexes fails, see `add_index`
+    pub fn ensure_indexes_for<T: model::YamabikoModel>(&self) -> Vec<index::Index> {
+        T::indexes()
+            .into_iter()
+            .map(|(field, kind)| {
+                let name = format!("{}#{}.index", field, kind);
+                if self.contains_index(&name) {
+                    index::Index::new(&name, &field, kind)
+                } else {
+                    self.add_index(&field, kind)
+                }
+            })
+            .collect()
+    }
+
+    /// Add the entries of the documents on main to the index in one go, skipping (and logging)
+    /// the documents which can't be parsed
+    fn populate_index(
+        &self,
+        repo: &Repository,
+        index: &index::Index,
+    ) -> Result<Vec<index::SkippedDocument>, git2::Error> {
+        if self.is_encrypted() {
+            return Ok(Vec::new());
+        }
+        let tree = Collection::current_commit(repo, "main")?.tree()?;
+        let (entries, skipped) = self.index_entries(repo, index, &tree)?;
+        index.add_entries(repo, &entries)?;
+        for entry in tree.iter().filter(namespace::is_namespace_tree) {
+            // unwrap: the namespace trees are named after the namespace
+            let scoped = index.in_namespace(entry.name().unwrap());
+            let (entries, _) = self.index_entries(repo, &scoped, &repo.find_tree(entry.id())?)?;
+            scoped.clear(repo)?;
+            scoped.add_entries(repo, &entries)?;
+        }
+        Ok(skipped)
+    }
+
+    /// Entries of the index for the documents in the tree, along with the documents
+    /// which can't be parsed
+    pub(crate) fn index_entries(
+        &self,
+        repo: &Repository,
+        index: &index::Index,
+        tree: &Tree,
+    ) -> Result<(index::Entries, Vec<index::SkippedDocument>), git2::Error> {
+        let mut entries: index::Entries = Vec::new();
+        let mut skipped = Vec::new();
+        let mut walk_error = None;
+        tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+            if entry.kind() != Some(ObjectType::Blob) {
+                // neither the index definitions nor the attachments are documents
+                let skip = namespace::is_reserved_tree(entry)
+                    || entry.name().is_some_and(|name| name.ends_with(".index"));
+                return match skip {
+                    true => TreeWalkResult::Skip,
+                    false => TreeWalkResult::Ok,
+                };
+            }
+            let result = (|| -> Result<(), git2::Error> {
+                // same as in set_batch - index entries point at hashes of the keys
+                let key = Self::key_from_path(root, entry.name().unwrap_or_default())?;
+                let oid = Oid::hash_object(ObjectType::Blob, key.as_bytes())?;
+                let blob = repo.find_blob(entry.id())?;
+                let mut index_values: HashMap<&index::Index, Vec<Field>> = HashMap::new();
+                index_values.insert(index, Vec::new());
+                match self
+                    .data_format
+                    .extract_indexes_raw(blob.content(), &mut index_values)
+                {
+                    Ok(()) => entries.extend(
+                        index_values
+                            .remove(index)
+                            .unwrap_or_default()
+                            .into_iter()
+                            .map(|value| (value, oid)),
+                    ),
+                    Err(reason) => {
+                        warn!("not indexing '{}' in {}: {}", key, index.name(), reason);
+                        skipped.push(index::SkippedDocument { key, reason });
+                    }
+                }
+                Ok(())
+            })();
+            match result {
+                Ok(()) => TreeWalkResult::Ok,
+                Err(err) => {
+                    walk_error = Some(err);
+                    TreeWalkResult::Abort
+                }
+            }
+        })?;
+        if let Some(err) = walk_error {
+            return Err(err);
+        }
+        Ok((entries, skipped))
+    }
+
+    /// Indexes registered on main along with the ones which only have a file in the `.index`
+    /// directory of the repository (an index gets its file once it has entries), sorted by name.
+    /// These are the indexes kept up to date by writes and used by queries - none if the
+    /// collection is encrypted.
+    pub fn index_list(&self) -> Vec<index::Index> {
+        // unwrap: main has to exist
+        self.indexes().unwrap()
+    }
+
+    /// All the indexes, for the writes which have to report a missing repository
+    fn indexes(&self) -> Result<Vec<index::Index>, git2::Error> {
+        if self.is_encrypted() {
+            return Ok(Vec::new());
+        }
+        Self::discover_indexes(&self.repository)
+    }
+
+    /// See `index_list`. The indexes are looked up on every use rather than once on load,
+    /// so that indexes added by another Collection (or process) are maintained as well.
+    /// Names which can't be parsed are logged and skipped.
+    fn discover_indexes(repo: &Repository) -> Result<Vec<index::Index>, git2::Error> {
+        let index_tree = Self::current_commit(repo, "main")?.tree()?;
+        let mut names: Vec<String> = index_tree
+            .iter()
+            .filter_map(|entry| entry.name().map(String::from))
+            .collect();
+        if let Ok(dir) = std::fs::read_dir(repo.path().join(".index")) {
+            names.extend(
+                dir.filter_map(|entry| entry.ok())
+                    .filter_map(|entry| entry.file_name().into_string().ok()),
+            );
+        }
+        let mut indexes = Vec::new();
+        for name in names.iter().filter(|name| name.ends_with(".index")) {
+            match index::Index::from_name(name) {
+                Ok(index) => indexes.push(index),
+                Err(_err) => {
+                    warn!("skipping malformed index '{}': {:?}", name, _err);
+                }
+            }
+        }
+        indexes.sort_by(|a, b| a.name().cmp(b.name()));
+        indexes.dedup();
+        Ok(indexes)
+    }
+
+    /// Same as `index_list`
+    pub fn list_indexes(&self) -> Vec<index::Index> {
+        self.index_list()
+    }
+
+    /// Whether there is an index with the given name (like `age#numeric.index`)
+    pub fn contains_index(&self, name: &str) -> bool {
+        self.list_indexes().iter().any(|index| index.name() == name)
+    }
+
+    /// Lowest value in the index with the given name (like `age#numeric.index`) along with
+    /// the hash of the key it belongs to, see `Index::min`.
+    /// None if there is no such index or it's empty.
+    pub fn query_min(&self, index_name: &str) -> Option<(Field, Oid)> {
+        self.find_index(index_name)?.min(&self.repository)
+    }
+
+    /// Highest value in the index with the given name along with the hash of the key
+    /// it belongs to, see `Index::max`. None if there is no such index or it's empty.
+    pub fn query_max(&self, index_name: &str) -> Option<(Field, Oid)> {
+        self.find_index(index_name)?.max(&self.repository)
+    }
+
+    fn find_index(&self, name: &str) -> Option<index::Index> {
+        self.list_indexes()
+            .into_iter()
+            .find(|index| index.name() == name)
+    }
+
+    fn index_field_map(&self) -> HashMap<String, index::Index> {
+        self.index_list()
+            .into_iter()
+            .map(|index| (index.indexed_field().to_string(), index))
+            .collect()
+    }
+
+    fn ensure_index_dir_exists(repo: &Repository) {
+        std::fs::create_dir_all(repo.path().join(".index")).unwrap();
+    }
+
+    fn make_tree<'a>(
+        repo: &'a Repository,
+        oid: &[u8],
+        root_tree: &'a Tree,
+        key: &str,
+        blob: Oid,
+    ) -> Result<Oid, git2::Error> {
+        let mut trees: Vec<(String, TreeBuilder)> =
+            vec![("".to_string(), repo.treebuilder(Some(root_tree))?)];
+        let natural_tree = key.contains("/");
+        if natural_tree {
+            let mut iterator = key.split("/").peekable();
+            while let Some(part) = iterator.next() {
+                let (parent_name, mut parent_tree) = trees.pop().unwrap();
+                if iterator.peek().is_none() {
+                    parent_tree.insert(part, blob, 0o100644)?;
+                    trees.push((parent_name, parent_tree));
+                } else {
+                    let tree_builder = parent_tree
+                        .get(part)
+                        .unwrap()
+                        .map(|x| {
+                            repo.treebuilder(Some(&x.to_object(repo).unwrap().into_tree().unwrap()))
+                        })
+                        .unwrap_or_else(|| repo.treebuilder(None))?;
+                    trees.push((parent_name, parent_tree));
+                    trees.push((part.to_string(), tree_builder));
+                }
+            }
+        } else {
+            for (part, hex_part) in oid.iter().enumerate().take(2) {
+                let (parent_name, parent_tree) = trees.pop().unwrap();
+                let name = format!("{hex_part:x}");
+                let mut tree_builder = parent_tree
+                    .get(&name)
+                    .unwrap()
+                    .map(|x| {
+                        repo.treebuilder(Some(&x.to_object(repo).unwrap().into_tree().unwrap()))
+                    })
+                    .unwrap_or_else(|| repo.treebuilder(None))?;
+                if part == 1 {
+                    tree_builder.insert(key, blob, 0o100644)?;
+                }
+                trees.push((parent_name, parent_tree));
+                trees.push((name, tree_builder));
+            }
+        }
+
+        loop {
+            let (self_name, self_tree) = trees.pop().unwrap();
+            if let Some((parent_name, mut parent_tree)) = trees.pop() {
+                let tree_id = self_tree.write()?;
+                parent_tree.insert(&self_name, tree_id, 0o040000)?;
+                trees.push((parent_name, parent_tree));
+            } else {
+                return self_tree.write();
+            }
+        }
+    }
+
+    /// Returns the tree with the blobs inserted at their paths. Every modified subtree
+    /// is written only once, no matter how many blobs end up in it.
+    /// If a path repeats, the blob which comes later wins.
+    fn insert_into_tree(
+        repo: &Repository,
+        tree: Option<&Tree>,
+        blobs: &[(&str, Oid)],
+    ) -> Result<Oid, git2::Error> {
+        let mut tree_builder = repo.treebuilder(tree)?;
+        let mut subtrees: BTreeMap<&str, Vec<(&str, Oid)>> = BTreeMap::new();
+        for (path, blob) in blobs {
+            match path.split_once("/") {
+                None => {
+                    tree_builder.insert(path, *blob, 0o100644)?;
+                }
+                Some((dir, rest)) => subtrees.entry(dir).or_default().push((rest, *blob)),
+            }
+        }
+        for (dir, blobs) in subtrees {
+            let subtree = match tree.and_then(|t| t.get_name(dir)) {
+                Some(entry) if entry.kind() == Some(ObjectType::Tree) => {
+                    Some(repo.find_tree(entry.id())?)
+                }
+                _ => None,
+            };
+            let new_subtree = Self::insert_into_tree(repo, subtree.as_ref(), &blobs)?;
+            tree_builder.insert(dir, new_subtree, 0o040000)?;
+        }
+        tree_builder.write()
+    }
+
+    /// Remove the document at the path along with its metadata
+    fn remove_document(
+        repo: &Repository,
+        tree: &Tree,
+        path: &str,
+    ) -> Result<Option<Oid>, git2::Error> {
+        let Some(new_root) = Self::remove_from_tree(repo, tree, path)? else {
+            return Ok(None);
+        };
+        let new_tree = repo.find_tree(new_root)?;
+        let meta_path = metadata::metadata_path(path);
+        Ok(Some(
+            Self::remove_from_tree(repo, &new_tree, &meta_path)?.unwrap_or(new_root),
+        ))
+    }
+
+    /// Metadata of the document at the path, changed at `now` to a value of the type,
+    /// to be inserted into the tree along with the document. Returns its path and blob.
+    fn stamp(
+        &self,
+        tree: &Tree,
+        path: &str,
+        now: i64,
+        type_tag: Option<&str>,
+    ) -> Result<(String, Oid), git2::Error> {
+        let previous = metadata::read(&self.repository, tree, path)?;
+        let mut meta = metadata::DocumentMeta::next(previous, now);
+        meta.type_tag = type_tag.map(String::from);
+        Ok((metadata::metadata_path(path), meta.write(&self.repository)?))
+    }
+
+    /// Returns the new tree with the path removed or None if there was nothing to remove.
+    /// Subtrees left empty after the removal are removed as well.
+    fn remove_from_tree(
+        repo: &Repository,
+        tree: &Tree,
+        path: &str,
+    ) -> Result<Option<Oid>, git2::Error> {
+        let mut tree_builder = repo.treebuilder(Some(tree))?;
+        match path.split_once("/") {
+            None => {
+                if tree_builder.get(path)?.is_none() {
+                    return Ok(None);
+                }
+                tree_builder.remove(path)?;
+            }
+            Some((dir, rest)) => {
+                let Some(entry) = tree.get_name(dir) else {
+                    return Ok(None);
+                };
+                if entry.kind() != Some(ObjectType::Tree) {
+                    return Ok(None);
+                }
+                let subtree = repo.find_tree(entry.id())?;
+                let Some(new_subtree) = Self::remove_from_tree(repo, &subtree, rest)? else {
+                    return Ok(None);
+                };
+                if repo.find_tree(new_subtree)?.is_empty() {
+                    tree_builder.remove(dir)?;
+                } else {
+                    tree_builder.insert(dir, new_subtree, 0o040000)?;
+                }
+            }
+        }
+        Ok(Some(tree_builder.write()?))
+    }
+
+    fn prepare_history_tags(&self, head: Oid, target: Oid) -> Result<(), git2::Error> {
+        let remotes = self.repository.remotes()?;
+        let tag_name = format!(
+            "revert-{}-{}-{}",
+            &head.to_string()[0..7],
+            &target.to_string()[0..7],
+            self.clock.now()
+        );
+        self.repository
+            .reference(format!("refs/tags/{}", tag_name).as_str(), head, true, "")?;
+        for remote in remotes.iter().flatten() {
+            let ref_name = format!("refs/history_tags/{}/{}", remote, tag_name);
+            self.repository.reference(&ref_name, head, true, "")?;
+            debug!("Prepared {} for push", &ref_name);
+        }
+        Ok(())
+    }
+
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(name = "collection.revert", skip(self), fields(branch = "main"))
+    )]
+    pub fn revert_main_to_commit(
+        &self,
+        commit: Oid,
+        keep_history: bool,
+    ) -> Result<(), error::RevertError> {
+        let _lock = self.write_lock()?;
+        let repo = &self.repository;
+        let target_commit = repo
+            .find_commit(commit)
+            .map_err(|_| error::RevertError::TargetCommitNotFound(commit))?;
+        let current_commit = Self::current_commit(repo, "main").map_err(|e| match e.code() {
+            ErrorCode::NotFound => error::RevertError::InvalidOperationTarget(String::from("main")),
+            _ => e.into(),
+        })?;
+        if keep_history {
+            self.prepare_history_tags(current_commit.id(), target_commit.id())?;
+        }
+        repo.reset(target_commit.as_object(), git2::ResetType::Soft, None)?;
+        self.log_insertions(
+            "main",
+            Some(&current_commit.tree()?),
+            &target_commit.tree()?,
+        );
+        Ok(())
+    }
+
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(
+            name = "collection.revert",
+            skip(self, target),
+            fields(branch = target.to_string(), commit = tracing::field::Empty)
+        )
+    )]
+    pub fn revert_n_commits(
+        &self,
+        n: usize,
+        target: OperationTarget,
+        keep_history: bool,
+    ) -> Result<(), error::RevertError> {
+        debug!("Reverting {} commits", n);
+        if n == 0 {
+            return Ok(());
+        }
+        let _lock = self.write_lock()?;
+        let repo = &self.repository;
+        let branch = target
+            .writable_branch()
+            .map_err(|_| error::RevertError::InvalidOperationTarget(target.to_string()))?;
+        let current_commit = Self::current_commit(repo, branch).map_err(|e| match e.code() {
+            ErrorCode::NotFound => error::RevertError::InvalidOperationTarget(branch.to_string()),
+            _ => e.into(),
+        })?;
+        let mut target_commit = current_commit.clone();
+        for _ in 0..n {
+            let parent_count = target_commit.parent_count();
+            if parent_count > 1 {
+                return Err(error::RevertError::BranchingHistory(target_commit.id()));
+            } else if parent_count == 0 {
+                debug!("No more parents to check");
+                break;
+            }
+            target_commit = target_commit.parent(0)?;
+            debug!("Current commit to revert: {:?}", target_commit.as_object());
+        }
+        if keep_history {
+            self.prepare_history_tags(current_commit.id(), target_commit.id())?;
+        }
+        record!("commit", target_commit.id().to_string());
+        repo.reset(target_commit.as_object(), git2::ResetType::Soft, None)?;
+        self.log_insertions(
+            branch,
+            Some(&current_commit.tree()?),
+            &target_commit.tree()?,
+        );
+        Ok(())
+    }
+
+    /// Up to `limit` keys on main, in the order they were inserted in (`Order::Descending`
+    /// for the most recent first), skipping the first `offset` of them. Deleted keys
+    /// are left out and a key inserted again after it was deleted counts as a new insertion,
+    /// while changing the value of a key doesn't move it. Keys inserted by the same commit
+    /// come in the order of their paths.
+    ///
+    /// The order is kept in a log next to the indexes. Like an index, it has to be built first
+    /// with `rebuild_insertion_log` (until then this fails with NotFound), and from then on
+    /// it's maintained by every write to main - merges and reverts included. If updating it
+    /// ever fails, it's dropped and has to be rebuilt again. Keys brought back
+    /// by a revert count as inserted again, while `rebuild_insertion_log` puts them where
+    /// the history has them.
+    pub fn keys_by_insertion(
+        &self,
+        order: index::Order,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<String>, git2::Error> {
+        insertions::keys(&self.repository, order, limit, offset)
+    }
+
+    /// Build the log of `keys_by_insertion` from the history of main (following
+    /// the first parents of the merges), replacing the current one, and maintain it
+    /// from now on. Returns the number of keys in it.
+    pub fn rebuild_insertion_log(&self) -> Result<usize, git2::Error> {
+        // no write to main can slip in between the walk and the log
+        let _lock = self.write_lock()?;
+        let repo = &self.repository;
+        let mut revwalk = repo.revwalk()?;
+        revwalk.push_ref("refs/heads/main")?;
+        revwalk.simplify_first_parent()?;
+        revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+        let mut previous: Option<Tree> = None;
+        let history = revwalk.map(|oid| {
+            let tree = repo.find_commit(oid?)?.tree()?;
+            let changes = self.changed_keys(previous.as_ref(), &tree)?;
+            previous = Some(tree);
+            Ok(changes)
+        });
+        insertions::rebuild(repo, history)
+    }
+
+    /// Net changes of keys between the `since` commit and the tip of the branch.
+    /// A key changed back and forth in the meantime is not reported.
+    /// Fails with NotAnAncestor if `since` is not in the history of the branch.
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(
+            level = "debug",
+            name = "collection.changes_since",
+            skip(self, target),
+            fields(branch = target.to_string(), changes = tracing::field::Empty)
+        )
+    )]
+    pub fn changes_since(
+        &self,
+        since: Oid,
+        target: OperationTarget,
+    ) -> Result<Vec<KeyChange>, error::ChangesError> {
+        let repo = &self.repository;
+        let tip = Self::target_commit(repo, target).map_err(|e| match e.code() {
+            ErrorCode::NotFound => error::ChangesError::InvalidOperationTarget(target.to_string()),
+            _ => e.into(),
+        })?;
+        let since_commit = repo
+            .find_commit(since)
+            .map_err(|_| error::ChangesError::CommitNotFound(since))?;
+        if tip.id() != since && !repo.graph_descendant_of(tip.id(), since)? {
+            return Err(error::ChangesError::NotAnAncestor(since));
+        }
+        let changes = self.changed_keys(Some(&since_commit.tree()?), &tip.tree()?)?;
+        record!("changes", changes.len());
+        Ok(changes)
+    }
+
+    /// Commits of the branch, newest first, following only the first parents
+    /// (so the commits of applied transactions show up as a part of the merge).
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(
+            level = "debug",
+            name = "collection.log",
+            skip(self, opts),
+            fields(
+                branch = opts.branch.to_string(),
+                limit = opts.limit,
+                commits = tracing::field::Empty
+            )
+        )
+    )]
+    pub fn log(&self, opts: LogOptions) -> Result<Vec<CommitInfo>, error::LogError> {
+        let repo = &self.repository;
+        let tip = Self::target_commit(repo, opts.branch).map_err(|e| match e.code() {
+            ErrorCode::NotFound => error::LogError::InvalidOperationTarget(opts.branch.to_string()),
+            _ => e.into(),
+        })?;
+        let mut revwalk = repo.revwalk()?;
+        revwalk.simplify_first_parent()?;
+        match opts.before {
+            Some(before) => {
+                let before_commit = repo
+                    .find_commit(before)
+                    .map_err(|_| error::LogError::CommitNotFound(before))?;
+                if tip.id() != before && !repo.graph_descendant_of(tip.id(), before)? {
+                    return Err(error::LogError::NotAnAncestor(before));
+                }
+                if let Ok(parent) = before_commit.parent_id(0) {
+                    revwalk.push(parent)?;
+                }
+            }
+            None => revwalk.push(tip.id())?,
+        }
+        let mut log = Vec::new();
+        for oid in revwalk.take(opts.limit) {
+            let commit = repo.find_commit(oid?)?;
+            let parent_tree = match commit.parent(0) {
+                Ok(parent) => Some(parent.tree()?),
+                Err(_) => None,
+            };
+            log.push(CommitInfo {
+                oid: commit.id(),
+                timestamp: DateTime::from_timestamp(commit.time().seconds(), 0).unwrap_or_default(),
+                author: String::from_utf8_lossy(commit.author().name_bytes()).to_string(),
+                message: String::from_utf8_lossy(commit.message_bytes()).to_string(),
+                keys: self.changed_keys(parent_tree.as_ref(), &commit.tree()?)?,
+            });
+        }
+        record!("commits", log.len());
+        Ok(log)
+    }
+
+    /// Up to `n` most recent values of the key, newest first, each with the commit which
+    /// introduced it. Follows the first parents like `log` and stops as soon as `n` values
+    /// are found. A value set again without changes doesn't count as a new version,
+    /// and neither does the removal of the key.
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(
+            level = "debug",
+            name = "collection.versions",
+            skip(self, target),
+            fields(
+                key = key,
+                branch = target.to_string(),
+                n = n,
+                versions = tracing::field::Empty
+            )
+        )
+    )]
+    pub fn versions(
+        &self,
+        key: &str,
+        n: usize,
+        target: OperationTarget,
+    ) -> Result<Vec<(Oid, Vec<u8>)>, error::GetObjectError> {
+        let path = Self::construct_path_to_key(key)?;
+        let repo = &self.repository;
+        let tip = Self::target_commit(repo, target).map_err(|e| match e.code() {
+            ErrorCode::NotFound => {
+                error::GetObjectError::InvalidOperationTarget(target.to_string())
+            }
+            _ => e.into(),
+        })?;
+        let mut revwalk = repo.revwalk()?;
+        revwalk.simplify_first_parent()?;
+        revwalk.push(tip.id())?;
+        // blobs found so far, and the oldest commit of the run of commits with the current one
+        let mut found: Vec<(Oid, Oid)> = Vec::new();
+        let mut run: Option<(Oid, Option<Oid>)> = None;
+        for oid in revwalk {
+            if found.len() >= n {
+                break;
+            }
+            let oid = oid?;
+            let commit = repo.find_commit(oid)?;
+            let blob = commit
+                .tree()?
+                .get_path(Path::new(&path))
+                .ok()
+                .map(|e| e.id());
+            match run {
+                Some((run_commit, Some(run_blob)))
+                    if blob != Some(run_blob)
+                        && found.last().is_none_or(|(_, last)| *last != run_blob) =>
+                {
+                    found.push((run_commit, run_blob))
+                }
+                _ => {}
+            }
+            run = Some((oid, blob));
+        }
+        if let Some((run_commit, Some(run_blob))) = run {
+            if found.len() < n && found.last().is_none_or(|(_, last)| *last != run_blob) {
+                found.push((run_commit, run_blob));
+            }
+        }
+        let mut versions = Vec::with_capacity(found.len());
+        for (commit, blob) in found {
+            let blob = repo.find_blob(blob)?;
+            versions.push((commit, self.open_value(blob.content())?.into_owned()));
+        }
+        record!("versions", versions.len());
+        Ok(versions)
+    }
+
+    fn changed_keys(&self, old: Option<&Tree>, new: &Tree) -> Result<Vec<KeyChange>, git2::Error> {
+        let diff = self.repository.diff_tree_to_tree(old, Some(new), None)?;
+        let mut changes = Vec::new();
+        for delta in diff.deltas() {
+            let (kind, file) = match delta.status() {
+                Delta::Added => (ChangeKind::Added, delta.new_file()),
+                Delta::Deleted => (ChangeKind::Deleted, delta.old_file()),
+                _ => (ChangeKind::Modified, delta.new_file()),
+            };
+            // unwrap: keys are always valid UTF-8
+            let path = file.path().and_then(|p| p.to_str()).unwrap();
+            // the documents of a namespace are laid out in its tree like in the root
+            let document_path = namespace::split_path(path).map_or(path, |(_, path)| path);
+            if document_path.rsplit_once("/").is_some_and(|(root, _)| {
+                root.ends_with(".index") || namespace::in_reserved_tree(root)
+            }) {
+                continue;
+            }
+            changes.push(KeyChange {
+                key: Self::key_from_full_path(path)?,
+                kind,
+            });
+        }
+        Ok(changes)
+    }
+
+    /// Key of the document at the path from the root. The keys of the documents
+    /// of a namespace are prefixed with its tree, like `users.namespace/a`.
+    fn key_from_full_path(path: &str) -> Result<String, git2::Error> {
+        if let Some((tree, path)) = namespace::split_path(path) {
+            return Ok(format!("{}/{}", tree, Self::key_from_full_path(path)?));
+        }
+        match path.rsplit_once("/") {
+            Some((root, name)) => Self::key_from_path(&format!("{}/", root), name),
+            None => Self::key_from_path("", path),
+        }
+    }
+
+    /// Reverse of key_from_full_path
+    fn construct_full_path(key: &str) -> Result<String, error::KeyError> {
+        match namespace::split_path(key) {
+            Some((tree, key)) => Ok(format!("{}/{}", tree, Self::construct_path_to_key(key)?)),
+            None => Self::construct_path_to_key(key),
+        }
+    }
+
+    /// Keys without a slash are sharded by the first two bytes of their git blob hash
+    /// (the SHA-1 `git hash-object --stdin` prints for the key), each written as lowercase
+    /// hex without zero padding - `a` (hash `2e65...`) is stored as `2e/65/a`, but `j`
+    /// (hash `0fe2...`) as `f/e2/j`. The same hash identifies the key in index entries,
+    /// so it's not configurable - changing it would require rewriting every tree and index.
+    fn construct_path_to_key(key: &str) -> Result<String, error::KeyError> {
+        if namespace::in_reserved_tree(key) {
+            return Err(error::KeyError::Reserved(key.to_string()));
+        }
+        if key.contains("/") {
+            return Ok(key.to_string());
+        }
+        let hash = Oid::hash_object(ObjectType::Blob, key.as_bytes())
+            .map_err(error::KeyError::NotHashable)?;
+        let hash_bytes = hash.as_bytes();
+        let mut path = String::new();
+        (0..2).for_each(|x| {
+            let val = &hash_bytes[x];
+            path.push_str(format!("{val:x}").as_ref());
+            path.push('/');
+        });
+        path.push_str(key);
+        Ok(path)
+    }
+
+    pub fn prefix_from_oid(oid: &Oid) -> String {
         let hash_bytes = oid.as_bytes();
         let mut path = String::new();
         (0..2).for_each(|x| {
@@ -654,55 +4400,2501 @@ impl Collection {
             path.push_str(format!("{val:x}").as_ref());
             path.push('/');
         });
-        debug!("Constructed prefix {}", path);
-        path
+        debug!("Constructed prefix {}", path);
+        path
+    }
+
+    /// Reverse of construct_path_to_key - `root` is the path of the tree containing `name`
+    /// as given by `Tree::walk`
+    pub fn key_from_path(root: &str, name: &str) -> Result<String, git2::Error> {
+        let hash = Oid::hash_object(ObjectType::Blob, name.as_bytes())?;
+        if root == Self::prefix_from_oid(&hash) {
+            return Ok(name.to_string());
+        }
+        Ok(format!("{}{}", root, name))
+    }
+
+    pub fn construct_oid_from_path(path: &str) -> Oid {
+        Oid::from_str(&path[path.len() - 22..].replace("/", "")).unwrap()
+    }
+}
+
+pub mod test;
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering::*;
+    use std::collections::{HashMap, HashSet};
+    use std::io::Read;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use git2::{BranchType, ObjectType, Oid, Repository};
+    use rstest::rstest;
+
+    use crate::{
+        clock::MockClock,
+        error,
+        field::Field,
+        index::{Index, IndexCursor, IndexType, Order},
+        metadata::DocumentMeta,
+        query::{q, QueryBuilder},
+        serialization::DataFormat,
+        ChangeKind, Collection, ConflictInfo, ConflictResolution, KeyChange, KeyConflict,
+        LogOptions, OperationTarget, Resolution,
+    };
+
+    use super::test::*;
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn set_and_get(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.set(
+            "key",
+            SampleDbStruct {
+                str_val: String::from("value"),
+            },
+            OperationTarget::Main,
+        )
+        .unwrap();
+        assert_eq!(
+            db.get::<SampleDbStruct>("key", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct {
+                str_val: String::from("value")
+            }
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn batch_set_and_get(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let mut hm = HashMap::new();
+        hm.insert(
+            "pref/a",
+            SampleDbStruct {
+                str_val: String::from("initial a value"),
+            },
+        );
+        hm.insert(
+            "pref/b",
+            SampleDbStruct {
+                str_val: String::from("initial b value"),
+            },
+        );
+        hm.insert(
+            "pref/c",
+            SampleDbStruct {
+                str_val: String::from("initial c value"),
+            },
+        );
+        let mut hm2 = hm.clone();
+        db.set_batch(hm, OperationTarget::Main).unwrap();
+        assert_eq!(
+            db.get::<SampleDbStruct>("pref/a", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct::new(String::from("initial a value"))
+        );
+        assert_eq!(
+            db.get::<SampleDbStruct>("pref/b", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct::new(String::from("initial b value"))
+        );
+        assert_eq!(
+            db.get::<SampleDbStruct>("pref/c", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct::new(String::from("initial c value"))
+        );
+        hm2.insert(
+            "pref/a",
+            SampleDbStruct::new(String::from("changed a value")),
+        );
+        db.set_batch(hm2, OperationTarget::Main).unwrap();
+        assert_eq!(
+            db.get::<SampleDbStruct>("pref/a", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct::new(String::from("changed a value"))
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn batch_set_order_independent(#[case] data_format: DataFormat) {
+        // the trees include the times the documents were written at
+        let td = tempfile::tempdir().unwrap();
+        let clock = std::sync::Arc::new(MockClock::new(1_700_000_000));
+        let db = Collection::initialize_with_clock(td.path(), data_format, clock).unwrap();
+        db.add_index("str_val", IndexType::Sequential);
+        let pairs: Vec<_> = (0..50)
+            .map(|i| {
+                let key = match i % 3 {
+                    0 => format!("key-{}", i),
+                    1 => format!("nested/key-{}", i),
+                    _ => format!("nested/deeper/key-{}", i),
+                };
+                (key, SampleDbStruct::new(i.to_string()))
+            })
+            .collect();
+        let forward = db.new_transaction(None).unwrap();
+        let reversed = db.new_transaction(None).unwrap();
+        let hashed = db.new_transaction(None).unwrap();
+        db.set_batch(pairs.clone(), OperationTarget::Transaction(&forward))
+            .unwrap();
+        db.set_batch(
+            pairs.iter().rev().cloned(),
+            OperationTarget::Transaction(&reversed),
+        )
+        .unwrap();
+        db.set_batch(
+            pairs.iter().cloned().collect::<HashMap<_, _>>(),
+            OperationTarget::Transaction(&hashed),
+        )
+        .unwrap();
+        let tree = |branch: &str| {
+            db.repository()
+                .find_branch(branch, BranchType::Local)
+                .unwrap()
+                .get()
+                .peel_to_tree()
+                .unwrap()
+                .id()
+        };
+        assert_eq!(tree(&forward), tree(&reversed));
+        assert_eq!(tree(&forward), tree(&hashed));
+
+        db.set_batch(
+            [
+                ("dup", SampleDbStruct::new(String::from("first"))),
+                ("a", SampleDbStruct::new(String::from("a"))),
+                ("dup", SampleDbStruct::new(String::from("last"))),
+            ],
+            OperationTarget::Main,
+        )
+        .unwrap();
+        assert_eq!(
+            db.get::<SampleDbStruct>("dup", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct::new(String::from("last"))
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn set_same_value_is_no_op(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let index = db.add_index("num_val", IndexType::Numeric);
+        let commits = || {
+            let mut revwalk = db.repository().revwalk().unwrap();
+            revwalk.push_ref("refs/heads/main").unwrap();
+            revwalk.count()
+        };
+        let first = db
+            .set(
+                "a",
+                InterigentDbStruct { num_val: 1 },
+                OperationTarget::Main,
+            )
+            .unwrap();
+        db.set(
+            "b",
+            InterigentDbStruct { num_val: 1 },
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let tip = db
+            .set(
+                "b",
+                InterigentDbStruct { num_val: 2 },
+                OperationTarget::Main,
+            )
+            .unwrap();
+        let before = commits();
+        let again = db
+            .set_batch(
+                [
+                    ("a", InterigentDbStruct { num_val: 1 }),
+                    ("b", InterigentDbStruct { num_val: 1 }),
+                    ("b", InterigentDbStruct { num_val: 2 }),
+                ],
+                OperationTarget::Main,
+            )
+            .unwrap();
+        assert_eq!(again, tip);
+        assert_ne!(again, first);
+        assert_eq!(commits(), before);
+        let hash = |key: &str| Oid::hash_object(ObjectType::Blob, key.as_bytes()).unwrap();
+        // the entries of the unchanged keys were left alone
+        let entries: Vec<(Field, Oid)> = index.scan(&db.repository, Order::Ascending).collect();
+        assert_eq!(
+            entries,
+            vec![(Field::Int(1), hash("a")), (Field::Int(2), hash("b"))]
+        );
+        db.set(
+            "a",
+            InterigentDbStruct { num_val: 3 },
+            OperationTarget::Main,
+        )
+        .unwrap();
+        assert_eq!(commits(), before + 1);
+    }
+
+    #[cfg(any(feature = "tracing", feature = "full"))]
+    #[test]
+    fn set_batch_emits_span() {
+        use std::sync::{Arc, Mutex};
+        use tracing_subscriber::fmt::{format::FmtSpan, MakeWriter};
+
+        #[derive(Clone, Default)]
+        struct CapturedOutput(Arc<Mutex<Vec<u8>>>);
+
+        impl std::io::Write for CapturedOutput {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        impl<'a> MakeWriter<'a> for CapturedOutput {
+            type Writer = Self;
+
+            fn make_writer(&'a self) -> Self::Writer {
+                self.clone()
+            }
+        }
+
+        let (db, _td) = create_db(DataFormat::Json);
+        let output = CapturedOutput::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(output.clone())
+            .with_span_events(FmtSpan::CLOSE)
+            .with_ansi(false)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            db.set_batch(
+                [
+                    ("a", SampleDbStruct::new(String::from("a"))),
+                    ("b", SampleDbStruct::new(String::from("b"))),
+                ],
+                OperationTarget::Main,
+            )
+            .unwrap();
+        });
+        let head = db
+            .repository()
+            .find_branch("main", BranchType::Local)
+            .unwrap()
+            .get()
+            .target()
+            .unwrap();
+        let logs = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("collection.set_batch"));
+        assert!(logs.contains("branch=\"main\""));
+        assert!(logs.contains("items=2"));
+        assert!(logs.contains(&format!("commit=\"{}\"", head)));
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_value_limit(#[case] data_format: DataFormat) {
+        let (db, td) = create_db(data_format);
+        db.add_index("str_val", IndexType::Sequential);
+        db.set_value_limit(64).unwrap();
+        let head = db.repository().head().unwrap().target().unwrap();
+        let err = db
+            .set_batch(
+                [
+                    ("small", SampleDbStruct::new(String::from("small"))),
+                    ("large", SampleDbStruct::new("x".repeat(100))),
+                ],
+                OperationTarget::Main,
+            )
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            error::SetObjectError::ValueTooLarge { limit: 64, .. }
+        ));
+        assert_eq!(db.repository().head().unwrap().target().unwrap(), head);
+        assert!(db.index_list()[0].git_index(db.repository()).is_empty());
+
+        db.set(
+            "small",
+            SampleDbStruct::new(String::from("small")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        assert!(matches!(
+            db.patch(
+                "small",
+                &serde_json::json!({"str_val": "x".repeat(100)}),
+                OperationTarget::Main
+            ),
+            Err(error::PatchError::Set(
+                error::SetObjectError::ValueTooLarge { .. }
+            ))
+        ));
+
+        let streamed = vec![7; 1000];
+        assert!(matches!(
+            db.set_reader("streamed", streamed.as_slice(), OperationTarget::Main),
+            Err(error::StreamError::Set(
+                error::SetObjectError::ValueTooLarge {
+                    size: 65,
+                    limit: 64
+                }
+            ))
+        ));
+        let streamed_oid = Oid::hash_object(ObjectType::Blob, &streamed).unwrap();
+        assert!(!db.repository().odb().unwrap().exists(streamed_oid));
+
+        // the limit is persisted
+        drop(db);
+        let db = Collection::initialize(td.path(), data_format).unwrap();
+        assert_eq!(db.value_limit().unwrap(), Some(64));
+        db.remove_value_limit().unwrap();
+        assert_eq!(db.value_limit().unwrap(), None);
+        db.set(
+            "large",
+            SampleDbStruct::new("x".repeat(100)),
+            OperationTarget::Main,
+        )
+        .unwrap();
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_create_in_memory(#[case] data_format: DataFormat) {
+        let db = Collection::create_in_memory(data_format).unwrap();
+        let path = db.repository().path().to_path_buf();
+        db.add_index("str_val", IndexType::Sequential);
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("a")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct::new(String::from("a"))
+        );
+        let query = QueryBuilder::query(q("str_val", Equal, "a"))
+            .execute(&db)
+            .unwrap();
+        assert_eq!(query.count, 1);
+        let other = Collection::create_in_memory(data_format).unwrap();
+        assert_ne!(other.repository().path(), path);
+        assert_eq!(other.get_raw("a", OperationTarget::Main).unwrap(), None);
+
+        drop(db);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_value_limit_boundary() {
+        let (db, _td) = create_db(DataFormat::Json);
+        let at_limit = serde_json::to_vec(&SampleDbStruct::new("x".repeat(32))).unwrap();
+        let over_limit = serde_json::to_vec(&SampleDbStruct::new("y".repeat(33))).unwrap();
+        let limit = at_limit.len() as u64;
+        assert_eq!(over_limit.len() as u64, limit + 1);
+        db.set_value_limit(limit).unwrap();
+
+        assert_eq!(
+            db.set_raw("over", &over_limit, OperationTarget::Main),
+            Err(error::SetObjectError::ValueTooLarge {
+                size: limit + 1,
+                limit
+            })
+        );
+        let over_oid = Oid::hash_object(ObjectType::Blob, &over_limit).unwrap();
+        assert!(!db.repository().odb().unwrap().exists(over_oid));
+        assert_eq!(db.get_raw("over", OperationTarget::Main).unwrap(), None);
+
+        db.set_raw("at", &at_limit, OperationTarget::Main).unwrap();
+        assert_eq!(
+            db.get_raw("at", OperationTarget::Main)
+                .unwrap()
+                .unwrap()
+                .len() as u64,
+            limit
+        );
+    }
+
+    #[test]
+    fn test_streaming_large_value() {
+        let (db, _td) = create_db(DataFormat::Json);
+        let value: Vec<u8> = (0..10 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        db.set_reader("large", value.as_slice(), OperationTarget::Main)
+            .unwrap();
+        let mut reader = db
+            .get_reader("large", OperationTarget::Main)
+            .unwrap()
+            .unwrap();
+        assert_eq!(reader.len(), value.len());
+        let mut read_back = Vec::new();
+        let mut chunk = [0; 64 * 1024];
+        loop {
+            let n = reader.read(&mut chunk).unwrap();
+            if n == 0 {
+                break;
+            }
+            read_back.extend_from_slice(&chunk[..n]);
+        }
+        assert!(read_back == value);
+        assert!(db
+            .get_reader("missing", OperationTarget::Main)
+            .unwrap()
+            .is_none());
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_get_non_existent_value(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        assert_eq!(
+            db.get::<SampleDbStruct>("key", OperationTarget::Main)
+                .unwrap(),
+            None
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_patch(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.set(
+            "a",
+            serde_json::json!({
+                "name": "a",
+                "nested": {"x": 1, "y": 2},
+                "removed": "soon"
+            }),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        db.patch(
+            "a",
+            &serde_json::json!({"nested": {"y": 3, "z": 4}, "removed": null}),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        assert_eq!(
+            db.get::<serde_json::Value>("a", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            serde_json::json!({
+                "name": "a",
+                "nested": {"x": 1, "y": 3, "z": 4}
+            })
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_patch_errors(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.set("list", vec![1, 2, 3], OperationTarget::Main)
+            .unwrap();
+        assert_eq!(
+            db.patch("missing", &serde_json::json!({}), OperationTarget::Main)
+                .unwrap_err(),
+            error::PatchError::KeyNotFound(String::from("missing"))
+        );
+        assert_eq!(
+            db.patch("list", &serde_json::json!({}), OperationTarget::Main)
+                .unwrap_err(),
+            error::PatchError::NotAnObject(String::from("list"))
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_patch_batch_updates_indexes(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.add_index("str_val", IndexType::Sequential);
+        db.set_batch(
+            [
+                ("a", SampleDbStruct::new(String::from("old"))),
+                ("b", SampleDbStruct::new(String::from("old"))),
+            ],
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let result = db
+            .patch_batch(
+                ["a", "b"],
+                &serde_json::json!({"str_val": "new"}),
+                OperationTarget::Main,
+            )
+            .unwrap();
+        assert_eq!(
+            db.repository().head().unwrap().target().unwrap(),
+            result.commit
+        );
+        let old = QueryBuilder::query(q("str_val", Equal, "old"))
+            .execute(&db)
+            .unwrap();
+        let new = QueryBuilder::query(q("str_val", Equal, "new"))
+            .execute(&db)
+            .unwrap();
+        assert_eq!(old.count, 0);
+        assert_eq!(new.count, 2);
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_delete(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.set_batch(
+            [
+                ("a", SampleDbStruct::new(String::from("a"))),
+                ("pref/b", SampleDbStruct::new(String::from("b"))),
+            ],
+            OperationTarget::Main,
+        )
+        .unwrap();
+        assert!(db.delete("a", OperationTarget::Main).unwrap());
+        assert!(db.delete("pref/b", OperationTarget::Main).unwrap());
+        assert!(!db.delete("pref/b", OperationTarget::Main).unwrap());
+        assert!(db
+            .get::<SampleDbStruct>("a", OperationTarget::Main)
+            .unwrap()
+            .is_none());
+        let tree = db.repository().head().unwrap().peel_to_tree().unwrap();
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn test_with_repository() {
+        let (mut db, td) = create_db(DataFormat::Json);
+        assert_eq!(
+            db.path().canonicalize().unwrap(),
+            td.path().canonicalize().unwrap()
+        );
+        let index = db.add_index("str_val", IndexType::Sequential);
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("a")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let main = db.head(OperationTarget::Main).unwrap();
+        let refs = crate::index::Index::snapshot_all(db.repository(), main).unwrap();
+        assert_eq!(
+            refs,
+            vec![format!("refs/yamabiko/indexes/{}", index.name())]
+        );
+        let snapshot = db.with_repository(|repo| {
+            let commit = repo
+                .find_reference(&refs[0])
+                .unwrap()
+                .peel_to_commit()
+                .unwrap();
+            (
+                commit.message().unwrap().to_string(),
+                commit.parent_id(0).unwrap(),
+            )
+        });
+        assert_eq!(snapshot, (String::from("index snapshot"), main));
+
+        db.with_repository_mut(|repo| repo.set_namespace("other"))
+            .unwrap();
+        assert_eq!(
+            db.with_repository(|repo| repo.namespace().map(String::from)),
+            Some(String::from("other"))
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_create_index_backfills(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.set_batch(
+            [
+                ("a", SampleDbStruct::new(String::from("a"))),
+                ("b", SampleDbStruct::new(String::from("b"))),
+            ],
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let garbage: &[u8] = &[0xff, 0x00, 0xfe, b'{', b'['];
+        db.set_reader("raw", garbage, OperationTarget::Main)
+            .unwrap();
+        db.put_attachment("a", "file", garbage, OperationTarget::Main)
+            .unwrap();
+
+        let (index, skipped) = db.create_index("str_val", IndexType::Sequential).unwrap();
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].key, "raw");
+        assert_eq!(index.git_index(db.repository()).len(), 2);
+        let query = QueryBuilder::query(q("str_val", Equal, "b"))
+            .execute(&db)
+            .unwrap();
+        assert_eq!(query.count, 1);
+
+        // the index is filled only once
+        let (again, skipped) = db.create_index("str_val", IndexType::Sequential).unwrap();
+        assert_eq!(again, index);
+        assert!(skipped.is_empty());
+        assert_eq!(index.git_index(db.repository()).len(), 2);
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_clear(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let index = db.add_index("str_val", IndexType::Sequential);
+        db.set_batch(
+            [
+                ("a", SampleDbStruct::new(String::from("a"))),
+                ("pref/b", SampleDbStruct::new(String::from("b"))),
+            ],
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let before = db.repository().head().unwrap().target().unwrap();
+        db.clear(OperationTarget::Main).unwrap();
+        let after = db.repository().head().unwrap().target().unwrap();
+        assert_ne!(before, after);
+        for key in ["a", "pref/b"] {
+            assert_eq!(db.get_raw(key, OperationTarget::Main).unwrap(), None);
+        }
+        assert!(index.git_index(db.repository()).is_empty());
+        assert_eq!(db.index_list(), vec![index.clone()]);
+        // nothing left to clear
+        db.clear(OperationTarget::Main).unwrap();
+        assert_eq!(db.repository().head().unwrap().target().unwrap(), after);
+
+        db.revert_main_to_commit(before, true).unwrap();
+        db.rebuild_index(&index).unwrap();
+        assert_eq!(
+            db.get::<SampleDbStruct>("pref/b", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct::new(String::from("b"))
+        );
+        let query = QueryBuilder::query(q("str_val", Equal, "a"))
+            .execute(&db)
+            .unwrap();
+        assert_eq!(query.count, 1);
+
+        // clearing a transaction leaves the indexes of main alone
+        let transaction = db.new_transaction(None).unwrap();
+        db.clear(OperationTarget::Transaction(&transaction))
+            .unwrap();
+        assert_eq!(
+            db.iter(OperationTarget::Transaction(&transaction))
+                .unwrap()
+                .count(),
+            0
+        );
+        assert_eq!(index.git_index(db.repository()).len(), 2);
+        let query = QueryBuilder::query(q("str_val", Equal, "a"))
+            .execute(&db)
+            .unwrap();
+        assert_eq!(query.count, 1);
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_metrics_hooks(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let metrics = std::sync::Arc::new(crate::metrics::test::RecordingMetrics::default());
+        let db = db.with_metrics(metrics.clone());
+        db.set_batch(
+            [
+                ("a", SampleDbStruct::new(String::from("a"))),
+                ("b", SampleDbStruct::new(String::from("b"))),
+            ],
+            OperationTarget::Main,
+        )
+        .unwrap();
+        db.get::<SampleDbStruct>("a", OperationTarget::Main)
+            .unwrap();
+        db.get::<SampleDbStruct>("c", OperationTarget::Main)
+            .unwrap();
+        db.delete("a", OperationTarget::Main).unwrap();
+        db.delete("a", OperationTarget::Main).unwrap();
+
+        let sets = metrics.sets.lock().unwrap();
+        assert_eq!(sets.len(), 1);
+        let (branch, items, bytes) = &sets[0];
+        assert_eq!((branch.as_str(), *items), ("main", 2));
+        assert!(*bytes > 0);
+        assert_eq!(
+            *metrics.gets.lock().unwrap(),
+            vec![(String::from("main"), true), (String::from("main"), false)]
+        );
+        // nothing was removed by the second delete, so there was no commit to measure
+        assert_eq!(
+            *metrics.deletes.lock().unwrap(),
+            vec![(String::from("main"), 1)]
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_changes_since(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.set_batch(
+            [
+                ("a", SampleDbStruct::new(String::from("a"))),
+                ("pref/b", SampleDbStruct::new(String::from("b"))),
+            ],
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let since = db.repository().head().unwrap().target().unwrap();
+        db.set(
+            "pref/b",
+            SampleDbStruct::new(String::from("new b")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        db.set(
+            "c",
+            SampleDbStruct::new(String::from("c")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        db.set(
+            "d",
+            SampleDbStruct::new(String::from("d")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        db.delete("d", OperationTarget::Main).unwrap();
+        db.delete("a", OperationTarget::Main).unwrap();
+        let mut changes = db.changes_since(since, OperationTarget::Main).unwrap();
+        changes.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(
+            changes,
+            vec![
+                KeyChange {
+                    key: String::from("a"),
+                    kind: ChangeKind::Deleted
+                },
+                KeyChange {
+                    key: String::from("c"),
+                    kind: ChangeKind::Added
+                },
+                KeyChange {
+                    key: String::from("pref/b"),
+                    kind: ChangeKind::Modified
+                },
+            ]
+        );
+        let tip = db.repository().head().unwrap().target().unwrap();
+        assert_eq!(
+            db.changes_since(tip, OperationTarget::Main).unwrap(),
+            vec![]
+        );
+        db.revert_n_commits(2, OperationTarget::Main, false)
+            .unwrap();
+        assert_eq!(
+            db.changes_since(tip, OperationTarget::Main).unwrap_err(),
+            error::ChangesError::NotAnAncestor(tip)
+        );
+    }
+
+    #[rstest]
+    #[case("main")]
+    #[case("HEAD")]
+    #[case("../evil")]
+    #[case("nested/name")]
+    #[case("with space")]
+    #[case("")]
+    fn test_invalid_transaction_name(#[case] name: &str) {
+        let (db, _td) = create_db(DataFormat::Json);
+        assert_eq!(
+            db.new_transaction(Some(name)),
+            Err(error::NewTransactionError::InvalidTransactionName(
+                name.to_string()
+            ))
+        );
+        assert_eq!(
+            db.new_transaction(Some("valid-name_1.0")),
+            Ok(String::from("valid-name_1.0"))
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_set_same_value_skips_commit(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let commits = || {
+            let mut revwalk = db.repository().revwalk().unwrap();
+            revwalk.push_ref("refs/heads/main").unwrap();
+            revwalk.count()
+        };
+        let value = SampleDbStruct::new(String::from("value"));
+        let first = db
+            .set_batch_with_result([("a", &value)], OperationTarget::Main)
+            .unwrap();
+        assert!(first.committed);
+        let before = commits();
+        let second = db
+            .set_batch_with_result([("a", &value)], OperationTarget::Main)
+            .unwrap();
+        assert!(!second.committed);
+        assert_eq!(second.commit, first.commit);
+        assert_eq!(commits(), before);
+        assert_eq!(db.set("a", &value, OperationTarget::Main), Ok(first.commit));
+
+        let changed = db
+            .set_batch_with_result([("a", &value), ("b", &value)], OperationTarget::Main)
+            .unwrap();
+        assert!(changed.committed);
+        assert_eq!(commits(), before + 1);
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_head(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let initial = db.head(OperationTarget::Main).unwrap();
+        let value = SampleDbStruct::new(String::from("value"));
+        let written = db
+            .set_batch_with_result([("a", &value)], OperationTarget::Main)
+            .unwrap();
+        assert_ne!(written.commit, initial);
+        assert_eq!(db.head(OperationTarget::Main), Ok(written.commit));
+        // a write which changes nothing leaves the version as it is
+        db.set("a", &value, OperationTarget::Main).unwrap();
+        assert_eq!(db.head(OperationTarget::Main), Ok(written.commit));
+
+        let t = db.new_transaction(None).unwrap();
+        let in_transaction = db
+            .set("b", &value, OperationTarget::Transaction(&t))
+            .unwrap();
+        assert_eq!(
+            db.head(OperationTarget::Transaction(&t)),
+            Ok(in_transaction)
+        );
+        assert_eq!(db.head(OperationTarget::Main), Ok(written.commit));
+
+        let deleted = db
+            .delete_batch_with_result(["a", "missing"], OperationTarget::Main)
+            .unwrap();
+        assert_eq!(deleted.deleted, 1);
+        assert_eq!(db.head(OperationTarget::Main), Ok(deleted.commit));
+        assert_eq!(
+            db.delete_batch_with_result(["a"], OperationTarget::Main),
+            Ok(crate::DeleteResult {
+                deleted: 0,
+                commit: deleted.commit
+            })
+        );
+        assert_eq!(db.head(OperationTarget::Commit(initial)), Ok(initial));
+        assert_eq!(
+            db.head(OperationTarget::Transaction("missing")),
+            Err(error::QueryError::InvalidOperationTarget(String::from(
+                "missing"
+            )))
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_get_range(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let value = SampleDbStruct::new(String::from("a rather long value"));
+        db.set("a", &value, OperationTarget::Main).unwrap();
+        let stored = data_format.serialize_with_indexes(&value, &mut HashMap::new());
+        let len = stored.len();
+        let range = |range: std::ops::Range<usize>| {
+            db.get_range("a", range, OperationTarget::Main)
+                .unwrap()
+                .unwrap()
+        };
+        assert_eq!(range(0..len), stored);
+        assert_eq!(range(2..6), stored[2..6].to_vec());
+        assert_eq!(range(3..len + 100), stored[3..].to_vec());
+        assert_eq!(range(len + 1..len + 5), Vec::<u8>::new());
+        #[allow(clippy::reversed_empty_ranges)]
+        let reversed = 6..2;
+        assert_eq!(range(reversed), Vec::<u8>::new());
+        assert_eq!(
+            db.get_range("missing", 0..10, OperationTarget::Main),
+            Ok(None)
+        );
+        assert_eq!(
+            db.get_range("a", 0..10, OperationTarget::Branch("missing")),
+            Err(error::GetObjectError::InvalidOperationTarget(String::from(
+                "missing"
+            )))
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_new_transaction_exists(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        assert!(db.branch_exists("main"));
+        assert!(!db.branch_exists("t"));
+        let t = db.new_transaction(Some("t")).unwrap();
+        assert!(db.branch_exists(&t));
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("a")),
+            OperationTarget::Transaction(&t),
+        )
+        .unwrap();
+        assert_eq!(
+            db.new_transaction(Some("t")),
+            Err(error::NewTransactionError::TransactionExists(String::from(
+                "t"
+            )))
+        );
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Transaction(&t))
+                .unwrap(),
+            Some(SampleDbStruct::new(String::from("a")))
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_resume_transaction(#[case] data_format: DataFormat) {
+        let (db, td) = create_db(data_format);
+        let t = db.new_transaction(None).unwrap();
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("a")),
+            OperationTarget::Transaction(&t),
+        )
+        .unwrap();
+        drop(db);
+
+        let db = Collection::initialize(td.path(), data_format).unwrap();
+        let t = db.resume_transaction(&t).unwrap();
+        db.set(
+            "b",
+            SampleDbStruct::new(String::from("b")),
+            OperationTarget::Transaction(&t),
+        )
+        .unwrap();
+        db.apply_transaction(&t, ConflictResolution::Abort).unwrap();
+        for key in ["a", "b"] {
+            assert!(db
+                .get::<SampleDbStruct>(key, OperationTarget::Main)
+                .unwrap()
+                .is_some());
+        }
+        assert_eq!(
+            db.resume_transaction(&t),
+            Err(error::TransactionError::TransactionNotFound)
+        );
+        assert_eq!(
+            db.resume_transaction("main"),
+            Err(error::TransactionError::TransactionNotFound)
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_gc_transactions(#[case] data_format: DataFormat) {
+        let td = tempfile::tempdir().unwrap();
+        let clock = std::sync::Arc::new(MockClock::new(1_700_000_000));
+        let db = Collection::initialize_with_clock(td.path(), data_format, clock.clone()).unwrap();
+        let write = |t: &str, key: &str| {
+            db.set(
+                key,
+                SampleDbStruct::new(String::from(key)),
+                OperationTarget::Transaction(t),
+            )
+            .unwrap();
+        };
+        let abandoned = db.new_transaction(Some("abandoned")).unwrap();
+        write(&abandoned, "a");
+        let merged = db.new_transaction(Some("merged")).unwrap();
+        write(&merged, "b");
+        db.merge(&merged, "main", ConflictResolution::Abort)
+            .unwrap();
+        let empty = db.new_transaction(Some("empty")).unwrap();
+        // a branch of the user, not a transaction
+        let head = db.repository().head().unwrap().peel_to_commit().unwrap();
+        db.repository().branch("archive", &head, false).unwrap();
+        db.set(
+            "d",
+            SampleDbStruct::new(String::from("d")),
+            OperationTarget::Branch("archive"),
+        )
+        .unwrap();
+        clock.advance(3600);
+        let recent = db.new_transaction(Some("recent")).unwrap();
+        write(&recent, "c");
+        clock.advance(60);
+
+        assert_eq!(db.gc_transactions(Duration::from_secs(7200)).unwrap(), 0);
+        assert_eq!(db.gc_transactions(Duration::from_secs(600)).unwrap(), 1);
+        let mut branches: Vec<String> = db
+            .repository()
+            .branches(Some(BranchType::Local))
+            .unwrap()
+            .map(|branch| branch.unwrap().0.name().unwrap().unwrap().to_string())
+            .collect();
+        branches.sort();
+        assert_eq!(
+            branches,
+            vec!["archive", "empty", "main", "merged", "recent"]
+        );
+        assert_eq!(
+            db.resume_transaction(&abandoned),
+            Err(error::TransactionError::TransactionNotFound)
+        );
+        assert_eq!(db.gc_transactions(Duration::ZERO).unwrap(), 1);
+        assert!(db.resume_transaction(&recent).is_err());
+        assert!(db.resume_transaction(&empty).is_ok());
+        assert!(db.branch_exists("archive"));
+        assert_eq!(
+            db.get::<SampleDbStruct>("b", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct::new(String::from("b"))
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_gc_transactions_created_before_markers(#[case] data_format: DataFormat) {
+        let td = tempfile::tempdir().unwrap();
+        let clock = std::sync::Arc::new(MockClock::new(1_700_000_000));
+        let db = Collection::initialize_with_clock(td.path(), data_format, clock.clone()).unwrap();
+        let old = db.new_transaction(Some("old")).unwrap();
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("a")),
+            OperationTarget::Transaction(&old),
+        )
+        .unwrap();
+        // the state of a repository written before transactions had markers
+        db.repository()
+            .find_reference("refs/transactions/old")
+            .unwrap()
+            .delete()
+            .unwrap();
+        db.repository()
+            .config()
+            .unwrap()
+            .remove("yamabiko.legacytransactionsmarked")
+            .unwrap();
+        drop(db);
+
+        clock.advance(60);
+        let db = Collection::initialize_with_clock(td.path(), data_format, clock.clone()).unwrap();
+        // branches created from now on are told apart from transactions
+        let head = db.repository().head().unwrap().peel_to_commit().unwrap();
+        db.repository().branch("archive", &head, false).unwrap();
+        db.set(
+            "b",
+            SampleDbStruct::new(String::from("b")),
+            OperationTarget::Branch("archive"),
+        )
+        .unwrap();
+        clock.advance(60);
+        let db = Collection::initialize_with_clock(td.path(), data_format, clock.clone()).unwrap();
+        assert_eq!(db.gc_transactions(Duration::ZERO).unwrap(), 1);
+        assert!(!db.branch_exists("old"));
+        assert!(db.branch_exists("archive"));
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_set_idempotent(#[case] data_format: DataFormat) {
+        let td = tempfile::tempdir().unwrap();
+        let clock = std::sync::Arc::new(MockClock::new(1_700_000_000));
+        let db = Collection::initialize_with_clock(td.path(), data_format, clock.clone()).unwrap();
+        assert_eq!(
+            db.idempotency_retention().unwrap(),
+            Duration::from_secs(24 * 60 * 60)
+        );
+        db.set_idempotency_retention(Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(db.idempotency_retention().unwrap(), Duration::from_secs(60));
+
+        let first = db
+            .set_idempotent(
+                "token",
+                "a",
+                SampleDbStruct::new(String::from("first")),
+                OperationTarget::Main,
+            )
+            .unwrap();
+        clock.advance(30);
+        let replayed = db
+            .set_idempotent(
+                "token",
+                "a",
+                SampleDbStruct::new(String::from("second")),
+                OperationTarget::Main,
+            )
+            .unwrap();
+        assert_eq!(replayed, first);
+        assert_eq!(db.repository().head().unwrap().target().unwrap(), first);
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap(),
+            Some(SampleDbStruct::new(String::from("first")))
+        );
+
+        // expired
+        clock.advance(30);
+        let rewritten = db
+            .set_idempotent(
+                "token",
+                "a",
+                SampleDbStruct::new(String::from("second")),
+                OperationTarget::Main,
+            )
+            .unwrap();
+        assert_ne!(rewritten, first);
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap(),
+            Some(SampleDbStruct::new(String::from("second")))
+        );
+
+        // failed writes don't use up the token
+        assert!(db
+            .set_idempotent(
+                "failing",
+                "a",
+                SampleDbStruct::new(String::from("x")),
+                OperationTarget::Branch("missing"),
+            )
+            .is_err());
+        assert_ne!(
+            db.set_idempotent(
+                "failing",
+                "a",
+                SampleDbStruct::new(String::from("third")),
+                OperationTarget::Main,
+            )
+            .unwrap(),
+            rewritten
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_concurrent_idempotent_writes(#[case] data_format: DataFormat) {
+        let (db, td) = create_db(data_format);
+        let before = db.stats().unwrap().commits;
+        let threads: Vec<_> = (0..8)
+            .map(|n| {
+                let path = td.path().to_path_buf();
+                std::thread::spawn(move || {
+                    let db = Collection::initialize(&path, data_format).unwrap();
+                    // every writer replays the same write, and makes one of its own
+                    let replayed = db
+                        .set_idempotent(
+                            "shared",
+                            "shared",
+                            SampleDbStruct::new(n.to_string()),
+                            OperationTarget::Main,
+                        )
+                        .unwrap();
+                    db.set_idempotent(
+                        &format!("own-{}", n),
+                        &format!("own-{}", n),
+                        SampleDbStruct::new(n.to_string()),
+                        OperationTarget::Main,
+                    )
+                    .unwrap();
+                    replayed
+                })
+            })
+            .collect();
+        let commits: HashSet<Oid> = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect();
+        assert_eq!(commits.len(), 1);
+        assert_eq!(db.stats().unwrap().commits, before + 9);
+        // no token was lost to a concurrent writer
+        for n in 0..8 {
+            let token = format!("own-{}", n);
+            assert!(db
+                .set_idempotent(
+                    &token,
+                    &token,
+                    SampleDbStruct::new(String::from("again")),
+                    OperationTarget::Main,
+                )
+                .is_ok());
+        }
+        assert_eq!(db.stats().unwrap().commits, before + 9);
+    }
+
+    #[rstest]
+    #[case(OperationTarget::Transaction("main"))]
+    #[case(OperationTarget::Transaction("HEAD"))]
+    #[case(OperationTarget::Transaction("refs/../../evil"))]
+    #[case(OperationTarget::Branch("with space"))]
+    #[case(OperationTarget::Branch("../evil"))]
+    fn test_invalid_operation_target(#[case] target: OperationTarget) {
+        let (db, _td) = create_db(DataFormat::Json);
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("main")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        assert!(!target.is_valid());
+        assert_eq!(
+            db.set("a", SampleDbStruct::new(String::from("other")), target),
+            Err(error::SetObjectError::InvalidOperationTarget(
+                target.to_string()
+            ))
+        );
+        assert_eq!(
+            db.delete("a", target),
+            Err(error::SetObjectError::InvalidOperationTarget(
+                target.to_string()
+            ))
+        );
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", target),
+            Err(error::GetObjectError::InvalidOperationTarget(
+                target.to_string()
+            ))
+        );
+        assert_eq!(
+            db.revert_n_commits(1, target, false),
+            Err(error::RevertError::InvalidOperationTarget(
+                target.to_string()
+            ))
+        );
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct::new(String::from("main"))
+        );
+    }
+
+    #[test]
+    fn test_missing_branch_target() {
+        let (db, _td) = create_db(DataFormat::Json);
+        assert!(OperationTarget::Branch("main").is_valid());
+        assert_eq!(
+            db.set(
+                "a",
+                SampleDbStruct::new(String::from("a")),
+                OperationTarget::Branch("missing")
+            ),
+            Err(error::SetObjectError::InvalidOperationTarget(String::from(
+                "missing"
+            )))
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_missing_transaction_target(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let target = OperationTarget::Transaction("typo");
+        assert!(target.is_valid());
+        assert_eq!(
+            db.set("a", SampleDbStruct::new(String::from("a")), target),
+            Err(error::SetObjectError::InvalidOperationTarget(
+                target.to_string()
+            ))
+        );
+        assert_eq!(
+            db.set_batch([("a", SampleDbStruct::new(String::from("a")))], target),
+            Err(error::SetObjectError::InvalidOperationTarget(
+                target.to_string()
+            ))
+        );
+        assert_eq!(
+            db.set_raw("a", b"{}", target),
+            Err(error::SetObjectError::InvalidOperationTarget(
+                target.to_string()
+            ))
+        );
+        assert_eq!(
+            db.delete_batch(["a"], target),
+            Err(error::SetObjectError::InvalidOperationTarget(
+                target.to_string()
+            ))
+        );
+        // reads of the same target fail the same way
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", target),
+            Err(error::GetObjectError::InvalidOperationTarget(
+                target.to_string()
+            ))
+        );
+        assert!(db
+            .repository()
+            .find_branch("typo", BranchType::Local)
+            .is_err());
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_preview_transaction(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("base")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let t = db.new_transaction(None).unwrap();
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("main")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        db.set(
+            "c",
+            SampleDbStruct::new(String::from("main only")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("transaction")),
+            OperationTarget::Transaction(&t),
+        )
+        .unwrap();
+        db.set(
+            "b",
+            SampleDbStruct::new(String::from("transaction only")),
+            OperationTarget::Transaction(&t),
+        )
+        .unwrap();
+        let main_head = db.repository().head().unwrap().target().unwrap();
+
+        let preview = db.preview_transaction(&t).unwrap();
+        assert_eq!(preview.conflicts.len(), 1);
+        let conflict = &preview.conflicts[0];
+        assert_eq!(conflict.key, "a");
+        let value = |v: &Option<Vec<u8>>| {
+            data_format
+                .deserialize::<SampleDbStruct>(v.as_ref().unwrap())
+                .str_val
+        };
+        assert_eq!(value(&conflict.base), "base");
+        assert_eq!(value(&conflict.ours), "main");
+        assert_eq!(value(&conflict.theirs), "transaction");
+        assert_eq!(
+            preview.changes,
+            vec![KeyChange {
+                key: String::from("b"),
+                kind: ChangeKind::Added
+            }]
+        );
+        assert_eq!(db.repository().head().unwrap().target().unwrap(), main_head);
+        assert!(db.repository().find_branch(&t, BranchType::Local).is_ok());
+
+        let outcome = db
+            .apply_transaction_with(&t, |_| Resolution::TakeTransaction)
+            .unwrap();
+        assert_eq!(outcome.commits_applied, 2);
+        for (key, expected) in [
+            ("a", "transaction"),
+            ("b", "transaction only"),
+            ("c", "main only"),
+        ] {
+            assert_eq!(
+                db.get::<SampleDbStruct>(key, OperationTarget::Main)
+                    .unwrap()
+                    .unwrap(),
+                SampleDbStruct::new(String::from(expected))
+            );
+        }
+        assert!(db.repository().find_branch(&t, BranchType::Local).is_err());
+    }
+
+    #[test]
+    fn test_apply_transaction_with_merged_documents() {
+        let (db, _td) = create_db(DataFormat::Json);
+        db.add_index("x", IndexType::Numeric);
+        db.set(
+            "doc",
+            serde_json::json!({"x": 1, "y": 1}),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let t = db.new_transaction(None).unwrap();
+        db.set(
+            "doc",
+            serde_json::json!({"x": 2, "y": 1}),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        db.set(
+            "doc",
+            serde_json::json!({"x": 1, "y": 2}),
+            OperationTarget::Transaction(&t),
+        )
+        .unwrap();
+
+        // take every field from the side which changed it
+        let merge = |conflict: KeyConflict| {
+            let parse = |v: Option<Vec<u8>>| -> serde_json::Value {
+                serde_json::from_slice(&v.unwrap()).unwrap()
+            };
+            let base = parse(conflict.base);
+            let ours = parse(conflict.ours);
+            let mut merged = parse(conflict.theirs);
+            for (field, value) in ours.as_object().unwrap() {
+                if base.get(field) != Some(value) {
+                    merged[field] = value.clone();
+                }
+            }
+            Resolution::Replace(serde_json::to_vec(&merged).unwrap())
+        };
+        let outcome = db.apply_transaction_with(&t, merge).unwrap();
+        assert_eq!(outcome.commits_applied, 1);
+        assert_eq!(
+            db.get::<serde_json::Value>("doc", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            serde_json::json!({"x": 2, "y": 2})
+        );
+        let matched = QueryBuilder::query(q("x", Equal, 2))
+            .execute(&db)
+            .unwrap()
+            .keys()
+            .unwrap();
+        assert_eq!(matched, vec!["doc"]);
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_apply_transaction_main_moved(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("base")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let t = db.new_transaction(None).unwrap();
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("main")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("transaction")),
+            OperationTarget::Transaction(&t),
+        )
+        .unwrap();
+        // the resolver runs after main was read, so another writer gets in right there
+        let result = db.apply_transaction_with(&t, |_| {
+            db.set(
+                "b",
+                SampleDbStruct::new(String::from("concurrent")),
+                OperationTarget::Main,
+            )
+            .unwrap();
+            Resolution::TakeTransaction
+        });
+        assert_eq!(result, Err(error::TransactionError::MainMoved));
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct::new(String::from("main"))
+        );
+        // the concurrent write survived and the transaction can be retried
+        db.apply_transaction_with(&t, |_| Resolution::TakeTransaction)
+            .unwrap();
+        for (key, expected) in [("a", "transaction"), ("b", "concurrent")] {
+            assert_eq!(
+                db.get::<SampleDbStruct>(key, OperationTarget::Main)
+                    .unwrap()
+                    .unwrap(),
+                SampleDbStruct::new(String::from(expected))
+            );
+        }
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_apply_transaction_with_invalid_resolution(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("base")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let t = db.new_transaction(None).unwrap();
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("main")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("transaction")),
+            OperationTarget::Transaction(&t),
+        )
+        .unwrap();
+        let result = db.apply_transaction_with(&t, |_| {
+            Resolution::Replace(b"\xff{ not a document".to_vec())
+        });
+        assert!(matches!(
+            result,
+            Err(error::TransactionError::InvalidResolution(_))
+        ));
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct::new(String::from("main"))
+        );
+        assert!(db
+            .apply_transaction_with(&t, |_| Resolution::TakeMain)
+            .is_ok());
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct::new(String::from("main"))
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_read_at_commit_and_branch(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("old")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let old_commit = db
+            .repository()
+            .head()
+            .unwrap()
+            .peel_to_commit()
+            .unwrap()
+            .id();
+        let branch = db.new_transaction(Some("analytics")).unwrap();
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("new")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Commit(old_commit))
+                .unwrap(),
+            Some(SampleDbStruct::new(String::from("old")))
+        );
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Branch(&branch))
+                .unwrap(),
+            Some(SampleDbStruct::new(String::from("old")))
+        );
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap(),
+            Some(SampleDbStruct::new(String::from("new")))
+        );
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Commit(Oid::zero())),
+            Err(error::GetObjectError::InvalidOperationTarget(
+                Oid::zero().to_string()
+            ))
+        );
+        let changes = db
+            .changes_since(old_commit, OperationTarget::Commit(old_commit))
+            .unwrap();
+        assert!(changes.is_empty());
+
+        assert_eq!(
+            db.set(
+                "a",
+                SampleDbStruct::new(String::from("rejected")),
+                OperationTarget::Commit(old_commit)
+            ),
+            Err(error::SetObjectError::ReadOnlyTarget)
+        );
+        assert_eq!(
+            db.delete("a", OperationTarget::Commit(old_commit)),
+            Err(error::SetObjectError::ReadOnlyTarget)
+        );
+        db.set(
+            "b",
+            SampleDbStruct::new(String::from("on branch")),
+            OperationTarget::Branch(&branch),
+        )
+        .unwrap();
+        assert!(db
+            .get::<SampleDbStruct>("b", OperationTarget::Main)
+            .unwrap()
+            .is_none());
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_log_pagination(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        for key in ["a", "pref/b", "c"] {
+            db.set(
+                key,
+                SampleDbStruct::new(String::from(key)),
+                OperationTarget::Main,
+            )
+            .unwrap();
+        }
+        let first_page = db
+            .log(LogOptions {
+                limit: 2,
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(first_page.len(), 2);
+        assert_eq!(
+            first_page[0].oid,
+            db.repository().head().unwrap().target().unwrap()
+        );
+        assert_eq!(first_page[0].author, "yamabiko");
+        assert_eq!(first_page[0].message, "set 1 items on main");
+        let keys: Vec<&str> = first_page
+            .iter()
+            .flat_map(|c| c.keys.iter().map(|k| k.key.as_str()))
+            .collect();
+        assert_eq!(keys, vec!["c", "pref/b"]);
+
+        let second_page = db
+            .log(LogOptions {
+                limit: 2,
+                before: Some(first_page[1].oid),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(second_page.len(), 2);
+        assert_eq!(
+            second_page[0].keys,
+            vec![KeyChange {
+                key: String::from("a"),
+                kind: ChangeKind::Added
+            }]
+        );
+        // the initial commit of the collection
+        assert!(second_page[1].keys.is_empty());
+        assert!(db
+            .log(LogOptions {
+                before: Some(second_page[1].oid),
+                ..Default::default()
+            })
+            .unwrap()
+            .is_empty());
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_log_applied_transaction(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let t = db.new_transaction(None).unwrap();
+        db.set_batch(
+            [
+                ("a", SampleDbStruct::new(String::from("a"))),
+                ("b", SampleDbStruct::new(String::from("b"))),
+            ],
+            OperationTarget::Transaction(&t),
+        )
+        .unwrap();
+        db.apply_transaction(&t, ConflictResolution::Abort).unwrap();
+        let log = db
+            .log(LogOptions {
+                limit: 1,
+                ..Default::default()
+            })
+            .unwrap();
+        let mut keys: Vec<&str> = log[0].keys.iter().map(|k| k.key.as_str()).collect();
+        keys.sort();
+        assert_eq!(keys, vec!["a", "b"]);
+        assert_eq!(
+            db.log(LogOptions {
+                branch: OperationTarget::Transaction(&t),
+                ..Default::default()
+            })
+            .unwrap_err(),
+            error::LogError::InvalidOperationTarget(t.clone())
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_versions(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let v1 = SampleDbStruct::new(String::from("v1"));
+        let v2 = SampleDbStruct::new(String::from("v2"));
+        let first = db.set("a", &v1, OperationTarget::Main).unwrap();
+        db.set("b", &v1, OperationTarget::Main).unwrap();
+        db.set("a", &v2, OperationTarget::Main).unwrap();
+        db.delete("a", OperationTarget::Main).unwrap();
+        let last = db.set("a", &v2, OperationTarget::Main).unwrap();
+
+        let versions = db.versions("a", 10, OperationTarget::Main).unwrap();
+        let versions: Vec<(Oid, SampleDbStruct)> = versions
+            .into_iter()
+            .map(|(oid, data)| (oid, data_format.deserialize(&data)))
+            .collect();
+        assert_eq!(versions, vec![(last, v2.clone()), (first, v1.clone())]);
+        let newest = db.versions("a", 1, OperationTarget::Main).unwrap();
+        assert_eq!(newest.len(), 1);
+        assert_eq!(newest[0].0, last);
+        assert!(db
+            .versions("a", 0, OperationTarget::Main)
+            .unwrap()
+            .is_empty());
+        assert!(db
+            .versions("missing", 10, OperationTarget::Main)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            db.versions("a", 10, OperationTarget::Branch("missing")),
+            Err(error::GetObjectError::InvalidOperationTarget(String::from(
+                "missing"
+            )))
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_get_with_meta(#[case] data_format: DataFormat) {
+        let td = tempfile::tempdir().unwrap();
+        let clock = std::sync::Arc::new(MockClock::new(1_700_000_000));
+        let db = Collection::initialize_with_clock(td.path(), data_format, clock.clone()).unwrap();
+        clock.advance(10);
+        let first = db
+            .set_batch(
+                [
+                    ("a", SampleDbStruct::new(String::from("a"))),
+                    ("b", SampleDbStruct::new(String::from("b"))),
+                ],
+                OperationTarget::Main,
+            )
+            .unwrap();
+        clock.advance(10);
+        db.set(
+            "b",
+            SampleDbStruct::new(String::from("new b")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        clock.advance(10);
+        // writing the same value again doesn't count as a change
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("a")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let (content, meta) = db
+            .get_with_meta("a", OperationTarget::Main)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            data_format.deserialize::<SampleDbStruct>(&content),
+            SampleDbStruct::new(String::from("a"))
+        );
+        assert_eq!(meta.commit, first);
+        assert_eq!(meta.last_modified.timestamp(), 1_700_000_010);
+        assert_eq!(meta.author, "yamabiko");
+
+        let t = db.new_transaction(None).unwrap();
+        clock.advance(10);
+        let in_transaction = db
+            .set(
+                "a",
+                SampleDbStruct::new(String::from("new a")),
+                OperationTarget::Transaction(&t),
+            )
+            .unwrap();
+        let (_, meta) = db
+            .get_with_meta("a", OperationTarget::Transaction(&t))
+            .unwrap()
+            .unwrap();
+        assert_eq!(meta.commit, in_transaction);
+        assert_eq!(meta.last_modified.timestamp(), 1_700_000_040);
+        let (_, meta) = db
+            .get_with_meta("a", OperationTarget::Commit(first))
+            .unwrap()
+            .unwrap();
+        assert_eq!(meta.commit, first);
+
+        assert_eq!(db.get_with_meta("c", OperationTarget::Main).unwrap(), None);
+        assert_eq!(
+            db.get_with_meta("a", OperationTarget::Branch("missing"))
+                .unwrap_err(),
+            error::GetObjectError::InvalidOperationTarget(String::from("missing"))
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_document_meta(#[case] data_format: DataFormat) {
+        let td = tempfile::tempdir().unwrap();
+        let clock = std::sync::Arc::new(MockClock::new(0));
+        let db = Collection::initialize_with_clock(td.path(), data_format, clock.clone()).unwrap();
+        let value = |v: &str| SampleDbStruct::new(String::from(v));
+        for (time, key) in [(10, "a"), (20, "b"), (30, "c")] {
+            clock.set(time);
+            db.set(key, value(key), OperationTarget::Main).unwrap();
+        }
+        clock.set(40);
+        db.set("a", value("new a"), OperationTarget::Main).unwrap();
+        // the same value again is not an update
+        clock.set(45);
+        db.set("b", value("b"), OperationTarget::Main).unwrap();
+        clock.set(50);
+        db.set_batch(
+            [("d", value("d")), ("e", value("e"))],
+            OperationTarget::Main,
+        )
+        .unwrap();
+
+        assert_eq!(
+            db.document_meta("a", OperationTarget::Main).unwrap(),
+            Some(DocumentMeta {
+                created_at: 10,
+                updated_at: 40,
+                revision: 2,
+                type_tag: None,
+                tombstone: None
+            })
+        );
+        assert_eq!(
+            db.document_meta("b", OperationTarget::Main).unwrap(),
+            Some(DocumentMeta {
+                created_at: 20,
+                updated_at: 20,
+                revision: 1,
+                type_tag: None,
+                tombstone: None
+            })
+        );
+        assert_eq!(
+            db.document_meta("d", OperationTarget::Main).unwrap(),
+            db.document_meta("e", OperationTarget::Main).unwrap()
+        );
+        assert_eq!(db.document_meta("x", OperationTarget::Main).unwrap(), None);
+        let (_, meta) = db
+            .get_with_meta("a", OperationTarget::Main)
+            .unwrap()
+            .unwrap();
+        assert_eq!(meta.document.unwrap().updated_at, 40);
+        assert_eq!(
+            QueryBuilder::all()
+                .order_by_updated()
+                .execute(&db)
+                .unwrap()
+                .keys()
+                .unwrap(),
+            vec!["d", "e", "a", "c", "b"]
+        );
+
+        // stamped when written to the transaction, not when it's rebased onto main
+        let t = db.new_transaction(None).unwrap();
+        clock.set(60);
+        db.set("b", value("b from t"), OperationTarget::Transaction(&t))
+            .unwrap();
+        db.set("same", value("same"), OperationTarget::Transaction(&t))
+            .unwrap();
+        clock.set(70);
+        db.set("c", value("new c"), OperationTarget::Main).unwrap();
+        db.set("same", value("same"), OperationTarget::Main)
+            .unwrap();
+        clock.set(80);
+        // the same value written on both sides is not a conflict
+        db.apply_transaction(&t, ConflictResolution::Abort).unwrap();
+        assert_eq!(
+            db.document_meta("b", OperationTarget::Main).unwrap(),
+            Some(DocumentMeta {
+                created_at: 20,
+                updated_at: 60,
+                revision: 2,
+                type_tag: None,
+                tombstone: None
+            })
+        );
+        assert_eq!(
+            db.document_meta("same", OperationTarget::Main)
+                .unwrap()
+                .unwrap()
+                .updated_at,
+            70
+        );
+        let query = QueryBuilder::all().order_by_updated().execute(&db).unwrap();
+        assert_eq!(query.keys().unwrap(), vec!["c", "same", "b", "d", "e", "a"]);
+        let page = query.page(2, Some("same")).unwrap();
+        assert_eq!(page.keys, vec!["b", "d"]);
+        assert_eq!(page.next, Some(String::from("d")));
+
+        // deleted along with the document
+        db.delete("a", OperationTarget::Main).unwrap();
+        clock.set(90);
+        db.set("a", value("a"), OperationTarget::Main).unwrap();
+        assert_eq!(
+            db.document_meta("a", OperationTarget::Main).unwrap(),
+            Some(DocumentMeta {
+                created_at: 90,
+                updated_at: 90,
+                revision: 1,
+                type_tag: None,
+                tombstone: None
+            })
+        );
+        assert_eq!(
+            db.set(".metadata", value("a"), OperationTarget::Main),
+            Err(error::SetObjectError::InvalidKey(
+                error::KeyError::Reserved(String::from(".metadata"))
+            ))
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_type_tags(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let sample = SampleDbStruct::new(String::from("a"));
+        db.set_typed("a", &sample, OperationTarget::Main).unwrap();
+        db.set_tagged(
+            "b",
+            InterigentDbStruct { num_val: 1 },
+            "interigent/v1",
+            OperationTarget::Main,
+        )
+        .unwrap();
+        db.set("c", &sample, OperationTarget::Main).unwrap();
+
+        assert_eq!(
+            db.type_of("a", OperationTarget::Main).unwrap(),
+            Some(String::from(std::any::type_name::<SampleDbStruct>()))
+        );
+        assert_eq!(
+            db.type_of("b", OperationTarget::Main).unwrap(),
+            Some(String::from("interigent/v1"))
+        );
+        assert_eq!(db.type_of("c", OperationTarget::Main).unwrap(), None);
+        assert_eq!(db.type_of("x", OperationTarget::Main).unwrap(), None);
+
+        assert_eq!(
+            db.get_as::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap(),
+            Some(sample.clone())
+        );
+        assert_eq!(
+            db.get_as::<InterigentDbStruct>("a", OperationTarget::Main),
+            Err(error::GetObjectError::TypeMismatch {
+                expected: String::from(std::any::type_name::<InterigentDbStruct>()),
+                found: String::from(std::any::type_name::<SampleDbStruct>()),
+            })
+        );
+        assert_eq!(
+            db.get_tagged::<InterigentDbStruct>("b", "interigent/v1", OperationTarget::Main)
+                .unwrap(),
+            Some(InterigentDbStruct { num_val: 1 })
+        );
+        assert!(matches!(
+            db.get_tagged::<InterigentDbStruct>("b", "interigent/v2", OperationTarget::Main),
+            Err(error::GetObjectError::TypeMismatch { .. })
+        ));
+        // untagged values are not checked
+        assert_eq!(
+            db.get_as::<SampleDbStruct>("c", OperationTarget::Main)
+                .unwrap(),
+            Some(sample.clone())
+        );
+        assert_eq!(
+            db.get_as::<SampleDbStruct>("x", OperationTarget::Main)
+                .unwrap(),
+            None
+        );
+
+        // tagging a value stored without a tag is a change, writing it untyped removes the tag
+        let tagged = db.set_typed("c", &sample, OperationTarget::Main).unwrap();
+        assert_eq!(db.repository().head().unwrap().target().unwrap(), tagged);
+        assert!(db.type_of("c", OperationTarget::Main).unwrap().is_some());
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("new")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        assert_eq!(db.type_of("a", OperationTarget::Main).unwrap(), None);
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_revert_n_commits(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("initial a value")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        db.set(
+            "b",
+            SampleDbStruct::new(String::from("initial b value")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        db.set(
+            "b",
+            SampleDbStruct::new(String::from("changed b value")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        assert_eq!(
+            db.get::<SampleDbStruct>("b", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct {
+                str_val: String::from("changed b value")
+            }
+        );
+        db.revert_n_commits(1, OperationTarget::Main, false)
+            .unwrap();
+        assert_eq!(
+            db.get::<SampleDbStruct>("b", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct {
+                str_val: String::from("initial b value")
+            }
+        );
     }
 
-    pub fn construct_oid_from_path(path: &str) -> Oid {
-        Oid::from_str(&path[path.len() - 22..].replace("/", "")).unwrap()
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_revert_to_commit(#[case] data_format: DataFormat) {
+        let (db, td) = create_db(data_format);
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("initial a value")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("change #1")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("change #2")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct {
+                str_val: String::from("change #2")
+            }
+        );
+        let repo = Repository::open(td.path()).unwrap();
+        let reference = repo
+            .find_branch("main", BranchType::Local)
+            .unwrap()
+            .into_reference();
+        let head_commit = reference.peel_to_commit().unwrap();
+        let first_commit = head_commit.parent(0).unwrap().parent(0).unwrap().clone();
+        db.revert_main_to_commit(first_commit.id(), false).unwrap();
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct {
+                str_val: String::from("initial a value")
+            }
+        );
     }
-}
 
-pub mod test;
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_simple_transaction(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("a val")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let t = db.new_transaction(None).unwrap();
+        db.set(
+            "b",
+            SampleDbStruct::new(String::from("b val")),
+            OperationTarget::Transaction(&t),
+        )
+        .unwrap();
+        assert_eq!(
+            db.get::<SampleDbStruct>("b", OperationTarget::Main)
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            db.get::<SampleDbStruct>("b", OperationTarget::Transaction(&t))
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct {
+                str_val: String::from("b val")
+            }
+        );
+        db.apply_transaction(&t, crate::ConflictResolution::Overwrite)
+            .unwrap();
+        assert_eq!(
+            db.get::<SampleDbStruct>("b", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct {
+                str_val: String::from("b val")
+            }
+        );
+    }
 
-#[cfg(test)]
-mod tests {
-    use std::cmp::Ordering::*;
-    use std::collections::HashMap;
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_transaction_overwrite(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("INIT\nline2")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let t = db.new_transaction(None).unwrap();
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("TRAN\nline2")),
+            OperationTarget::Transaction(&t),
+        )
+        .unwrap();
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("MAIN\nline2")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct {
+                str_val: String::from("MAIN\nline2")
+            }
+        );
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Transaction(&t))
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct {
+                str_val: String::from("TRAN\nline2")
+            }
+        );
+        db.apply_transaction(&t, crate::ConflictResolution::Overwrite)
+            .unwrap();
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct {
+                str_val: String::from("TRAN\nline2")
+            }
+        );
+    }
 
-    use git2::{BranchType, Repository};
-    use rstest::rstest;
+    #[test]
+    fn test_transaction_merge_driver() {
+        let (db, _td) = create_db(DataFormat::Json);
+        let db = db.with_merge_driver(std::sync::Arc::new(crate::merge::JsonFieldMerge));
+        db.set(
+            "doc",
+            serde_json::json!({"a": 1, "b": 1, "c": 1}),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let first = db.new_transaction(None).unwrap();
+        let second = db.new_transaction(None).unwrap();
+        let conflicting = db.new_transaction(None).unwrap();
+        db.set(
+            "doc",
+            serde_json::json!({"a": 2, "b": 1, "c": 1}),
+            OperationTarget::Transaction(&first),
+        )
+        .unwrap();
+        db.set(
+            "doc",
+            serde_json::json!({"a": 1, "b": 2}),
+            OperationTarget::Transaction(&second),
+        )
+        .unwrap();
+        db.set(
+            "doc",
+            serde_json::json!({"a": 3, "b": 1, "c": 1}),
+            OperationTarget::Transaction(&conflicting),
+        )
+        .unwrap();
+        db.apply_transaction(&first, ConflictResolution::Custom)
+            .unwrap();
+        db.apply_transaction(&second, ConflictResolution::Custom)
+            .unwrap();
+        assert_eq!(
+            db.get::<serde_json::Value>("doc", OperationTarget::Main)
+                .unwrap(),
+            Some(serde_json::json!({"a": 2, "b": 2}))
+        );
 
-    use crate::{
-        error,
-        index::{Index, IndexType},
-        query::{q, QueryBuilder},
-        serialization::DataFormat,
-        OperationTarget,
-    };
+        let head = db.repository().head().unwrap().target().unwrap();
+        assert_eq!(
+            db.apply_transaction(&conflicting, ConflictResolution::Custom),
+            Err(error::TransactionError::MergeConflict {
+                key: String::from("doc")
+            })
+        );
+        assert_eq!(db.repository().head().unwrap().target().unwrap(), head);
 
-    use super::test::*;
+        let (without_driver, _td) = create_db(DataFormat::Json);
+        let t = without_driver.new_transaction(None).unwrap();
+        assert_eq!(
+            without_driver.apply_transaction(&t, ConflictResolution::Custom),
+            Err(error::TransactionError::NoMergeDriver)
+        );
+    }
 
     #[rstest]
     #[case(DataFormat::Json)]
     #[case(DataFormat::Yaml)]
     #[case(DataFormat::Pot)]
-    fn set_and_get(#[case] data_format: DataFormat) {
+    fn test_transaction_conflict_callback(#[case] data_format: DataFormat) {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let clock = Arc::new(MockClock::new(1_000));
+        let db =
+            Collection::initialize_with_clock(tmpdir.path(), data_format, clock.clone()).unwrap();
+        let value = |s: &str| SampleDbStruct::new(String::from(s));
+        db.set_batch(
+            [("a", value("base")), ("b", value("base"))],
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let t = db.new_transaction(None).unwrap();
+        clock.set(1_100);
+        db.set("a", value("tran"), OperationTarget::Transaction(&t))
+            .unwrap();
+        clock.set(1_200);
+        db.set_batch(
+            [("a", value("main")), ("b", value("main"))],
+            OperationTarget::Main,
+        )
+        .unwrap();
+        clock.set(1_300);
+        db.set("b", value("tran"), OperationTarget::Transaction(&t))
+            .unwrap();
+        clock.set(1_400);
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_by_callback = seen.clone();
+        let newest_wins = ConflictResolution::Callback(Box::new(move |info: &ConflictInfo| {
+            seen_by_callback.lock().unwrap().push((
+                info.key.clone(),
+                info.base.is_some(),
+                info.ours_time,
+                info.theirs_time,
+            ));
+            if info.theirs_time > info.ours_time {
+                Resolution::TakeTransaction
+            } else {
+                Resolution::TakeMain
+            }
+        }));
+        db.apply_transaction(&t, newest_wins).unwrap();
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                (String::from("a"), true, 1_200, 1_100),
+                // the first commit only changed "a", which was resolved to the value of main
+                (String::from("b"), true, 1_200, 1_300)
+            ]
+        );
+        for (key, expected) in [("a", "main"), ("b", "tran")] {
+            assert_eq!(
+                db.get::<SampleDbStruct>(key, OperationTarget::Main)
+                    .unwrap(),
+                Some(value(expected))
+            );
+        }
+        let meta = db
+            .document_meta("b", OperationTarget::Main)
+            .unwrap()
+            .unwrap();
+        assert_eq!(meta.updated_at, 1_300);
+
+        let t = db.new_transaction(None).unwrap();
+        db.set("a", value("tran"), OperationTarget::Transaction(&t))
+            .unwrap();
+        db.set("a", value("other"), OperationTarget::Main).unwrap();
+        let replace = ConflictResolution::Callback(Box::new(|_: &ConflictInfo| {
+            Resolution::Replace(b"{".to_vec())
+        }));
+        let head = db.repository().head().unwrap().target().unwrap();
+        assert!(matches!(
+            db.apply_transaction(&t, replace),
+            Err(error::TransactionError::InvalidResolution(_))
+        ));
+        assert_eq!(db.repository().head().unwrap().target().unwrap(), head);
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_transaction_discard(#[case] data_format: DataFormat) {
         let (db, _td) = create_db(data_format);
         db.set(
-            "key",
-            SampleDbStruct {
-                str_val: String::from("value"),
-            },
+            "a",
+            SampleDbStruct::new(String::from("INIT\nline2")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let t = db.new_transaction(None).unwrap();
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("TRAN\nline2")),
+            OperationTarget::Transaction(&t),
+        )
+        .unwrap();
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("MAIN\nline2")),
             OperationTarget::Main,
         )
         .unwrap();
         assert_eq!(
-            db.get::<SampleDbStruct>("key", OperationTarget::Main)
+            db.get::<SampleDbStruct>("a", OperationTarget::Main)
                 .unwrap()
                 .unwrap(),
             SampleDbStruct {
-                str_val: String::from("value")
+                str_val: String::from("MAIN\nline2")
+            }
+        );
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Transaction(&t))
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct {
+                str_val: String::from("TRAN\nline2")
+            }
+        );
+        db.apply_transaction(&t, crate::ConflictResolution::DiscardChanges)
+            .unwrap();
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct {
+                str_val: String::from("MAIN\nline2")
             }
         );
     }
@@ -711,70 +6903,116 @@ mod tests {
     #[case(DataFormat::Json)]
     #[case(DataFormat::Yaml)]
     #[case(DataFormat::Pot)]
-    fn batch_set_and_get(#[case] data_format: DataFormat) {
+    fn test_transaction_abort(#[case] data_format: DataFormat) {
         let (db, _td) = create_db(data_format);
-        let mut hm = HashMap::new();
-        hm.insert(
-            "pref/a",
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("INIT\nline2")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let t = db.new_transaction(None).unwrap();
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("TRAN\nline2")),
+            OperationTarget::Transaction(&t),
+        )
+        .unwrap();
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("MAIN\nline2")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
             SampleDbStruct {
-                str_val: String::from("initial a value"),
-            },
+                str_val: String::from("MAIN\nline2")
+            }
         );
-        hm.insert(
-            "pref/b",
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Transaction(&t))
+                .unwrap()
+                .unwrap(),
             SampleDbStruct {
-                str_val: String::from("initial b value"),
-            },
+                str_val: String::from("TRAN\nline2")
+            }
         );
-        hm.insert(
-            "pref/c",
-            SampleDbStruct {
-                str_val: String::from("initial c value"),
-            },
+        assert_eq!(
+            db.apply_transaction(&t, crate::ConflictResolution::Abort)
+                .unwrap_err(),
+            error::TransactionError::Aborted
         );
-        let mut hm2 = hm.clone();
-        db.set_batch(hm, OperationTarget::Main).unwrap();
         assert_eq!(
-            db.get::<SampleDbStruct>("pref/a", OperationTarget::Main)
+            db.get::<SampleDbStruct>("a", OperationTarget::Main)
                 .unwrap()
                 .unwrap(),
-            SampleDbStruct::new(String::from("initial a value"))
+            SampleDbStruct {
+                str_val: String::from("MAIN\nline2")
+            }
         );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_merge_between_transactions(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let source = db.new_transaction(Some("source")).unwrap();
+        let target = db.new_transaction(Some("target")).unwrap();
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("a val")),
+            OperationTarget::Transaction(&source),
+        )
+        .unwrap();
+        db.set(
+            "b",
+            SampleDbStruct::new(String::from("b val")),
+            OperationTarget::Transaction(&target),
+        )
+        .unwrap();
+        let outcome = db
+            .merge(&source, &target, crate::ConflictResolution::Overwrite)
+            .unwrap();
+        assert_eq!(outcome.commits_applied, 1);
         assert_eq!(
-            db.get::<SampleDbStruct>("pref/b", OperationTarget::Main)
+            db.get::<SampleDbStruct>("a", OperationTarget::Transaction(&target))
                 .unwrap()
                 .unwrap(),
-            SampleDbStruct::new(String::from("initial b value"))
+            SampleDbStruct::new(String::from("a val"))
         );
         assert_eq!(
-            db.get::<SampleDbStruct>("pref/c", OperationTarget::Main)
+            db.get::<SampleDbStruct>("b", OperationTarget::Transaction(&target))
                 .unwrap()
                 .unwrap(),
-            SampleDbStruct::new(String::from("initial c value"))
-        );
-        hm2.insert(
-            "pref/a",
-            SampleDbStruct::new(String::from("changed a value")),
+            SampleDbStruct::new(String::from("b val"))
         );
-        db.set_batch(hm2, OperationTarget::Main).unwrap();
         assert_eq!(
-            db.get::<SampleDbStruct>("pref/a", OperationTarget::Main)
-                .unwrap()
+            db.get::<SampleDbStruct>("a", OperationTarget::Main)
                 .unwrap(),
-            SampleDbStruct::new(String::from("changed a value"))
+            None
         );
+        assert!(db
+            .repository()
+            .find_branch(&source, BranchType::Local)
+            .is_ok());
     }
 
     #[rstest]
     #[case(DataFormat::Json)]
     #[case(DataFormat::Yaml)]
     #[case(DataFormat::Pot)]
-    fn test_get_non_existent_value(#[case] data_format: DataFormat) {
+    fn test_merge_into_non_existing_branch(#[case] data_format: DataFormat) {
         let (db, _td) = create_db(data_format);
+        let source = db.new_transaction(None).unwrap();
         assert_eq!(
-            db.get::<SampleDbStruct>("key", OperationTarget::Main)
-                .unwrap(),
-            None
+            db.merge(&source, "nope", crate::ConflictResolution::Abort)
+                .unwrap_err(),
+            error::TransactionError::InvalidOperationTarget(String::from("nope"))
         );
     }
 
@@ -782,190 +7020,328 @@ mod tests {
     #[case(DataFormat::Json)]
     #[case(DataFormat::Yaml)]
     #[case(DataFormat::Pot)]
-    fn test_revert_n_commits(#[case] data_format: DataFormat) {
+    fn test_adding_index(#[case] data_format: DataFormat) {
         let (db, _td) = create_db(data_format);
+        db.add_index("str_val", IndexType::Sequential);
+        db.add_index("str_val", IndexType::Sequential);
         db.set(
             "a",
-            SampleDbStruct::new(String::from("initial a value")),
+            SampleDbStruct::new(String::from("test value")),
             OperationTarget::Main,
         )
         .unwrap();
+        let index_list = db.index_list();
+        assert_eq!(index_list.len(), 1);
+        assert_eq!(
+            index_list[0],
+            Index::new("str_val#sequential.index", "str_val", IndexType::Sequential)
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_list_indexes(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        assert!(db.list_indexes().is_empty());
+        db.add_index("str_val", IndexType::Sequential);
+        db.add_index("num_val", IndexType::Numeric);
+        let index_dir = db.repository().path().join(".index");
+        for junk in [
+            "no_dot",
+            "no_hash.index",
+            "field#unknown.index",
+            "notes.txt",
+        ] {
+            std::fs::write(index_dir.join(junk), b"junk").unwrap();
+        }
+        assert_eq!(
+            db.list_indexes(),
+            vec![
+                Index::new("num_val#numeric.index", "num_val", IndexType::Numeric),
+                Index::new("str_val#sequential.index", "str_val", IndexType::Sequential),
+            ]
+        );
+        assert!(db.contains_index("num_val#numeric.index"));
+        assert!(!db.contains_index("num_val#sequential.index"));
+        assert!(!db.contains_index("no_hash.index"));
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_indexes_maintained_after_load(#[case] data_format: DataFormat) {
+        let (db, td) = create_db(data_format);
+        let registered = db.add_index("num_val", IndexType::Numeric);
+        // an index with a file, but not registered on main
+        let unregistered = Index::new("str_val#sequential.index", "str_val", IndexType::Sequential);
+        unregistered
+            .create_entry(
+                db.repository(),
+                Oid::zero(),
+                &Field::String(String::from("old")),
+            )
+            .unwrap();
+        std::fs::write(
+            db.repository().path().join(".index").join("junk.index"),
+            b"junk",
+        )
+        .unwrap();
+        drop(db);
+
+        let db = Collection::builder()
+            .data_format(data_format)
+            .load(td.path())
+            .unwrap();
+        assert_eq!(
+            db.index_list(),
+            vec![registered.clone(), unregistered.clone()]
+        );
         db.set(
-            "b",
-            SampleDbStruct::new(String::from("initial b value")),
+            "a",
+            InterigentDbStruct { num_val: 3 },
             OperationTarget::Main,
         )
         .unwrap();
         db.set(
             "b",
-            SampleDbStruct::new(String::from("changed b value")),
+            SampleDbStruct::new(String::from("b")),
             OperationTarget::Main,
         )
         .unwrap();
-        assert_eq!(
-            db.get::<SampleDbStruct>("b", OperationTarget::Main)
-                .unwrap()
-                .unwrap(),
-            SampleDbStruct {
-                str_val: String::from("changed b value")
-            }
-        );
-        db.revert_n_commits(1, OperationTarget::Main, false)
+        assert_eq!(registered.git_index(db.repository()).len(), 1);
+        assert_eq!(unregistered.git_index(db.repository()).len(), 2);
+        let result = QueryBuilder::query(q("str_val", Equal, "b"))
+            .execute(&db)
             .unwrap();
-        assert_eq!(
-            db.get::<SampleDbStruct>("b", OperationTarget::Main)
-                .unwrap()
-                .unwrap(),
-            SampleDbStruct {
-                str_val: String::from("initial b value")
-            }
-        );
+        assert_eq!(result.count, 1);
     }
 
     #[rstest]
     #[case(DataFormat::Json)]
     #[case(DataFormat::Yaml)]
     #[case(DataFormat::Pot)]
-    fn test_revert_to_commit(#[case] data_format: DataFormat) {
-        let (db, td) = create_db(data_format);
+    fn test_index_content(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.add_index("str_val", IndexType::Sequential);
         db.set(
             "a",
-            SampleDbStruct::new(String::from("initial a value")),
+            SampleDbStruct::new(String::from("1val")),
             OperationTarget::Main,
         )
         .unwrap();
         db.set(
-            "a",
-            SampleDbStruct::new(String::from("change #1")),
+            "b",
+            SampleDbStruct::new(String::from("1val")),
             OperationTarget::Main,
         )
         .unwrap();
         db.set(
-            "a",
-            SampleDbStruct::new(String::from("change #2")),
+            "c",
+            SampleDbStruct::new(String::from("2val")),
             OperationTarget::Main,
         )
         .unwrap();
+        let index_values: Vec<git2::IndexEntry> = db.index_list()[0]
+            .git_index(&db.repository)
+            .iter()
+            .collect();
+        assert_eq!(index_values.len(), 3);
+        assert_eq!(index_values[0].path, "1val/fffffffffffffffe".as_bytes());
+        assert_eq!(index_values[1].path, "1val/ffffffffffffffff".as_bytes());
+        assert_eq!(index_values[2].path, "2val/ffffffffffffffff".as_bytes());
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_index_scan(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let index = db.add_index("num_val", IndexType::Numeric);
+        for (key, num_val) in [("a", 20), ("b", 10), ("c", 20), ("d", 30), ("e", 20)] {
+            db.set(key, InterigentDbStruct { num_val }, OperationTarget::Main)
+                .unwrap();
+        }
+        let hash = |key: &str| Oid::hash_object(ObjectType::Blob, key.as_bytes()).unwrap();
+        let ascending: Vec<(Field, Oid)> = index.scan(&db.repository, Order::Ascending).collect();
         assert_eq!(
-            db.get::<SampleDbStruct>("a", OperationTarget::Main)
-                .unwrap()
-                .unwrap(),
-            SampleDbStruct {
-                str_val: String::from("change #2")
-            }
+            ascending,
+            vec![
+                (Field::Int(10), hash("b")),
+                (Field::Int(20), hash("a")),
+                (Field::Int(20), hash("c")),
+                (Field::Int(20), hash("e")),
+                (Field::Int(30), hash("d")),
+            ]
         );
-        let repo = Repository::open(td.path()).unwrap();
-        let reference = repo
-            .find_branch("main", BranchType::Local)
-            .unwrap()
-            .into_reference();
-        let head_commit = reference.peel_to_commit().unwrap();
-        let first_commit = head_commit.parent(0).unwrap().parent(0).unwrap().clone();
-        db.revert_main_to_commit(first_commit.id(), false).unwrap();
+        let descending: Vec<(Field, Oid)> = index.scan(&db.repository, Order::Descending).collect();
         assert_eq!(
-            db.get::<SampleDbStruct>("a", OperationTarget::Main)
-                .unwrap()
-                .unwrap(),
-            SampleDbStruct {
-                str_val: String::from("initial a value")
+            descending,
+            ascending.into_iter().rev().collect::<Vec<(Field, Oid)>>()
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_query_min_max(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let index = db.add_index("num_val", IndexType::Numeric);
+        assert_eq!(db.query_min(index.name()), None);
+        for (key, num_val) in [("a", 10), ("b", 30), ("c", 20), ("d", 10), ("e", 30)] {
+            db.set(key, InterigentDbStruct { num_val }, OperationTarget::Main)
+                .unwrap();
+        }
+        let hash = |key: &str| Oid::hash_object(ObjectType::Blob, key.as_bytes()).unwrap();
+        // ties go to the oldest entry for the minimum and the most recent one for the maximum
+        assert_eq!(
+            db.query_min(index.name()),
+            Some((Field::Int(10), hash("a")))
+        );
+        assert_eq!(
+            db.query_max(index.name()),
+            Some((Field::Int(30), hash("e")))
+        );
+        assert_eq!(index.min(&db.repository), db.query_min(index.name()));
+        assert_eq!(index.max(&db.repository), db.query_max(index.name()));
+        assert_eq!(db.query_max("missing#numeric.index"), None);
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_index_pagination(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let index = db.add_index("num_val", IndexType::Numeric);
+        for (i, num_val) in [3, 1, 2, 1, 3, 3, 2].into_iter().enumerate() {
+            db.set(
+                &format!("key-{}", i),
+                InterigentDbStruct { num_val },
+                OperationTarget::Main,
+            )
+            .unwrap();
+        }
+        for order in [Order::Ascending, Order::Descending] {
+            let all: Vec<(Field, Oid)> = index.scan(&db.repository, order).collect();
+            let mut paged = Vec::new();
+            let mut cursor = None;
+            loop {
+                let page = index.page(&db.repository, order, cursor.as_ref(), 2);
+                assert!(page.entries.len() <= 2);
+                paged.extend(page.entries);
+                match page.next {
+                    Some(next) => {
+                        cursor = Some(IndexCursor::from_token(&next.to_token()).unwrap());
+                    }
+                    None => break,
+                }
             }
-        );
+            assert_eq!(paged, all);
+        }
     }
 
     #[rstest]
     #[case(DataFormat::Json)]
     #[case(DataFormat::Yaml)]
     #[case(DataFormat::Pot)]
-    fn test_simple_transaction(#[case] data_format: DataFormat) {
+    fn test_index_pagination_concurrent_writes(#[case] data_format: DataFormat) {
         let (db, _td) = create_db(data_format);
+        let index = db.add_index("num_val", IndexType::Numeric);
+        for (key, num_val) in [("a", 1), ("b", 2), ("c", 3)] {
+            db.set(key, InterigentDbStruct { num_val }, OperationTarget::Main)
+                .unwrap();
+        }
+        let first = index.page(&db.repository, Order::Ascending, None, 2);
+        assert_eq!(
+            first.entries.iter().map(|e| &e.0).collect::<Vec<&Field>>(),
+            vec![&Field::Int(1), &Field::Int(2)]
+        );
+        // the cursor survives its own entry being removed
+        db.delete("b", OperationTarget::Main).unwrap();
         db.set(
-            "a",
-            SampleDbStruct::new(String::from("a val")),
+            "d",
+            InterigentDbStruct { num_val: 0 },
             OperationTarget::Main,
         )
         .unwrap();
-        let t = db.new_transaction(None).unwrap();
         db.set(
-            "b",
-            SampleDbStruct::new(String::from("b val")),
-            OperationTarget::Transaction(&t),
+            "e",
+            InterigentDbStruct { num_val: 4 },
+            OperationTarget::Main,
         )
         .unwrap();
+        let second = index.page(&db.repository, Order::Ascending, first.next.as_ref(), 2);
         assert_eq!(
-            db.get::<SampleDbStruct>("b", OperationTarget::Main)
-                .unwrap(),
-            None
-        );
-        assert_eq!(
-            db.get::<SampleDbStruct>("b", OperationTarget::Transaction(&t))
-                .unwrap()
-                .unwrap(),
-            SampleDbStruct {
-                str_val: String::from("b val")
-            }
-        );
-        db.apply_transaction(&t, crate::ConflictResolution::Overwrite)
-            .unwrap();
-        assert_eq!(
-            db.get::<SampleDbStruct>("b", OperationTarget::Main)
-                .unwrap()
-                .unwrap(),
-            SampleDbStruct {
-                str_val: String::from("b val")
-            }
+            second.entries.iter().map(|e| &e.0).collect::<Vec<&Field>>(),
+            vec![&Field::Int(3), &Field::Int(4)]
         );
+        assert_eq!(second.next, None);
+        assert_eq!(IndexCursor::from_token("abc"), None);
+        assert_eq!(IndexCursor::from_token("zz"), None);
     }
 
     #[rstest]
     #[case(DataFormat::Json)]
     #[case(DataFormat::Yaml)]
     #[case(DataFormat::Pot)]
-    fn test_transaction_overwrite(#[case] data_format: DataFormat) {
+    fn test_index_content_numeric(#[case] data_format: DataFormat) {
         let (db, _td) = create_db(data_format);
+        db.add_index("num_val", IndexType::Numeric);
         db.set(
-            "a",
-            SampleDbStruct::new(String::from("INIT\nline2")),
+            "b",
+            InterigentDbStruct { num_val: 20 },
             OperationTarget::Main,
         )
         .unwrap();
-        let t = db.new_transaction(None).unwrap();
         db.set(
-            "a",
-            SampleDbStruct::new(String::from("TRAN\nline2")),
-            OperationTarget::Transaction(&t),
+            "c",
+            InterigentDbStruct { num_val: 2 },
+            OperationTarget::Main,
         )
         .unwrap();
         db.set(
-            "a",
-            SampleDbStruct::new(String::from("MAIN\nline2")),
+            "d",
+            InterigentDbStruct { num_val: 3 },
+            OperationTarget::Main,
+        )
+        .unwrap();
+        db.set(
+            "e",
+            FloatyDbStruct { num_val: -11.0 },
             OperationTarget::Main,
         )
         .unwrap();
+        db.set("a", FloatyDbStruct { num_val: 2.11 }, OperationTarget::Main)
+            .unwrap();
+        let index_values: Vec<git2::IndexEntry> = db.index_list()[0]
+            .git_index(&db.repository)
+            .iter()
+            .collect();
+        assert_eq!(index_values.len(), 5);
         assert_eq!(
-            db.get::<SampleDbStruct>("a", OperationTarget::Main)
-                .unwrap()
-                .unwrap(),
-            SampleDbStruct {
-                str_val: String::from("MAIN\nline2")
-            }
+            String::from_utf8(index_values[0].path.clone()).unwrap(),
+            format!("{:016x}/ffffffffffffffff", !(-11.0_f64).to_bits())
         );
         assert_eq!(
-            db.get::<SampleDbStruct>("a", OperationTarget::Transaction(&t))
-                .unwrap()
-                .unwrap(),
-            SampleDbStruct {
-                str_val: String::from("TRAN\nline2")
-            }
+            String::from_utf8(index_values[1].path.clone()).unwrap(),
+            format!("{:016x}/ffffffffffffffff", 2.0_f64.to_bits() | 1 << 63)
         );
-        db.apply_transaction(&t, crate::ConflictResolution::Overwrite)
-            .unwrap();
         assert_eq!(
-            db.get::<SampleDbStruct>("a", OperationTarget::Main)
-                .unwrap()
-                .unwrap(),
-            SampleDbStruct {
-                str_val: String::from("TRAN\nline2")
-            }
+            String::from_utf8(index_values[2].path.clone()).unwrap(),
+            format!("{:016x}/ffffffffffffffff", 2.11_f64.to_bits() | 1 << 63)
+        );
+        assert_eq!(
+            String::from_utf8(index_values[3].path.clone()).unwrap(),
+            format!("{:016x}/ffffffffffffffff", 3.0_f64.to_bits() | 1 << 63)
+        );
+        assert_eq!(
+            String::from_utf8(index_values[4].path.clone()).unwrap(),
+            format!("{:016x}/ffffffffffffffff", 20.0_f64.to_bits() | 1 << 63)
         );
     }
 
@@ -973,108 +7349,317 @@ mod tests {
     #[case(DataFormat::Json)]
     #[case(DataFormat::Yaml)]
     #[case(DataFormat::Pot)]
-    fn test_transaction_discard(#[case] data_format: DataFormat) {
+    fn test_writing_to_correct_index(#[case] data_format: DataFormat) {
         let (db, _td) = create_db(data_format);
+        db.add_index("str_val", IndexType::Numeric);
         db.set(
             "a",
-            SampleDbStruct::new(String::from("INIT\nline2")),
+            SampleDbStruct::new(String::from("test")),
             OperationTarget::Main,
         )
         .unwrap();
-        let t = db.new_transaction(None).unwrap();
+        let index_values: Vec<git2::IndexEntry> = db.index_list()[0]
+            .git_index(&db.repository)
+            .iter()
+            .collect();
+        assert_eq!(index_values.len(), 0);
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_index_population(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
         db.set(
             "a",
-            SampleDbStruct::new(String::from("TRAN\nline2")),
-            OperationTarget::Transaction(&t),
+            SampleDbStruct::new(String::from("test")),
+            OperationTarget::Main,
         )
         .unwrap();
+        db.add_index("str_val", IndexType::Sequential);
+        let index_values: Vec<git2::IndexEntry> = db.index_list()[0]
+            .git_index(&db.repository)
+            .iter()
+            .collect();
+        assert_eq!(index_values.len(), 1);
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_index_removes_entries_on_update(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.add_index("str_val", IndexType::Sequential);
+        let query = QueryBuilder::query(q("str_val", Equal, "test"));
         db.set(
             "a",
-            SampleDbStruct::new(String::from("MAIN\nline2")),
+            SampleDbStruct::new(String::from("test")),
             OperationTarget::Main,
         )
         .unwrap();
-        assert_eq!(
-            db.get::<SampleDbStruct>("a", OperationTarget::Main)
-                .unwrap()
-                .unwrap(),
-            SampleDbStruct {
-                str_val: String::from("MAIN\nline2")
-            }
-        );
-        assert_eq!(
-            db.get::<SampleDbStruct>("a", OperationTarget::Transaction(&t))
-                .unwrap()
-                .unwrap(),
-            SampleDbStruct {
-                str_val: String::from("TRAN\nline2")
-            }
-        );
-        db.apply_transaction(&t, crate::ConflictResolution::DiscardChanges)
+        assert_eq!(query.execute(&db).unwrap().count, 1);
+        db.set("a", FloatyDbStruct { num_val: 69.0 }, OperationTarget::Main)
             .unwrap();
-        assert_eq!(
-            db.get::<SampleDbStruct>("a", OperationTarget::Main)
-                .unwrap()
-                .unwrap(),
-            SampleDbStruct {
-                str_val: String::from("MAIN\nline2")
-            }
-        );
+        assert_eq!(query.execute(&db).unwrap().count, 0);
     }
 
     #[rstest]
     #[case(DataFormat::Json)]
     #[case(DataFormat::Yaml)]
     #[case(DataFormat::Pot)]
-    fn test_transaction_abort(#[case] data_format: DataFormat) {
+    fn test_index_entry_update(#[case] data_format: DataFormat) {
         let (db, _td) = create_db(data_format);
+        db.add_index("str_val", IndexType::Sequential);
+        let query = QueryBuilder::query(q("str_val", Equal, "test"));
         db.set(
             "a",
-            SampleDbStruct::new(String::from("INIT\nline2")),
+            SampleDbStruct::new(String::from("test")),
             OperationTarget::Main,
         )
         .unwrap();
-        let t = db.new_transaction(None).unwrap();
+        assert_eq!(query.execute(&db).unwrap().count, 1);
         db.set(
             "a",
-            SampleDbStruct::new(String::from("TRAN\nline2")),
-            OperationTarget::Transaction(&t),
+            SampleDbStruct::new(String::from("test2")),
+            OperationTarget::Main,
         )
         .unwrap();
+        assert_eq!(query.execute(&db).unwrap().count, 0);
+        let updated_query = QueryBuilder::query(q("str_val", Equal, "test2"));
+        assert_eq!(updated_query.execute(&db).unwrap().count, 1);
+    }
+
+    #[test]
+    fn test_key_path_layout() {
+        // the first two bytes of `printf a | git hash-object --stdin` (2e65...),
+        // external tools rely on the layout
+        assert_eq!(Collection::construct_path_to_key("a").unwrap(), "2e/65/a");
+        // the bytes aren't zero padded: `j` hashes to 0fe2...
+        assert_eq!(Collection::construct_path_to_key("j").unwrap(), "f/e2/j");
+        assert_eq!(
+            Collection::construct_path_to_key("nested/a").unwrap(),
+            "nested/a"
+        );
+    }
+
+    #[test]
+    fn test_storage_error_classification() {
+        let missing = git2::Error::new(
+            git2::ErrorCode::NotFound,
+            git2::ErrorClass::Repository,
+            "could not find repository at '/nowhere'",
+        );
+        let full = git2::Error::new(
+            git2::ErrorCode::GenericError,
+            git2::ErrorClass::Os,
+            "failed to write object: No space left on device",
+        );
+        let denied = git2::Error::new(
+            git2::ErrorCode::GenericError,
+            git2::ErrorClass::Os,
+            "failed to make directory: Permission denied",
+        );
+        let other = git2::Error::from_str("something else");
+        assert_eq!(
+            error::StorageError::from_git(&missing),
+            Some(error::StorageError::RepositoryMissing)
+        );
+        assert!(matches!(
+            error::StorageError::from_git(&full),
+            Some(error::StorageError::OutOfSpace(_))
+        ));
+        assert!(matches!(
+            error::SetObjectError::from(denied),
+            error::SetObjectError::Storage(error::StorageError::PermissionDenied(_))
+        ));
+        assert_eq!(error::StorageError::from_git(&other), None);
+
+        let io = |kind| error::StorageError::from_io(&std::io::Error::from(kind));
+        assert!(matches!(
+            io(std::io::ErrorKind::PermissionDenied),
+            error::StorageError::PermissionDenied(_)
+        ));
+        assert!(matches!(
+            io(std::io::ErrorKind::ReadOnlyFilesystem),
+            error::StorageError::PermissionDenied(_)
+        ));
+        assert!(matches!(
+            io(std::io::ErrorKind::Other),
+            error::StorageError::Unavailable(_)
+        ));
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_repository_missing(#[case] data_format: DataFormat) {
+        let (db, td) = create_db(data_format);
+        assert_eq!(db.ping(), Ok(()));
+        std::fs::remove_dir_all(td.path()).unwrap();
+        assert_eq!(db.ping(), Err(error::StorageError::RepositoryMissing));
+        assert_eq!(
+            db.set(
+                "a",
+                SampleDbStruct::new(String::from("a")),
+                OperationTarget::Main
+            ),
+            Err(error::SetObjectError::Storage(
+                error::StorageError::RepositoryMissing
+            ))
+        );
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Main),
+            Err(error::GetObjectError::Storage(
+                error::StorageError::RepositoryMissing
+            ))
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read_only_objects() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (db, _td) = create_db(DataFormat::Json);
+        let objects = db.repository().path().join("objects");
+        let read_only = std::fs::Permissions::from_mode(0o555);
+        let mut dirs = vec![objects.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(&dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    dirs.push(path);
+                }
+            }
+            std::fs::set_permissions(&dir, read_only.clone()).unwrap();
+        }
+        // permissions don't apply to root (or with CAP_DAC_OVERRIDE)
+        let skipped = tempfile::tempfile_in(&objects).is_ok();
+        let result = (!skipped).then(|| {
+            (
+                db.ping(),
+                db.set(
+                    "a",
+                    SampleDbStruct::new(String::from("a")),
+                    OperationTarget::Main,
+                ),
+            )
+        });
+        // let the temporary directory be removed
+        let mut dirs = vec![objects];
+        while let Some(dir) = dirs.pop() {
+            std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+            for entry in std::fs::read_dir(&dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    dirs.push(path);
+                }
+            }
+        }
+        let Some((ping, set)) = result else {
+            eprintln!("skipping test_read_only_objects: running as root, permissions don't apply");
+            return;
+        };
+        assert!(matches!(
+            ping,
+            Err(error::StorageError::PermissionDenied(_))
+        ));
+        assert!(matches!(
+            set,
+            Err(error::SetObjectError::Storage(
+                error::StorageError::PermissionDenied(_)
+            ))
+        ));
+    }
+
+    #[cfg(any(feature = "encryption", feature = "full"))]
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_encryption(#[case] data_format: DataFormat) {
+        use crate::encryption::Encryption;
+
+        let td = tempfile::tempdir().unwrap();
+        let db = Collection::initialize(td.path(), data_format)
+            .unwrap()
+            .with_encryption(Encryption::new(&[1; 32]));
+        db.add_index("str_val", IndexType::Sequential);
+        assert!(db.index_list().is_empty());
         db.set(
             "a",
-            SampleDbStruct::new(String::from("MAIN\nline2")),
+            SampleDbStruct::new(String::from("secret value")),
             OperationTarget::Main,
         )
         .unwrap();
+        // scans the decrypted documents
+        let query = QueryBuilder::query(q("str_val", Equal, "secret value"))
+            .execute(&db)
+            .unwrap();
+        assert_eq!(query.count, 1);
+        db.set_reader("raw", &b"secret stream"[..], OperationTarget::Main)
+            .unwrap();
+        db.put_attachment("a", "file", &b"secret file"[..], OperationTarget::Main)
+            .unwrap();
+        db.namespace("ns")
+            .unwrap()
+            .set(
+                "b",
+                SampleDbStruct::new(String::from("secret namespaced")),
+                OperationTarget::Main,
+            )
+            .unwrap();
+
         assert_eq!(
             db.get::<SampleDbStruct>("a", OperationTarget::Main)
-                .unwrap()
                 .unwrap(),
-            SampleDbStruct {
-                str_val: String::from("MAIN\nline2")
-            }
+            Some(SampleDbStruct::new(String::from("secret value")))
         );
+        let mut streamed = Vec::new();
+        db.get_reader("raw", OperationTarget::Main)
+            .unwrap()
+            .unwrap()
+            .read_to_end(&mut streamed)
+            .unwrap();
+        assert_eq!(streamed, b"secret stream");
         assert_eq!(
-            db.get::<SampleDbStruct>("a", OperationTarget::Transaction(&t))
-                .unwrap()
+            db.get_attachment("a", "file", OperationTarget::Main)
                 .unwrap(),
-            SampleDbStruct {
-                str_val: String::from("TRAN\nline2")
-            }
-        );
-        assert_eq!(
-            db.apply_transaction(&t, crate::ConflictResolution::Abort)
-                .unwrap_err(),
-            error::TransactionError::Aborted
+            Some(b"secret file".to_vec())
         );
         assert_eq!(
-            db.get::<SampleDbStruct>("a", OperationTarget::Main)
+            db.namespace("ns")
                 .unwrap()
+                .get::<SampleDbStruct>("b", OperationTarget::Main)
                 .unwrap(),
-            SampleDbStruct {
-                str_val: String::from("MAIN\nline2")
+            Some(SampleDbStruct::new(String::from("secret namespaced")))
+        );
+
+        // nothing stored in the repository contains the values in plain text
+        let repo = db.repository();
+        let mut odb_blobs = Vec::new();
+        repo.odb()
+            .unwrap()
+            .foreach(|oid| {
+                odb_blobs.push(*oid);
+                true
+            })
+            .unwrap();
+        for oid in odb_blobs {
+            if let Ok(blob) = repo.find_blob(oid) {
+                assert!(!blob.content().windows(6).any(|w| w == b"secret"));
             }
+        }
+
+        let other_key = Collection::initialize(td.path(), data_format)
+            .unwrap()
+            .with_encryption(Encryption::new(&[2; 32]));
+        assert_eq!(
+            other_key.get::<SampleDbStruct>("a", OperationTarget::Main),
+            Err(error::GetObjectError::DecryptionFailed)
         );
     }
 
@@ -1082,200 +7667,584 @@ mod tests {
     #[case(DataFormat::Json)]
     #[case(DataFormat::Yaml)]
     #[case(DataFormat::Pot)]
-    fn test_adding_index(#[case] data_format: DataFormat) {
+    fn test_commit_hooks(#[case] data_format: DataFormat) {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let committed = Arc::new(Mutex::new(Vec::new()));
+        let (first, second, post) = (calls.clone(), calls.clone(), committed.clone());
         let (db, _td) = create_db(data_format);
-        db.add_index("str_val", IndexType::Sequential);
-        db.add_index("str_val", IndexType::Sequential);
+        let index = db.add_index("num_val", IndexType::Numeric);
+        // the post-commit hooks run once the indexes are updated
+        let indexed = Arc::new(Mutex::new(Vec::new()));
+        let (seen, hooked_index) = (indexed.clone(), index.clone());
+        let path = db.repository().path().to_path_buf();
+        let db = db
+            .on_pre_commit(move |write| {
+                first.lock().unwrap().push("first");
+                match write.keys.iter().any(|written| written.key == "vetoed") {
+                    true => Err(error::HookError(String::from("no vetoed keys"))),
+                    false => Ok(()),
+                }
+            })
+            .on_pre_commit(move |write| {
+                second.lock().unwrap().push("second");
+                if write.keys.iter().any(|written| written.key == "panics") {
+                    panic!("refusing to write panics");
+                }
+                Ok(())
+            })
+            .on_post_commit(move |write| post.lock().unwrap().push(write.clone()))
+            .on_post_commit(move |_| {
+                let repo = Repository::open(&path).unwrap();
+                seen.lock().unwrap().push(hooked_index.min(&repo).is_some());
+            });
+        let main_before = db.repository().refname_to_id("refs/heads/main").unwrap();
+
+        assert_eq!(
+            db.set(
+                "vetoed",
+                InterigentDbStruct { num_val: 1 },
+                OperationTarget::Main
+            ),
+            Err(error::SetObjectError::Vetoed(error::HookError(
+                String::from("no vetoed keys")
+            )))
+        );
+        // the second hook never ran and nothing was written
+        assert_eq!(*calls.lock().unwrap(), vec!["first"]);
+        assert!(committed.lock().unwrap().is_empty());
+        assert_eq!(
+            db.repository().refname_to_id("refs/heads/main").unwrap(),
+            main_before
+        );
+        assert_eq!(index.min(db.repository()), None);
+        assert!(matches!(
+            db.set(
+                "panics",
+                InterigentDbStruct { num_val: 1 },
+                OperationTarget::Main
+            ),
+            Err(error::SetObjectError::Vetoed(error::HookError(reason)))
+                if reason.contains("refusing to write panics")
+        ));
+        assert_eq!(index.min(db.repository()), None);
+
+        calls.lock().unwrap().clear();
+        let commit = db
+            .set(
+                "a",
+                InterigentDbStruct { num_val: 1 },
+                OperationTarget::Main,
+            )
+            .unwrap();
+        assert_eq!(*calls.lock().unwrap(), vec!["first", "second"]);
+        {
+            let committed = committed.lock().unwrap();
+            assert_eq!(committed.len(), 1);
+            assert_eq!(committed[0].commit, commit);
+            assert_eq!(committed[0].branch, "main");
+            assert_eq!(committed[0].keys.len(), 1);
+            assert_eq!(committed[0].keys[0].key, "a");
+            assert_eq!(committed[0].keys[0].kind, ChangeKind::Added);
+            assert!(committed[0].keys[0].size.is_some());
+        }
+        assert!(index.min(db.repository()).is_some());
+        assert_eq!(*indexed.lock().unwrap(), vec![true]);
+
+        // merges of transactions go through the hooks too
+        let t = db.new_transaction(None).unwrap();
         db.set(
-            "a",
-            SampleDbStruct::new(String::from("test value")),
+            "vetoed",
+            InterigentDbStruct { num_val: 2 },
             OperationTarget::Main,
         )
-        .unwrap();
-        let index_list = db.index_list();
-        assert_eq!(index_list.len(), 1);
-        assert_eq!(
-            index_list[0],
-            Index::new("str_val#sequential.index", "str_val", IndexType::Sequential)
-        );
+        .unwrap_err();
+        db.delete("a", OperationTarget::Transaction(&t)).unwrap();
+        let tip = db.repository().refname_to_id("refs/heads/main").unwrap();
+        let outcome = db
+            .apply_transaction(&t, ConflictResolution::Overwrite)
+            .unwrap();
+        let committed = committed.lock().unwrap();
+        let merged = committed.last().unwrap();
+        assert_eq!(merged.commit, outcome.head);
+        assert_ne!(merged.commit, tip);
+        assert_eq!(merged.branch, "main");
+        assert_eq!(merged.keys[0].kind, ChangeKind::Deleted);
+        assert_eq!(merged.keys[0].size, None);
     }
 
     #[rstest]
     #[case(DataFormat::Json)]
     #[case(DataFormat::Yaml)]
     #[case(DataFormat::Pot)]
-    fn test_index_content(#[case] data_format: DataFormat) {
+    fn test_delete_prefix(#[case] data_format: DataFormat) {
         let (db, _td) = create_db(data_format);
         db.add_index("str_val", IndexType::Sequential);
-        db.set(
-            "a",
-            SampleDbStruct::new(String::from("1val")),
-            OperationTarget::Main,
-        )
-        .unwrap();
-        db.set(
-            "b",
-            SampleDbStruct::new(String::from("1val")),
+        let keys = ["tenant1/a", "tenant1/b/c", "tenant1x", "tenant2/a", "other"];
+        db.set_batch(
+            keys.map(|key| (key, SampleDbStruct::new(String::from("v")))),
             OperationTarget::Main,
         )
         .unwrap();
-        db.set(
-            "c",
-            SampleDbStruct::new(String::from("2val")),
-            OperationTarget::Main,
-        )
-        .unwrap();
-        let index_values: Vec<git2::IndexEntry> = db.index_list()[0]
-            .git_index(&db.repository)
-            .iter()
-            .collect();
-        assert_eq!(index_values.len(), 3);
-        assert_eq!(index_values[0].path, "1val/fffffffffffffffe".as_bytes());
-        assert_eq!(index_values[1].path, "1val/ffffffffffffffff".as_bytes());
-        assert_eq!(index_values[2].path, "2val/ffffffffffffffff".as_bytes());
+        let commits = || {
+            let mut revwalk = db.repository().revwalk().unwrap();
+            revwalk.push_ref("refs/heads/main").unwrap();
+            revwalk.count()
+        };
+        let before = commits();
+
+        assert_eq!(
+            db.delete_prefix("tenant1", OperationTarget::Main).unwrap(),
+            3
+        );
+        assert_eq!(commits(), before + 1);
+        for key in ["tenant1/a", "tenant1/b/c", "tenant1x"] {
+            assert_eq!(
+                db.get::<SampleDbStruct>(key, OperationTarget::Main)
+                    .unwrap(),
+                None
+            );
+        }
+        // the empty directories of the keys are gone
+        let tree = db
+            .repository()
+            .find_branch("main", BranchType::Local)
+            .unwrap()
+            .get()
+            .peel_to_tree()
+            .unwrap();
+        assert!(tree.get_name("tenant1").is_none());
+        assert!(tree
+            .get_path(std::path::Path::new(".metadata/tenant1"))
+            .is_err());
+        let result = QueryBuilder::query(q("str_val", Equal, "v"))
+            .execute(&db)
+            .unwrap();
+        assert_eq!(result.count, 2);
+        let mut remaining = result.keys().unwrap();
+        remaining.sort();
+        assert_eq!(remaining, vec!["other", "tenant2/a"]);
+
+        assert_eq!(
+            db.delete_prefix("tenant1", OperationTarget::Main).unwrap(),
+            0
+        );
+        assert_eq!(commits(), before + 1);
     }
 
     #[rstest]
     #[case(DataFormat::Json)]
     #[case(DataFormat::Yaml)]
     #[case(DataFormat::Pot)]
-    fn test_index_content_numeric(#[case] data_format: DataFormat) {
-        let (db, _td) = create_db(data_format);
-        db.add_index("num_val", IndexType::Numeric);
-        db.set(
-            "b",
-            InterigentDbStruct { num_val: 20 },
-            OperationTarget::Main,
-        )
-        .unwrap();
-        db.set(
-            "c",
-            InterigentDbStruct { num_val: 2 },
-            OperationTarget::Main,
-        )
-        .unwrap();
-        db.set(
-            "d",
-            InterigentDbStruct { num_val: 3 },
-            OperationTarget::Main,
-        )
-        .unwrap();
-        db.set(
-            "e",
-            FloatyDbStruct { num_val: -11.0 },
-            OperationTarget::Main,
-        )
-        .unwrap();
-        db.set("a", FloatyDbStruct { num_val: 2.11 }, OperationTarget::Main)
+    fn test_read_remote_target(#[case] data_format: DataFormat) {
+        let (primary, _td) = create_db(data_format);
+        let (replica, _replica_td) = create_db(data_format);
+        primary
+            .set(
+                "a",
+                SampleDbStruct::new(String::from("primary")),
+                OperationTarget::Main,
+            )
             .unwrap();
-        let index_values: Vec<git2::IndexEntry> = db.index_list()[0]
-            .git_index(&db.repository)
-            .iter()
-            .collect();
-        assert_eq!(index_values.len(), 5);
+        replica
+            .repository()
+            .remote("origin", primary.path().to_str().unwrap())
+            .unwrap();
+        let origin = OperationTarget::Remote {
+            remote: "origin",
+            branch: "main",
+        };
         assert_eq!(
-            String::from_utf8(index_values[0].path.clone()).unwrap(),
-            format!("0/{:16x}/ffffffffffffffff", (-11.0_f64).to_bits())
+            replica.get::<SampleDbStruct>("a", origin),
+            Err(error::GetObjectError::InvalidOperationTarget(
+                origin.to_string()
+            ))
         );
+
+        replica.refresh_remote("origin").unwrap();
         assert_eq!(
-            String::from_utf8(index_values[1].path.clone()).unwrap(),
-            format!("1/{:16x}/ffffffffffffffff", 2.0_f64.to_bits())
+            replica
+                .get::<SampleDbStruct>("a", origin)
+                .unwrap()
+                .unwrap()
+                .str_val,
+            "primary"
         );
+        // the local main of the replica is untouched
         assert_eq!(
-            String::from_utf8(index_values[2].path.clone()).unwrap(),
-            format!("1/{:16x}/ffffffffffffffff", 2.11_f64.to_bits())
+            replica
+                .get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap(),
+            None
         );
+        let result = QueryBuilder::query(q("str_val", Equal, "primary"))
+            .execute_at(&replica, origin)
+            .unwrap();
+        assert_eq!(result.count, 1);
+
+        primary
+            .set(
+                "a",
+                SampleDbStruct::new(String::from("updated")),
+                OperationTarget::Main,
+            )
+            .unwrap();
+        replica.refresh_remote("origin").unwrap();
         assert_eq!(
-            String::from_utf8(index_values[3].path.clone()).unwrap(),
-            format!("1/{:16x}/ffffffffffffffff", 3.0_f64.to_bits())
+            replica
+                .get::<SampleDbStruct>("a", origin)
+                .unwrap()
+                .unwrap()
+                .str_val,
+            "updated"
         );
         assert_eq!(
-            String::from_utf8(index_values[4].path.clone()).unwrap(),
-            format!("1/{:16x}/ffffffffffffffff", 20.0_f64.to_bits())
+            replica.set("b", SampleDbStruct::new(String::from("b")), origin),
+            Err(error::SetObjectError::ReadOnlyTarget)
         );
+        assert!(!OperationTarget::Remote {
+            remote: "a/b",
+            branch: "main"
+        }
+        .is_valid());
     }
 
     #[rstest]
     #[case(DataFormat::Json)]
     #[case(DataFormat::Yaml)]
     #[case(DataFormat::Pot)]
-    fn test_writing_to_correct_index(#[case] data_format: DataFormat) {
+    fn test_keys_by_insertion(#[case] data_format: DataFormat) {
         let (db, _td) = create_db(data_format);
-        db.add_index("str_val", IndexType::Numeric);
-        db.set(
-            "a",
-            SampleDbStruct::new(String::from("test")),
-            OperationTarget::Main,
-        )
-        .unwrap();
-        let index_values: Vec<git2::IndexEntry> = db.index_list()[0]
-            .git_index(&db.repository)
-            .iter()
-            .collect();
-        assert_eq!(index_values.len(), 0);
+        let value = |v: &str| SampleDbStruct::new(v.to_string());
+        assert_eq!(db.rebuild_insertion_log().unwrap(), 0);
+        for key in ["a", "b", "c"] {
+            db.set(key, value(key), OperationTarget::Main).unwrap();
+        }
+        db.delete("b", OperationTarget::Main).unwrap();
+        db.set("b", value("b"), OperationTarget::Main).unwrap();
+        // changing the value doesn't count as an insertion
+        db.set("a", value("changed"), OperationTarget::Main)
+            .unwrap();
+        let keys = |order, limit, offset| db.keys_by_insertion(order, limit, offset).unwrap();
+        assert_eq!(keys(Order::Ascending, 10, 0), vec!["a", "c", "b"]);
+        assert_eq!(keys(Order::Descending, 10, 0), vec!["b", "c", "a"]);
+        assert_eq!(keys(Order::Descending, 1, 1), vec!["c"]);
+        assert!(keys(Order::Ascending, 10, 3).is_empty());
+
+        // transactions count once they are merged into main
+        let t = db.new_transaction(None).unwrap();
+        db.set("d", value("d"), OperationTarget::Transaction(&t))
+            .unwrap();
+        db.delete("c", OperationTarget::Transaction(&t)).unwrap();
+        assert_eq!(keys(Order::Ascending, 10, 0), vec!["a", "c", "b"]);
+        db.apply_transaction(&t, ConflictResolution::Overwrite)
+            .unwrap();
+        assert_eq!(keys(Order::Ascending, 10, 0), vec!["a", "b", "d"]);
+        db.revert_n_commits(2, OperationTarget::Main, false)
+            .unwrap();
+        // c was brought back by the revert
+        assert_eq!(keys(Order::Ascending, 10, 0), vec!["a", "b", "c"]);
+
+        // the history only has c inserted before b
+        std::fs::remove_file(db.repository().path().join(".index/insertions.log")).unwrap();
+        db.set("e", value("e"), OperationTarget::Main).unwrap();
+        assert_eq!(
+            db.keys_by_insertion(Order::Ascending, 10, 0)
+                .unwrap_err()
+                .code(),
+            git2::ErrorCode::NotFound
+        );
+        assert_eq!(db.rebuild_insertion_log().unwrap(), 4);
+        assert_eq!(keys(Order::Ascending, 10, 0), vec!["a", "c", "b", "e"]);
+
+        // a log which can't be updated doesn't fail the write, it's dropped instead
+        let log = db.repository().path().join(".index/insertions.log");
+        std::fs::write(&log, b"not an index").unwrap();
+        db.set("f", value("f"), OperationTarget::Main).unwrap();
+        assert!(!log.exists());
+        assert!(db.keys_by_insertion(Order::Ascending, 10, 0).is_err());
+        assert_eq!(db.rebuild_insertion_log().unwrap(), 5);
     }
 
     #[rstest]
     #[case(DataFormat::Json)]
     #[case(DataFormat::Yaml)]
     #[case(DataFormat::Pot)]
-    fn test_index_population(#[case] data_format: DataFormat) {
-        let (db, _td) = create_db(data_format);
+    fn test_clone_from(#[case] data_format: DataFormat) {
+        let (source, source_dir) = create_db(data_format);
+        let index = source.add_index("str_val", IndexType::Sequential);
+        source
+            .set_batch(
+                [
+                    ("a", SampleDbStruct::new(String::from("a"))),
+                    ("b", SampleDbStruct::new(String::from("b"))),
+                ],
+                OperationTarget::Main,
+            )
+            .unwrap();
+        let main = source.head(OperationTarget::Main).unwrap();
+        Index::snapshot_all(source.repository(), main).unwrap();
+        let url = source_dir.path().to_str().unwrap();
+        let target_dir = tempfile::tempdir().unwrap();
+        let path = target_dir.path().join("clone");
+
+        let db = Collection::clone_from(url, &path, data_format, None).unwrap();
+        assert_eq!(
+            db.get::<SampleDbStruct>("b", OperationTarget::Main)
+                .unwrap(),
+            Some(SampleDbStruct::new(String::from("b")))
+        );
+        assert_eq!(db.list_indexes(), vec![index]);
+        assert_eq!(
+            crate::replica::Replicator::configured(&path).unwrap(),
+            vec!["origin"]
+        );
+        assert!(db
+            .repository()
+            .remotes()
+            .unwrap()
+            .iter()
+            .all(|remote| remote != Some("origin")));
+
+        // the clone replicates back to the source
         db.set(
-            "a",
-            SampleDbStruct::new(String::from("test")),
+            "c",
+            SampleDbStruct::new(String::from("c")),
             OperationTarget::Main,
         )
         .unwrap();
-        db.add_index("str_val", IndexType::Sequential);
-        let index_values: Vec<git2::IndexEntry> = db.index_list()[0]
-            .git_index(&db.repository)
-            .iter()
-            .collect();
-        assert_eq!(index_values.len(), 1);
+        crate::replica::Replicator::load(&path, "origin", None)
+            .unwrap()
+            .replicate()
+            .unwrap();
+        assert!(source
+            .get::<SampleDbStruct>("c", OperationTarget::Main)
+            .unwrap()
+            .is_some());
+
+        assert_eq!(
+            Collection::clone_from(url, &path, data_format, None).err(),
+            Some(error::InitializationError::AlreadyExists)
+        );
     }
 
     #[rstest]
     #[case(DataFormat::Json)]
     #[case(DataFormat::Yaml)]
     #[case(DataFormat::Pot)]
-    fn test_index_removes_entries_on_update(#[case] data_format: DataFormat) {
-        let (db, _td) = create_db(data_format);
-        db.add_index("str_val", IndexType::Sequential);
-        let query = QueryBuilder::query(q("str_val", Equal, "test"));
+    fn test_clone_from_cleans_up(#[case] data_format: DataFormat) {
+        let target_dir = tempfile::tempdir().unwrap();
+        let path = target_dir.path().join("clone");
+        let missing = target_dir.path().join("missing");
+        assert!(matches!(
+            Collection::clone_from(missing.to_str().unwrap(), &path, data_format, None),
+            Err(error::InitializationError::InternalGitError(_))
+        ));
+        assert!(!path.exists());
+
+        let (source, source_dir) = create_db(data_format);
+        source
+            .set(
+                "a",
+                SampleDbStruct::new(String::from("a")),
+                OperationTarget::Main,
+            )
+            .unwrap();
+        let url = source_dir.path().to_str().unwrap();
+        // an empty directory is fine to clone into, and it's kept empty on failure
+        std::fs::create_dir(&path).unwrap();
+        let wrong_format = match data_format {
+            DataFormat::Pot => DataFormat::Json,
+            _ => DataFormat::Pot,
+        };
+        let result = Collection::clone_from(url, &path, wrong_format, None);
+        assert!(matches!(
+            result,
+            Err(error::InitializationError::NotACollection(_))
+        ));
+        assert!(path.exists());
+        assert_eq!(std::fs::read_dir(&path).unwrap().count(), 0);
+        assert!(Collection::clone_from(url, &path, data_format, None).is_ok());
+    }
+
+    #[rstest]
+    #[case(ConflictResolution::Overwrite, "transaction")]
+    #[case(ConflictResolution::DiscardChanges, "main")]
+    #[case(ConflictResolution::Callback(Box::new(|_: &ConflictInfo| Resolution::TakeTransaction)), "transaction")]
+    fn test_binary_values_merged_whole(
+        #[case] conflict_resolution: ConflictResolution,
+        #[case] winner: &str,
+    ) {
+        let (db, _td) = create_db(DataFormat::Pot);
+        // values git could merge line by line if they were text
+        let lines = |first: &str, last: &str| {
+            let mut lines = vec![String::from("line"); 10];
+            lines[0] = String::from(first);
+            lines[9] = String::from(last);
+            SampleDbStruct::new(lines.join("\n"))
+        };
+        db.set("a", lines("base", "base"), OperationTarget::Main)
+            .unwrap();
+        let t = db.new_transaction(None).unwrap();
+        db.set("a", lines("main", "base"), OperationTarget::Main)
+            .unwrap();
         db.set(
             "a",
-            SampleDbStruct::new(String::from("test")),
-            OperationTarget::Main,
+            lines("base", "transaction"),
+            OperationTarget::Transaction(&t),
         )
         .unwrap();
-        assert_eq!(query.execute(&db).unwrap().count, 1);
-        db.set("a", FloatyDbStruct { num_val: 69.0 }, OperationTarget::Main)
-            .unwrap();
-        assert_eq!(query.execute(&db).unwrap().count, 0);
+        let preview = db.preview_transaction(&t).unwrap();
+        assert_eq!(
+            preview
+                .conflicts
+                .iter()
+                .map(|c| c.key.as_str())
+                .collect::<Vec<_>>(),
+            vec!["a"]
+        );
+        db.apply_transaction(&t, conflict_resolution).unwrap();
+        let expected = match winner {
+            "main" => lines("main", "base"),
+            _ => lines("base", "transaction"),
+        };
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap(),
+            Some(expected)
+        );
+    }
+
+    #[test]
+    fn test_restore_value_conflicts() {
+        let (db, _td) = create_db(DataFormat::Pot);
+        let repo = db.repository();
+        let tree = |value: &str| {
+            let mut builder = repo.treebuilder(None).unwrap();
+            let blob = repo.blob(value.as_bytes()).unwrap();
+            builder.insert("doc", blob, 0o100644).unwrap();
+            builder
+                .insert("same", repo.blob(b"same").unwrap(), 0o100644)
+                .unwrap();
+            repo.find_tree(builder.write().unwrap()).unwrap()
+        };
+        let base = tree("a\nb\nc\nd\ne\n");
+        let ours = tree("A\nb\nc\nd\ne\n");
+        let theirs = tree("a\nb\nc\nd\nE\n");
+        let mut index = repo.merge_trees(&base, &ours, &theirs, None).unwrap();
+        // git merged the lines of both sides
+        assert!(!index.has_conflicts());
+        let paths =
+            Collection::restore_value_conflicts(&mut index, (&base, &ours, &theirs)).unwrap();
+        assert_eq!(paths, vec!["doc"]);
+        let conflict = index.conflicts().unwrap().next().unwrap().unwrap();
+        let id = |tree: &git2::Tree| Some(tree.get_name("doc").unwrap().id());
+        assert_eq!(conflict.ancestor.map(|entry| entry.id), id(&base));
+        assert_eq!(conflict.our.map(|entry| entry.id), id(&ours));
+        assert_eq!(conflict.their.map(|entry| entry.id), id(&theirs));
+        assert!(index.get_path(std::path::Path::new("same"), 0).is_some());
     }
 
     #[rstest]
     #[case(DataFormat::Json)]
     #[case(DataFormat::Yaml)]
     #[case(DataFormat::Pot)]
-    fn test_index_entry_update(#[case] data_format: DataFormat) {
+    fn test_soft_delete_and_restore(#[case] data_format: DataFormat) {
         let (db, _td) = create_db(data_format);
-        db.add_index("str_val", IndexType::Sequential);
-        let query = QueryBuilder::query(q("str_val", Equal, "test"));
-        db.set(
-            "a",
-            SampleDbStruct::new(String::from("test")),
-            OperationTarget::Main,
-        )
-        .unwrap();
-        assert_eq!(query.execute(&db).unwrap().count, 1);
-        db.set(
-            "a",
-            SampleDbStruct::new(String::from("test2")),
+        let clock = Arc::new(MockClock::new(100));
+        let db = db.with_clock(clock.clone());
+        let index = db.add_index("str_val", IndexType::Sequential);
+        let count = |value: &str| {
+            QueryBuilder::query(q("str_val", Equal, value))
+                .execute(&db)
+                .unwrap()
+                .count
+        };
+        let a = SampleDbStruct::new(String::from("a"));
+        db.set_batch(
+            [
+                ("a", a.clone()),
+                ("b", SampleDbStruct::new(String::from("b"))),
+            ],
             OperationTarget::Main,
         )
         .unwrap();
-        assert_eq!(query.execute(&db).unwrap().count, 1);
+        assert_eq!(count("a"), 1);
+
+        clock.set(200);
+        assert!(db.soft_delete("a", OperationTarget::Main).unwrap());
+        assert!(!db.soft_delete("a", OperationTarget::Main).unwrap());
+        assert!(!db.soft_delete("missing", OperationTarget::Main).unwrap());
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap(),
+            None
+        );
+        assert_eq!(db.document_meta("a", OperationTarget::Main).unwrap(), None);
+        assert_eq!(db.iter(OperationTarget::Main).unwrap().count(), 1);
+        assert_eq!(count("a"), 0);
+        assert_eq!(index.git_index(db.repository()).len(), 1);
+        let (value, meta) = db
+            .get_with_meta("a", OperationTarget::Main)
+            .unwrap()
+            .unwrap();
+        assert!(value.is_empty());
+        assert_eq!(
+            meta.commit,
+            db.repository().refname_to_id("refs/heads/main").unwrap()
+        );
+        let tombstone = meta.document.unwrap().tombstone.unwrap();
+        assert_eq!(tombstone.deleted_at, 200);
+
+        clock.set(300);
+        assert!(db.restore("a", OperationTarget::Main).unwrap());
+        assert!(!db.restore("a", OperationTarget::Main).unwrap());
+        assert!(!db.restore("b", OperationTarget::Main).unwrap());
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap(),
+            Some(a.clone())
+        );
+        assert_eq!(count("a"), 1);
+        let meta = db
+            .document_meta("a", OperationTarget::Main)
+            .unwrap()
+            .unwrap();
+        assert_eq!((meta.created_at, meta.updated_at), (100, 300));
+        assert_eq!(meta.revision, 3);
+        assert_eq!(meta.tombstone, None);
+
+        // writing over a tombstone starts the document anew
+        db.soft_delete("a", OperationTarget::Main).unwrap();
+        db.set("a", a.clone(), OperationTarget::Main).unwrap();
+        let meta = db
+            .document_meta("a", OperationTarget::Main)
+            .unwrap()
+            .unwrap();
+        assert_eq!((meta.created_at, meta.revision), (300, 1));
+        assert!(!db.restore("a", OperationTarget::Main).unwrap());
+
+        // only the expired tombstones are purged, in a single commit
+        db.soft_delete("a", OperationTarget::Main).unwrap();
+        clock.set(400);
+        db.soft_delete("b", OperationTarget::Main).unwrap();
+        let commits = db.stats().unwrap().commits;
+        assert_eq!(
+            db.purge_tombstones(Duration::from_secs(100), OperationTarget::Main)
+                .unwrap(),
+            1
+        );
+        assert_eq!(db.stats().unwrap().commits, commits + 1);
+        assert!(db
+            .get_with_meta("a", OperationTarget::Main)
+            .unwrap()
+            .is_none());
+        assert!(!db.restore("a", OperationTarget::Main).unwrap());
+        assert_eq!(
+            db.purge_tombstones(Duration::from_secs(100), OperationTarget::Main)
+                .unwrap(),
+            0
+        );
+        assert!(db.restore("b", OperationTarget::Main).unwrap());
+        assert_eq!(count("b"), 1);
     }
 }
@@ -0,0 +1,232 @@
+use std::cell::{Cell, RefCell};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use git2::Repository;
+
+use crate::{debug, error};
+
+/// File in the directory of the repository locked by the writes. The lock is advisory:
+/// it only keeps out other Collections (in this process or others), not git itself.
+pub(crate) const LOCK_FILE: &str = ".yamabiko.lock";
+
+/// How long a write waits for the lock unless set with `Collection::with_lock_timeout`
+pub(crate) const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+const RETRY_INTERVAL: Duration = Duration::from_millis(2);
+
+/// Exclusive lock on the repository, held from reading the tip of the branch until it's moved
+/// to the new commit. Released when dropped - or by the OS, if the process dies holding it.
+pub(crate) struct WriteLock {
+    _file: File,
+}
+
+/// The lock as held by a single Collection, which may take it again while holding it -
+/// e.g. an idempotent write holds it across recording the token and the write itself.
+/// Another Collection (even in the same process) still has to wait.
+#[derive(Default)]
+pub(crate) struct CollectionLock {
+    held: RefCell<Option<WriteLock>>,
+    depth: Cell<usize>,
+}
+
+/// Releases the lock once the last guard of the Collection is dropped
+pub(crate) struct LockGuard<'l> {
+    lock: &'l CollectionLock,
+}
+
+impl CollectionLock {
+    pub(crate) fn acquire(
+        &self,
+        repo: &Repository,
+        timeout: Duration,
+    ) -> Result<LockGuard<'_>, error::LockError> {
+        if self.depth.get() == 0 {
+            *self.held.borrow_mut() = Some(acquire(repo, timeout)?);
+        }
+        self.depth.set(self.depth.get() + 1);
+        Ok(LockGuard { lock: self })
+    }
+}
+
+impl Drop for LockGuard<'_> {
+    fn drop(&mut self) {
+        let depth = self.lock.depth.get() - 1;
+        self.lock.depth.set(depth);
+        if depth == 0 {
+            self.lock.held.borrow_mut().take();
+        }
+    }
+}
+
+/// Wait up to `timeout` for the lock of the repository
+pub(crate) fn acquire(repo: &Repository, timeout: Duration) -> Result<WriteLock, error::LockError> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(repo.path().join(LOCK_FILE))
+        .map_err(|err| error::LockError::Io(err.to_string()))?;
+    let deadline = Instant::now().checked_add(timeout);
+    loop {
+        match file.try_lock() {
+            Ok(()) => return Ok(WriteLock { _file: file }),
+            Err(TryLockError::WouldBlock) => {
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    debug!("{} is still locked after {:?}", LOCK_FILE, timeout);
+                    return Err(error::LockError::Timeout(timeout));
+                }
+                thread::sleep(RETRY_INTERVAL);
+            }
+            Err(TryLockError::Error(err)) => return Err(error::LockError::Io(err.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use rstest::rstest;
+
+    use crate::{error, serialization::DataFormat, test::*, Collection, OperationTarget};
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_write_lock_contention(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let db = db.with_lock_timeout(Duration::from_millis(20));
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("a")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let t = db.new_transaction(None).unwrap();
+        db.set(
+            "c",
+            SampleDbStruct::new(String::from("c")),
+            OperationTarget::Transaction(&t),
+        )
+        .unwrap();
+        let held = super::acquire(db.repository(), Duration::ZERO).unwrap();
+        assert_eq!(
+            db.set(
+                "b",
+                SampleDbStruct::new(String::from("b")),
+                OperationTarget::Main,
+            ),
+            Err(error::SetObjectError::Locked(error::LockError::Timeout(
+                Duration::from_millis(20)
+            )))
+        );
+        assert!(matches!(
+            db.apply_transaction(&t, crate::ConflictResolution::Abort),
+            Err(error::TransactionError::Locked(_))
+        ));
+        // reads don't wait for the lock
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap(),
+            Some(SampleDbStruct::new(String::from("a")))
+        );
+        drop(held);
+        db.set(
+            "b",
+            SampleDbStruct::new(String::from("b")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_index_creation_and_squash_take_the_lock(#[case] data_format: DataFormat) {
+        let (db, td) = create_db(data_format);
+        let db = db.with_lock_timeout(Duration::from_millis(20));
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("a")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let first = db.head(OperationTarget::Main).unwrap();
+        db.set(
+            "b",
+            SampleDbStruct::new(String::from("b")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let squasher = crate::squash::Squasher::initialize(td.path()).unwrap();
+        let held = super::acquire(db.repository(), Duration::ZERO).unwrap();
+        let head = db.head(OperationTarget::Main).unwrap();
+        let err = db
+            .create_index("str_val", crate::index::IndexType::Sequential)
+            .unwrap_err();
+        assert!(matches!(err, crate::error::IndexError::Locked(_)));
+        drop(held);
+        assert_eq!(db.head(OperationTarget::Main), Ok(head));
+        let (index, _) = db
+            .create_index("str_val", crate::index::IndexType::Sequential)
+            .unwrap();
+        let held = super::acquire(db.repository(), Duration::ZERO).unwrap();
+        assert!(matches!(
+            db.rebuild_index(&index),
+            Err(crate::error::IndexError::Locked(_))
+        ));
+        drop(held);
+        db.rebuild_index(&index).unwrap();
+        let held = super::acquire(db.repository(), Duration::ZERO).unwrap();
+        let head = db.head(OperationTarget::Main).unwrap();
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(held);
+        });
+        // waits for the lock instead of rebasing main from under the writer
+        squasher.squash_before_commit(first).unwrap();
+        writer.join().unwrap();
+        assert_ne!(db.head(OperationTarget::Main), Ok(head));
+        assert_eq!(
+            db.get::<SampleDbStruct>("b", OperationTarget::Main)
+                .unwrap(),
+            Some(SampleDbStruct::new(String::from("b")))
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_concurrent_writers_keep_every_commit(#[case] data_format: DataFormat) {
+        let (db, td) = create_db(data_format);
+        let threads: Vec<_> = (0..4)
+            .map(|n| {
+                let path = td.path().to_path_buf();
+                thread::spawn(move || {
+                    // every writer has its own repository handle, like another process would
+                    let db = Collection::initialize(&path, data_format).unwrap();
+                    for i in 0..10 {
+                        let key = format!("{}-{}", n, i);
+                        db.set(
+                            &key,
+                            SampleDbStruct::new(key.clone()),
+                            OperationTarget::Main,
+                        )
+                        .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(db.iter(OperationTarget::Main).unwrap().count(), 40);
+        assert_eq!(db.stats().unwrap().commits, 41);
+    }
+}
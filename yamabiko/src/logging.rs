@@ -1,6 +1,93 @@
+//! Diagnostics emitted by yamabiko.
+//!
+//! With the `log` feature, internal `debug!` (and `warn!`) messages go to the `log` facade.
+//! With the `tracing` feature, they become `tracing` events and every public operation opens
+//! a span. Without a subscriber interested in them, spans cost a single cached callsite check:
+//! neither the fields of the span nor the values recorded on it later (see `record!`)
+//! are computed. `debug!` events are emitted at DEBUG and `warn!` ones (e.g. documents
+//! skipped by an index) at WARN.
+//!
+//! | Span                                | Level | Fields                                                                 |
+//! |-------------------------------------|-------|------------------------------------------------------------------------|
+//! | `collection.get`                    | DEBUG | `key`, `branch`                                                        |
+//! | `collection.get_with_meta`          | DEBUG | `key`, `branch`                                                        |
+//! | `collection.get_by_oid`             | DEBUG | `oid`                                                                  |
+//! | `collection.changes_since`          | DEBUG | `since`, `branch`, `changes`                                           |
+//! | `collection.log`                    | DEBUG | `branch`, `limit`, `commits`                                           |
+//! | `collection.versions`               | DEBUG | `key`, `branch`, `n`, `versions`                                       |
+//! | `collection.check`                  | DEBUG | `opts`, `problems`                                                     |
+//! | `collection.health`                 | DEBUG |                                                                        |
+//! | `collection.stats`                  | DEBUG |                                                                        |
+//! | `collection.dedup_stats`            | DEBUG |                                                                        |
+//! | `collection.verify`                 | DEBUG | `objects`, `problems`                                                  |
+//! | `collection.verify_signatures`      | DEBUG | `valid`, `invalid`                                                     |
+//! | `collection.preview_transaction`    | DEBUG | `name`, `conflicts`                                                    |
+//! | `query.execute`                     | DEBUG | `target`, `strategy`, `count`                                          |
+//! | `collection.set_batch`              | INFO  | `branch`, `items`, `commit`                                            |
+//! | `collection.delete_batch`           | INFO  | `branch`, `items`, `commit`                                            |
+//! | `collection.delete_prefix`          | INFO  | `prefix`, `branch`, `items`                                            |
+//! | `collection.clear`                  | INFO  | `branch`, `commit`                                                     |
+//! | `collection.soft_delete`            | INFO  | `key`, `branch`                                                        |
+//! | `collection.restore`                | INFO  | `key`, `branch`                                                        |
+//! | `collection.purge_tombstones`       | INFO  | `branch`, `items`                                                      |
+//! | `collection.patch_batch`            | INFO  | `branch`, `items`, `commit`                                            |
+//! | `collection.set_reader`             | INFO  | `key`, `branch`, `commit`                                              |
+//! | `collection.put_attachment`         | INFO  | `key`, `name`, `branch`, `commit`                                      |
+//! | `collection.merge`                  | INFO  | `source`, `target`, `conflict_resolution`, `commits_rebased`, `commit` |
+//! | `collection.apply_transaction`      | INFO  | `name`, `conflict_resolution`, `commits_rebased`                       |
+//! | `collection.apply_transaction_with` | INFO  | `name`, `conflicts`, `commit`                                          |
+//! | `collection.migrate_all`            | INFO  | `branch`, `migrated`, `failed`                                         |
+//! | `collection.create_index`           | INFO  | `field`, `kind`, `options`, `skipped`                                  |
+//! | `collection.gc_transactions`        | INFO  | `older_than`, `removed`                                                |
+//! | `collection.revert`                 | INFO  | `branch`, `commit`/`n`, `keep_history`                                 |
+//! | `squasher.squash`                   | INFO  | `before`, `commit`                                                     |
+//! | `replica.replicate`                 | INFO  | `remote`, `outcome`                                                    |
+//! | `replica.replicate_commit`          | INFO  | `remote`, `commit`, `outcome`                                          |
+//! | `replica.push`                      | INFO  | `remote`, `duration_ms`, `attempts`, `result`                          |
+//!
+//! `commit` is the oid of the commit created (or reset to) by the operation,
+//! `before` the one whose history `Squasher::squash_before_commit` squashes.
+//! The `branch` of reads from `OperationTarget::Commit` (and the `target` of such queries)
+//! is the oid of the commit.
+
 #[macro_export]
 macro_rules! debug { ($($x:tt)*) => (
     #[cfg(feature = "log")] {
         log::debug!($($x)*)
     }
+    #[cfg(any(feature = "tracing", feature = "full"))] {
+        tracing::debug!($($x)*)
+    }
+) }
+
+#[macro_export]
+macro_rules! warn { ($($x:tt)*) => (
+    #[cfg(feature = "log")] {
+        log::warn!($($x)*)
+    }
+    #[cfg(any(feature = "tracing", feature = "full"))] {
+        tracing::warn!($($x)*)
+    }
+    // the arguments still count as used (but are never evaluated) with nowhere to log to
+    #[cfg(not(any(feature = "log", feature = "tracing", feature = "full")))] {
+        if false {
+            let _ = format_args!($($x)*);
+        }
+    }
 ) }
+
+/// Record a value on a field of the current tracing span.
+/// The field has to be declared (possibly as `Empty`) when the span is created.
+/// The value is only computed if the span is enabled.
+#[macro_export]
+macro_rules! record {
+    ($field:expr, $value:expr) => {
+        #[cfg(any(feature = "tracing", feature = "full"))]
+        {
+            let span = tracing::Span::current();
+            if !span.is_disabled() {
+                span.record($field, $value);
+            }
+        }
+    };
+}
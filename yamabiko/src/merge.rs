@@ -0,0 +1,143 @@
+use serde_json::{Map, Value};
+
+use crate::error::MergeError;
+
+/// Result of merging the two versions of a document
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum MergeOutcome {
+    /// Contains the merged document
+    Merged(Vec<u8>),
+    /// The versions can't be merged, the merge fails with a MergeConflict
+    Conflict,
+}
+
+/// Merges the values of a key changed on both sides, used with `ConflictResolution::Custom`
+/// (see `Collection::with_merge_driver`).
+///
+/// `ours` is the value on the branch merged into (main when applying a transaction)
+/// and `theirs` the value in the commit being applied, both already decrypted.
+/// Keys deleted on one of the sides never reach the driver - they are always a conflict.
+pub trait MergeDriver: Send + Sync {
+    fn merge(
+        &self,
+        key: &str,
+        base: Option<&[u8]>,
+        ours: &[u8],
+        theirs: &[u8],
+    ) -> Result<MergeOutcome, MergeError>;
+}
+
+/// Merges JSON objects field by field: a field changed (added or removed) on one side only
+/// takes that change, and only a field changed differently on both sides is a conflict.
+/// Nested objects are compared as a whole. Meant for collections using `DataFormat::Json`.
+pub struct JsonFieldMerge;
+
+impl JsonFieldMerge {
+    fn parse(key: &str, data: &[u8]) -> Result<Map<String, Value>, MergeError> {
+        match serde_json::from_slice(data) {
+            Ok(Value::Object(map)) => Ok(map),
+            Ok(_) => Err(MergeError(format!("{} is not a JSON object", key))),
+            Err(err) => Err(MergeError(format!("{} is not valid JSON: {}", key, err))),
+        }
+    }
+}
+
+impl MergeDriver for JsonFieldMerge {
+    fn merge(
+        &self,
+        key: &str,
+        base: Option<&[u8]>,
+        ours: &[u8],
+        theirs: &[u8],
+    ) -> Result<MergeOutcome, MergeError> {
+        // added on both sides - every field counts as added
+        let base = match base {
+            Some(base) => Self::parse(key, base)?,
+            None => Map::new(),
+        };
+        let ours = Self::parse(key, ours)?;
+        let theirs = Self::parse(key, theirs)?;
+        let mut merged = ours.clone();
+        for (field, value) in theirs.iter() {
+            if ours.get(field) == Some(value) || base.get(field) == Some(value) {
+                continue;
+            }
+            if ours.get(field) != base.get(field) {
+                return Ok(MergeOutcome::Conflict);
+            }
+            merged.insert(field.clone(), value.clone());
+        }
+        // removed in theirs
+        for (field, value) in base.iter() {
+            if theirs.contains_key(field) || !ours.contains_key(field) {
+                continue;
+            }
+            if ours.get(field) != Some(value) {
+                return Ok(MergeOutcome::Conflict);
+            }
+            merged.remove(field);
+        }
+        // unwrap: a map of JSON values always serializes
+        Ok(MergeOutcome::Merged(
+            serde_json::to_vec(&Value::Object(merged)).unwrap(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merge(base: Option<&str>, ours: &str, theirs: &str) -> Result<MergeOutcome, MergeError> {
+        JsonFieldMerge.merge(
+            "key",
+            base.map(str::as_bytes),
+            ours.as_bytes(),
+            theirs.as_bytes(),
+        )
+    }
+
+    fn merged(value: &str) -> Result<MergeOutcome, MergeError> {
+        let value: Value = serde_json::from_str(value).unwrap();
+        Ok(MergeOutcome::Merged(serde_json::to_vec(&value).unwrap()))
+    }
+
+    #[test]
+    fn test_json_field_merge() {
+        let base = Some(r#"{"a": 1, "b": 1, "c": 1}"#);
+        assert_eq!(
+            merge(base, r#"{"a": 2, "b": 1, "c": 1}"#, r#"{"a": 1, "b": 2}"#),
+            merged(r#"{"a": 2, "b": 2}"#)
+        );
+        assert_eq!(
+            merge(
+                base,
+                r#"{"a": 2, "c": 1}"#,
+                r#"{"a": 2, "b": 1, "c": 1, "d": 1}"#
+            ),
+            merged(r#"{"a": 2, "c": 1, "d": 1}"#)
+        );
+        assert_eq!(
+            merge(
+                base,
+                r#"{"a": 2, "b": 1, "c": 1}"#,
+                r#"{"a": 3, "b": 1, "c": 1}"#
+            ),
+            Ok(MergeOutcome::Conflict)
+        );
+        assert_eq!(
+            merge(base, r#"{"a": 2, "b": 1, "c": 1}"#, r#"{"b": 1, "c": 1}"#),
+            Ok(MergeOutcome::Conflict)
+        );
+        assert_eq!(
+            merge(None, r#"{"a": 1}"#, r#"{"a": 1, "b": 1}"#),
+            merged(r#"{"a": 1, "b": 1}"#)
+        );
+        assert_eq!(
+            merge(None, r#"{"a": 1}"#, r#"{"a": 2}"#),
+            Ok(MergeOutcome::Conflict)
+        );
+        assert!(merge(base, "[1]", r#"{"a": 1}"#).is_err());
+        assert!(merge(base, r#"{"a": 1}"#, "{not json").is_err());
+    }
+}
@@ -0,0 +1,307 @@
+use git2::{Commit, ErrorCode, ObjectType, Oid, Repository, Signature};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{debug, error, Collection};
+
+/// Reference to the commit of the operational metadata. Every key is a blob at the root
+/// of its tree. Nothing under `refs/heads` points at it,
+/// so it's never part of the documents, their history or the replicated data by default.
+pub(crate) const META_REF: &str = "refs/yamabiko/meta";
+
+fn is_valid_key(key: &str) -> bool {
+    !key.is_empty() && key != "." && key != ".." && !key.contains(['/', '\0'])
+}
+
+fn tip(repo: &Repository) -> Result<Option<Commit<'_>>, git2::Error> {
+    match repo.find_reference(META_REF) {
+        Ok(reference) => Ok(Some(reference.peel_to_commit()?)),
+        Err(err) if err.code() == ErrorCode::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+fn read(
+    repo: &Repository,
+    commit: Option<&Commit>,
+    key: &str,
+) -> Result<Option<Vec<u8>>, git2::Error> {
+    let Some(commit) = commit else {
+        return Ok(None);
+    };
+    let tree = commit.tree()?;
+    let blob = match tree.get_name(key) {
+        Some(entry) if entry.kind() == Some(ObjectType::Blob) => entry.id(),
+        _ => return Ok(None),
+    };
+    Ok(Some(repo.find_blob(blob)?.content().to_vec()))
+}
+
+pub(crate) fn get(repo: &Repository, key: &str) -> Result<Option<Vec<u8>>, error::MetaError> {
+    if !is_valid_key(key) {
+        return Err(error::MetaError::InvalidKey(key.to_string()));
+    }
+    Ok(read(repo, tip(repo)?.as_ref(), key)?)
+}
+
+/// Replace the value of the key with the one returned by `f` (given the current one,
+/// None removes the key) in a new commit. If another writer moves the reference in the
+/// meantime, `f` is called again with the value they left - so it may run more than once.
+/// Returns the new value.
+///
+/// The commit has no parent: the compare-and-swap only needs the old value of the reference,
+/// and a chain of every past update would only grow. A reference moved back to a commit
+/// it pointed at earlier holds the same values, so it's still safe to swap.
+pub(crate) fn update<F>(
+    repo: &Repository,
+    signature: &Signature,
+    key: &str,
+    mut f: F,
+) -> Result<Option<Vec<u8>>, error::MetaError>
+where
+    F: FnMut(Option<&[u8]>) -> Result<Option<Vec<u8>>, error::MetaError>,
+{
+    if !is_valid_key(key) {
+        return Err(error::MetaError::InvalidKey(key.to_string()));
+    }
+    loop {
+        let parent = tip(repo)?;
+        let current = read(repo, parent.as_ref(), key)?;
+        let new = f(current.as_deref())?;
+        if new == current {
+            return Ok(new);
+        }
+        let tree = parent.as_ref().map(|commit| commit.tree()).transpose()?;
+        let mut builder = repo.treebuilder(tree.as_ref())?;
+        let message = match &new {
+            Some(value) => {
+                builder.insert(key, repo.blob(value)?, 0o100644)?;
+                format!("set {}", key)
+            }
+            None => {
+                builder.remove(key)?;
+                format!("remove {}", key)
+            }
+        };
+        let tree = repo.find_tree(builder.write()?)?;
+        let commit = repo.commit(None, signature, signature, &message, &tree, &[])?;
+        // a zero id only matches a missing reference - unlike creating it without `force`,
+        // which checks whether it exists before taking its lock
+        let expected = parent.as_ref().map_or(Oid::zero(), |parent| parent.id());
+        let moved = repo.reference_matching(META_REF, commit, true, expected, &message);
+        match moved {
+            Ok(_) => return Ok(new),
+            Err(err)
+                if matches!(
+                    err.code(),
+                    ErrorCode::Modified | ErrorCode::Exists | ErrorCode::Locked
+                ) =>
+            {
+                debug!("{} was moved by another writer, retrying", META_REF);
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+pub(crate) fn get_json<T>(repo: &Repository, key: &str) -> Result<Option<T>, error::MetaError>
+where
+    T: DeserializeOwned,
+{
+    match get(repo, key)? {
+        Some(value) => serde_json::from_slice(&value)
+            .map(Some)
+            .map_err(|err| error::MetaError::InvalidValue(format!("{}: {}", key, err))),
+        None => Ok(None),
+    }
+}
+
+/// Key-value store for operational state (replica status, counters, bookkeeping of
+/// extensions) kept apart from the documents, returned by `Collection::metadata`.
+///
+/// Values live in a commit of their own under `refs/yamabiko/meta`, so writing them
+/// doesn't create commits on `main`, they aren't listed along with the documents, counted
+/// in the statistics or conflict with transactions, and they're only pushed to the replicas
+/// which opted in (see `Replicator::set_push_metadata`). Every write is a commit moved into
+/// place with a compare-and-swap, so concurrent writers (in other threads or processes)
+/// never lose each other's updates. Keys can't be empty or contain a slash.
+pub struct MetaStore<'c> {
+    collection: &'c Collection,
+}
+
+impl<'c> MetaStore<'c> {
+    pub(crate) fn new(collection: &'c Collection) -> Self {
+        Self { collection }
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>, error::MetaError> {
+        get(self.collection.repository(), key)
+    }
+
+    pub fn set(&self, key: &str, value: &[u8]) -> Result<(), error::MetaError> {
+        self.update(key, |_| Ok(Some(value.to_vec())))?;
+        Ok(())
+    }
+
+    /// Returns whether the key existed
+    pub fn remove(&self, key: &str) -> Result<bool, error::MetaError> {
+        let mut existed = false;
+        self.update(key, |current| {
+            existed = current.is_some();
+            Ok(None)
+        })?;
+        Ok(existed)
+    }
+
+    /// Atomically replace the value with the one computed from the current one, see `update`
+    /// of the module. The closure may be called more than once if there are other writers.
+    pub fn update<F>(&self, key: &str, f: F) -> Result<Option<Vec<u8>>, error::MetaError>
+    where
+        F: FnMut(Option<&[u8]>) -> Result<Option<Vec<u8>>, error::MetaError>,
+    {
+        update(
+            self.collection.repository(),
+            &self.collection.signature(),
+            key,
+            f,
+        )
+    }
+
+    /// Value stored with `set_json`
+    pub fn get_json<T>(&self, key: &str) -> Result<Option<T>, error::MetaError>
+    where
+        T: DeserializeOwned,
+    {
+        get_json(self.collection.repository(), key)
+    }
+
+    pub fn set_json<T>(&self, key: &str, value: &T) -> Result<(), error::MetaError>
+    where
+        T: Serialize,
+    {
+        let value = serde_json::to_vec(value)
+            .map_err(|err| error::MetaError::InvalidValue(format!("{}: {}", key, err)))?;
+        self.set(key, &value)
+    }
+
+    /// Add `by` to the counter stored under the key (starting at 0) and return the result
+    pub fn increment(&self, key: &str, by: i64) -> Result<i64, error::MetaError> {
+        let mut result = 0;
+        self.update(key, |current| {
+            let current = match current {
+                Some(value) => std::str::from_utf8(value)
+                    .ok()
+                    .and_then(|value| value.parse::<i64>().ok())
+                    .ok_or_else(|| {
+                        error::MetaError::InvalidValue(format!("{} is not a counter", key))
+                    })?,
+                None => 0,
+            };
+            result = current.saturating_add(by);
+            Ok(Some(result.to_string().into_bytes()))
+        })?;
+        Ok(result)
+    }
+
+    /// All the keys in the store, sorted
+    pub fn keys(&self) -> Result<Vec<String>, error::MetaError> {
+        let Some(commit) = tip(self.collection.repository())? else {
+            return Ok(Vec::new());
+        };
+        Ok(commit
+            .tree()?
+            .iter()
+            .filter_map(|entry| entry.name().map(String::from))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use rstest::rstest;
+
+    use crate::{error, serialization::DataFormat, test::*, Collection, OperationTarget};
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_meta_store(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let main = db.repository().refname_to_id("refs/heads/main").unwrap();
+        let meta = db.metadata();
+        assert_eq!(meta.get("a").unwrap(), None);
+        meta.set("a", b"first").unwrap();
+        meta.set_json("b", &vec![1, 2, 3]).unwrap();
+        assert_eq!(meta.get("a").unwrap(), Some(b"first".to_vec()));
+        assert_eq!(meta.get_json::<Vec<u32>>("b").unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(meta.increment("count", 2).unwrap(), 2);
+        assert_eq!(meta.increment("count", -5).unwrap(), -3);
+        assert!(matches!(
+            meta.increment("a", 1),
+            Err(error::MetaError::InvalidValue(_))
+        ));
+        assert_eq!(meta.keys().unwrap(), vec!["a", "b", "count"]);
+        assert!(meta.remove("a").unwrap());
+        assert!(!meta.remove("a").unwrap());
+        for key in ["", "nested/key", ".."] {
+            assert_eq!(
+                meta.get(key),
+                Err(error::MetaError::InvalidKey(key.to_string()))
+            );
+        }
+
+        // kept apart from the documents
+        assert_eq!(
+            db.repository().refname_to_id("refs/heads/main").unwrap(),
+            main
+        );
+        assert_eq!(db.iter(OperationTarget::Main).unwrap().count(), 0);
+        assert_eq!(db.stats().unwrap().keys, 0);
+        assert_eq!(db.stats().unwrap().commits, 1);
+        // every update replaces the commit instead of adding to a chain
+        let tip = db
+            .repository()
+            .find_reference(super::META_REF)
+            .unwrap()
+            .peel_to_commit()
+            .unwrap();
+        assert_eq!(tip.parent_count(), 0);
+        assert_eq!(meta.keys().unwrap(), vec!["b", "count"]);
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_meta_store_concurrent_writers(#[case] data_format: DataFormat) {
+        let (db, td) = create_db(data_format);
+        let threads: Vec<_> = (0..4)
+            .map(|n| {
+                let path = td.path().to_path_buf();
+                thread::spawn(move || {
+                    let db = Collection::initialize(&path, data_format).unwrap();
+                    for i in 0..10 {
+                        db.metadata().increment("counter", 1).unwrap();
+                        db.metadata()
+                            .set(&format!("thread-{}", n), i.to_string().as_bytes())
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let meta = db.metadata();
+        assert_eq!(meta.increment("counter", 0).unwrap(), 40);
+        for n in 0..4 {
+            assert_eq!(
+                meta.get(&format!("thread-{}", n)).unwrap(),
+                Some(b"9".to_vec())
+            );
+        }
+    }
+}
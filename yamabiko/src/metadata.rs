@@ -0,0 +1,217 @@
+use std::path::Path;
+
+use git2::{ObjectType, Oid, Repository, Tree, TreeEntry};
+use serde::{Deserialize, Serialize};
+
+use crate::namespace;
+
+/// Metadata of the documents is kept in a tree at the root of the branch named `.metadata`,
+/// laid out like the root itself - the metadata of the document stored at `2e/65/a` is
+/// at `.metadata/2e/65/a`. It's written in the same commit as the document, so it moves
+/// along with it through transactions and replication. Keys with a `.metadata` segment
+/// are reserved.
+pub const METADATA_TREE: &str = ".metadata";
+
+/// The last values of the documents removed with `Collection::soft_delete` are kept in a tree
+/// named `.tombstones`, laid out like `.metadata`. The branch keeps them reachable (through
+/// squashes, reverts and gc) until the tombstones are purged. Keys with a `.tombstones` segment
+/// are reserved.
+pub const TOMBSTONES_TREE: &str = ".tombstones";
+
+/// Timestamps (seconds since the Unix epoch, from the Clock of the collection) and
+/// the number of writes of a document, see `Collection::document_meta`
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct DocumentMeta {
+    /// When the document was first written (since it was last deleted).
+    pub created_at: i64,
+    /// When the value of the document last changed.
+    pub updated_at: i64,
+    /// Number of times the value of the document changed, starting at 1.
+    pub revision: u64,
+    /// Type of the value, for documents written with `Collection::set_typed`
+    /// or `Collection::set_tagged` (see `Collection::type_of`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub type_tag: Option<String>,
+    /// Set if the document was removed with `Collection::soft_delete` and can still be
+    /// brought back with `Collection::restore`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tombstone: Option<Tombstone>,
+}
+
+/// What's left of a document removed with `Collection::soft_delete`
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Tombstone {
+    /// When the document was removed.
+    pub deleted_at: i64,
+    /// Blob of the last value of the document, kept under `.tombstones`.
+    pub blob: String,
+}
+
+impl DocumentMeta {
+    /// Metadata after the value changes at `now`, keeping the type tag.
+    /// A document written over a tombstone starts anew.
+    pub(crate) fn next(previous: Option<DocumentMeta>, now: i64) -> Self {
+        match previous.filter(|previous| previous.tombstone.is_none()) {
+            Some(previous) => Self {
+                created_at: previous.created_at,
+                updated_at: now,
+                revision: previous.revision + 1,
+                type_tag: previous.type_tag,
+                tombstone: None,
+            },
+            None => Self {
+                created_at: now,
+                updated_at: now,
+                revision: 1,
+                type_tag: None,
+                tombstone: None,
+            },
+        }
+    }
+
+    /// Metadata left behind when the document holding the blob is soft-deleted at `now`
+    pub(crate) fn buried(previous: Option<DocumentMeta>, blob: Oid, now: i64) -> Self {
+        Self {
+            tombstone: Some(Tombstone {
+                deleted_at: now,
+                blob: blob.to_string(),
+            }),
+            ..Self::next(previous, now)
+        }
+    }
+
+    /// Metadata of a document changed on both sides of a merge: created at the earlier time,
+    /// updated at the later one (with the type tag and tombstone of that side), with the higher
+    /// revision. A tombstone is kept only if the document was soft-deleted on both sides.
+    pub(crate) fn combine(self, other: DocumentMeta) -> Self {
+        let both_deleted = self.tombstone.is_some() && other.tombstone.is_some();
+        let (type_tag, tombstone) = match self.updated_at >= other.updated_at {
+            true => (self.type_tag, self.tombstone),
+            false => (other.type_tag, other.tombstone),
+        };
+        Self {
+            created_at: self.created_at.min(other.created_at),
+            updated_at: self.updated_at.max(other.updated_at),
+            revision: self.revision.max(other.revision),
+            type_tag,
+            tombstone: tombstone.filter(|_| both_deleted),
+        }
+    }
+
+    pub(crate) fn write(&self, repo: &Repository) -> Result<Oid, git2::Error> {
+        // unwrap: a struct of numbers and strings always serializes
+        repo.blob(&serde_json::to_vec(self).unwrap())
+    }
+
+    pub(crate) fn from_blob(repo: &Repository, blob: Oid) -> Result<Self, git2::Error> {
+        serde_json::from_slice(repo.find_blob(blob)?.content())
+            .map_err(|err| git2::Error::from_str(&format!("invalid document metadata: {}", err)))
+    }
+}
+
+/// Whether the entry is the tree holding the metadata of the documents
+pub(crate) fn is_metadata_tree(entry: &TreeEntry) -> bool {
+    entry.kind() == Some(ObjectType::Tree) && entry.name() == Some(METADATA_TREE)
+}
+
+/// Whether a segment of the path is `.metadata`
+pub(crate) fn in_metadata(path: &str) -> bool {
+    path.split('/').any(|segment| segment == METADATA_TREE)
+}
+
+/// Whether the entry is the tree holding the values of the soft-deleted documents
+pub(crate) fn is_tombstones_tree(entry: &TreeEntry) -> bool {
+    entry.kind() == Some(ObjectType::Tree) && entry.name() == Some(TOMBSTONES_TREE)
+}
+
+/// Whether a segment of the path is `.tombstones`
+pub(crate) fn in_tombstones(path: &str) -> bool {
+    path.split('/').any(|segment| segment == TOMBSTONES_TREE)
+}
+
+/// Path of the last value of the soft-deleted document, given its path
+pub(crate) fn tombstone_path(document_path: &str) -> String {
+    match namespace::split_path(document_path) {
+        Some((tree, path)) => format!("{}/{}/{}", tree, TOMBSTONES_TREE, path),
+        None => format!("{}/{}", TOMBSTONES_TREE, document_path),
+    }
+}
+
+/// Path of the metadata, given the path of the document. The documents of a namespace
+/// keep it in the tree of the namespace, as if it was a root of its own.
+pub(crate) fn metadata_path(document_path: &str) -> String {
+    match namespace::split_path(document_path) {
+        Some((tree, path)) => format!("{}/{}/{}", tree, METADATA_TREE, path),
+        None => format!("{}/{}", METADATA_TREE, document_path),
+    }
+}
+
+/// Metadata of the document stored at the path, None for documents written before
+/// the metadata was introduced (or without a document at the path)
+pub(crate) fn read(
+    repo: &Repository,
+    tree: &Tree,
+    document_path: &str,
+) -> Result<Option<DocumentMeta>, git2::Error> {
+    match tree.get_path(Path::new(&metadata_path(document_path))) {
+        Ok(entry) => Ok(Some(DocumentMeta::from_blob(repo, entry.id())?)),
+        Err(_) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_and_combine() {
+        let created = DocumentMeta::next(None, 10);
+        assert_eq!(
+            created,
+            DocumentMeta {
+                created_at: 10,
+                updated_at: 10,
+                revision: 1,
+                type_tag: None,
+                tombstone: None
+            }
+        );
+        let updated = DocumentMeta::next(Some(created.clone()), 20);
+        assert_eq!(
+            updated,
+            DocumentMeta {
+                created_at: 10,
+                updated_at: 20,
+                revision: 2,
+                type_tag: None,
+                tombstone: None
+            }
+        );
+        let other = DocumentMeta {
+            created_at: 5,
+            updated_at: 15,
+            revision: 3,
+            type_tag: Some(String::from("T")),
+            tombstone: None,
+        };
+        assert_eq!(
+            updated.combine(other.clone()),
+            DocumentMeta {
+                created_at: 5,
+                updated_at: 20,
+                revision: 3,
+                type_tag: None,
+                tombstone: None
+            }
+        );
+        // the tag of the side updated later
+        assert_eq!(
+            other
+                .combine(DocumentMeta::next(Some(created), 12))
+                .type_tag,
+            Some(String::from("T"))
+        );
+        assert!(in_metadata(".metadata/2e/65"));
+        assert!(!in_metadata("a.metadata/2e"));
+    }
+}
@@ -0,0 +1,82 @@
+use std::time::Duration;
+
+/// Receives measurements of the operations performed by a Collection or a Replicator,
+/// so they can be exported as counters and histograms (e.g. with the `metrics` or `prometheus`
+/// crates). Every method does nothing by default - implement only the ones you need.
+pub trait Metrics: Send + Sync {
+    /// `items` documents taking `bytes` bytes were written to `branch` in a single commit.
+    /// `latency` covers serializing, indexing and committing them.
+    fn record_set(&self, _branch: &str, _items: usize, _bytes: usize, _latency: Duration) {}
+
+    /// `items` documents were removed from `branch` in a single commit.
+    fn record_delete(&self, _branch: &str, _items: usize, _latency: Duration) {}
+
+    /// A key was looked up on `branch`.
+    fn record_get(&self, _branch: &str, _found: bool) {}
+
+    /// Data was pushed (or failed to be pushed) to the remote.
+    /// Not called when the replication was skipped because of the ReplicationMethod.
+    fn record_replication(
+        &self,
+        _remote: &str,
+        _succeeded: bool,
+        _attempts: usize,
+        _latency: Duration,
+    ) {
+    }
+}
+
+/// Discards all measurements. Used unless other Metrics are provided.
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use super::Metrics;
+
+    /// Remembers what was recorded, without the latencies
+    #[derive(Default)]
+    pub struct RecordingMetrics {
+        pub sets: Mutex<Vec<(String, usize, usize)>>,
+        pub deletes: Mutex<Vec<(String, usize)>>,
+        pub gets: Mutex<Vec<(String, bool)>>,
+        pub replications: Mutex<Vec<(String, bool, usize)>>,
+    }
+
+    impl Metrics for RecordingMetrics {
+        fn record_set(&self, branch: &str, items: usize, bytes: usize, _latency: Duration) {
+            self.sets
+                .lock()
+                .unwrap()
+                .push((branch.to_string(), items, bytes));
+        }
+
+        fn record_delete(&self, branch: &str, items: usize, _latency: Duration) {
+            self.deletes
+                .lock()
+                .unwrap()
+                .push((branch.to_string(), items));
+        }
+
+        fn record_get(&self, branch: &str, found: bool) {
+            self.gets.lock().unwrap().push((branch.to_string(), found));
+        }
+
+        fn record_replication(
+            &self,
+            remote: &str,
+            succeeded: bool,
+            attempts: usize,
+            _latency: Duration,
+        ) {
+            self.replications
+                .lock()
+                .unwrap()
+                .push((remote.to_string(), succeeded, attempts));
+        }
+    }
+}
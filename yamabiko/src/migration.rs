@@ -0,0 +1,227 @@
+use std::collections::BTreeMap;
+
+use git2::{ErrorCode, ObjectType, TreeWalkResult};
+
+use crate::{
+    debug, error::MigrationError, namespace, record, Collection, OperationTarget,
+    RepositoryAbstraction,
+};
+
+/// Reserved top-level field holding the schema version of a document.
+/// Documents without it are at version 0.
+pub const SCHEMA_VERSION_FIELD: &str = "_schema_version";
+
+/// Number of migrated documents written in a single commit
+const MIGRATION_BATCH_SIZE: usize = 500;
+
+/// Upgrades a document from the previous schema version to the one it was registered with
+pub type Migration =
+    Box<dyn Fn(serde_json::Value) -> Result<serde_json::Value, MigrationError> + Send>;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct MigrationReport {
+    /// Keys of the documents upgraded to the latest version.
+    pub migrated: Vec<String>,
+    /// Keys of the documents that were already at the latest version.
+    pub current: Vec<String>,
+    /// Keys of the documents that could not be migrated, along with the errors.
+    /// They are left untouched.
+    pub failed: Vec<(String, MigrationError)>,
+}
+
+fn documents_on_branch(
+    collection: &Collection,
+    branch: &str,
+) -> Result<Vec<(String, git2::Oid)>, MigrationError> {
+    let repo = collection.repository();
+    let tree = Collection::current_commit(repo, branch)
+        .map_err(|e| match e.code() {
+            ErrorCode::NotFound => MigrationError::InvalidOperationTarget(branch.to_string()),
+            _ => e.into(),
+        })?
+        .tree()?;
+    let mut documents = Vec::new();
+    let mut walk_error = None;
+    tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+        let Some(name) = entry.name() else {
+            return TreeWalkResult::Skip;
+        };
+        if (root.is_empty() && name.ends_with(".index")) || namespace::is_reserved_tree(entry) {
+            return TreeWalkResult::Skip;
+        }
+        if entry.kind() != Some(ObjectType::Blob) {
+            return TreeWalkResult::Ok;
+        }
+        match Collection::key_from_path(root, name) {
+            Ok(key) => documents.push((key, entry.id())),
+            Err(err) => {
+                walk_error = Some(err);
+                return TreeWalkResult::Abort;
+            }
+        }
+        TreeWalkResult::Ok
+    })?;
+    if let Some(err) = walk_error {
+        return Err(err.into());
+    }
+    Ok(documents)
+}
+
+fn upgrade(
+    migrations: &BTreeMap<u32, Migration>,
+    mut document: serde_json::Value,
+    from: u32,
+    to: u32,
+) -> Result<serde_json::Value, MigrationError> {
+    for (_version, migration) in migrations.range(from + 1..) {
+        document = migration(document)?;
+        debug!("upgraded document to version {}", _version);
+    }
+    let fields = document
+        .as_object_mut()
+        .ok_or(MigrationError::NotAnObject)?;
+    fields.insert(SCHEMA_VERSION_FIELD.to_string(), to.into());
+    Ok(document)
+}
+
+pub(crate) fn migrate_all(
+    collection: &Collection,
+    migrations: &BTreeMap<u32, Migration>,
+    target: OperationTarget,
+) -> Result<MigrationReport, MigrationError> {
+    let mut report = MigrationReport::default();
+    let branch = target
+        .writable_branch()
+        .map_err(|_| MigrationError::InvalidOperationTarget(target.to_string()))?;
+    let documents = documents_on_branch(collection, branch)?;
+    let Some(&latest) = migrations.keys().next_back() else {
+        report.current = documents.into_iter().map(|(key, _)| key).collect();
+        return Ok(report);
+    };
+    let repo = collection.repository();
+    let mut batch = Vec::new();
+    for (key, oid) in documents {
+        let blob = repo.find_blob(oid)?;
+        let content = collection
+            .open_value(blob.content())
+            .map_err(|_| MigrationError::DecryptionFailed)?;
+        let document: serde_json::Value = collection.data_format.deserialize(&content);
+        let version = document
+            .get(SCHEMA_VERSION_FIELD)
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0) as u32;
+        if version >= latest {
+            report.current.push(key);
+            continue;
+        }
+        match upgrade(migrations, document, version, latest) {
+            Ok(document) => batch.push((key, document)),
+            Err(err) => report.failed.push((key, err)),
+        }
+        if batch.len() == MIGRATION_BATCH_SIZE {
+            write_batch(collection, &mut batch, &mut report, target)?;
+        }
+    }
+    write_batch(collection, &mut batch, &mut report, target)?;
+    record!("migrated", report.migrated.len());
+    record!("failed", report.failed.len());
+    Ok(report)
+}
+
+/// Every batch is a separate commit, so an interrupted migration picks up where it stopped:
+/// documents from the committed batches already carry the latest version.
+fn write_batch(
+    collection: &Collection,
+    batch: &mut Vec<(String, serde_json::Value)>,
+    report: &mut MigrationReport,
+    target: OperationTarget,
+) -> Result<(), MigrationError> {
+    if batch.is_empty() {
+        return Ok(());
+    }
+    collection.set_batch(batch.iter().map(|(k, v)| (k, v)), target)?;
+    report.migrated.extend(batch.drain(..).map(|(key, _)| key));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+    use serde_json::json;
+
+    use crate::{error::MigrationError, serialization::DataFormat, test::*, OperationTarget};
+
+    fn add_float_val(mut document: serde_json::Value) -> Result<serde_json::Value, MigrationError> {
+        let fields = document
+            .as_object_mut()
+            .ok_or(MigrationError::NotAnObject)?;
+        if fields.get("str_val") == Some(&json!("broken")) {
+            return Err(MigrationError::Failed(String::from("broken document")));
+        }
+        fields.entry("float_val").or_insert(json!(0.0));
+        Ok(document)
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_migrate_v1_to_v2(#[case] data_format: DataFormat) {
+        let (mut db, _td) = create_db(data_format);
+        let v1_docs = (0..10).map(|i| {
+            (
+                format!("key-{}", i),
+                json!({"str_val": format!("value {}", i), "usize_val": i}),
+            )
+        });
+        db.set_batch(v1_docs, OperationTarget::Main).unwrap();
+        db.set(
+            "broken",
+            json!({"str_val": "broken", "usize_val": 0}),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        db.register_migration(1, Ok);
+        db.register_migration(2, add_float_val);
+        let commits_before = commit_count(&db, "main");
+
+        let report = db.migrate_all(OperationTarget::Main).unwrap();
+        assert_eq!(report.migrated.len(), 10);
+        assert!(report.current.is_empty());
+        assert_eq!(
+            report.failed,
+            vec![(
+                String::from("broken"),
+                MigrationError::Failed(String::from("broken document"))
+            )]
+        );
+        assert_eq!(commit_count(&db, "main"), commits_before + 1);
+        assert_eq!(
+            db.get::<ComplexDbStruct>("key-3", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            ComplexDbStruct::new(String::from("value 3"), 3, 0.0)
+        );
+
+        let report = db.migrate_all(OperationTarget::Main).unwrap();
+        assert!(report.migrated.is_empty());
+        assert_eq!(report.current.len(), 10);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(commit_count(&db, "main"), commits_before + 1);
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_migrate_non_existent_target(#[case] data_format: DataFormat) {
+        let (mut db, _td) = create_db(data_format);
+        db.register_migration(1, Ok);
+        assert_eq!(
+            db.migrate_all(OperationTarget::Transaction("missing")),
+            Err(MigrationError::InvalidOperationTarget(String::from(
+                "missing"
+            )))
+        );
+    }
+}
@@ -0,0 +1,91 @@
+use crate::index::IndexType;
+
+#[cfg(any(feature = "derive", feature = "full"))]
+pub use yamabiko_derive::YamabikoModel;
+
+/// Type stored in a Collection which declares the indexes it's meant to be queried by,
+/// so that they're defined next to the fields instead of being kept in sync by hand.
+///
+/// With the `derive` feature it can be derived, marking the indexed fields
+/// with `#[yamabiko(index = "numeric")]` (or `"sequential"`, `"collection"`).
+/// Once created with `Collection::ensure_indexes_for`, the indexes are maintained
+/// by every write like any other index.
+pub trait YamabikoModel {
+    /// Names of the indexed fields along with the types of their indexes
+    fn indexes() -> Vec<(String, IndexType)>;
+}
+
+#[cfg(all(test, any(feature = "derive", feature = "full")))]
+mod tests {
+    use std::cmp::Ordering::*;
+
+    use rstest::rstest;
+
+    use crate::{
+        index::{Index, IndexType},
+        model::YamabikoModel,
+        query::{q, QueryBuilder, ResolutionStrategy},
+        serialization::DataFormat,
+        test::*,
+        OperationTarget,
+    };
+
+    #[test]
+    fn test_derived_indexes() {
+        assert_eq!(
+            ComplexDbStruct::indexes(),
+            vec![
+                (String::from("str_val"), IndexType::Sequential),
+                (String::from("usize_val"), IndexType::Numeric)
+            ]
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_ensure_indexes_for(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.add_index("usize_val", IndexType::Numeric);
+        db.set(
+            "before",
+            ComplexDbStruct::new(String::from("a"), 1, 1.0),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let indexes = db.ensure_indexes_for::<ComplexDbStruct>();
+        assert_eq!(
+            indexes,
+            vec![
+                Index::new("str_val#sequential.index", "str_val", IndexType::Sequential),
+                Index::new("usize_val#numeric.index", "usize_val", IndexType::Numeric)
+            ]
+        );
+        assert_eq!(db.list_indexes().len(), 2);
+        db.set(
+            "after",
+            ComplexDbStruct::new(String::from("b"), 2, 1.0),
+            OperationTarget::Main,
+        )
+        .unwrap();
+
+        // backfilled for the document written before the index existed
+        let by_str = QueryBuilder::query(q("str_val", Equal, "a"))
+            .execute(&db)
+            .unwrap();
+        assert_eq!(
+            by_str.resolution_strategy,
+            ResolutionStrategy::UseIndexes(vec![indexes[0].clone()])
+        );
+        assert_eq!(by_str.keys().unwrap(), vec![String::from("before")]);
+        let by_num = QueryBuilder::query(q("usize_val", Greater, 1))
+            .execute(&db)
+            .unwrap();
+        assert_eq!(
+            by_num.resolution_strategy,
+            ResolutionStrategy::UseIndexes(vec![indexes[1].clone()])
+        );
+        assert_eq!(by_num.keys().unwrap(), vec![String::from("after")]);
+    }
+}
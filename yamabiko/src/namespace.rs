@@ -0,0 +1,912 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use git2::{
+    BranchType, Delta, ErrorCode, ObjectType, Oid, Repository, Tree, TreeEntry, TreeWalkResult,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    attachment, debug, error, index::Index, meta, metadata, serialization::DataFormat, Collection,
+    OperationTarget, WriteResult,
+};
+
+/// Documents of a namespace are kept in a tree at the root of the branch named
+/// `<namespace>.namespace`, laid out like the root itself. Keys with a segment ending like this
+/// are reserved, so keys of different namespaces (and of the collection) never clash.
+pub const NAMESPACE_SUFFIX: &str = ".namespace";
+
+/// Reference the usage of the namespaces was kept under before it moved to the metadata,
+/// deleted once the usage is stored again
+const LEGACY_USAGE_REF: &str = "refs/yamabiko/namespace-usage";
+
+/// Limits of a namespace, see `Namespace::set_quota`. `None` means there is no limit.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Quota {
+    pub max_keys: Option<u64>,
+    /// Limit of the total size of the stored values (encrypted, if the collection is).
+    pub max_bytes: Option<u64>,
+}
+
+/// Number and total size of the documents of a namespace, see `Namespace::usage`
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Usage {
+    pub keys: u64,
+    pub bytes: u64,
+}
+
+/// Usage of the namespace in the tree it was counted in
+#[derive(Serialize, Deserialize)]
+struct CountedUsage {
+    tree: String,
+    #[serde(flatten)]
+    usage: Usage,
+}
+
+/// Key in the metadata (see `Collection::metadata`) under which the usage of the namespace
+/// is kept for every branch it was written on
+fn usage_key(name: &str) -> String {
+    format!("namespace.{}.usage", name)
+}
+
+fn parse_usage(content: Option<&[u8]>) -> Result<BTreeMap<String, CountedUsage>, git2::Error> {
+    match content {
+        Some(content) => serde_json::from_slice(content)
+            .map_err(|err| git2::Error::from_str(&format!("invalid namespace usage: {}", err))),
+        None => Ok(BTreeMap::new()),
+    }
+}
+
+fn counted_usage(
+    repo: &Repository,
+    name: &str,
+) -> Result<BTreeMap<String, CountedUsage>, git2::Error> {
+    parse_usage(meta::get(repo, &usage_key(name))?.as_deref())
+}
+
+/// Remember the usage of the namespace in the tree written to the branch, dropping
+/// the branches which are gone. Stored with a compare-and-swap, so writes to other
+/// branches (or namespaces) don't drop each other's usage.
+fn store_usage(
+    collection: &Collection,
+    name: &str,
+    branch: &str,
+    tree: Oid,
+    usage: Usage,
+) -> Result<(), git2::Error> {
+    let repo = &collection.repository;
+    meta::update(repo, &collection.signature(), &usage_key(name), |current| {
+        let mut counted = parse_usage(current)?;
+        counted.retain(|branch, _| repo.find_branch(branch, BranchType::Local).is_ok());
+        counted.insert(
+            branch.to_string(),
+            CountedUsage {
+                tree: tree.to_string(),
+                usage,
+            },
+        );
+        // unwrap: a map of strings and numbers always serializes
+        Ok(Some(serde_json::to_vec(&counted).unwrap()))
+    })?;
+    match repo.find_reference(LEGACY_USAGE_REF) {
+        Ok(mut reference) => reference.delete(),
+        Err(err) if err.code() == ErrorCode::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// The tree of the namespace and the rest of the path inside it, for the paths (or the keys,
+/// see `Collection::changes_since`) of the documents of a namespace
+pub(crate) fn split_path(path: &str) -> Option<(&str, &str)> {
+    path.split_once('/')
+        .filter(|(tree, _)| tree.ends_with(NAMESPACE_SUFFIX))
+}
+
+/// Whether the entry is a tree holding a namespace
+pub(crate) fn is_namespace_tree(entry: &TreeEntry) -> bool {
+    entry.kind() == Some(ObjectType::Tree)
+        && entry
+            .name()
+            .is_some_and(|name| name.ends_with(NAMESPACE_SUFFIX))
+}
+
+/// Whether the entry is a tree which doesn't hold documents of the collection itself
+/// (attachments, a namespace or the metadata) - to be skipped when walking documents
+pub(crate) fn is_reserved_tree(entry: &TreeEntry) -> bool {
+    attachment::is_attachments_tree(entry)
+        || is_namespace_tree(entry)
+        || metadata::is_metadata_tree(entry)
+        || metadata::is_tombstones_tree(entry)
+}
+
+/// Whether a segment of the path is the name of a reserved tree (see `is_reserved_tree`) -
+/// for the root given by `Tree::walk` this means it's inside one, a key like that would clash
+pub(crate) fn in_reserved_tree(path: &str) -> bool {
+    attachment::in_attachments(path)
+        || metadata::in_metadata(path)
+        || metadata::in_tombstones(path)
+        || path
+            .split('/')
+            .any(|segment| segment.ends_with(NAMESPACE_SUFFIX))
+}
+
+/// Logically separate set of documents in the same repository as the collection, returned by
+/// `Collection::namespace`. Its documents are part of the branches like any other, so they
+/// are written by transactions and replicated along with the rest, and the writes go through
+/// the same path as the ones of the collection - the hooks and `Collection::changes_since`
+/// see them under `<namespace>.namespace/<key>`. The indexes of the collection are kept
+/// for every namespace separately, queries only see the documents of a namespace with
+/// `QueryBuilder::in_namespace`. They are not migrated nor listed by the collection.
+pub struct Namespace<'c> {
+    collection: &'c Collection,
+    name: String,
+}
+
+impl<'c> Namespace<'c> {
+    pub(crate) fn new(collection: &'c Collection, name: &str) -> Result<Self, error::KeyError> {
+        if name.is_empty() || name.contains('/') || name.contains('\0') || in_reserved_tree(name) {
+            return Err(error::KeyError::InvalidNamespace(name.to_string()));
+        }
+        Ok(Self {
+            collection,
+            name: name.to_string(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn tree_path(&self) -> String {
+        format!("{}{}", self.name, NAMESPACE_SUFFIX)
+    }
+
+    fn path_to_key(&self, key: &str) -> Result<String, error::KeyError> {
+        Ok(format!(
+            "{}/{}",
+            self.tree_path(),
+            Collection::construct_path_to_key(key)?
+        ))
+    }
+
+    fn get_content(
+        &self,
+        key: &str,
+        target: OperationTarget,
+    ) -> Result<Option<Vec<u8>>, error::GetObjectError> {
+        let path = self.path_to_key(key)?;
+        let tree = self.collection.target_tree(target)?;
+        let Ok(entry) = tree.get_path(Path::new(&path)) else {
+            return Ok(None);
+        };
+        Ok(Some(self.collection.entry_content(&entry)?))
+    }
+
+    pub fn get_raw(
+        &self,
+        key: &str,
+        target: OperationTarget,
+    ) -> Result<Option<String>, error::GetObjectError> {
+        match self.get_content(key, target)? {
+            Some(content) => Ok(Some(String::from_utf8(content)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get<D>(
+        &self,
+        key: &str,
+        target: OperationTarget,
+    ) -> Result<Option<D>, error::GetObjectError>
+    where
+        D: DeserializeOwned,
+    {
+        Ok(self
+            .get_content(key, target)?
+            .map(|content| self.collection.data_format.deserialize(&content)))
+    }
+
+    /// Same as `Collection::set_batch`, within the namespace
+    pub fn set_batch<S, I, T>(
+        &self,
+        items: I,
+        target: OperationTarget,
+    ) -> Result<Oid, error::SetObjectError>
+    where
+        S: Serialize,
+        I: IntoIterator<Item = (T, S)>,
+        T: AsRef<str>,
+    {
+        Ok(self.set_batch_with_result(items, target)?.commit)
+    }
+
+    /// Same as `Collection::set_batch_with_result`, within the namespace
+    pub fn set_batch_with_result<S, I, T>(
+        &self,
+        items: I,
+        target: OperationTarget,
+    ) -> Result<WriteResult, error::SetObjectError>
+    where
+        S: Serialize,
+        I: IntoIterator<Item = (T, S)>,
+        T: AsRef<str>,
+    {
+        self.collection.set_batch_with_indexing_fn(
+            items,
+            target,
+            DataFormat::serialize_with_indexes,
+            None,
+            Some(self),
+        )
+    }
+
+    pub fn set<S>(
+        &self,
+        key: &str,
+        value: S,
+        target: OperationTarget,
+    ) -> Result<Oid, error::SetObjectError>
+    where
+        S: Serialize,
+    {
+        self.set_batch([(key, value)], target)
+    }
+
+    /// Remove the keys from the namespace in a single commit.
+    /// Returns the number of keys actually removed.
+    pub fn delete_batch<I, T>(
+        &self,
+        keys: I,
+        target: OperationTarget,
+    ) -> Result<usize, error::SetObjectError>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<str>,
+    {
+        Ok(self
+            .collection
+            .delete_batch_in(Some(self), keys, target)?
+            .deleted)
+    }
+
+    /// Remove the key from the namespace. Returns false if there was nothing to remove.
+    pub fn delete(
+        &self,
+        key: &str,
+        target: OperationTarget,
+    ) -> Result<bool, error::SetObjectError> {
+        Ok(self.delete_batch([key], target)? > 0)
+    }
+
+    /// Same as `Collection::patch_batch`, within the namespace
+    pub fn patch_batch<I, T>(
+        &self,
+        keys: I,
+        patch: &serde_json::Value,
+        target: OperationTarget,
+    ) -> Result<WriteResult, error::PatchError>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<str>,
+    {
+        self.collection
+            .patch_batch_in(Some(self), keys, patch, target)
+    }
+
+    /// Tree of the namespace in the root, an empty one if the namespace has no documents there
+    pub(crate) fn tree(&self, root_tree: &Tree) -> Result<Tree<'c>, git2::Error> {
+        let repo = &self.collection.repository;
+        match self.tree_in(root_tree) {
+            Some(tree) => repo.find_tree(tree),
+            None => repo.find_tree(repo.treebuilder(None)?.write()?),
+        }
+    }
+
+    /// The root with the tree of the namespace replaced by the given one
+    /// (or dropped, if it's empty)
+    pub(crate) fn graft(&self, root_tree: &Tree, tree: Oid) -> Result<Oid, git2::Error> {
+        let repo = &self.collection.repository;
+        let mut tb = repo.treebuilder(Some(root_tree))?;
+        if repo.find_tree(tree)?.is_empty() {
+            if tb.get(self.tree_path())?.is_some() {
+                tb.remove(self.tree_path())?;
+            }
+        } else {
+            tb.insert(self.tree_path(), tree, 0o040000)?;
+        }
+        tb.write()
+    }
+
+    /// The indexes of the collection over the documents of the namespace
+    /// (see `Index::in_namespace`). The ones which don't have a file yet - e.g. those
+    /// of a namespace written before the index was added - are filled from the tree first.
+    pub(crate) fn indexes(
+        &self,
+        indexes: &[Index],
+        tree: &Tree,
+    ) -> Result<Vec<Index>, git2::Error> {
+        let repo = &self.collection.repository;
+        let mut scoped = Vec::new();
+        for index in indexes {
+            let index = index.in_namespace(&self.tree_path());
+            if !index.has_file(repo) {
+                debug!(
+                    "filling {} from the documents of {}",
+                    index.name(),
+                    self.name
+                );
+                let (entries, _) = self.collection.index_entries(repo, &index, tree)?;
+                index.clear(repo)?;
+                index.add_entries(repo, &entries)?;
+            }
+            scoped.push(index);
+        }
+        Ok(scoped)
+    }
+
+    /// All the keys in the namespace, sorted
+    pub fn keys(&self, target: OperationTarget) -> Result<Vec<String>, error::GetObjectError> {
+        let repo = &self.collection.repository;
+        let root_tree = self.collection.target_tree(target)?;
+        let Ok(entry) = root_tree.get_path(Path::new(&self.tree_path())) else {
+            return Ok(Vec::new());
+        };
+        let tree = repo.find_tree(entry.id())?;
+        let mut keys = Vec::new();
+        let mut walk_error = None;
+        tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+            if is_reserved_tree(entry) {
+                return TreeWalkResult::Skip;
+            }
+            if entry.kind() != Some(ObjectType::Blob) {
+                return TreeWalkResult::Ok;
+            }
+            match Collection::key_from_path(root, entry.name().unwrap_or_default()) {
+                Ok(key) => {
+                    keys.push(key);
+                    TreeWalkResult::Ok
+                }
+                Err(err) => {
+                    walk_error = Some(err);
+                    TreeWalkResult::Abort
+                }
+            }
+        })?;
+        if let Some(err) = walk_error {
+            return Err(err.into());
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// Number of the documents in the namespace
+    pub fn count(&self, target: OperationTarget) -> Result<usize, error::GetObjectError> {
+        Ok(self.keys(target)?.len())
+    }
+
+    fn quota_config(&self, limit: &str) -> String {
+        format!("yamabiko.namespace.{}.{}", self.name, limit)
+    }
+
+    /// Reject the writes which would take the namespace over the limits of the quota.
+    /// Writes which don't add to the usage (like deletes) are accepted even if it's over
+    /// the limits already, e.g. after lowering them. The quota is persisted in the config
+    /// of the repository and replaces the previous one, `Quota::default()` lifts it.
+    ///
+    /// Writes to transactions are checked against the usage on the transaction, merging
+    /// the transaction (or reverting main) is never rejected.
+    pub fn set_quota(&self, quota: Quota) -> Result<(), git2::Error> {
+        let mut config = self.collection.repository.config()?;
+        for (limit, value) in [("maxkeys", quota.max_keys), ("maxbytes", quota.max_bytes)] {
+            let name = self.quota_config(limit);
+            match value {
+                Some(value) => config.set_i64(&name, value.min(i64::MAX as u64) as i64)?,
+                None => match config.remove(&name) {
+                    Err(err) if err.code() == ErrorCode::NotFound => {}
+                    result => result?,
+                },
+            }
+        }
+        Ok(())
+    }
+
+    pub fn quota(&self) -> Result<Quota, git2::Error> {
+        let config = self.collection.repository.config()?;
+        let limit = |limit| match config.get_i64(&self.quota_config(limit)) {
+            Ok(value) => Ok(Some(value.max(0) as u64)),
+            Err(err) if err.code() == ErrorCode::NotFound => Ok(None),
+            Err(err) => Err(err),
+        };
+        Ok(Quota {
+            max_keys: limit("maxkeys")?,
+            max_bytes: limit("maxbytes")?,
+        })
+    }
+
+    /// Number and total size of the documents in the namespace.
+    ///
+    /// The usage is kept up to date by the writes of the namespace (for every branch),
+    /// it's only counted from the documents once the namespace was changed otherwise -
+    /// by merging a transaction or reverting. Reading it never writes anything.
+    pub fn usage(&self, target: OperationTarget) -> Result<Usage, error::GetObjectError> {
+        let root_tree = self.collection.target_tree(target)?;
+        Ok(self.usage_in(&root_tree)?)
+    }
+
+    fn tree_in(&self, root_tree: &Tree) -> Option<Oid> {
+        root_tree
+            .get_path(Path::new(&self.tree_path()))
+            .ok()
+            .map(|entry| entry.id())
+    }
+
+    fn usage_in(&self, root_tree: &Tree) -> Result<Usage, git2::Error> {
+        let repo = &self.collection.repository;
+        let Some(tree) = self.tree_in(root_tree) else {
+            return Ok(Usage::default());
+        };
+        let tree_id = tree.to_string();
+        if let Some(counted) = counted_usage(repo, &self.name)?
+            .into_values()
+            .find(|counted| counted.tree == tree_id)
+        {
+            return Ok(counted.usage);
+        }
+        debug!("counting the documents of {}", self.name);
+        let odb = repo.odb()?;
+        let mut usage = Usage::default();
+        let mut walk_error = None;
+        repo.find_tree(tree)?
+            .walk(git2::TreeWalkMode::PreOrder, |_, entry| {
+                if is_reserved_tree(entry) {
+                    return TreeWalkResult::Skip;
+                }
+                if entry.kind() != Some(ObjectType::Blob) {
+                    return TreeWalkResult::Ok;
+                }
+                match odb.read_header(entry.id()) {
+                    Ok((size, _)) => {
+                        usage.keys += 1;
+                        usage.bytes += size as u64;
+                        TreeWalkResult::Ok
+                    }
+                    Err(err) => {
+                        walk_error = Some(err);
+                        TreeWalkResult::Abort
+                    }
+                }
+            })?;
+        if let Some(err) = walk_error {
+            return Err(err);
+        }
+        Ok(usage)
+    }
+
+    /// Usage once the root is replaced with the new one, counted from the documents
+    /// which changed. Fails if it would exceed the quota.
+    pub(crate) fn usage_after(
+        &self,
+        old_root: &Tree,
+        new_root: &Tree,
+    ) -> Result<Usage, error::SetObjectError> {
+        let repo = &self.collection.repository;
+        let before = self.usage_in(old_root)?;
+        let old_tree = self
+            .tree_in(old_root)
+            .map(|id| repo.find_tree(id))
+            .transpose()?;
+        let new_tree = self
+            .tree_in(new_root)
+            .map(|id| repo.find_tree(id))
+            .transpose()?;
+        let diff = repo.diff_tree_to_tree(old_tree.as_ref(), new_tree.as_ref(), None)?;
+        let odb = repo.odb()?;
+        let size = |id: Oid| -> Result<i64, git2::Error> { Ok(odb.read_header(id)?.0 as i64) };
+        let (mut keys, mut bytes) = (before.keys as i64, before.bytes as i64);
+        for delta in diff.deltas() {
+            let (old, new) = (delta.old_file(), delta.new_file());
+            if new
+                .path()
+                .or(old.path())
+                .and_then(|path| path.to_str())
+                .is_some_and(in_reserved_tree)
+            {
+                continue;
+            }
+            match delta.status() {
+                Delta::Added => {
+                    keys += 1;
+                    bytes += size(new.id())?;
+                }
+                Delta::Deleted => {
+                    keys -= 1;
+                    bytes -= size(old.id())?;
+                }
+                _ => bytes += size(new.id())? - size(old.id())?,
+            }
+        }
+        let after = Usage {
+            keys: keys.max(0) as u64,
+            bytes: bytes.max(0) as u64,
+        };
+        let quota = self.quota()?;
+        for (which, limit, before, after) in [
+            (
+                error::QuotaLimit::Keys,
+                quota.max_keys,
+                before.keys,
+                after.keys,
+            ),
+            (
+                error::QuotaLimit::Bytes,
+                quota.max_bytes,
+                before.bytes,
+                after.bytes,
+            ),
+        ] {
+            if limit.is_some_and(|limit| after > limit && after > before) {
+                debug!(
+                    "{} would exceed the {} quota of {}",
+                    after, which, self.name
+                );
+                return Err(error::SetObjectError::QuotaExceeded {
+                    namespace: self.name.clone(),
+                    which,
+                });
+            }
+        }
+        Ok(after)
+    }
+
+    /// Remember the usage as of the root written by the namespace to the branch
+    pub(crate) fn store_usage(
+        &self,
+        branch: &str,
+        root_tree: &Tree,
+        usage: Usage,
+    ) -> Result<(), git2::Error> {
+        match self.tree_in(root_tree) {
+            Some(tree) => store_usage(self.collection, &self.name, branch, tree, usage),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering::*;
+    use std::collections::HashMap;
+
+    use rstest::rstest;
+
+    use super::Quota;
+    use crate::{
+        error::{KeyError, QuotaLimit, SetObjectError},
+        index::IndexType,
+        query::{q, QueryBuilder, ResolutionStrategy},
+        serialization::DataFormat,
+        test::*,
+        ChangeKind, KeyChange, OperationTarget,
+    };
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_namespaces_dont_collide(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.add_index("str_val", IndexType::Sequential);
+        let users = db.namespace("users").unwrap();
+        let orders = db.namespace("orders").unwrap();
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("root")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        users
+            .set_batch(
+                [
+                    ("a", SampleDbStruct::new(String::from("user"))),
+                    ("nested/b", SampleDbStruct::new(String::from("user"))),
+                ],
+                OperationTarget::Main,
+            )
+            .unwrap();
+        orders
+            .set(
+                "a",
+                SampleDbStruct::new(String::from("order")),
+                OperationTarget::Main,
+            )
+            .unwrap();
+
+        for (value, expected) in [
+            (db.get::<SampleDbStruct>("a", OperationTarget::Main), "root"),
+            (
+                users.get::<SampleDbStruct>("a", OperationTarget::Main),
+                "user",
+            ),
+            (
+                orders.get::<SampleDbStruct>("a", OperationTarget::Main),
+                "order",
+            ),
+        ] {
+            assert_eq!(value.unwrap().unwrap().str_val, expected);
+        }
+        assert_eq!(
+            users.keys(OperationTarget::Main).unwrap(),
+            vec!["a", "nested/b"]
+        );
+        assert_eq!(orders.count(OperationTarget::Main).unwrap(), 1);
+        assert_eq!(
+            users.get_raw("missing", OperationTarget::Main).unwrap(),
+            None
+        );
+
+        // the collection only sees its own documents
+        let result = QueryBuilder::query(q("str_val", Equal, "user"))
+            .execute(&db)
+            .unwrap();
+        assert_eq!(result.count, 0);
+        let result = QueryBuilder::query(q("str_val", Equal, "root"))
+            .execute(&db)
+            .unwrap();
+        assert_eq!(result.count, 1);
+
+        assert!(users.delete("a", OperationTarget::Main).unwrap());
+        assert!(!users.delete("a", OperationTarget::Main).unwrap());
+        assert_eq!(users.keys(OperationTarget::Main).unwrap(), vec!["nested/b"]);
+        assert!(db
+            .get::<SampleDbStruct>("a", OperationTarget::Main)
+            .unwrap()
+            .is_some());
+        assert!(orders
+            .get::<SampleDbStruct>("a", OperationTarget::Main)
+            .unwrap()
+            .is_some());
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_namespace_indexes_and_queries(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let users = db.namespace("users").unwrap();
+        let value = |name: &str| SampleDbStruct::new(name.to_string());
+        // written before the index was added, which fills it for the namespace as well
+        users
+            .set("a", value("user"), OperationTarget::Main)
+            .unwrap();
+        let index = db.add_index("str_val", IndexType::Sequential);
+        let since = users
+            .set("b", value("other"), OperationTarget::Main)
+            .unwrap();
+        users
+            .set("c", value("user"), OperationTarget::Main)
+            .unwrap();
+        db.set("c", value("user"), OperationTarget::Main).unwrap();
+
+        let query = || QueryBuilder::query(q("str_val", Equal, "user")).in_namespace(&users);
+        let result = query().execute(&db).unwrap();
+        assert_eq!(
+            result.resolution_strategy,
+            ResolutionStrategy::UseIndexes(vec![index.in_namespace("users.namespace")])
+        );
+        let mut keys = result.keys().unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["a", "c"]);
+        let result = QueryBuilder::query(q("str_val", Equal, "user"))
+            .execute(&db)
+            .unwrap();
+        assert_eq!(result.keys().unwrap(), vec!["c"]);
+
+        let changes = db.changes_since(since, OperationTarget::Main).unwrap();
+        assert_eq!(changes.len(), 2);
+        for key in ["users.namespace/c", "c"] {
+            assert!(changes.contains(&KeyChange {
+                key: key.to_string(),
+                kind: ChangeKind::Added
+            }));
+        }
+
+        // the writes of the result go to the namespace too
+        let result = query().execute(&db).unwrap();
+        assert_eq!(result.delete_all(OperationTarget::Main).unwrap(), 2);
+        assert_eq!(users.keys(OperationTarget::Main).unwrap(), vec!["b"]);
+        assert_eq!(query().execute(&db).unwrap().count, 0);
+        assert!(db
+            .get::<SampleDbStruct>("c", OperationTarget::Main)
+            .unwrap()
+            .is_some());
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_namespace_in_transaction(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let users = db.namespace("users").unwrap();
+        let t = db.new_transaction(None).unwrap();
+        users
+            .set(
+                "a",
+                SampleDbStruct::new(String::from("a")),
+                OperationTarget::Transaction(&t),
+            )
+            .unwrap();
+        assert_eq!(users.count(OperationTarget::Main).unwrap(), 0);
+        db.apply_transaction(&t, crate::ConflictResolution::Overwrite)
+            .unwrap();
+        assert_eq!(users.count(OperationTarget::Main).unwrap(), 1);
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_namespace_quota(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let users = db.namespace("users").unwrap();
+        let orders = db.namespace("orders").unwrap();
+        users
+            .set_quota(Quota {
+                max_keys: Some(2),
+                max_bytes: None,
+            })
+            .unwrap();
+        let value = |name: &str| SampleDbStruct::new(name.to_string());
+        users
+            .set_batch(
+                [("a", value("a")), ("b", value("b"))],
+                OperationTarget::Main,
+            )
+            .unwrap();
+        assert_eq!(
+            users.set("c", value("c"), OperationTarget::Main),
+            Err(SetObjectError::QuotaExceeded {
+                namespace: String::from("users"),
+                which: QuotaLimit::Keys
+            })
+        );
+        assert_eq!(users.count(OperationTarget::Main).unwrap(), 2);
+        // replacing a value doesn't add a key
+        users.set("a", value("aa"), OperationTarget::Main).unwrap();
+        // other namespaces and the collection still accept writes
+        orders
+            .set_batch(
+                [("a", value("a")), ("b", value("b")), ("c", value("c"))],
+                OperationTarget::Main,
+            )
+            .unwrap();
+        db.set("c", value("c"), OperationTarget::Main).unwrap();
+
+        let usage = users.usage(OperationTarget::Main).unwrap();
+        assert_eq!(usage.keys, 2);
+        let stored: u64 = ["aa", "b"]
+            .map(|name| {
+                data_format
+                    .serialize_with_indexes(value(name), &mut HashMap::new())
+                    .len() as u64
+            })
+            .iter()
+            .sum();
+        assert_eq!(usage.bytes, stored);
+
+        assert!(users.delete("b", OperationTarget::Main).unwrap());
+        users.set("c", value("c"), OperationTarget::Main).unwrap();
+
+        users
+            .set_quota(Quota {
+                max_keys: None,
+                max_bytes: Some(usage.bytes),
+            })
+            .unwrap();
+        assert_eq!(
+            users.set("d", value("d"), OperationTarget::Main),
+            Err(SetObjectError::QuotaExceeded {
+                namespace: String::from("users"),
+                which: QuotaLimit::Bytes
+            })
+        );
+        users.set_quota(Quota::default()).unwrap();
+        assert_eq!(users.quota().unwrap(), Quota::default());
+        users.set("d", value("d"), OperationTarget::Main).unwrap();
+        assert_eq!(users.usage(OperationTarget::Main).unwrap().keys, 3);
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_namespace_usage_through_merges(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let users = db.namespace("users").unwrap();
+        let value = |name: &str| SampleDbStruct::new(name.to_string());
+        users.set("a", value("a"), OperationTarget::Main).unwrap();
+        let t = db.new_transaction(None).unwrap();
+        users
+            .set_batch(
+                [("b", value("b")), ("c", value("c"))],
+                OperationTarget::Transaction(&t),
+            )
+            .unwrap();
+        assert_eq!(
+            users.usage(OperationTarget::Transaction(&t)).unwrap().keys,
+            3
+        );
+        users.delete("a", OperationTarget::Main).unwrap();
+        assert_eq!(users.usage(OperationTarget::Main).unwrap().keys, 0);
+        db.apply_transaction(&t, crate::ConflictResolution::Overwrite)
+            .unwrap();
+        assert_eq!(users.usage(OperationTarget::Main).unwrap().keys, 2);
+        db.revert_n_commits(1, OperationTarget::Main, false)
+            .unwrap();
+        assert_eq!(
+            users.usage(OperationTarget::Main).unwrap().keys,
+            users.count(OperationTarget::Main).unwrap() as u64
+        );
+        assert_eq!(users.usage(OperationTarget::Main).unwrap().keys, 0);
+    }
+
+    #[test]
+    fn test_namespace_usage_per_branch() {
+        let (db, _td) = create_db(DataFormat::Json);
+        let users = db.namespace("users").unwrap();
+        let value = |name: &str| SampleDbStruct::new(name.to_string());
+        let t = db.new_transaction(None).unwrap();
+        users.set("a", value("a"), OperationTarget::Main).unwrap();
+        users
+            .set("b", value("b"), OperationTarget::Transaction(&t))
+            .unwrap();
+        users.set("c", value("c"), OperationTarget::Main).unwrap();
+        // both branches keep their own count
+        let counted = super::counted_usage(db.repository(), "users").unwrap();
+        assert_eq!(counted.keys().collect::<Vec<_>>(), vec!["main", t.as_str()]);
+        assert_eq!(counted["main"].usage.keys, 2);
+        assert_eq!(counted[t.as_str()].usage.keys, 1);
+
+        // reading the usage doesn't write anything, even if it had to be counted
+        db.apply_transaction(&t, crate::ConflictResolution::Overwrite)
+            .unwrap();
+        let meta = db
+            .repository()
+            .refname_to_id(crate::meta::META_REF)
+            .unwrap();
+        assert_eq!(users.usage(OperationTarget::Main).unwrap().keys, 3);
+        assert_eq!(
+            db.repository()
+                .refname_to_id(crate::meta::META_REF)
+                .unwrap(),
+            meta
+        );
+        // the gone transaction is dropped with the next write
+        users.set("d", value("d"), OperationTarget::Main).unwrap();
+        let counted = super::counted_usage(db.repository(), "users").unwrap();
+        assert_eq!(counted.keys().collect::<Vec<_>>(), vec!["main"]);
+        assert_eq!(counted["main"].usage.keys, 4);
+    }
+
+    #[test]
+    fn test_reserved_names() {
+        let (db, _td) = create_db(DataFormat::Json);
+        for name in ["", "a/b", "a.namespace", "a.attachments"] {
+            assert_eq!(
+                db.namespace(name).err(),
+                Some(KeyError::InvalidNamespace(name.to_string()))
+            );
+        }
+        assert_eq!(
+            db.set(
+                "users.namespace/a",
+                SampleDbStruct::new(String::from("a")),
+                OperationTarget::Main
+            ),
+            Err(SetObjectError::InvalidKey(KeyError::Reserved(
+                String::from("users.namespace/a")
+            )))
+        );
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use git2::Oid;
+use serde::Serialize;
+
+use crate::serialization::DataFormat;
+use crate::{debug, error, Collection, OperationTarget, WriteResult};
+
+/// Maximum number of items the writer puts in a single commit
+const MAX_COALESCED_ITEMS: usize = 10_000;
+
+struct WriteRequest {
+    branch: String,
+    items: Vec<(String, Vec<u8>)>,
+    respond: Sender<Result<Oid, error::SetObjectError>>,
+}
+
+/// Handle to a writer thread which owns its own Collection and is the only one
+/// updating the branches through it.
+///
+/// A Collection can't be shared between threads (git2::Repository isn't Sync), so concurrent
+/// writers would have to take turns on a mutex, each creating a separate commit.
+/// Writes submitted through a WritePipeline are queued instead: the writer waits up to `window`
+/// after the first pending request for more of them to arrive and commits them all at once,
+/// one commit per branch. Every caller blocks until the commit containing its write is created.
+///
+/// The handle can be cloned and sent to other threads. The writer stops once all the handles
+/// are dropped.
+#[derive(Clone)]
+pub struct WritePipeline {
+    sender: Sender<WriteRequest>,
+    data_format: DataFormat,
+}
+
+impl WritePipeline {
+    pub(crate) fn start(
+        collection: &Collection,
+        window: Duration,
+    ) -> Result<Self, error::InitializationError> {
+        let writer = collection.reopen()?;
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || Self::run(writer, receiver, window));
+        Ok(Self {
+            sender,
+            data_format: collection.data_format,
+        })
+    }
+
+    pub fn set<S>(
+        &self,
+        key: &str,
+        value: S,
+        target: OperationTarget,
+    ) -> Result<Oid, error::PipelineError>
+    where
+        S: Serialize,
+    {
+        self.set_batch([(key, value)], target)
+    }
+
+    /// Items of a single call always end up in the same commit.
+    /// Returns the commit they were written in.
+    pub fn set_batch<S, I, T>(
+        &self,
+        items: I,
+        target: OperationTarget,
+    ) -> Result<Oid, error::PipelineError>
+    where
+        S: Serialize,
+        I: IntoIterator<Item = (T, S)>,
+        T: AsRef<str>,
+    {
+        let items = items
+            .into_iter()
+            .map(|(key, value)| {
+                let data = self
+                    .data_format
+                    .serialize_with_indexes(value, &mut HashMap::new());
+                (key.as_ref().to_string(), data)
+            })
+            .collect();
+        self.set_batch_raw(items, target)
+    }
+
+    pub fn set_batch_raw(
+        &self,
+        items: Vec<(String, Vec<u8>)>,
+        target: OperationTarget,
+    ) -> Result<Oid, error::PipelineError> {
+        let branch = target.writable_branch()?;
+        let (respond, response) = mpsc::channel();
+        self.sender
+            .send(WriteRequest {
+                branch: branch.to_string(),
+                items,
+                respond,
+            })
+            .map_err(|_| error::PipelineError::WriterStopped)?;
+        Ok(response
+            .recv()
+            .map_err(|_| error::PipelineError::WriterStopped)??)
+    }
+
+    fn run(collection: Collection, receiver: Receiver<WriteRequest>, window: Duration) {
+        while let Ok(first) = receiver.recv() {
+            let deadline = Instant::now() + window;
+            let mut items = first.items.len();
+            let mut requests = vec![first];
+            while items < MAX_COALESCED_ITEMS {
+                match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(request) => {
+                        items += request.items.len();
+                        requests.push(request);
+                    }
+                    Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            debug!("Coalesced {} write requests", requests.len());
+            while let Some(branch) = requests.first().map(|r| r.branch.clone()) {
+                let (on_branch, rest) = requests.into_iter().partition(|r| r.branch == branch);
+                requests = rest;
+                Self::commit(&collection, &branch, on_branch);
+            }
+        }
+    }
+
+    fn commit(collection: &Collection, branch: &str, requests: Vec<WriteRequest>) {
+        let target = OperationTarget::Branch(branch);
+        let items = requests
+            .iter()
+            .flat_map(|r| r.items.iter().map(|(k, v)| (k, v.as_slice())));
+        match collection.set_batch_with_indexing_fn(
+            items,
+            target,
+            DataFormat::serialize_with_indexes_raw,
+            None,
+            None,
+        ) {
+            Ok(WriteResult { commit, .. }) => {
+                for request in requests {
+                    // the caller is gone if sending fails, nobody to report to
+                    let _ = request.respond.send(Ok(commit));
+                }
+            }
+            // nothing was written - retry the requests one by one,
+            // so that only the ones at fault get the error
+            Err(_) if requests.len() > 1 => {
+                for request in requests {
+                    Self::commit(collection, branch, vec![request]);
+                }
+            }
+            Err(err) => {
+                if let Some(request) = requests.into_iter().next() {
+                    let _ = request.respond.send(Err(err));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use git2::BranchType;
+    use rstest::rstest;
+
+    use crate::{error, serialization::DataFormat, test::*, OperationTarget};
+
+    #[test]
+    fn test_concurrent_writers() {
+        let (db, _td) = create_db(DataFormat::Json);
+        let pipeline = db.write_pipeline(Duration::from_millis(5)).unwrap();
+        let writers: Vec<_> = (0..16)
+            .map(|t| {
+                let pipeline = pipeline.clone();
+                thread::spawn(move || {
+                    for i in 0..1000 {
+                        pipeline
+                            .set(
+                                &format!("key-{}-{}", t, i),
+                                SampleDbStruct::new(format!("{} {}", t, i)),
+                                OperationTarget::Main,
+                            )
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        for t in 0..16 {
+            for i in 0..1000 {
+                assert_eq!(
+                    db.get::<SampleDbStruct>(&format!("key-{}-{}", t, i), OperationTarget::Main)
+                        .unwrap()
+                        .unwrap(),
+                    SampleDbStruct::new(format!("{} {}", t, i))
+                );
+            }
+        }
+        let mut revwalk = db.repository().revwalk().unwrap();
+        let head = db
+            .repository()
+            .find_branch("main", BranchType::Local)
+            .unwrap()
+            .get()
+            .target()
+            .unwrap();
+        revwalk.push(head).unwrap();
+        let commits = revwalk.count();
+        assert!(commits < 16_000 / 4, "{} commits", commits);
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_pipeline_errors(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.set_value_limit(64).unwrap();
+        let pipeline = Arc::new(db.write_pipeline(Duration::from_millis(50)).unwrap());
+        let too_large = {
+            let pipeline = pipeline.clone();
+            thread::spawn(move || {
+                pipeline.set(
+                    "large",
+                    SampleDbStruct::new("x".repeat(100)),
+                    OperationTarget::Main,
+                )
+            })
+        };
+        let small = pipeline.set(
+            "small",
+            SampleDbStruct::new(String::from("x")),
+            OperationTarget::Main,
+        );
+        assert!(matches!(
+            too_large.join().unwrap(),
+            Err(error::PipelineError::Set(
+                error::SetObjectError::ValueTooLarge { .. }
+            ))
+        ));
+        assert!(small.is_ok());
+        assert!(db
+            .get::<SampleDbStruct>("small", OperationTarget::Main)
+            .unwrap()
+            .is_some());
+        assert_eq!(
+            pipeline.set(
+                "a",
+                SampleDbStruct::new(String::from("x")),
+                OperationTarget::Commit(git2::Oid::zero())
+            ),
+            Err(error::PipelineError::Set(
+                error::SetObjectError::ReadOnlyTarget
+            ))
+        );
+    }
+
+    #[cfg(any(feature = "encryption", feature = "full"))]
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_pipeline_keeps_configuration(#[case] data_format: DataFormat) {
+        use crate::{clock::MockClock, encryption::Encryption, index::IndexType, Collection};
+
+        let td = tempfile::tempdir().unwrap();
+        let db = Collection::initialize(td.path(), data_format)
+            .unwrap()
+            .with_clock(Arc::new(MockClock::new(1_700_000_000)))
+            .with_encryption(Encryption::new(&[3; 32]));
+        db.add_index("str_val", IndexType::Sequential);
+        let pipeline = db.write_pipeline(Duration::from_millis(5)).unwrap();
+        let commit = pipeline
+            .set(
+                "a",
+                SampleDbStruct::new(String::from("secret value")),
+                OperationTarget::Main,
+            )
+            .unwrap();
+        let repo = db.repository();
+        assert_eq!(
+            repo.find_commit(commit).unwrap().time().seconds(),
+            1_700_000_000
+        );
+        let path = Collection::construct_path_to_key("a").unwrap();
+        let stored = repo
+            .find_commit(commit)
+            .unwrap()
+            .tree()
+            .unwrap()
+            .get_path(std::path::Path::new(&path))
+            .unwrap()
+            .to_object(repo)
+            .unwrap()
+            .peel_to_blob()
+            .unwrap();
+        assert!(!stored
+            .content()
+            .windows(b"secret".len())
+            .any(|w| w == b"secret"));
+        assert!(db.index_list().is_empty());
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap(),
+            Some(SampleDbStruct::new(String::from("secret value")))
+        );
+    }
+}
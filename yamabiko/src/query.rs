@@ -1,13 +1,18 @@
 use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
 use std::ops::{BitAnd, BitOr};
 
 use git2::{ObjectType, Oid, Repository, Tree, TreeWalkResult};
+use serde::Serialize;
 
 use crate::field::Field;
-use crate::index::Index;
+use crate::index::{Index, IndexType};
 use crate::serialization::DataFormat;
-use crate::{debug, error, Collection, RepositoryAbstraction};
+use crate::{
+    debug, error, metadata, namespace, record, Collection, OperationTarget, RepositoryAbstraction,
+    WriteResult,
+};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum ResolutionStrategy {
@@ -16,9 +21,13 @@ pub enum ResolutionStrategy {
 }
 
 #[derive(Default)]
-pub struct QueryBuilder {
+pub struct QueryBuilder<'t> {
     query: Option<QueryGroup>,
     limit: Option<usize>,
+    target: Option<OperationTarget<'t>>,
+    order_by_updated: bool,
+    /// Name of the namespace set with `in_namespace`
+    namespace: Option<String>,
 }
 
 pub fn q<V: Into<Field>>(field: &str, comparator: Ordering, value: V) -> QueryGroup {
@@ -27,7 +36,20 @@ pub fn q<V: Into<Field>>(field: &str, comparator: Ordering, value: V) -> QueryGr
         field_query: FieldQuery {
             field: field.to_string(),
             value: value.into(),
-            comparator,
+            operator: Operator::Compare(comparator),
+        },
+    }
+}
+
+/// Match the documents whose field is an array with the value among its elements.
+/// Uses the `IndexType::Collection` index of the field, if there is one.
+pub fn contains<V: Into<Field>>(field: &str, value: V) -> QueryGroup {
+    QueryGroup {
+        next_group: Vec::new(),
+        field_query: FieldQuery {
+            field: field.to_string(),
+            value: value.into(),
+            operator: Operator::Contains,
         },
     }
 }
@@ -39,161 +61,181 @@ pub struct QueryGroup {
 }
 
 impl QueryGroup {
-    fn resolve(&self, data_format: &DataFormat, data: &[u8]) -> bool {
-        let mut result = data_format.match_field(
-            data,
-            &self.field_query.field,
-            &self.field_query.value,
-            self.field_query.comparator,
-        );
-        for group in &self.next_group {
-            result = match group.1 {
-                Chain::And => result && group.0.resolve(data_format, data),
-                Chain::Or => result || group.0.resolve(data_format, data),
-            };
+    /// Match documents matched by both this and the other group, same as `self & other`
+    pub fn and(self, other: QueryGroup) -> Self {
+        self & other
+    }
+
+    /// Match documents matched by this or the other group, same as `self | other`
+    pub fn or(self, other: QueryGroup) -> Self {
+        self | other
+    }
+
+    /// Groups chained with `and` and `or` are evaluated from left to right, so `a | b & c`
+    /// means `(a | b) & c` - nest the groups to say otherwise, as in `a | (b & c)`.
+    fn compile(&self) -> Expr<'_> {
+        let mut expr = Expr::Condition(&self.field_query);
+        for (group, chain) in &self.next_group {
+            expr = expr.chain(*chain, group.compile());
         }
-        result
+        expr
     }
+}
 
-    fn resolution_strategy<'a, 'b>(
-        &'a self,
-        indexes: &'b HashMap<String, Index>,
-    ) -> ResolutionStrategy
-    where
-        'b: 'a,
-    {
-        let mut indexes_used: Vec<Index> = Vec::new();
-        match indexes.get(&self.field_query.field) {
-            Some(index) => indexes_used.push(index.clone()),
-            None => return ResolutionStrategy::Scan,
-        }
-        for group in &self.next_group {
-            match group.0.resolution_strategy(indexes) {
-                ResolutionStrategy::Scan => match group.1 {
-                    Chain::And => return ResolutionStrategy::UseIndexes(indexes_used),
-                    Chain::Or => return ResolutionStrategy::Scan,
-                },
-                ResolutionStrategy::UseIndexes(mut ind) => indexes_used.append(&mut ind),
+/// Query compiled into a tree, with the chains of `and` and `or` flattened
+#[derive(Debug)]
+enum Expr<'q> {
+    Condition(&'q FieldQuery),
+    All(Vec<Expr<'q>>),
+    Any(Vec<Expr<'q>>),
+}
+
+impl<'q> Expr<'q> {
+    fn chain(self, chain: Chain, other: Expr<'q>) -> Self {
+        match (chain, self) {
+            (Chain::And, Expr::All(mut all)) => {
+                all.push(other);
+                Expr::All(all)
+            }
+            (Chain::Or, Expr::Any(mut any)) => {
+                any.push(other);
+                Expr::Any(any)
             }
+            (Chain::And, expr) => Expr::All(vec![expr, other]),
+            (Chain::Or, expr) => Expr::Any(vec![expr, other]),
         }
-        ResolutionStrategy::UseIndexes(indexes_used)
     }
 
-    #[allow(clippy::too_many_arguments)]
-    fn resolve_with_indexes<'a, 'i, I>(
-        &self,
-        index_iterator: &mut I,
-        repo: &Repository,
-        results: &mut HashSet<Oid>,
-        chain: Chain,
-        data_format: &DataFormat,
-        main_tree: &git2::Tree,
-        limit: usize,
-    ) -> Result<(), error::QueryError>
-    where
-        I: Iterator<Item = &'i Index>,
-    {
-        match index_iterator.next() {
-            Some(index) => {
-                let git_index = index.git_index(repo);
-                let mut new_res = HashSet::new();
-                let mut cur = match self.field_query.comparator {
-                    Ordering::Less => 0,
-                    Ordering::Equal => git_index
-                        .find_prefix(self.field_query.prefix_query())
-                        .unwrap_or(0),
-                    Ordering::Greater => match git_index.len() {
-                        0 => 0,
-                        _ => git_index.len() - 1,
-                    },
-                };
-                while let Some(entry) = git_index.get(cur) {
-                    let val = Field::from_index_entry(&entry);
-                    debug!("found the following value in the index: {:?}", val);
-                    if let Some(v) = val {
-                        let cmp = self.field_query.value.partial_cmp(&v);
-                        if cmp == Some(self.field_query.comparator) {
-                            new_res.insert(entry.id);
-                        } else if cmp.is_some() {
-                            break;
-                        }
-                    }
-                    if (cur == 0 && self.field_query.comparator == Ordering::Greater)
-                        || (cur >= git_index.len()
-                            && self.field_query.comparator != Ordering::Greater)
-                    {
-                        break;
-                    }
-                    match self.field_query.comparator {
-                        Ordering::Less => cur += 1,
-                        Ordering::Equal => cur += 1,
-                        Ordering::Greater => cur -= 1,
-                    }
+    fn matches(&self, data_format: &DataFormat, data: &[u8]) -> bool {
+        match self {
+            Expr::Condition(field_query) => match field_query.operator {
+                Operator::Compare(comparator) => data_format.match_field(
+                    data,
+                    &field_query.field,
+                    &field_query.value,
+                    comparator,
+                ),
+                Operator::Contains => {
+                    data_format.match_element(data, &field_query.field, &field_query.value)
                 }
-                match chain {
-                    Chain::Or => {
-                        results.extend(&new_res);
-                    }
-                    Chain::And => {
-                        results.retain(|x| new_res.contains(x));
-                    }
-                }
-                if results.is_empty() {
-                    return Ok(());
-                }
-                for g in &self.next_group {
-                    g.0.resolve_with_indexes(
-                        index_iterator,
-                        repo,
-                        results,
-                        g.1,
-                        data_format,
-                        main_tree,
-                        limit,
-                    )?;
+            },
+            Expr::All(all) => all.iter().all(|expr| expr.matches(data_format, data)),
+            Expr::Any(any) => any.iter().any(|expr| expr.matches(data_format, data)),
+        }
+    }
+
+    /// How the indexes narrow the candidates down, if they can. An `All` needs just one
+    /// indexed condition, while every alternative of an `Any` has to be indexed - otherwise
+    /// all documents have to be checked anyway. `execute` and `explain` both follow this.
+    fn access<'i>(&self, indexes: &'i HashMap<String, Index>) -> Option<Access<'q, 'i>> {
+        match self {
+            Expr::Condition(field_query) => field_query
+                .index(indexes)
+                .map(|index| Access::Lookup(field_query, index)),
+            Expr::All(all) => {
+                let mut found: Vec<Access> =
+                    all.iter().filter_map(|expr| expr.access(indexes)).collect();
+                match found.len() {
+                    0 => None,
+                    1 => found.pop(),
+                    _ => Some(Access::Intersect(found)),
                 }
             }
-            None => {
-                debug!("No index; Scanning...");
-                if results.is_empty() {
-                    main_tree.walk(git2::TreeWalkMode::PostOrder, |_, entry| {
-                        debug!("Found an entry {}", entry.id());
-                        let entry_kind = entry.kind();
-                        if entry_kind != Some(ObjectType::Blob) {
-                            debug!("Type is {:?}, skipping", entry_kind);
-                            return TreeWalkResult::Skip;
-                        }
-                        let blob = entry.to_object(repo).unwrap();
-                        let blob_content = blob.as_blob().unwrap().content();
-                        if self.resolve(data_format, blob_content) {
-                            results.insert(entry.id());
-                        }
-                        if results.len() >= limit {
-                            return TreeWalkResult::Abort;
-                        }
-                        TreeWalkResult::Ok
-                    })?;
-                } else {
-                    // scan only matching elements
-                    let mut retained = 0;
-                    results.retain(|v| {
-                        if retained >= limit {
-                            return false;
-                        }
-                        let entry = main_tree.get_id(*v).unwrap();
-                        let blob = entry.to_object(repo).unwrap();
-                        let blob_content = blob.as_blob().unwrap().content();
-                        let res = self.resolve(data_format, blob_content);
-                        if res {
-                            retained += 1;
-                        }
-                        res
-                    });
+            Expr::Any(any) => any
+                .iter()
+                .map(|expr| expr.access(indexes))
+                .collect::<Option<_>>()
+                .map(Access::Union),
+        }
+    }
+
+    fn conditions(&self, found: &mut Vec<&'q FieldQuery>) {
+        match self {
+            Expr::Condition(field_query) => found.push(field_query),
+            Expr::All(exprs) | Expr::Any(exprs) => {
+                exprs.iter().for_each(|expr| expr.conditions(found))
+            }
+        }
+    }
+}
+
+/// Index lookups finding the candidates for the query
+#[derive(Debug)]
+enum Access<'q, 'i> {
+    Lookup(&'q FieldQuery, &'i Index),
+    Intersect(Vec<Access<'q, 'i>>),
+    Union(Vec<Access<'q, 'i>>),
+}
+
+impl Access<'_, '_> {
+    /// Keys (hashes of them, as in the index entries) found in the indexes. The most
+    /// selective lookup of an intersection drives it, the others narrow it down further.
+    fn keys(&self, repo: &Repository) -> HashSet<Oid> {
+        match self {
+            Access::Lookup(field_query, index) => field_query.lookup(index, repo),
+            Access::Intersect(all) => {
+                let mut found: Vec<HashSet<Oid>> =
+                    all.iter().map(|access| access.keys(repo)).collect();
+                found.sort_by_key(|keys| keys.len());
+                let mut found = found.into_iter();
+                // unwrap: intersections have at least two lookups
+                let mut keys = found.next().unwrap();
+                for other in found {
+                    keys.retain(|key| other.contains(key));
                 }
+                keys
             }
+            Access::Union(any) => any.iter().flat_map(|access| access.keys(repo)).collect(),
         }
-        Ok(())
     }
+
+    /// Indexes in the order of the lookups
+    fn indexes(&self, used: &mut Vec<Index>) {
+        match self {
+            Access::Lookup(_, index) => used.push((*index).clone()),
+            Access::Intersect(accesses) | Access::Union(accesses) => {
+                accesses.iter().for_each(|access| access.indexes(used))
+            }
+        }
+    }
+
+    fn looks_up(&self, condition: &FieldQuery) -> bool {
+        match self {
+            Access::Lookup(field_query, _) => std::ptr::eq(*field_query, condition),
+            Access::Intersect(accesses) | Access::Union(accesses) => {
+                accesses.iter().any(|access| access.looks_up(condition))
+            }
+        }
+    }
+
+    fn describe(&self, repo: &Repository) -> (AccessPlan, usize) {
+        match self {
+            Access::Lookup(field_query, index) => (
+                AccessPlan::IndexRange {
+                    index: index.name().to_string(),
+                    condition: field_query.to_string(),
+                },
+                index.git_index(repo).len(),
+            ),
+            Access::Intersect(accesses) | Access::Union(accesses) => {
+                let (plans, entries): (Vec<_>, Vec<_>) =
+                    accesses.iter().map(|access| access.describe(repo)).unzip();
+                let plan = match self {
+                    Access::Intersect(_) => AccessPlan::Intersection(plans),
+                    _ => AccessPlan::Union(plans),
+                };
+                (plan, entries.into_iter().sum())
+            }
+        }
+    }
+}
+
+/// The decision on how to run a query, made in one place for both `execute` and `explain`
+struct Plan<'q, 'i> {
+    expr: Expr<'q>,
+    access: Option<Access<'q, 'i>>,
+    /// Conditions left to check against the documents themselves
+    filters: Vec<&'q FieldQuery>,
 }
 
 impl BitOr for QueryGroup {
@@ -220,44 +262,513 @@ enum Chain {
     Or,
 }
 
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Operator {
+    Compare(Ordering),
+    /// The value is one of the elements of the field
+    Contains,
+}
+
+impl Display for Operator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Operator::Compare(Ordering::Less) => "<",
+            Operator::Compare(Ordering::Equal) => "=",
+            Operator::Compare(Ordering::Greater) => ">",
+            Operator::Contains => "contains",
+        })
+    }
+}
+
 #[derive(Debug)]
 struct FieldQuery {
     field: String,
     value: Field,
-    comparator: Ordering,
+    operator: Operator,
 }
 
 impl FieldQuery {
-    fn prefix_query(&self) -> String {
+    /// Index of the field which can answer the query - a `Collection` index holds
+    /// the elements of the field, so it's only of use to `contains` and vice versa
+    fn index<'i>(&self, indexes: &'i HashMap<String, Index>) -> Option<&'i Index> {
+        indexes.get(&self.field).filter(|index| {
+            (index.kind() == IndexType::Collection) == (self.operator == Operator::Contains)
+        })
+    }
+
+    /// Hashes of the keys with a value matching the query in the index
+    fn lookup(&self, index: &Index, repo: &Repository) -> HashSet<Oid> {
+        let comparator = match self.operator {
+            Operator::Compare(comparator) => comparator,
+            // entries of the elements are looked up like values equal to the one queried
+            Operator::Contains => Ordering::Equal,
+        };
+        let value = index.normalize(&self.value);
+        let git_index = index.git_index(repo);
+        let mut keys = HashSet::new();
+        let mut cur = match comparator {
+            Ordering::Less => 0,
+            Ordering::Equal => git_index
+                .find_prefix(Self::prefix_query(&value))
+                .unwrap_or(0),
+            Ordering::Greater => match git_index.len() {
+                0 => 0,
+                _ => git_index.len() - 1,
+            },
+        };
+        while let Some(entry) = git_index.get(cur) {
+            let val = Field::from_index_entry(&entry);
+            debug!("found the following value in the index: {:?}", val);
+            if let Some(v) = val {
+                let cmp = value.partial_cmp(&v);
+                if cmp == Some(comparator) {
+                    keys.insert(entry.id);
+                } else if cmp.is_some() {
+                    break;
+                }
+            }
+            if (cur == 0 && comparator == Ordering::Greater)
+                || (cur >= git_index.len() && comparator != Ordering::Greater)
+            {
+                break;
+            }
+            match comparator {
+                Ordering::Less => cur += 1,
+                Ordering::Equal => cur += 1,
+                Ordering::Greater => cur -= 1,
+            }
+        }
+        keys
+    }
+
+    fn prefix_query(value: &Field) -> String {
+        // the separator keeps values which are prefixes of other values apart
+        format!("{}/", value.to_index_value())
+    }
+}
+
+impl Display for FieldQuery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match &self.value {
-            Field::Int(v) => format!(
-                "{}/{:16x}",
-                match v.is_positive() {
-                    true => 1,
-                    false => 0,
-                },
-                (*v as f64).to_bits()
-            ),
-            Field::Float(v) => format!(
-                "{}/{:16x}",
-                match v.is_sign_positive() {
-                    true => 1,
-                    false => 0,
-                },
-                v.to_bits()
-            ),
-            Field::String(s) => s.to_owned(),
+            Field::String(value) => write!(f, "{} {} {:?}", self.field, self.operator, value),
+            value => write!(f, "{} {} {}", self.field, self.operator, value),
+        }
+    }
+}
+
+/// How `execute` would run a query, as described by `QueryBuilder::explain`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QueryPlan {
+    pub access: AccessPlan,
+    /// Upper bound of the index entries read by the lookups, or of the documents
+    /// read by a scan
+    pub estimated_entries: usize,
+    /// Conditions checked against the documents found, after the index lookups (if any)
+    pub filters: Vec<String>,
+    pub sort: SortPlan,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum AccessPlan {
+    /// Every document of the tree is read
+    Scan,
+    /// The entries of the index matching the condition are read
+    IndexRange { index: String, condition: String },
+    /// Documents found by all the lookups
+    Intersection(Vec<AccessPlan>),
+    /// Documents found by any of the lookups
+    Union(Vec<AccessPlan>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum SortPlan {
+    /// By key, as done by `QueryResult::page`
+    Key,
+    /// Newest first, as set with `QueryBuilder::order_by_updated`
+    UpdatedAt,
+}
+
+impl Display for AccessPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (name, plans) = match self {
+            AccessPlan::Scan => return f.write_str("Scan"),
+            AccessPlan::IndexRange { index, condition } => {
+                return write!(f, "IndexRange {} ({})", index, condition)
+            }
+            AccessPlan::Intersection(plans) => ("Intersection", plans),
+            AccessPlan::Union(plans) => ("Union", plans),
+        };
+        let plans: Vec<String> = plans.iter().map(ToString::to_string).collect();
+        write!(f, "{}[{}]", name, plans.join(", "))
+    }
+}
+
+impl Display for QueryPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "access: {}", self.access)?;
+        writeln!(f, "estimated entries: {}", self.estimated_entries)?;
+        if !self.filters.is_empty() {
+            writeln!(f, "filter: {}", self.filters.join(", "))?;
+        }
+        if let Some(limit) = self.limit {
+            writeln!(f, "limit: {}", limit)?;
+        }
+        match self.sort {
+            SortPlan::Key => write!(f, "sort: key"),
+            SortPlan::UpdatedAt => write!(f, "sort: updated at, newest first"),
         }
     }
 }
 
-pub struct QueryResult {
+pub struct QueryResult<'c> {
     pub results: HashSet<git2::Oid>,
     pub count: usize,
     pub resolution_strategy: ResolutionStrategy,
+    collection: &'c Collection,
+    /// Root tree the query was executed against (the tree of the namespace, for
+    /// queries `in_namespace`)
+    tree: Oid,
+    order_by_updated: bool,
+    namespace: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct QueryPage {
+    pub keys: Vec<String>,
+    /// Cursor to pass to get the next page. `None` if this is the last one.
+    pub next: Option<String>,
+}
+
+/// Outcome of QueryResult::update_all
+#[derive(Debug)]
+pub struct UpdateReport<E> {
+    /// Number of documents written back.
+    pub updated: usize,
+    /// Keys for which the update function failed, along with the errors.
+    pub failures: Vec<(String, E)>,
+}
+
+impl QueryResult<'_> {
+    fn namespace(&self) -> Option<namespace::Namespace<'_>> {
+        // unwrap: the name was taken from a namespace
+        self.namespace
+            .as_deref()
+            .map(|name| self.collection.namespace(name).unwrap())
+    }
+
+    /// Keys of the matched documents along with the oids of their blobs on main
+    pub(crate) fn matched_documents(&self) -> Result<Vec<(String, Oid)>, git2::Error> {
+        let repo = self.collection.repository();
+        let tree = repo.find_tree(self.tree)?;
+        let mut documents = Vec::new();
+        let mut walk_error = None;
+        tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+            let Some(name) = entry.name() else {
+                return TreeWalkResult::Skip;
+            };
+            if (root.is_empty() && name.ends_with(".index")) || namespace::is_reserved_tree(entry) {
+                return TreeWalkResult::Skip;
+            }
+            if entry.kind() != Some(ObjectType::Blob) {
+                return TreeWalkResult::Ok;
+            }
+            let key = match Collection::key_from_path(root, name) {
+                Ok(key) => key,
+                Err(err) => {
+                    walk_error = Some(err);
+                    return TreeWalkResult::Abort;
+                }
+            };
+            // scans yield blob oids while indexes yield hashes of the keys
+            let matched = self.results.contains(&entry.id())
+                || Oid::hash_object(ObjectType::Blob, key.as_bytes())
+                    .map(|hash| self.results.contains(&hash))
+                    .unwrap_or(false);
+            if matched {
+                documents.push((key, entry.id()));
+            }
+            TreeWalkResult::Ok
+        })?;
+        if let Some(err) = walk_error {
+            return Err(err);
+        }
+        Ok(documents)
+    }
+
+    /// Keys of all the documents matched by the query, the most recently updated first
+    /// if the query was built with `order_by_updated`
+    pub fn keys(&self) -> Result<Vec<String>, error::QueryError> {
+        let keys = self.matched_documents()?.into_iter().map(|(key, _)| key);
+        if !self.order_by_updated {
+            return Ok(keys.collect());
+        }
+        let repo = self.collection.repository();
+        let tree = repo.find_tree(self.tree)?;
+        let mut stamped = Vec::new();
+        for key in keys {
+            // unwrap: the keys were read from the paths of the documents
+            let path = Collection::construct_path_to_key(&key).unwrap();
+            let updated_at = metadata::read(repo, &tree, &path)?.map(|meta| meta.updated_at);
+            stamped.push((updated_at, key));
+        }
+        // newest first and the documents without metadata last, ties broken by the keys
+        stamped.sort_by(|(a, a_key), (b, b_key)| b.cmp(a).then_with(|| a_key.cmp(b_key)));
+        Ok(stamped.into_iter().map(|(_, key)| key).collect())
+    }
+
+    /// Up to `limit` keys of the matched documents, in lexicographic order (or in the order
+    /// of `keys` with `order_by_updated`), following the cursor from the previous page
+    /// (or from the start, if there is none). Returns the cursor to the next page, if there is one.
+    /// With `order_by_updated`, the cursor is looked up among the keys, so a page following
+    /// a key which was updated or deleted since is empty.
+    pub fn page(&self, limit: usize, cursor: Option<&str>) -> Result<QueryPage, error::QueryError> {
+        let mut keys: Vec<String> = match self.order_by_updated {
+            true => {
+                let keys = self.keys()?.into_iter();
+                match cursor {
+                    Some(cursor) => keys.skip_while(|key| key != cursor).skip(1).collect(),
+                    None => keys.collect(),
+                }
+            }
+            false => {
+                let mut keys: Vec<String> = self
+                    .matched_documents()?
+                    .into_iter()
+                    .map(|(key, _)| key)
+                    .filter(|key| cursor.is_none_or(|c| key.as_str() > c))
+                    .collect();
+                keys.sort();
+                keys
+            }
+        };
+        let next = match keys.len() > limit {
+            true => {
+                keys.truncate(limit);
+                keys.last().cloned()
+            }
+            false => None,
+        };
+        Ok(QueryPage { keys, next })
+    }
+
+    /// Delete every matched document in a single commit.
+    /// Returns the number of documents deleted.
+    pub fn delete_all(&self, target: OperationTarget) -> Result<usize, error::SetObjectError> {
+        let keys = self.matched_documents()?.into_iter().map(|(key, _)| key);
+        match self.namespace() {
+            Some(namespace) => namespace.delete_batch(keys, target),
+            None => self.collection.delete_batch(keys, target),
+        }
+    }
+
+    /// Apply the same JSON merge patch to every matched document in a single commit
+    pub fn patch_all(
+        &self,
+        patch: &serde_json::Value,
+        target: OperationTarget,
+    ) -> Result<WriteResult, error::PatchError> {
+        let keys = self
+            .matched_documents()
+            .map_err(|e| error::PatchError::Set(e.into()))?
+            .into_iter()
+            .map(|(key, _)| key);
+        match self.namespace() {
+            Some(namespace) => namespace.patch_batch(keys, patch, target),
+            None => self.collection.patch_batch(keys, patch, target),
+        }
+    }
+
+    /// Pass every matched document (in the version matched by the query) through `update_fn`
+    /// and save the results in a single commit.
+    /// Documents for which `update_fn` fails are reported and left unchanged. If
+    /// `abort_on_failure` is set, nothing is written as soon as any document fails.
+    pub fn update_all<F, E>(
+        &self,
+        mut update_fn: F,
+        target: OperationTarget,
+        abort_on_failure: bool,
+    ) -> Result<UpdateReport<E>, error::SetObjectError>
+    where
+        F: FnMut(&str, serde_json::Value) -> Result<serde_json::Value, E>,
+    {
+        let repo = self.collection.repository();
+        let mut updated = Vec::new();
+        let mut failures = Vec::new();
+        for (key, oid) in self.matched_documents()? {
+            let blob = repo.find_blob(oid)?;
+            let content = self
+                .collection
+                .open_value(blob.content())
+                .map_err(git2::Error::from)?;
+            let document: serde_json::Value = self.collection.data_format.deserialize(&content);
+            match update_fn(&key, document) {
+                Ok(document) => updated.push((key, document)),
+                Err(err) => {
+                    failures.push((key, err));
+                    if abort_on_failure {
+                        return Ok(UpdateReport {
+                            updated: 0,
+                            failures,
+                        });
+                    }
+                }
+            }
+        }
+        let count = updated.len();
+        if count > 0 {
+            match self.namespace() {
+                Some(namespace) => namespace.set_batch(updated, target)?,
+                None => self.collection.set_batch(updated, target)?,
+            };
+        }
+        Ok(UpdateReport {
+            updated: count,
+            failures,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregate {
+    Count,
+    Sum,
+    Min,
+    Max,
+    Average,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AggregateResult {
+    /// Number of documents with a numeric value in the aggregated field.
+    pub count: usize,
+    /// The aggregate itself. Min, max and average of no values are None.
+    pub value: Option<f64>,
+    pub resolution_strategy: ResolutionStrategy,
+}
+
+#[derive(Default)]
+struct Accumulator {
+    count: usize,
+    sum: f64,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+impl Accumulator {
+    fn add(&mut self, field: &Field) {
+        let value = match field {
+            Field::Int(i) => *i as f64,
+            Field::Float(f) => *f,
+            Field::String(_) => return,
+        };
+        self.count += 1;
+        self.sum += value;
+        self.min = Some(self.min.map_or(value, |m| m.min(value)));
+        self.max = Some(self.max.map_or(value, |m| m.max(value)));
+    }
+
+    fn finish(
+        self,
+        aggregate: Aggregate,
+        resolution_strategy: ResolutionStrategy,
+    ) -> AggregateResult {
+        let value = match aggregate {
+            Aggregate::Count => Some(self.count as f64),
+            Aggregate::Sum => Some(self.sum),
+            Aggregate::Min => self.min,
+            Aggregate::Max => self.max,
+            Aggregate::Average => match self.count {
+                0 => None,
+                n => Some(self.sum / n as f64),
+            },
+        };
+        AggregateResult {
+            count: self.count,
+            value,
+            resolution_strategy,
+        }
+    }
 }
 
-impl Iterator for QueryResult {
+/// Aggregate the numeric values of `field` in the documents on main matching the filter.
+/// If there is a numeric index on the field, only the index entries are read.
+/// Otherwise, every (matching) document has to be deserialized.
+pub(crate) fn aggregate(
+    collection: &Collection,
+    field: &str,
+    aggregate: Aggregate,
+    filter: Option<QueryGroup>,
+) -> Result<AggregateResult, error::QueryError> {
+    let repo = collection.repository();
+    let index = collection
+        .index_list()
+        .into_iter()
+        .find(|i| i.indexed_field() == field && i.kind() == IndexType::Numeric);
+    let filter_result = match filter {
+        Some(filter) => Some(QueryBuilder::query(filter).execute(collection)?),
+        None => None,
+    };
+    let mut accumulator = Accumulator::default();
+    if let Some(index) = index {
+        // index entries point at hashes of the keys
+        let allowed: Option<HashSet<Oid>> = match filter_result {
+            Some(result) => Some(
+                result
+                    .matched_documents()?
+                    .iter()
+                    .map(|(key, _)| Oid::hash_object(ObjectType::Blob, key.as_bytes()))
+                    .collect::<Result<_, _>>()?,
+            ),
+            None => None,
+        };
+        for entry in index.git_index(repo).iter() {
+            if allowed.as_ref().is_some_and(|a| !a.contains(&entry.id)) {
+                continue;
+            }
+            if let Some(value) = Field::from_index_entry(&entry) {
+                accumulator.add(&value);
+            }
+        }
+        return Ok(accumulator.finish(aggregate, ResolutionStrategy::UseIndexes(vec![index])));
+    }
+    let mut add_blob = |oid: Oid| -> Result<(), git2::Error> {
+        let blob = repo.find_blob(oid)?;
+        let content = collection.open_value(blob.content())?;
+        if let Some(value) = collection.data_format.extract_field(&content, field) {
+            accumulator.add(&value);
+        }
+        Ok(())
+    };
+    let blobs = match filter_result {
+        Some(result) => result.matched_documents()?,
+        None => {
+            // not a HashSet like in walk_the_tree - documents with equal content count separately
+            let mut all = Vec::new();
+            Collection::current_commit(repo, "main")?.tree()?.walk(
+                git2::TreeWalkMode::PreOrder,
+                |root, entry| {
+                    if (root.is_empty() && entry.name().is_some_and(|n| n.ends_with(".index")))
+                        || namespace::is_reserved_tree(entry)
+                    {
+                        return TreeWalkResult::Skip;
+                    }
+                    if entry.kind() == Some(ObjectType::Blob) {
+                        all.push((String::new(), entry.id()));
+                    }
+                    TreeWalkResult::Ok
+                },
+            )?;
+            all
+        }
+    };
+    for (_, oid) in blobs {
+        add_blob(oid)?;
+    }
+    Ok(accumulator.finish(aggregate, ResolutionStrategy::Scan))
+}
+
+impl Iterator for QueryResult<'_> {
     type Item = String;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -265,12 +776,15 @@ impl Iterator for QueryResult {
     }
 }
 
-impl QueryBuilder {
+impl<'t> QueryBuilder<'t> {
     /// Create QueryBuilder with the set query expression
     pub fn query(query: QueryGroup) -> Self {
         Self {
             query: Some(query),
             limit: None,
+            target: None,
+            order_by_updated: false,
+            namespace: None,
         }
     }
 
@@ -279,9 +793,54 @@ impl QueryBuilder {
         Self {
             query: None,
             limit: None,
+            target: None,
+            order_by_updated: false,
+            namespace: None,
         }
     }
 
+    /// Narrow the query down to the documents also matched by the group
+    pub fn and(mut self, group: QueryGroup) -> Self {
+        self.query = Some(match self.query.take() {
+            Some(query) => query & group,
+            None => group,
+        });
+        self
+    }
+
+    /// Extend the query to the documents matched by the group.
+    /// A builder created with `all` matches everything already.
+    pub fn or(mut self, group: QueryGroup) -> Self {
+        self.query = self.query.take().map(|query| query | group);
+        self
+    }
+
+    /// Run the query against the target when using `execute`, instead of main.
+    /// With `OperationTarget::Commit` the results are the ones the query would have returned
+    /// back when the commit was the tip of the branch. Indexes only describe the current state
+    /// of main, so on any other target the whole tree is scanned, which can be slow
+    /// for big collections.
+    pub fn at(mut self, target: OperationTarget<'t>) -> Self {
+        self.target = Some(target);
+        self
+    }
+
+    /// Query the documents of the namespace instead of the ones of the collection, using
+    /// the indexes of the collection as they are kept for the namespace. The writes
+    /// of `QueryResult` (like `delete_all`) go to the namespace as well.
+    pub fn in_namespace(mut self, namespace: &namespace::Namespace) -> Self {
+        self.namespace = Some(namespace.name().to_string());
+        self
+    }
+
+    /// Order the keys returned by `keys` and `page` by the time the documents were last
+    /// updated, newest first (see `Collection::document_meta`). Documents written before
+    /// the metadata was introduced come last.
+    pub fn order_by_updated(mut self) -> Self {
+        self.order_by_updated = true;
+        self
+    }
+
     // Set the optional limit to the results returned
     // This can greatly reduce query times when scanning the collection
     // Note that if there is no advantage to be gained from the limit, more results will be returned
@@ -294,15 +853,179 @@ impl QueryBuilder {
         &self,
         collection: &Collection,
     ) -> Result<ResolutionStrategy, error::QueryError> {
+        let all_indexes = self.indexes(collection, OperationTarget::Main)?;
+        Ok(self.strategy_with(&all_indexes))
+    }
+
+    fn namespace<'c>(&self, collection: &'c Collection) -> Option<namespace::Namespace<'c>> {
+        // unwrap: the name was taken from a namespace
+        self.namespace
+            .as_deref()
+            .map(|name| collection.namespace(name).unwrap())
+    }
+
+    /// Indexes which describe the documents queried on the target, by their fields.
+    /// Those of a namespace which weren't filled yet (see `Index::in_namespace`) are left out.
+    fn indexes(
+        &self,
+        collection: &Collection,
+        target: OperationTarget,
+    ) -> Result<HashMap<String, Index>, error::QueryError> {
+        if !matches!(target, OperationTarget::Main) {
+            return Ok(HashMap::new());
+        }
+        let indexes = collection.index_field_map();
+        Ok(match self.namespace(collection) {
+            Some(namespace) => indexes
+                .into_iter()
+                .map(|(field, index)| (field, index.in_namespace(&namespace.tree_path())))
+                .filter(|(_, index)| index.has_file(collection.repository()))
+                .collect(),
+            None => indexes,
+        })
+    }
+
+    /// Tree holding the documents queried on the target
+    fn tree<'c>(
+        &self,
+        collection: &'c Collection,
+        target: OperationTarget,
+    ) -> Result<Tree<'c>, error::QueryError> {
+        let tree = Collection::target_commit(collection.repository(), target)
+            .map_err(|e| match e.code() {
+                git2::ErrorCode::NotFound => {
+                    error::QueryError::InvalidOperationTarget(target.to_string())
+                }
+                _ => e.into(),
+            })?
+            .tree()?;
+        Ok(match self.namespace(collection) {
+            Some(namespace) => namespace.tree(&tree)?,
+            None => tree,
+        })
+    }
+
+    fn strategy_with(&self, indexes: &HashMap<String, Index>) -> ResolutionStrategy {
+        match self.plan(indexes).and_then(|plan| plan.access) {
+            Some(access) => {
+                let mut used = Vec::new();
+                access.indexes(&mut used);
+                ResolutionStrategy::UseIndexes(used)
+            }
+            None => ResolutionStrategy::Scan,
+        }
+    }
+
+    /// `None` for builders created with `all`, which walk the whole tree
+    fn plan<'i>(&self, indexes: &'i HashMap<String, Index>) -> Option<Plan<'_, 'i>> {
+        let expr = self.query.as_ref()?.compile();
+        let access = expr.access(indexes);
+        let mut filters = Vec::new();
+        expr.conditions(&mut filters);
+        if let Some(access) = &access {
+            filters.retain(|condition| !access.looks_up(condition));
+        }
+        Some(Plan {
+            expr,
+            access,
+            filters,
+        })
+    }
+
+    /// Describe how `execute` would run the query - with which indexes, if any - without
+    /// running it. Only the sizes of the indexes (or the tree, for scans) are read.
+    pub fn explain(&self, collection: &Collection) -> Result<QueryPlan, error::QueryError> {
+        let repo = collection.repository();
+        let target = self.target.unwrap_or(OperationTarget::Main);
+        let all_indexes = self.indexes(collection, target)?;
+        let plan = self.plan(&all_indexes);
+        let (access, estimated_entries) = match plan.as_ref().and_then(|plan| plan.access.as_ref())
+        {
+            Some(access) => access.describe(repo),
+            None => {
+                let tree = self.tree(collection, target)?;
+                let mut documents = 0;
+                tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+                    if (root.is_empty() && entry.name().is_some_and(|n| n.ends_with(".index")))
+                        || namespace::is_reserved_tree(entry)
+                    {
+                        return TreeWalkResult::Skip;
+                    }
+                    if entry.kind() == Some(ObjectType::Blob) {
+                        documents += 1;
+                    }
+                    TreeWalkResult::Ok
+                })?;
+                (AccessPlan::Scan, documents)
+            }
+        };
+        let filters = plan
+            .map(|plan| plan.filters.iter().map(ToString::to_string).collect())
+            .unwrap_or_default();
+        Ok(QueryPlan {
+            access,
+            estimated_entries,
+            filters,
+            sort: match self.order_by_updated {
+                true => SortPlan::UpdatedAt,
+                false => SortPlan::Key,
+            },
+            limit: self.limit,
+        })
+    }
+
+    /// Check the documents (just the candidates, if there are any) against the expression.
+    /// Candidates are identified by the hashes of their keys, like in the indexes,
+    /// while documents found by scanning by the oids of their blobs.
+    fn filter_documents(
+        results: &mut HashSet<Oid>,
+        expr: &Expr,
+        candidates: Option<&HashSet<Oid>>,
+        collection: &Collection,
+        tree: &Tree,
+        limit: usize,
+    ) -> Result<(), git2::Error> {
         let repo = collection.repository();
-        let resolution_strategy = match &self.query {
-            Some(q) => {
-                let all_indexes = Collection::index_field_map(repo);
-                q.resolution_strategy(&all_indexes)
+        let data_format = &collection.data_format;
+        let mut walk_error = None;
+        tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+            let Some(name) = entry.name() else {
+                return TreeWalkResult::Skip;
+            };
+            if (root.is_empty() && name.ends_with(".index")) || namespace::is_reserved_tree(entry) {
+                return TreeWalkResult::Skip;
+            }
+            if entry.kind() != Some(ObjectType::Blob) {
+                return TreeWalkResult::Ok;
+            }
+            let mut check = || -> Result<(), git2::Error> {
+                let id = match candidates {
+                    Some(candidates) => {
+                        let key = Collection::key_from_path(root, name)?;
+                        let hash = Oid::hash_object(ObjectType::Blob, key.as_bytes())?;
+                        if !candidates.contains(&hash) {
+                            return Ok(());
+                        }
+                        hash
+                    }
+                    None => entry.id(),
+                };
+                let blob = repo.find_blob(entry.id())?;
+                if expr.matches(data_format, &collection.open_value(blob.content())?) {
+                    results.insert(id);
+                }
+                Ok(())
+            };
+            if let Err(err) = check() {
+                walk_error = Some(err);
+                return TreeWalkResult::Abort;
             }
-            None => ResolutionStrategy::Scan,
-        };
-        Ok(resolution_strategy)
+            match results.len() >= limit {
+                true => TreeWalkResult::Abort,
+                false => TreeWalkResult::Ok,
+            }
+        })?;
+        walk_error.map_or(Ok(()), Err)
     }
 
     fn walk_the_tree(
@@ -310,10 +1033,10 @@ impl QueryBuilder {
         tree: Tree,
         limit: Option<usize>,
     ) -> Result<(), git2::Error> {
-        tree.walk(git2::TreeWalkMode::PostOrder, |_, entry| {
+        tree.walk(git2::TreeWalkMode::PostOrder, |root, entry| {
             debug!("Found an entry {}", entry.id());
             let entry_kind = entry.kind();
-            if entry_kind != Some(ObjectType::Blob) {
+            if entry_kind != Some(ObjectType::Blob) || namespace::in_reserved_tree(root) {
                 debug!("Type is {:?}, skipping", entry_kind);
                 return TreeWalkResult::Skip;
             }
@@ -327,38 +1050,77 @@ impl QueryBuilder {
         })
     }
 
-    pub fn execute(&self, collection: &Collection) -> Result<QueryResult, error::QueryError> {
+    /// Execute the query against main, or the target set with `at`
+    pub fn execute<'c>(
+        &self,
+        collection: &'c Collection,
+    ) -> Result<QueryResult<'c>, error::QueryError> {
+        self.execute_at(collection, self.target.unwrap_or(OperationTarget::Main))
+    }
+
+    /// Execute the query against the collection as it is on the target, ignoring the one
+    /// set with `at`. Indexes describe only main, so queries on any other target
+    /// scan the whole tree.
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(
+            level = "debug",
+            name = "query.execute",
+            skip_all,
+            fields(
+                target = target.to_string(),
+                strategy = tracing::field::Empty,
+                count = tracing::field::Empty
+            )
+        )
+    )]
+    pub fn execute_at<'c>(
+        &self,
+        collection: &'c Collection,
+        target: OperationTarget,
+    ) -> Result<QueryResult<'c>, error::QueryError> {
         let repo = collection.repository();
-        let resolution_strategy = self.resultion_strategy(collection)?;
+        let all_indexes = self.indexes(collection, target)?;
+        let plan = self.plan(&all_indexes);
+        let resolution_strategy = self.strategy_with(&all_indexes);
         debug!(
             "determined the resolution strategy: {:?}",
             resolution_strategy.clone()
         );
         let mut keys = HashSet::new();
-        let tree = Collection::current_commit(repo, "main")?.tree()?;
-        if let Some(query) = &self.query {
-            let indexes_to_use = match resolution_strategy {
-                ResolutionStrategy::Scan => Vec::new(),
-                ResolutionStrategy::UseIndexes(ref ind) => ind.clone(),
-            };
-            debug!("executing a query: {:?}", query);
-            query.resolve_with_indexes(
-                &mut indexes_to_use.iter(),
-                repo,
-                &mut keys,
-                Chain::Or,
-                &collection.data_format,
-                &tree,
-                self.limit.unwrap_or(usize::MAX),
-            )?;
+        let tree = self.tree(collection, target)?;
+        let tree_id = tree.id();
+        if let Some(plan) = plan {
+            debug!("executing a query: {:?}", plan.expr);
+            let limit = self.limit.unwrap_or(usize::MAX);
+            match plan.access.map(|access| access.keys(repo)) {
+                // every condition was answered by the indexes
+                Some(candidates) if plan.filters.is_empty() || candidates.is_empty() => {
+                    keys = candidates
+                }
+                candidates => Self::filter_documents(
+                    &mut keys,
+                    &plan.expr,
+                    candidates.as_ref(),
+                    collection,
+                    &tree,
+                    limit,
+                )?,
+            }
         } else {
             Self::walk_the_tree(&mut keys, tree, self.limit)?;
         }
         let count = keys.len();
+        record!("strategy", format!("{:?}", resolution_strategy));
+        record!("count", count);
         Ok(QueryResult {
             results: keys,
             count,
             resolution_strategy,
+            collection,
+            tree: tree_id,
+            order_by_updated: self.order_by_updated,
+            namespace: self.namespace.clone(),
         })
     }
 }
@@ -367,7 +1129,7 @@ impl QueryBuilder {
 mod tests {
     use crate::{
         index::{Index, IndexType},
-        query::{q, QueryBuilder},
+        query::{contains, q, QueryBuilder},
         serialization::DataFormat,
         test::*,
         OperationTarget,
@@ -375,7 +1137,7 @@ mod tests {
     use rstest::rstest;
     use std::cmp::Ordering::*;
 
-    use super::ResolutionStrategy;
+    use super::{AccessPlan, Aggregate, AggregateResult, QueryPlan, ResolutionStrategy, SortPlan};
 
     #[rstest]
     #[case(DataFormat::Json)]
@@ -560,6 +1322,182 @@ mod tests {
         )
     }
 
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_delete_all_with_index(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let index = db.add_index("usize_val", IndexType::Numeric);
+        db.set_batch(
+            [
+                ("a", ComplexDbStruct::new(String::from("a"), 5, 1.0)),
+                ("b", ComplexDbStruct::new(String::from("b"), 50, 1.0)),
+                ("pref/c", ComplexDbStruct::new(String::from("c"), 500, 1.0)),
+            ],
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let query_result = QueryBuilder::query(q("usize_val", Greater, 10))
+            .execute(&db)
+            .unwrap();
+        assert_eq!(query_result.count, 2);
+        let mut keys = query_result.keys().unwrap();
+        keys.sort();
+        assert_eq!(keys, vec![String::from("b"), String::from("pref/c")]);
+        assert_eq!(query_result.delete_all(OperationTarget::Main).unwrap(), 2);
+        assert!(db
+            .get::<ComplexDbStruct>("b", OperationTarget::Main)
+            .unwrap()
+            .is_none());
+        assert!(db
+            .get::<ComplexDbStruct>("pref/c", OperationTarget::Main)
+            .unwrap()
+            .is_none());
+        assert!(db
+            .get::<ComplexDbStruct>("a", OperationTarget::Main)
+            .unwrap()
+            .is_some());
+        assert_eq!(index.git_index(db.repository()).len(), 1);
+        let query_result = QueryBuilder::query(q("usize_val", Greater, 10))
+            .execute(&db)
+            .unwrap();
+        assert_eq!(query_result.count, 0);
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_update_all(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.set_batch(
+            [
+                ("a", ComplexDbStruct::new(String::from("a"), 5, 1.0)),
+                ("b", ComplexDbStruct::new(String::from("b"), 50, 1.0)),
+                ("c", ComplexDbStruct::new(String::from("c"), 500, 1.0)),
+            ],
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let query_result = QueryBuilder::query(q("usize_val", Greater, 10))
+            .execute(&db)
+            .unwrap();
+        let report = query_result
+            .update_all(
+                |key, mut document| {
+                    if key == "c" {
+                        return Err("not this one");
+                    }
+                    document["usize_val"] = serde_json::json!(1);
+                    Ok(document)
+                },
+                OperationTarget::Main,
+                false,
+            )
+            .unwrap();
+        assert_eq!(report.updated, 1);
+        assert_eq!(report.failures, vec![(String::from("c"), "not this one")]);
+        assert_eq!(
+            db.get::<ComplexDbStruct>("b", OperationTarget::Main)
+                .unwrap()
+                .unwrap()
+                .usize_val,
+            1
+        );
+        assert_eq!(
+            db.get::<ComplexDbStruct>("c", OperationTarget::Main)
+                .unwrap()
+                .unwrap()
+                .usize_val,
+            500
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_aggregate_indexed_and_scan_match(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.set_batch(
+            [
+                ("a", ComplexDbStruct::new(String::from("x"), 5, -2.5)),
+                ("b", ComplexDbStruct::new(String::from("y"), 50, 4.0)),
+                ("c", ComplexDbStruct::new(String::from("x"), 500, 4.0)),
+                ("d", ComplexDbStruct::new(String::from("y"), 7, 10.25)),
+            ],
+            OperationTarget::Main,
+        )
+        .unwrap();
+        db.set(
+            "e",
+            SampleDbStruct::new(String::from("x")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let aggregates = [
+            Aggregate::Count,
+            Aggregate::Sum,
+            Aggregate::Min,
+            Aggregate::Max,
+            Aggregate::Average,
+        ];
+        let run_all = |field: &str, filtered: bool| {
+            aggregates
+                .iter()
+                .map(|agg| {
+                    let filter = filtered.then(|| q("str_val", Equal, "x"));
+                    db.aggregate(field, *agg, filter).unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
+        let scanned = run_all("float_val", false);
+        let scanned_filtered = run_all("float_val", true);
+        assert_eq!(scanned[0].value, Some(4.0));
+        assert_eq!(scanned[1].value, Some(15.75));
+        assert_eq!(scanned[2].value, Some(-2.5));
+        assert_eq!(scanned[3].value, Some(10.25));
+        assert_eq!(scanned_filtered[1].value, Some(1.5));
+        assert!(scanned
+            .iter()
+            .all(|r| r.resolution_strategy == ResolutionStrategy::Scan));
+
+        db.add_index("float_val", IndexType::Numeric);
+        let indexed = run_all("float_val", false);
+        let indexed_filtered = run_all("float_val", true);
+        assert!(indexed
+            .iter()
+            .all(|r| matches!(r.resolution_strategy, ResolutionStrategy::UseIndexes(_))));
+        let values = |results: &Vec<AggregateResult>| {
+            results
+                .iter()
+                .map(|r| (r.count, r.value))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(values(&scanned), values(&indexed));
+        assert_eq!(values(&scanned_filtered), values(&indexed_filtered));
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_aggregate_empty(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.add_index("usize_val", IndexType::Numeric);
+        for agg in [Aggregate::Min, Aggregate::Max, Aggregate::Average] {
+            assert_eq!(db.aggregate("usize_val", agg, None).unwrap().value, None);
+            assert_eq!(db.aggregate("float_val", agg, None).unwrap().value, None);
+        }
+        assert_eq!(
+            db.aggregate("usize_val", Aggregate::Count, None)
+                .unwrap()
+                .value,
+            Some(0.0)
+        );
+    }
+
     #[rstest]
     #[case(DataFormat::Json)]
     #[case(DataFormat::Yaml)]
@@ -587,4 +1525,420 @@ mod tests {
         let query_result = QueryBuilder::all().maybe_limit(2).execute(&db).unwrap();
         assert_eq!(query_result.count, 2);
     }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_query_pages(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        for key in ["e", "a", "d", "b", "c"] {
+            db.set(
+                key,
+                ComplexDbStruct::new(String::from(key), 1, 1.0),
+                OperationTarget::Main,
+            )
+            .unwrap();
+        }
+        db.set(
+            "f",
+            ComplexDbStruct::new(String::from("f"), 2, 1.0),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let result = QueryBuilder::query(q("usize_val", Equal, 1))
+            .execute(&db)
+            .unwrap();
+        let first = result.page(2, None).unwrap();
+        assert_eq!(first.keys, vec!["a", "b"]);
+        let second = result.page(2, first.next.as_deref()).unwrap();
+        assert_eq!(second.keys, vec!["c", "d"]);
+        let third = result.page(2, second.next.as_deref()).unwrap();
+        assert_eq!(third.keys, vec!["e"]);
+        assert_eq!(third.next, None);
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_query_at_commit(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.add_index("usize_val", IndexType::Numeric);
+        db.set(
+            "a",
+            ComplexDbStruct::new(String::from("a"), 1, 1.0),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let old_commit = db
+            .repository()
+            .head()
+            .unwrap()
+            .peel_to_commit()
+            .unwrap()
+            .id();
+        db.set(
+            "a",
+            ComplexDbStruct::new(String::from("a"), 2, 1.0),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        db.set(
+            "b",
+            ComplexDbStruct::new(String::from("b"), 1, 1.0),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let query = QueryBuilder::query(q("usize_val", Equal, 1));
+        assert_eq!(query.execute(&db).unwrap().keys().unwrap(), vec!["b"]);
+        let result = query
+            .execute_at(&db, OperationTarget::Commit(old_commit))
+            .unwrap();
+        assert_eq!(result.resolution_strategy, ResolutionStrategy::Scan);
+        assert_eq!(result.keys().unwrap(), vec!["a"]);
+        assert!(matches!(
+            query.execute_at(&db, OperationTarget::Branch("missing")),
+            Err(crate::error::QueryError::InvalidOperationTarget(target)) if target == "missing"
+        ));
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_query_builder_at(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.add_index("str_val", IndexType::Sequential);
+        for key in ["a", "b", "c"] {
+            db.set(
+                key,
+                ComplexDbStruct::new(String::from("old"), 1, 1.0),
+                OperationTarget::Main,
+            )
+            .unwrap();
+        }
+        let snapshot = db
+            .set(
+                "c",
+                ComplexDbStruct::new(String::from("new"), 1, 1.0),
+                OperationTarget::Main,
+            )
+            .unwrap();
+        db.set(
+            "a",
+            ComplexDbStruct::new(String::from("new"), 1, 1.0),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        db.set(
+            "d",
+            ComplexDbStruct::new(String::from("old"), 1, 1.0),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let mut current = QueryBuilder::query(q("str_val", Equal, "old"))
+            .execute(&db)
+            .unwrap()
+            .keys()
+            .unwrap();
+        current.sort();
+        assert_eq!(current, vec!["b", "d"]);
+        let historical = QueryBuilder::query(q("str_val", Equal, "old"))
+            .at(OperationTarget::Commit(snapshot))
+            .execute(&db)
+            .unwrap();
+        assert_eq!(historical.resolution_strategy, ResolutionStrategy::Scan);
+        let mut keys = historical.keys().unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["a", "b"]);
+    }
+
+    fn set_logical_samples(db: &crate::Collection) {
+        for (key, str_val, usize_val) in [
+            ("a", "active", 50),
+            ("b", "active", 150),
+            ("c", "inactive", 50),
+            ("d", "featured", 500),
+            ("e", "inactive", 300),
+        ] {
+            db.set(
+                key,
+                ComplexDbStruct::new(String::from(str_val), usize_val, 1.0),
+                OperationTarget::Main,
+            )
+            .unwrap();
+        }
+    }
+
+    fn sorted_keys(query: QueryBuilder, db: &crate::Collection) -> Vec<String> {
+        let mut keys = query.execute(db).unwrap().keys().unwrap();
+        keys.sort();
+        keys
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_logical_precedence(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        set_logical_samples(&db);
+        // chains are evaluated from left to right: (active or featured) and < 100
+        let chained = QueryBuilder::query(
+            q("str_val", Equal, "active")
+                .or(q("str_val", Equal, "featured"))
+                .and(q("usize_val", Less, 100)),
+        );
+        assert_eq!(sorted_keys(chained, &db), vec!["a"]);
+        // active or (featured and < 100)
+        let grouped = QueryBuilder::query(
+            q("str_val", Equal, "active").or(q("str_val", Equal, "featured").and(q(
+                "usize_val",
+                Less,
+                100,
+            ))),
+        );
+        assert_eq!(sorted_keys(grouped, &db), vec!["a", "b"]);
+        let built = QueryBuilder::all()
+            .and(q("str_val", Equal, "inactive"))
+            .or(q("usize_val", Greater, 400));
+        assert_eq!(sorted_keys(built, &db), vec!["c", "d", "e"]);
+        // active and (< 100 or > 400)
+        let nested = QueryBuilder::query(
+            q("str_val", Equal, "active")
+                & (q("usize_val", Less, 100) | q("usize_val", Greater, 400)),
+        );
+        let result = nested.execute(&db).unwrap();
+        assert_eq!(result.resolution_strategy, ResolutionStrategy::Scan);
+        assert_eq!(result.keys().unwrap(), vec!["a"]);
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_or_across_indexed_fields(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.add_index("usize_val", IndexType::Numeric);
+        db.add_index("str_val", IndexType::Sequential);
+        set_logical_samples(&db);
+        let query =
+            QueryBuilder::query(q("usize_val", Greater, 200).or(q("str_val", Equal, "active")));
+        assert_eq!(
+            query.resultion_strategy(&db).unwrap(),
+            ResolutionStrategy::UseIndexes(vec![
+                Index::new("usize_val#numeric.index", "usize_val", IndexType::Numeric),
+                Index::new("str_val#sequential.index", "str_val", IndexType::Sequential),
+            ])
+        );
+        assert_eq!(sorted_keys(query, &db), vec!["a", "b", "d", "e"]);
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_indexed_condition_with_post_filter(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.add_index("usize_val", IndexType::Numeric);
+        set_logical_samples(&db);
+        // the index finds a and c, the other condition is checked against the documents
+        let query = QueryBuilder::query(
+            q("usize_val", Equal, 50)
+                .and(q("str_val", Equal, "inactive").or(q("str_val", Equal, "featured"))),
+        );
+        let result = query.execute(&db).unwrap();
+        assert_eq!(
+            result.resolution_strategy,
+            ResolutionStrategy::UseIndexes(vec![Index::new(
+                "usize_val#numeric.index",
+                "usize_val",
+                IndexType::Numeric
+            )])
+        );
+        assert_eq!(result.keys().unwrap(), vec!["c"]);
+    }
+
+    fn set_tagged_samples(db: &crate::Collection) {
+        db.set_batch(
+            [
+                ("a", TaggedDbStruct::new(&["red", "green"])),
+                // the duplicate is indexed once
+                ("b", TaggedDbStruct::new(&["green", "blue", "green"])),
+                ("c", TaggedDbStruct::new(&["red!", "re"])),
+                ("d", TaggedDbStruct::new(&[])),
+            ],
+            OperationTarget::Main,
+        )
+        .unwrap();
+        db.set(
+            "e",
+            SampleDbStruct::new(String::from("red")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_contains_with_collection_index(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let (scanned, _scanned_td) = create_db(data_format);
+        let index = db.add_index("tags", IndexType::Collection);
+        set_tagged_samples(&db);
+        set_tagged_samples(&scanned);
+        assert_eq!(index.git_index(db.repository()).len(), 6);
+        for (tag, keys) in [
+            ("red", vec!["a"]),
+            ("green", vec!["a", "b"]),
+            ("re", vec!["c"]),
+            ("purple", vec![]),
+        ] {
+            let query = QueryBuilder::query(contains("tags", tag));
+            assert_eq!(
+                query.resultion_strategy(&db).unwrap(),
+                ResolutionStrategy::UseIndexes(vec![index.clone()])
+            );
+            assert_eq!(sorted_keys(query, &db), keys);
+            let query = QueryBuilder::query(contains("tags", tag));
+            assert_eq!(sorted_keys(query, &scanned), keys);
+        }
+
+        // the tag list shrinks, its other entries stay
+        db.set("b", TaggedDbStruct::new(&["blue"]), OperationTarget::Main)
+            .unwrap();
+        assert_eq!(
+            sorted_keys(QueryBuilder::query(contains("tags", "green")), &db),
+            vec!["a"]
+        );
+        assert_eq!(
+            sorted_keys(QueryBuilder::query(contains("tags", "blue")), &db),
+            vec!["b"]
+        );
+        db.delete("a", OperationTarget::Main).unwrap();
+        assert_eq!(index.git_index(db.repository()).len(), 3);
+        assert!(db.check(Default::default()).unwrap().is_ok());
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_collection_index_only_for_contains(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let index = db.add_index("tags", IndexType::Collection);
+        set_tagged_samples(&db);
+        let query = QueryBuilder::query(q("tags", Equal, "red"));
+        assert_eq!(
+            query.resultion_strategy(&db).unwrap(),
+            ResolutionStrategy::Scan
+        );
+        assert!(sorted_keys(query, &db).is_empty());
+        // the index is filled the same way from the documents already there
+        db.rebuild_index(&index).unwrap();
+        assert_eq!(index.git_index(db.repository()).len(), 6);
+        assert_eq!(
+            sorted_keys(
+                QueryBuilder::query(contains("tags", "red").or(contains("tags", "blue"))),
+                &db
+            ),
+            vec!["a", "b"]
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_explain_index_range(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.add_index("usize_val", IndexType::Numeric);
+        set_logical_samples(&db);
+        let entries = db
+            .index_list()
+            .into_iter()
+            .next()
+            .unwrap()
+            .git_index(db.repository())
+            .len();
+        let usize_range = |condition: &str| AccessPlan::IndexRange {
+            index: String::from("usize_val#numeric.index"),
+            condition: condition.to_string(),
+        };
+        assert_eq!(
+            QueryBuilder::query(q("usize_val", Greater, 10))
+                .explain(&db)
+                .unwrap(),
+            QueryPlan {
+                access: usize_range("usize_val > 10"),
+                estimated_entries: entries,
+                filters: vec![],
+                sort: SortPlan::Key,
+                limit: None,
+            }
+        );
+        let between = QueryBuilder::query(q("usize_val", Greater, 10))
+            .and(q("usize_val", Less, 300))
+            .and(q("str_val", Equal, "active"))
+            .order_by_updated();
+        let plan = between.explain(&db).unwrap();
+        assert_eq!(
+            plan.access,
+            AccessPlan::Intersection(vec![
+                usize_range("usize_val > 10"),
+                usize_range("usize_val < 300")
+            ])
+        );
+        assert_eq!(plan.estimated_entries, 2 * entries);
+        assert_eq!(plan.filters, vec![r#"str_val = "active""#]);
+        assert_eq!(plan.sort, SortPlan::UpdatedAt);
+        assert_eq!(
+            plan.to_string(),
+            "access: Intersection[IndexRange usize_val#numeric.index (usize_val > 10), \
+             IndexRange usize_val#numeric.index (usize_val < 300)]\n\
+             estimated entries: 10\n\
+             filter: str_val = \"active\"\n\
+             sort: updated at, newest first"
+        );
+        // explain decides the same way as execute
+        assert_eq!(
+            between.execute(&db).unwrap().resolution_strategy,
+            between.resultion_strategy(&db).unwrap()
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_explain_scan(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.add_index("usize_val", IndexType::Numeric);
+        set_logical_samples(&db);
+        // an index can't help when one of the alternatives isn't indexed
+        let query = QueryBuilder::query(q("str_val", Equal, "active").or(q("usize_val", Less, 5)))
+            .maybe_limit(2);
+        let plan = query.explain(&db).unwrap();
+        assert_eq!(plan.access, AccessPlan::Scan);
+        assert_eq!(plan.estimated_entries, 5);
+        assert_eq!(plan.filters, vec![r#"str_val = "active""#, "usize_val < 5"]);
+        assert_eq!(plan.limit, Some(2));
+        assert_eq!(
+            serde_json::to_value(&plan).unwrap(),
+            serde_json::json!({
+                "access": "Scan",
+                "estimated_entries": 5,
+                "filters": ["str_val = \"active\"", "usize_val < 5"],
+                "sort": "Key",
+                "limit": 2,
+            })
+        );
+        assert_eq!(
+            query.execute(&db).unwrap().resolution_strategy,
+            ResolutionStrategy::Scan
+        );
+    }
 }
@@ -1,29 +1,256 @@
 use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
-use chrono::{DateTime, Utc};
-use git2::{Cred, ErrorCode, PushOptions, Reference, Remote, RemoteCallbacks, Repository};
+use chrono::DateTime;
+use git2::build::RepoBuilder;
+use git2::{
+    Cred, ErrorCode, FetchOptions, Oid, ProxyOptions, PushOptions, Reference, Remote,
+    RemoteCallbacks, Repository, Signature,
+};
 use rand::Rng;
+use serde::{Deserialize, Serialize};
 
-use crate::{debug, error, RepositoryAbstraction};
+use crate::clock::{Clock, SystemClock};
+use crate::index::{Index, SNAPSHOT_REFS};
+use crate::metrics::{Metrics, NoopMetrics};
+use crate::{debug, error, lock, meta, record, RepositoryAbstraction};
 
-#[derive(Clone)]
+/// Keys stored under `yamabiko.replica.<name>` in the configuration of the repository
+const REPLICA_CONFIG_KEYS: [&str; 10] = [
+    "url",
+    "method",
+    "period",
+    "chance",
+    "maxattempts",
+    "initialbackoffms",
+    "maxbackoffms",
+    "proxy",
+    "packbuilderparallelism",
+    "pushmeta",
+];
+
+/// Keys which were stored under `yamabiko.<remote name>` before the configuration
+/// of a replica moved to `yamabiko.replica.<name>`. They're still read from there
+/// when the new key is missing, and removed once the setting is changed.
+const LEGACY_CONFIG_KEYS: [&str; 2] = ["proxy", "packbuilderparallelism"];
+
+fn replica_config_key(name: &str, key: &str) -> String {
+    format!("yamabiko.replica.{}.{}", name, key)
+}
+
+/// Urls git can push to: `<scheme>://<host>/...` with one of the schemes git speaks
+/// (`file://` may leave the host out), scp-like `user@host:path` or a path on the disk.
+/// Control characters aren't allowed anywhere, whitespace only in paths.
+fn is_valid_url(url: &str) -> bool {
+    if url.trim().is_empty() || url.chars().any(|c| c.is_control()) {
+        return false;
+    }
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return true;
+    };
+    if url.chars().any(char::is_whitespace) {
+        return false;
+    }
+    match scheme {
+        "file" => !rest.is_empty(),
+        "http" | "https" | "ssh" | "git" | "git+ssh" | "ssh+git" => {
+            rest.split('/').next().is_some_and(|host| !host.is_empty())
+        }
+        _ => false,
+    }
+}
+
+/// Like `get_*` on a Config, but a missing key is None rather than an error
+pub(crate) fn optional<T>(value: Result<T, git2::Error>) -> Result<Option<T>, git2::Error> {
+    match value {
+        Ok(value) => Ok(Some(value)),
+        Err(err) if err.code() == ErrorCode::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+fn remove_config_key(config: &mut git2::Config, key: &str) -> Result<(), git2::Error> {
+    match config.remove(key) {
+        Err(err) if err.code() == ErrorCode::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum ReplicationMethod {
     All,
     Periodic(i64),
     Random(f64),
 }
 
+/// How many times a failed push should be attempted and how long to wait in between.
+/// The delay doubles after every failed attempt, up to `max_backoff`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_attempts: usize,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    /// A single attempt, no retries
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: usize, initial_backoff: Duration, max_backoff: Duration) -> Self {
+        Self {
+            max_attempts,
+            initial_backoff,
+            max_backoff,
+        }
+    }
+
+    /// Delay before the next attempt, given the number of attempts that already failed
+    pub fn backoff(&self, failed_attempts: usize) -> Duration {
+        let exponent = failed_attempts.saturating_sub(1).min(u32::MAX as usize) as u32;
+        self.initial_backoff
+            .saturating_mul(2_u32.saturating_pow(exponent))
+            .min(self.max_backoff)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ReplicationOutcome {
+    /// Replication was not attempted this time because of the chosen ReplicationMethod.
+    Skipped,
+    /// Data was pushed to the remote. Contains the number of attempts it took.
+    Replicated(usize),
+}
+
+impl ReplicationOutcome {
+    pub fn replicated(&self) -> bool {
+        matches!(self, Self::Replicated(_))
+    }
+}
+
+/// Result of replicating a specific commit with `Replicator::replicate_commit`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ReplicaPushResult {
+    /// Replication was not attempted this time because of the chosen ReplicationMethod.
+    Skipped,
+    /// Main was pushed to the remote while pointing exactly at the commit.
+    Pushed { commit: Oid, attempts: usize },
+    /// Main has moved on in the meantime - a newer commit, which has the requested one
+    /// in its history, was pushed instead.
+    Superseded {
+        commit: Oid,
+        pushed: Oid,
+        attempts: usize,
+    },
+}
+
+impl ReplicaPushResult {
+    /// The requested commit is on the remote now
+    pub fn replicated(&self) -> bool {
+        !matches!(self, Self::Skipped)
+    }
+}
+
+/// Outcome of the latest pushes to a replica, see `Replicator::status`.
+/// Times are in seconds since the Unix epoch.
+#[derive(Debug, PartialEq, Eq, Clone, Default, Serialize, Deserialize)]
+pub struct ReplicaStatus {
+    /// When main was last pushed successfully.
+    pub last_success: Option<i64>,
+    /// Why the last failed push failed, kept after later pushes succeed.
+    pub last_error: Option<String>,
+    /// When the last failed push failed.
+    pub last_error_at: Option<i64>,
+}
+
+/// Key of the ReplicaStatus (as json) of the replica in the MetaStore
+fn status_key(name: &str) -> String {
+    format!("replica.{}.status", name.replace('/', "%2F"))
+}
+
+/// Status of the replica of the given name, the default one if it was never pushed to
+pub(crate) fn read_status(
+    repo: &Repository,
+    name: &str,
+) -> Result<ReplicaStatus, error::MetaError> {
+    Ok(meta::get_json(repo, &status_key(name))?.unwrap_or_default())
+}
+
+/// Names of all the replicas stored in the configuration of the repository, sorted
+pub(crate) fn configured_in(repo: &Repository) -> Result<Vec<String>, git2::Error> {
+    let config = repo.config()?;
+    let mut names = Vec::new();
+    let mut entries = config.entries(Some(r"^yamabiko\.replica\..*\.url$"))?;
+    while let Some(entry) = entries.next() {
+        let entry = entry?;
+        if let Some(name) = entry
+            .name()
+            .and_then(|n| n.strip_prefix("yamabiko.replica."))
+            .and_then(|n| n.strip_suffix(".url"))
+        {
+            names.push(name.to_string());
+        }
+    }
+    names.sort();
+    names.dedup();
+    Ok(names)
+}
+
+/// Proxy used when pushing to the remote
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ReplicaProxy {
+    /// Detect the proxy from the git configuration (`remote.<name>.proxy`, `http.proxy`)
+    /// and the environment (`https_proxy`, `http_proxy`).
+    Auto,
+    /// Always go through the proxy at this URL.
+    Url(String),
+}
+
+/// Progress of sending the objects to the remote, reported while pushing
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct PushProgress {
+    /// Number of objects sent so far.
+    pub objects: usize,
+    /// Number of objects to send in total.
+    pub total_objects: usize,
+    /// Number of bytes sent so far.
+    pub bytes: usize,
+}
+
 pub struct Replicator {
     repository: Repository,
+    name: String,
     remote_name: String,
     remote_url: String,
     replication_method: ReplicationMethod,
     credentials: Option<RemoteCredentials>,
+    retry_policy: RetryPolicy,
+    metrics: Arc<dyn Metrics>,
+    push_progress: Option<Sender<PushProgress>>,
+    clock: Arc<dyn Clock>,
 }
 
 impl RepositoryAbstraction for Replicator {}
 
 impl Replicator {
+    /// Set up replication to the remote and store its url and ReplicationMethod in the
+    /// configuration of the repository (replacing the previous ones if the replica already
+    /// existed), so that it can be brought back with `Replicator::load` after a restart.
+    /// Credentials are never stored.
+    ///
+    /// Fails with `InvalidReplicaUrl` if the url can't point at a repository (see `is_valid_url`)
+    /// and with `RemoteConflict` if the repository has a git remote of the same name pointing
+    /// elsewhere, which isn't a replica.
     pub fn initialize(
         repo_path: &Path,
         remote_name: &str,
@@ -31,18 +258,357 @@ impl Replicator {
         replication_method: ReplicationMethod,
         credentials: Option<RemoteCredentials>,
     ) -> Result<Self, error::InitializationError> {
-        let repo = Self::load_or_create_repo(repo_path)?;
+        if !is_valid_url(remote_url) {
+            return Err(error::InitializationError::InvalidReplicaUrl(
+                remote_url.to_string(),
+            ));
+        }
+        let repo = Self::load_or_create_repo(repo_path, SystemClock.now())?;
         let remote_name_formatted = format!("_repl_{}", remote_name);
+        if let Ok(remote) = repo.find_remote(&remote_name_formatted) {
+            let current_url = remote.url().unwrap_or_default().to_string();
+            let configured = optional(
+                repo.config()?
+                    .get_string(&replica_config_key(remote_name, "url")),
+            )?
+            .is_some();
+            if current_url != remote_url {
+                if !configured {
+                    return Err(error::InitializationError::RemoteConflict(current_url));
+                }
+                repo.remote_set_url(&remote_name_formatted, remote_url)?;
+            }
+        }
         Self::ensure_remote(&repo, &remote_name_formatted, remote_url)?;
-        Ok(Self {
+        let replicator = Self {
             repository: repo,
+            name: remote_name.to_string(),
             remote_name: remote_name_formatted,
             remote_url: remote_url.to_string(),
             replication_method,
             credentials,
+            retry_policy: RetryPolicy::default(),
+            metrics: Arc::new(NoopMetrics),
+            push_progress: None,
+            clock: Arc::new(SystemClock),
+        };
+        replicator.store_config()?;
+        Ok(replicator)
+    }
+
+    /// Bring back a replica set up with `Replicator::initialize` in the past, using the url,
+    /// ReplicationMethod and RetryPolicy stored in the configuration of the repository
+    pub fn load(
+        repo_path: &Path,
+        remote_name: &str,
+        credentials: Option<RemoteCredentials>,
+    ) -> Result<Self, error::InitializationError> {
+        let repo = Self::load_existing_repo(repo_path)?;
+        let mut replicator = Self {
+            repository: repo,
+            name: remote_name.to_string(),
+            remote_name: format!("_repl_{}", remote_name),
+            remote_url: String::new(),
+            replication_method: ReplicationMethod::All,
+            credentials,
+            retry_policy: RetryPolicy::default(),
+            metrics: Arc::new(NoopMetrics),
+            push_progress: None,
+            clock: Arc::new(SystemClock),
+        };
+        replicator.reload()?;
+        Ok(replicator)
+    }
+
+    /// Names of all the replicas stored in the configuration of the repository, sorted
+    pub fn configured(repo_path: &Path) -> Result<Vec<String>, error::InitializationError> {
+        let repo = Self::load_existing_repo(repo_path)?;
+        Ok(configured_in(&repo)?)
+    }
+
+    /// Read the url, ReplicationMethod and RetryPolicy from the configuration of the repository
+    /// again, to pick up changes made to it by other processes (or by hand)
+    pub fn reload(&mut self) -> Result<(), error::InitializationError> {
+        let config = self.repository.config()?;
+        let key = |key: &str| replica_config_key(&self.name, key);
+        let Some(url) = optional(config.get_string(&key("url")))? else {
+            return Err(error::InitializationError::ReplicaNotConfigured(
+                self.name.clone(),
+            ));
+        };
+        let invalid = |key: &str| {
+            error::InitializationError::InvalidReplicaConfiguration(replica_config_key(
+                &self.name, key,
+            ))
+        };
+        let method = optional(config.get_string(&key("method")))?;
+        let replication_method = match method.as_deref() {
+            None | Some("all") => ReplicationMethod::All,
+            Some("periodic") => ReplicationMethod::Periodic(
+                optional(config.get_i64(&key("period")))?.ok_or_else(|| invalid("period"))?,
+            ),
+            Some("random") => ReplicationMethod::Random(
+                optional(config.get_string(&key("chance")))?
+                    .and_then(|chance| chance.parse().ok())
+                    .ok_or_else(|| invalid("chance"))?,
+            ),
+            Some(_) => return Err(invalid("method")),
+        };
+        let default = RetryPolicy::default();
+        let millis = |key: &str, default: Duration| -> Result<Duration, git2::Error> {
+            Ok(optional(config.get_i64(key))?
+                .map(|ms| Duration::from_millis(ms.max(0) as u64))
+                .unwrap_or(default))
+        };
+        let retry_policy = RetryPolicy {
+            max_attempts: optional(config.get_i64(&key("maxattempts")))?
+                .map(|attempts| attempts.max(1) as usize)
+                .unwrap_or(default.max_attempts),
+            initial_backoff: millis(&key("initialbackoffms"), default.initial_backoff)?,
+            max_backoff: millis(&key("maxbackoffms"), default.max_backoff)?,
+        };
+        if url != self.remote_url {
+            if self.repository.find_remote(&self.remote_name).is_ok() {
+                self.repository.remote_set_url(&self.remote_name, &url)?;
+            } else {
+                self.repository.remote(&self.remote_name, &url)?;
+            }
+        }
+        self.remote_url = url;
+        self.replication_method = replication_method;
+        self.retry_policy = retry_policy;
+        Ok(())
+    }
+
+    /// Stop replicating to the remote for good - delete the remote and its configuration
+    pub fn remove(self) -> Result<(), git2::Error> {
+        let mut config = self.repository.config()?;
+        for key in REPLICA_CONFIG_KEYS {
+            remove_config_key(&mut config, &self.config_key(key))?;
+        }
+        for key in LEGACY_CONFIG_KEYS {
+            remove_config_key(&mut config, &self.legacy_config_key(key))?;
+        }
+        let signature = self.signature();
+        meta::update(
+            &self.repository,
+            &signature,
+            &status_key(&self.name),
+            |_| Ok(None),
+        )?;
+        match self.repository.remote_delete(&self.remote_name) {
+            Err(err) if err.code() == ErrorCode::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    /// When main was last pushed to the replica and why the last push failed
+    pub fn status(&self) -> Result<ReplicaStatus, error::MetaError> {
+        read_status(&self.repository, &self.name)
+    }
+
+    fn record_status(&self, result: Result<(), &git2::Error>) -> Result<(), error::MetaError> {
+        let now = self.clock.now();
+        let signature = Self::signature_at(now);
+        meta::update(
+            &self.repository,
+            &signature,
+            &status_key(&self.name),
+            |current| {
+                let mut status: ReplicaStatus = match current {
+                    Some(current) => serde_json::from_slice(current).unwrap_or_default(),
+                    None => ReplicaStatus::default(),
+                };
+                match result {
+                    Ok(()) => status.last_success = Some(now),
+                    Err(err) => {
+                        status.last_error = Some(err.message().to_string());
+                        status.last_error_at = Some(now);
+                    }
+                }
+                // unwrap: a struct of strings and numbers always serializes
+                Ok(Some(serde_json::to_vec(&status).unwrap()))
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Also push the operational metadata of the collection (see `Collection::metadata`)
+    /// to the replica, which doesn't happen by default.
+    /// Stored in the configuration of the repository, like the proxy.
+    pub fn set_push_metadata(&self, push: bool) -> Result<(), git2::Error> {
+        self.repository
+            .config()?
+            .set_bool(&self.config_key("pushmeta"), push)
+    }
+
+    pub fn push_metadata(&self) -> Result<bool, git2::Error> {
+        Ok(optional(
+            self.repository
+                .config()?
+                .get_bool(&self.config_key("pushmeta")),
+        )?
+        .unwrap_or(false))
+    }
+
+    fn store_config(&self) -> Result<(), git2::Error> {
+        let mut config = self.repository.config()?;
+        config.set_str(&self.config_key("url"), &self.remote_url)?;
+        remove_config_key(&mut config, &self.config_key("period"))?;
+        remove_config_key(&mut config, &self.config_key("chance"))?;
+        match self.replication_method {
+            ReplicationMethod::All => config.set_str(&self.config_key("method"), "all"),
+            ReplicationMethod::Periodic(period) => {
+                config.set_str(&self.config_key("method"), "periodic")?;
+                config.set_i64(&self.config_key("period"), period)
+            }
+            ReplicationMethod::Random(chance) => {
+                config.set_str(&self.config_key("method"), "random")?;
+                config.set_str(&self.config_key("chance"), &chance.to_string())
+            }
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn url(&self) -> &str {
+        &self.remote_url
+    }
+
+    pub fn replication_method(&self) -> &ReplicationMethod {
+        &self.replication_method
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
+    /// Retry failed pushes according to the given policy.
+    /// Only applies to this Replicator, see `set_retry_policy` for storing it.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Retry failed pushes according to the given policy and store it in the configuration
+    /// of the repository, so that `Replicator::load` uses it as well
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) -> Result<(), git2::Error> {
+        let mut config = self.repository.config()?;
+        config.set_i64(
+            &self.config_key("maxattempts"),
+            retry_policy.max_attempts.min(i64::MAX as usize) as i64,
+        )?;
+        config.set_i64(
+            &self.config_key("initialbackoffms"),
+            retry_policy
+                .initial_backoff
+                .as_millis()
+                .min(i64::MAX as u128) as i64,
+        )?;
+        config.set_i64(
+            &self.config_key("maxbackoffms"),
+            retry_policy.max_backoff.as_millis().min(i64::MAX as u128) as i64,
+        )?;
+        self.retry_policy = retry_policy;
+        Ok(())
+    }
+
+    /// Report the outcomes of the pushes to the given Metrics
+    pub fn with_metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Take the timestamps of the status, the reflog of periodic replication and the commits
+    /// written from now on from the given Clock, see `Collection::with_clock`.
+    /// The waits between the attempts of a push (see `RetryPolicy`) go through it as well.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn signature<'a>(&self) -> Signature<'a> {
+        Self::signature_at(self.clock.now())
+    }
+
+    /// Send the progress of every push to the given channel.
+    /// Pushing doesn't wait for the receiver, and carries on if it's gone.
+    pub fn with_push_progress(mut self, sender: Sender<PushProgress>) -> Self {
+        self.push_progress = Some(sender);
+        self
+    }
+
+    fn config_key(&self, key: &str) -> String {
+        replica_config_key(&self.name, key)
+    }
+
+    /// Where the setting was stored before, see LEGACY_CONFIG_KEYS
+    fn legacy_config_key(&self, key: &str) -> String {
+        format!("yamabiko.{}.{}", self.remote_name, key)
+    }
+
+    /// Value of the setting, read from its legacy key if it's not under the current one
+    fn config_value<T>(
+        &self,
+        key: &str,
+        get: impl Fn(&git2::Config, &str) -> Result<T, git2::Error>,
+    ) -> Result<Option<T>, git2::Error> {
+        let config = self.repository.config()?;
+        match optional(get(&config, &self.config_key(key)))? {
+            Some(value) => Ok(Some(value)),
+            None => optional(get(&config, &self.legacy_config_key(key))),
+        }
+    }
+
+    /// Store the setting under its current key, dropping the legacy one
+    fn set_config_value(
+        &self,
+        key: &str,
+        set: impl FnOnce(&mut git2::Config, &str) -> Result<(), git2::Error>,
+    ) -> Result<(), git2::Error> {
+        let mut config = self.repository.config()?;
+        set(&mut config, &self.config_key(key))?;
+        remove_config_key(&mut config, &self.legacy_config_key(key))
+    }
+
+    /// Push through a proxy. The setting is stored in the configuration of the repository,
+    /// so it applies to every Replicator initialized with the same remote name from now on.
+    pub fn set_proxy(&self, proxy: ReplicaProxy) -> Result<(), git2::Error> {
+        let value = match &proxy {
+            ReplicaProxy::Auto => "auto",
+            ReplicaProxy::Url(url) => url.as_str(),
+        };
+        self.set_config_value("proxy", |config, key| config.set_str(key, value))
+    }
+
+    /// Go back to pushing without a proxy
+    pub fn remove_proxy(&self) -> Result<(), git2::Error> {
+        self.set_config_value("proxy", remove_config_key)
+    }
+
+    pub fn proxy(&self) -> Result<Option<ReplicaProxy>, git2::Error> {
+        let proxy = self.config_value("proxy", git2::Config::get_string)?;
+        Ok(proxy.map(|value| match value.as_str() {
+            "auto" => ReplicaProxy::Auto,
+            _ => ReplicaProxy::Url(value),
+        }))
+    }
+
+    /// Number of threads used to build the pack sent to the remote, 0 means one per CPU.
+    /// Stored in the configuration of the repository, like the proxy.
+    pub fn set_packbuilder_parallelism(&self, threads: u32) -> Result<(), git2::Error> {
+        self.set_config_value("packbuilderparallelism", |config, key| {
+            config.set_i64(key, threads as i64)
         })
     }
 
+    pub fn packbuilder_parallelism(&self) -> Result<Option<u32>, git2::Error> {
+        let threads = self.config_value("packbuilderparallelism", git2::Config::get_i64)?;
+        Ok(threads.map(|threads| threads.clamp(0, u32::MAX as i64) as u32))
+    }
+
     fn ensure_remote<'a>(
         repo: &'a Repository,
         remote_name: &str,
@@ -62,6 +628,7 @@ impl Replicator {
     fn resolve_periodic_ref<'a>(
         repo: &'a Repository,
         remote_name: &str,
+        signature: &Signature,
     ) -> Result<Reference<'a>, git2::Error> {
         let ref_name = Self::last_push_ref(remote_name);
         let reference = repo.find_reference(&ref_name);
@@ -77,7 +644,7 @@ impl Replicator {
                 let head = repo.head().unwrap();
                 reflog.append(
                     head.target().unwrap(),
-                    &Self::signature(),
+                    signature,
                     Some(0.to_string().as_str()),
                 )?;
                 reflog.write()?;
@@ -86,14 +653,31 @@ impl Replicator {
         }
     }
 
-    fn tags_to_push(&self) -> Result<Vec<String>, git2::Error> {
+    /// The commit of main to push, along with the snapshots of the indexes taken at it
+    /// (see `Index::snapshot`). Writes update the indexes right after moving main,
+    /// the write lock keeps them out in between.
+    fn pin_main(&self) -> Result<(Oid, Vec<String>), git2::Error> {
+        let _lock = lock::acquire(&self.repository, lock::DEFAULT_LOCK_TIMEOUT)?;
+        let main = Self::current_commit(&self.repository, "main")?.id();
+        Ok((main, Index::snapshot_all(&self.repository, main)?))
+    }
+
+    /// Main is pinned to the given commit, so that writes made while pushing aren't included.
+    /// The snapshots of the indexes taken at that commit are pushed along.
+    fn tags_to_push(&self, main: Oid, snapshots: &[String]) -> Result<Vec<String>, git2::Error> {
         let glob = format!("refs/history_tags/{}/*", self.remote_name);
         let refs = self.repository.references_glob(glob.as_str())?;
         let mut to_push = Vec::new();
-        to_push.push(String::from("+refs/heads/main"));
+        to_push.push(format!("+{}:refs/heads/main", main));
+        for index_ref in snapshots {
+            to_push.push(format!("+{}:{}", index_ref, index_ref));
+        }
+        if self.push_metadata()? && self.repository.find_reference(meta::META_REF).is_ok() {
+            to_push.push(format!("+{}:{}", meta::META_REF, meta::META_REF));
+        }
         for reference in refs.flatten() {
             let ref_name = reference.name().unwrap();
-            let last_part = ref_name.split('/').last().unwrap();
+            let last_part = ref_name.split('/').next_back().unwrap();
             let tag_name = format!("refs/tags/{}", last_part);
             self.repository.tag_lightweight(
                 last_part,
@@ -106,7 +690,7 @@ impl Replicator {
         let refs_rm = self.repository.references_glob(glob_rm.as_str())?;
         for reference in refs_rm.flatten() {
             let ref_name = reference.name().unwrap();
-            let last_part = ref_name.split('/').last().unwrap();
+            let last_part = ref_name.split('/').next_back().unwrap();
             let tag_name = format!(":refs/tags/{}", last_part);
             to_push.push(tag_name);
         }
@@ -115,7 +699,7 @@ impl Replicator {
 
     fn remove_old_tags(&self, list: &Vec<String>) -> Result<(), git2::Error> {
         for tag in list {
-            if tag == "+refs/heads/main" {
+            if !tag.starts_with("refs/tags/") && !tag.starts_with(":refs/tags/") {
                 continue;
             }
             let history_tag = tag.replace(format!("refs/tags/{}__", self.remote_name).as_str(), "");
@@ -138,47 +722,106 @@ impl Replicator {
 
     /// Try to replicate data to the remote specified during Replicator::initialize.
     /// Depending on the chosen ReplicationMethod, it may or may not actually happen.
-    /// That's why a bool is returned -> true indicates successful replication, while false means
-    /// that the replication was not even attempted (this result might be different when called
-    /// again in the future)
-    pub fn replicate(&self) -> Result<bool, error::ReplicationError> {
+    /// That's why a ReplicationOutcome is returned -> Replicated indicates successful replication
+    /// (along with the number of push attempts made according to the RetryPolicy), while Skipped
+    /// means that the replication was not even attempted (this result might be different when
+    /// called again in the future).
+    /// The push happens on the calling thread and is finished by the time this returns,
+    /// so there is no replication left in flight that a shutdown would have to wait for.
+    /// Use `spawn_worker` to push from a thread of its own instead.
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(
+            name = "replica.replicate",
+            skip_all,
+            fields(remote = self.remote_name.as_str(), outcome = tracing::field::Empty)
+        )
+    )]
+    pub fn replicate(&self) -> Result<ReplicationOutcome, error::ReplicationError> {
+        if !self.should_replicate()? {
+            record!("outcome", "skipped");
+            return Ok(ReplicationOutcome::Skipped);
+        }
+        let (main, snapshots) = self.pin_main()?;
+        let attempts = self.push(main, &snapshots)?;
+        record!("outcome", "replicated");
+        Ok(ReplicationOutcome::Replicated(attempts))
+    }
+
+    /// Replicate main, acknowledging that the given commit (for example the one returned by
+    /// `Collection::set_batch`) made it to the remote.
+    /// Main is pushed as it is now, which may already be past the commit because of later writes -
+    /// in that case the result is Superseded and contains the commit that was actually pushed.
+    /// Fails with CommitNotOnMain if the commit is not in the history of main anymore
+    /// (e.g. it was reverted), since pushing main would not replicate it.
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(
+            name = "replica.replicate_commit",
+            skip_all,
+            fields(
+                remote = self.remote_name.as_str(),
+                commit = commit.to_string(),
+                outcome = tracing::field::Empty
+            )
+        )
+    )]
+    pub fn replicate_commit(
+        &self,
+        commit: Oid,
+    ) -> Result<ReplicaPushResult, error::ReplicationError> {
+        if !self.should_replicate()? {
+            record!("outcome", "skipped");
+            return Ok(ReplicaPushResult::Skipped);
+        }
+        let (main, snapshots) = self.pin_main()?;
+        if main != commit && !self.repository.graph_descendant_of(main, commit)? {
+            return Err(error::ReplicationError::CommitNotOnMain(commit));
+        }
+        let attempts = self.push(main, &snapshots)?;
+        if main == commit {
+            record!("outcome", "pushed");
+            Ok(ReplicaPushResult::Pushed { commit, attempts })
+        } else {
+            record!("outcome", "superseded");
+            debug!("{} was superseded by {} when pushing", commit, main);
+            Ok(ReplicaPushResult::Superseded {
+                commit,
+                pushed: main,
+                attempts,
+            })
+        }
+    }
+
+    fn should_replicate(&self) -> Result<bool, error::ReplicationError> {
         let rand_res: f64 = rand::thread_rng().gen();
         let replicate = match self.replication_method {
             ReplicationMethod::All => true,
             ReplicationMethod::Random(chance) => rand_res < chance,
             ReplicationMethod::Periodic(peroid) => {
-                Self::resolve_periodic_ref(&self.repository, &self.remote_name)?;
+                Self::resolve_periodic_ref(&self.repository, &self.remote_name, &self.signature())?;
                 let reflog = &self
                     .repository
                     .reflog(Self::last_push_ref(self.remote_name.as_str()).as_str())?;
                 debug!("Reflog has {} entries", reflog.len());
                 let last_push = reflog.get(0).unwrap().message().unwrap().parse().unwrap();
                 let next_push_timestamp = DateTime::from_timestamp(last_push, 0).unwrap();
-                next_push_timestamp.timestamp() + peroid < Utc::now().timestamp()
+                next_push_timestamp.timestamp() + peroid < self.clock.now()
             }
         };
-        if !replicate {
-            return Ok(false);
-        }
+        Ok(replicate)
+    }
+
+    /// Push main, pinned to the given commit, along with the history tags and the snapshots
+    /// of the indexes (see `pin_main`). Returns the number of attempts it took.
+    fn push(&self, main: Oid, snapshots: &[String]) -> Result<usize, error::ReplicationError> {
         let mut remote = Self::ensure_remote(
             &self.repository,
             self.remote_name.as_str(),
             self.remote_url.as_str(),
         )?;
         let mut tags_to_remove = Vec::new();
-        let mut callbacks = RemoteCallbacks::new();
-        if let Some(ref cred) = self.credentials {
-            callbacks.credentials(|_, username_from_url, _| {
-                Cred::ssh_key(
-                    cred.username
-                        .as_deref()
-                        .unwrap_or(username_from_url.unwrap_or("git")),
-                    cred.publickey.as_deref(),
-                    cred.privatekey.as_path(),
-                    cred.passphrase.as_deref(),
-                )
-            });
-        }
+        let mut callbacks = remote_callbacks(self.credentials.as_ref());
         callbacks.push_update_reference(|reference, result| {
             if let Some(_result) = result {
                 debug!("Pushing {} failed: {}", reference, _result);
@@ -188,29 +831,155 @@ impl Replicator {
             tags_to_remove.push(reference.to_string());
             Ok(())
         });
+        if let Some(ref sender) = self.push_progress {
+            callbacks.push_transfer_progress(|objects, total_objects, bytes| {
+                // nobody is listening anymore, which is fine
+                let _ = sender.send(PushProgress {
+                    objects,
+                    total_objects,
+                    bytes,
+                });
+            });
+        }
         let mut push_options = PushOptions::new();
         push_options.remote_callbacks(callbacks);
-        let tags_to_push = self.tags_to_push()?;
-        remote.push(tags_to_push.as_ref(), Some(&mut push_options))?;
+        if let Some(proxy) = self.proxy()? {
+            let mut proxy_options = ProxyOptions::new();
+            match proxy {
+                ReplicaProxy::Auto => proxy_options.auto(),
+                ReplicaProxy::Url(ref url) => proxy_options.url(url),
+            };
+            push_options.proxy_options(proxy_options);
+        }
+        if let Some(threads) = self.packbuilder_parallelism()? {
+            push_options.packbuilder_parallelism(threads);
+        }
+        let tags_to_push = self.tags_to_push(main, snapshots)?;
+        #[cfg(any(feature = "tracing", feature = "full"))]
+        let _span = tracing::info_span!(
+            "replica.push",
+            remote = self.remote_name.as_str(),
+            duration_ms = tracing::field::Empty,
+            attempts = tracing::field::Empty,
+            result = tracing::field::Empty
+        )
+        .entered();
+        let push_start = Instant::now();
+        let mut attempts = 0;
+        let push_result = loop {
+            attempts += 1;
+            match remote.push(tags_to_push.as_ref(), Some(&mut push_options)) {
+                Err(_err) if attempts < self.retry_policy.max_attempts => {
+                    let backoff = self.retry_policy.backoff(attempts);
+                    debug!(
+                        "Push attempt #{} to {} failed: {}; retrying in {:?}",
+                        attempts, self.remote_name, _err, backoff
+                    );
+                    self.clock.sleep(backoff);
+                }
+                result => break result,
+            }
+        };
+        let push_duration = push_start.elapsed();
+        self.metrics.record_replication(
+            &self.remote_name,
+            push_result.is_ok(),
+            attempts,
+            push_duration,
+        );
+        record!("duration_ms", push_duration.as_millis() as u64);
+        record!("attempts", attempts);
+        record!(
+            "result",
+            match &push_result {
+                Ok(_) => String::from("ok"),
+                Err(err) => err.message().to_string(),
+            }
+        );
+        self.record_status(push_result.as_ref().map(|_| ()))?;
+        push_result?;
         drop(push_options);
         self.remove_old_tags(&tags_to_remove)?;
         if let ReplicationMethod::Periodic(_) = self.replication_method {
-            let current_time = Utc::now().timestamp();
+            let current_time = self.clock.now();
             let mut reflog = self
                 .repository
                 .reflog(&Self::last_push_ref(self.remote_name.as_str()))?;
-
-            // unwrap: head has to exist and point at something
-            let head_target = self.repository.head().unwrap().target().unwrap();
-
             reflog.append(
-                head_target,
-                &Self::signature(),
+                main,
+                &Self::signature_at(current_time),
                 Some(current_time.to_string().as_str()),
             )?;
             reflog.write()?;
         }
-        Ok(true)
+        Ok(attempts)
+    }
+}
+
+/// Replicates on a thread of its own, so writers never wait for the network.
+/// Created with `Replicator::spawn_worker`.
+///
+/// The queue holds at most one request: `notify` while a request is already waiting
+/// is merged into it, since every push sends main as it is when the push starts.
+/// That way there is at most one push per replica in flight and at most one waiting.
+pub struct ReplicationWorker {
+    queue: Option<SyncSender<()>>,
+    handle: Option<JoinHandle<Replicator>>,
+}
+
+impl ReplicationWorker {
+    /// Ask for a replication without waiting for it. Returns false if it was merged
+    /// into a request which is already waiting (or the worker is gone).
+    pub fn notify(&self) -> bool {
+        match self.queue.as_ref().map(|queue| queue.try_send(())) {
+            Some(Ok(())) => true,
+            Some(Err(TrySendError::Full(()))) => {
+                debug!("replication already queued, merging the request");
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// Finish the requests in the queue and return the Replicator.
+    /// Waits for the push in flight, so it can take as long as the network does.
+    pub fn shutdown(mut self) -> Replicator {
+        drop(self.queue.take());
+        self.handle
+            .take()
+            .expect("the worker is only joined once")
+            .join()
+            .expect("replication worker panicked")
+    }
+}
+
+impl Replicator {
+    /// Move the Replicator to a thread of its own which replicates whenever it's notified
+    /// with `ReplicationWorker::notify`. The result of every replication is sent to `outcomes`,
+    /// if given. Dropping the worker without `shutdown` lets the thread finish the requests
+    /// in the queue in the background.
+    pub fn spawn_worker(
+        self,
+        outcomes: Option<Sender<Result<ReplicationOutcome, error::ReplicationError>>>,
+    ) -> ReplicationWorker {
+        let (queue, requests): (SyncSender<()>, Receiver<()>) = mpsc::sync_channel(1);
+        let handle = std::thread::spawn(move || {
+            for () in requests {
+                let outcome = self.replicate();
+                if let Err(_err) = &outcome {
+                    debug!("replication to {} failed: {:?}", self.name, _err);
+                }
+                if let Some(outcomes) = &outcomes {
+                    // nobody listening anymore is not a reason to stop replicating
+                    let _ = outcomes.send(outcome);
+                }
+            }
+            self
+        });
+        ReplicationWorker {
+            queue: Some(queue),
+            handle: Some(handle),
+        }
     }
 }
 
@@ -222,15 +991,72 @@ pub struct RemoteCredentials {
     pub passphrase: Option<String>,
 }
 
+/// Callbacks authenticating with the ssh key of the credentials, if there are any
+fn remote_callbacks(credentials: Option<&RemoteCredentials>) -> RemoteCallbacks<'_> {
+    let mut callbacks = RemoteCallbacks::new();
+    if let Some(cred) = credentials {
+        callbacks.credentials(|_, username_from_url, _| {
+            Cred::ssh_key(
+                cred.username
+                    .as_deref()
+                    .unwrap_or(username_from_url.unwrap_or("git")),
+                cred.publickey.as_deref(),
+                cred.privatekey.as_path(),
+                cred.passphrase.as_deref(),
+            )
+        });
+    }
+    callbacks
+}
+
+/// Clone the repository at `url` into a new bare repository at `path`, along with the
+/// snapshots of the indexes the remote has (see `Index::snapshot`). The remote is not
+/// kept, `Collection::clone_from` sets it up as a replica instead.
+pub(crate) fn clone_repository(
+    url: &str,
+    path: &Path,
+    credentials: Option<&RemoteCredentials>,
+) -> Result<Repository, git2::Error> {
+    let mut fetch_options = FetchOptions::new();
+    fetch_options.remote_callbacks(remote_callbacks(credentials));
+    let repo = RepoBuilder::new()
+        .bare(true)
+        .fetch_options(fetch_options)
+        .clone(url, path)?;
+    {
+        let mut remote = repo.find_remote("origin")?;
+        let mut fetch_options = FetchOptions::new();
+        fetch_options.remote_callbacks(remote_callbacks(credentials));
+        let snapshots = format!("+{}*:{}*", SNAPSHOT_REFS, SNAPSHOT_REFS);
+        remote.fetch(&[snapshots.as_str()], Some(&mut fetch_options), None)?;
+    }
+    repo.remote_delete("origin")?;
+    Ok(repo)
+}
+
 #[cfg(test)]
 mod tests {
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
     use git2::Reference;
 
+    use std::cmp::Ordering::*;
+
     use crate::{
-        replica::{ReplicationMethod, Replicator},
+        clock::{Clock, MockClock},
+        error,
+        index::{IndexType, Order},
+        metrics::test::RecordingMetrics,
+        query::{q, QueryBuilder, ResolutionStrategy},
+        replica::{
+            ReplicaProxy, ReplicaPushResult, ReplicaStatus, ReplicationMethod, ReplicationOutcome,
+            Replicator, RetryPolicy,
+        },
         serialization::DataFormat,
-        test::{create_db, SampleDbStruct},
-        OperationTarget,
+        test::{create_db, create_linked_pair, InterigentDbStruct, SampleDbStruct},
+        Collection, OperationTarget,
     };
 
     use rstest::rstest;
@@ -245,6 +1071,80 @@ mod tests {
         Replicator::initialize(td.path(), "test", "test", ReplicationMethod::All, None).unwrap();
     }
 
+    #[rstest]
+    #[case("")]
+    #[case("   ")]
+    #[case("https://")]
+    #[case("https:///repo.git")]
+    #[case("ftp://example.com/repo.git")]
+    #[case("https://example.com/my repo.git")]
+    #[case("file://")]
+    fn test_replica_invalid_url(#[case] url: &str) {
+        let (_db, td) = create_db(DataFormat::Json);
+        assert_eq!(
+            Replicator::initialize(td.path(), "test", url, ReplicationMethod::All, None).err(),
+            Some(error::InitializationError::InvalidReplicaUrl(
+                url.to_string()
+            ))
+        );
+        assert!(_db.repository().find_remote("_repl_test").is_err());
+    }
+
+    #[test]
+    fn test_replica_path_with_spaces() {
+        let (_db, td) = create_db(DataFormat::Json);
+        let backup = tempfile::tempdir().unwrap();
+        let path = backup.path().join("my backups");
+        let url = path.to_str().unwrap();
+        let replica =
+            Replicator::initialize(td.path(), "test", url, ReplicationMethod::All, None).unwrap();
+        assert_eq!(replica.url(), url);
+    }
+
+    #[test]
+    fn test_replica_conflicting_remote() {
+        let (db, td) = create_db(DataFormat::Json);
+        db.repository()
+            .remote("_repl_test", "https://example.com/elsewhere.git")
+            .unwrap();
+        assert_eq!(
+            Replicator::initialize(
+                td.path(),
+                "test",
+                "https://example.com/repo.git",
+                ReplicationMethod::All,
+                None
+            )
+            .err(),
+            Some(error::InitializationError::RemoteConflict(String::from(
+                "https://example.com/elsewhere.git"
+            )))
+        );
+        // the same url is fine
+        Replicator::initialize(
+            td.path(),
+            "test",
+            "https://example.com/elsewhere.git",
+            ReplicationMethod::All,
+            None,
+        )
+        .unwrap();
+        // and now that it's a replica, its url can be replaced
+        let repl = Replicator::initialize(
+            td.path(),
+            "test",
+            "git@example.com:repo.git",
+            ReplicationMethod::All,
+            None,
+        )
+        .unwrap();
+        assert_eq!(repl.url(), "git@example.com:repo.git");
+        assert_eq!(
+            db.repository().find_remote("_repl_test").unwrap().url(),
+            Some("git@example.com:repo.git")
+        );
+    }
+
     #[rstest]
     #[case(DataFormat::Json)]
     #[case(DataFormat::Yaml)]
@@ -267,7 +1167,7 @@ mod tests {
         )
         .unwrap();
         let result = repl.replicate().unwrap();
-        assert!(result);
+        assert_eq!(result, ReplicationOutcome::Replicated(1));
         assert_eq!(
             db_backup
                 .get::<SampleDbStruct>("a", OperationTarget::Main)
@@ -283,29 +1183,238 @@ mod tests {
     #[case(DataFormat::Json)]
     #[case(DataFormat::Yaml)]
     #[case(DataFormat::Pot)]
-    fn test_replica_periodic(#[case] data_format: DataFormat) {
-        let (db, _td) = create_db(data_format);
-        let (db_backup, _td_backup) = create_db(data_format);
+    fn test_replica_receives_indexes(#[case] data_format: DataFormat) {
+        let (db, td) = create_db(data_format);
+        let (db_backup, td_backup) = create_db(data_format);
+        let index = db.add_index("num_val", IndexType::Numeric);
+        for (key, num_val) in [("a", 1), ("b", 5), ("c", 9)] {
+            db.set(key, InterigentDbStruct { num_val }, OperationTarget::Main)
+                .unwrap();
+        }
         let repl = Replicator::initialize(
-            _td.path(),
+            td.path(),
             "test",
-            _td_backup.path().to_str().unwrap(),
-            ReplicationMethod::Periodic(0),
+            td_backup.path().to_str().unwrap(),
+            ReplicationMethod::All,
             None,
         )
         .unwrap();
+        repl.replicate().unwrap();
+        assert!(db_backup
+            .repository()
+            .find_reference("refs/yamabiko/indexes/num_val#numeric.index")
+            .is_ok());
+        drop(db_backup);
+
+        // the replica takes over without rebuilding the index
+        let db_backup = Collection::initialize(td_backup.path(), data_format).unwrap();
+        assert_eq!(
+            index
+                .scan(db_backup.repository(), Order::Ascending)
+                .collect::<Vec<_>>(),
+            index
+                .scan(db.repository(), Order::Ascending)
+                .collect::<Vec<_>>()
+        );
+        let result = QueryBuilder::query(q("num_val", Greater, 3))
+            .execute(&db_backup)
+            .unwrap();
+        assert_eq!(result.count, 2);
+        assert_eq!(
+            result.resolution_strategy,
+            ResolutionStrategy::UseIndexes(vec![index.clone()])
+        );
+
+        // an existing index file is replaced once a newer snapshot arrives
         db.set(
-            "a",
-            SampleDbStruct::new(String::from("a value")),
+            "d",
+            InterigentDbStruct { num_val: 4 },
             OperationTarget::Main,
         )
         .unwrap();
-        let result = repl.replicate().unwrap();
-        assert!(result);
+        repl.replicate().unwrap();
+        drop(db_backup);
+        let db_backup = Collection::initialize(td_backup.path(), data_format).unwrap();
         assert_eq!(
-            db_backup
-                .get::<SampleDbStruct>("a", OperationTarget::Main)
-                .unwrap()
+            index.scan(db_backup.repository(), Order::Ascending).count(),
+            4
+        );
+        // the snapshot is of the pushed commit of main
+        let snapshot = db_backup
+            .repository()
+            .find_reference("refs/yamabiko/indexes/num_val#numeric.index")
+            .unwrap()
+            .peel_to_commit()
+            .unwrap()
+            .parent_id(0)
+            .unwrap();
+        assert_eq!(
+            snapshot,
+            db_backup
+                .repository()
+                .refname_to_id("refs/heads/main")
+                .unwrap()
+        );
+
+        // but not when the snapshot stays the same
+        db_backup
+            .set(
+                "e",
+                InterigentDbStruct { num_val: 6 },
+                OperationTarget::Main,
+            )
+            .unwrap();
+        drop(db_backup);
+        let db_backup = Collection::initialize(td_backup.path(), data_format).unwrap();
+        assert_eq!(
+            index.scan(db_backup.repository(), Order::Ascending).count(),
+            5
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_replica_receives_namespace_indexes(#[case] data_format: DataFormat) {
+        let (db, td) = create_db(data_format);
+        let (db_backup, td_backup) = create_db(data_format);
+        let index = db
+            .add_index("num_val", IndexType::Numeric)
+            .in_namespace("users.namespace");
+        let users = db.namespace("users").unwrap();
+        for (key, num_val) in [("a", 1), ("b", 5), ("c", 9)] {
+            users
+                .set(key, InterigentDbStruct { num_val }, OperationTarget::Main)
+                .unwrap();
+        }
+        let repl = Replicator::initialize(
+            td.path(),
+            "test",
+            td_backup.path().to_str().unwrap(),
+            ReplicationMethod::All,
+            None,
+        )
+        .unwrap();
+        repl.replicate().unwrap();
+        assert!(db_backup
+            .repository()
+            .find_reference("refs/yamabiko/indexes/users.namespace/num_val#numeric.index")
+            .is_ok());
+        drop(db_backup);
+
+        let db_backup = Collection::initialize(td_backup.path(), data_format).unwrap();
+        assert_eq!(
+            index
+                .scan(db_backup.repository(), Order::Ascending)
+                .collect::<Vec<_>>(),
+            index
+                .scan(db.repository(), Order::Ascending)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            index.scan(db_backup.repository(), Order::Ascending).count(),
+            3
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_replica_periodic(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let (db_backup, _td_backup) = create_db(data_format);
+        let repl = Replicator::initialize(
+            _td.path(),
+            "test",
+            _td_backup.path().to_str().unwrap(),
+            ReplicationMethod::Periodic(0),
+            None,
+        )
+        .unwrap();
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("a value")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let result = repl.replicate().unwrap();
+        assert_eq!(result, ReplicationOutcome::Replicated(1));
+        assert_eq!(
+            db_backup
+                .get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct {
+                str_val: String::from("a value")
+            }
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_replica_retry_after_failure(#[case] data_format: DataFormat) {
+        /// Creates the remote while the replicator waits to retry, without waiting
+        struct CreateRemoteOnSleep {
+            path: PathBuf,
+            data_format: DataFormat,
+            slept: Mutex<Vec<Duration>>,
+        }
+
+        impl Clock for CreateRemoteOnSleep {
+            fn now(&self) -> i64 {
+                1_700_000_000
+            }
+
+            fn sleep(&self, duration: Duration) {
+                self.slept.lock().unwrap().push(duration);
+                Collection::initialize(&self.path, self.data_format).unwrap();
+            }
+        }
+
+        let (db, _td) = create_db(data_format);
+        let (_, _td_backup) = create_db(data_format);
+        let backup_path = _td_backup.path().join("backup");
+        let clock = Arc::new(CreateRemoteOnSleep {
+            path: backup_path.clone(),
+            data_format,
+            slept: Mutex::new(Vec::new()),
+        });
+        let repl = Replicator::initialize(
+            _td.path(),
+            "test",
+            backup_path.to_str().unwrap(),
+            ReplicationMethod::All,
+            None,
+        )
+        .unwrap()
+        .with_retry_policy(RetryPolicy::new(
+            3,
+            Duration::from_millis(1000),
+            Duration::from_millis(1000),
+        ))
+        .with_clock(clock.clone());
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("a value")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        // the first attempt fails since the remote doesn't exist yet
+        let result = repl.replicate().unwrap();
+        assert_eq!(result, ReplicationOutcome::Replicated(2));
+        assert_eq!(
+            *clock.slept.lock().unwrap(),
+            vec![Duration::from_millis(1000)]
+        );
+        let db_backup = Collection::initialize(&backup_path, data_format).unwrap();
+        assert_eq!(
+            db_backup
+                .get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap()
                 .unwrap(),
             SampleDbStruct {
                 str_val: String::from("a value")
@@ -313,6 +1422,534 @@ mod tests {
         );
     }
 
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_replica_metrics(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let (_, _td_backup) = create_db(data_format);
+        let metrics = Arc::new(RecordingMetrics::default());
+        let repl = Replicator::initialize(
+            _td.path(),
+            "test",
+            _td_backup.path().to_str().unwrap(),
+            ReplicationMethod::All,
+            None,
+        )
+        .unwrap()
+        .with_metrics(metrics.clone());
+        let failing_repl = Replicator::initialize(
+            _td.path(),
+            "failing",
+            _td_backup.path().join("missing").to_str().unwrap(),
+            ReplicationMethod::All,
+            None,
+        )
+        .unwrap()
+        .with_metrics(metrics.clone());
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("a value")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        repl.replicate().unwrap();
+        assert!(failing_repl.replicate().is_err());
+        assert_eq!(
+            *metrics.replications.lock().unwrap(),
+            vec![
+                (String::from("_repl_test"), true, 1),
+                (String::from("_repl_failing"), false, 1)
+            ]
+        );
+    }
+
+    fn remote_main(db: &Collection) -> git2::Oid {
+        db.repository()
+            .find_branch("main", git2::BranchType::Local)
+            .unwrap()
+            .get()
+            .target()
+            .unwrap()
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_replicate_commit(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let (db_backup, _td_backup) = create_db(data_format);
+        let repl = Replicator::initialize(
+            _td.path(),
+            "test",
+            _td_backup.path().to_str().unwrap(),
+            ReplicationMethod::All,
+            None,
+        )
+        .unwrap();
+        let first = db
+            .set(
+                "a",
+                SampleDbStruct::new(String::from("a value")),
+                OperationTarget::Main,
+            )
+            .unwrap();
+        assert_eq!(
+            repl.replicate_commit(first).unwrap(),
+            ReplicaPushResult::Pushed {
+                commit: first,
+                attempts: 1
+            }
+        );
+        assert_eq!(remote_main(&db_backup), first);
+
+        // the second write lands before the first one is replicated
+        let second = db
+            .set(
+                "b",
+                SampleDbStruct::new(String::from("b value")),
+                OperationTarget::Main,
+            )
+            .unwrap();
+        let third = db
+            .set(
+                "c",
+                SampleDbStruct::new(String::from("c value")),
+                OperationTarget::Main,
+            )
+            .unwrap();
+        assert_eq!(
+            repl.replicate_commit(second).unwrap(),
+            ReplicaPushResult::Superseded {
+                commit: second,
+                pushed: third,
+                attempts: 1
+            }
+        );
+        assert_eq!(
+            repl.replicate_commit(third).unwrap(),
+            ReplicaPushResult::Pushed {
+                commit: third,
+                attempts: 1
+            }
+        );
+        assert_eq!(remote_main(&db_backup), third);
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_replicate_reverted_commit(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let (db_backup, _td_backup) = create_db(data_format);
+        let repl = Replicator::initialize(
+            _td.path(),
+            "test",
+            _td_backup.path().to_str().unwrap(),
+            ReplicationMethod::All,
+            None,
+        )
+        .unwrap();
+        let backup_main = remote_main(&db_backup);
+        let reverted = db
+            .set(
+                "a",
+                SampleDbStruct::new(String::from("a value")),
+                OperationTarget::Main,
+            )
+            .unwrap();
+        db.revert_n_commits(1, OperationTarget::Main, false)
+            .unwrap();
+        assert_eq!(
+            repl.replicate_commit(reverted),
+            Err(error::ReplicationError::CommitNotOnMain(reverted))
+        );
+        assert_eq!(remote_main(&db_backup), backup_main);
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_replica_push_progress(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let (db_backup, _td_backup) = create_db(data_format);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let repl = Replicator::initialize(
+            _td.path(),
+            "test",
+            _td_backup.path().to_str().unwrap(),
+            ReplicationMethod::All,
+            None,
+        )
+        .unwrap()
+        .with_push_progress(sender);
+        repl.set_proxy(ReplicaProxy::Auto).unwrap();
+        repl.set_packbuilder_parallelism(2).unwrap();
+        db.set_batch(
+            (0..300).map(|i| (format!("key-{}", i), SampleDbStruct::new(i.to_string()))),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        repl.replicate().unwrap();
+        let progress: Vec<_> = receiver.try_iter().collect();
+        let last = progress.last().unwrap();
+        assert!(last.total_objects > 300);
+        assert_eq!(last.objects, last.total_objects);
+        assert!(last.bytes > 0);
+        assert!(db_backup
+            .get::<SampleDbStruct>("key-299", OperationTarget::Main)
+            .unwrap()
+            .is_some());
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_replica_push_options_persist(#[case] data_format: DataFormat) {
+        let (db, td) = create_db(data_format);
+        let repl = Replicator::initialize(td.path(), "test", "test", ReplicationMethod::All, None)
+            .unwrap();
+        assert_eq!(repl.proxy().unwrap(), None);
+        assert_eq!(repl.packbuilder_parallelism().unwrap(), None);
+        repl.set_proxy(ReplicaProxy::Url(String::from("http://proxy.local:3128")))
+            .unwrap();
+        repl.set_packbuilder_parallelism(4).unwrap();
+        drop(repl);
+        drop(db);
+
+        let _db = Collection::initialize(td.path(), data_format).unwrap();
+        let repl = Replicator::initialize(td.path(), "test", "test", ReplicationMethod::All, None)
+            .unwrap();
+        assert_eq!(
+            repl.proxy().unwrap(),
+            Some(ReplicaProxy::Url(String::from("http://proxy.local:3128")))
+        );
+        assert_eq!(repl.packbuilder_parallelism().unwrap(), Some(4));
+        let other =
+            Replicator::initialize(td.path(), "other", "other", ReplicationMethod::All, None)
+                .unwrap();
+        assert_eq!(other.proxy().unwrap(), None);
+        repl.remove_proxy().unwrap();
+        assert_eq!(repl.proxy().unwrap(), None);
+    }
+
+    #[test]
+    fn test_replica_legacy_config_keys() {
+        let (db, td) = create_db(DataFormat::Json);
+        let mut config = db.repository().config().unwrap();
+        config.set_str("yamabiko._repl_test.proxy", "auto").unwrap();
+        config
+            .set_i64("yamabiko._repl_test.packbuilderparallelism", 2)
+            .unwrap();
+        let repl = Replicator::initialize(td.path(), "test", "test", ReplicationMethod::All, None)
+            .unwrap();
+        assert_eq!(repl.proxy().unwrap(), Some(ReplicaProxy::Auto));
+        assert_eq!(repl.packbuilder_parallelism().unwrap(), Some(2));
+
+        repl.set_packbuilder_parallelism(8).unwrap();
+        assert_eq!(repl.packbuilder_parallelism().unwrap(), Some(8));
+        repl.remove_proxy().unwrap();
+        assert_eq!(repl.proxy().unwrap(), None);
+        let config = db.repository().config().unwrap().snapshot().unwrap();
+        assert!(config
+            .get_entry("yamabiko._repl_test.packbuilderparallelism")
+            .is_err());
+        assert!(config.get_entry("yamabiko._repl_test.proxy").is_err());
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_replica_load(#[case] data_format: DataFormat) {
+        let (db, td) = create_db(data_format);
+        let (db_backup, _td_backup) = create_db(data_format);
+        let mut repl = Replicator::initialize(
+            td.path(),
+            "test",
+            _td_backup.path().to_str().unwrap(),
+            ReplicationMethod::Periodic(0),
+            None,
+        )
+        .unwrap();
+        let retry_policy =
+            RetryPolicy::new(3, Duration::from_millis(10), Duration::from_millis(200));
+        repl.set_retry_policy(retry_policy.clone()).unwrap();
+        Replicator::initialize(
+            td.path(),
+            "other",
+            "other",
+            ReplicationMethod::Random(0.25),
+            None,
+        )
+        .unwrap();
+        drop(repl);
+        drop(db);
+
+        let db = Collection::initialize(td.path(), data_format).unwrap();
+        assert_eq!(
+            Replicator::configured(td.path()).unwrap(),
+            vec![String::from("other"), String::from("test")]
+        );
+        let other = Replicator::load(td.path(), "other", None).unwrap();
+        assert_eq!(other.url(), "other");
+        assert_eq!(*other.replication_method(), ReplicationMethod::Random(0.25));
+        let repl = Replicator::load(td.path(), "test", None).unwrap();
+        assert_eq!(*repl.replication_method(), ReplicationMethod::Periodic(0));
+        assert_eq!(*repl.retry_policy(), retry_policy);
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("a value")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        assert_eq!(repl.replicate().unwrap(), ReplicationOutcome::Replicated(1));
+        assert!(db_backup
+            .get::<SampleDbStruct>("a", OperationTarget::Main)
+            .unwrap()
+            .is_some());
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_replica_reload(#[case] data_format: DataFormat) {
+        let (db, td) = create_db(data_format);
+        let (db_backup, _td_backup) = create_db(data_format);
+        let mut repl = Replicator::initialize(
+            td.path(),
+            "test",
+            _td_backup.path().join("missing").to_str().unwrap(),
+            ReplicationMethod::Random(0.0),
+            None,
+        )
+        .unwrap();
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("a value")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        assert_eq!(repl.replicate().unwrap(), ReplicationOutcome::Skipped);
+        // edited by another process
+        let mut config = git2::Repository::open_bare(td.path())
+            .unwrap()
+            .config()
+            .unwrap();
+        config
+            .set_str(
+                "yamabiko.replica.test.url",
+                _td_backup.path().to_str().unwrap(),
+            )
+            .unwrap();
+        config
+            .set_str("yamabiko.replica.test.method", "all")
+            .unwrap();
+        repl.reload().unwrap();
+        assert_eq!(repl.replicate().unwrap(), ReplicationOutcome::Replicated(1));
+        assert!(db_backup
+            .get::<SampleDbStruct>("a", OperationTarget::Main)
+            .unwrap()
+            .is_some());
+
+        config
+            .set_str("yamabiko.replica.test.method", "sometimes")
+            .unwrap();
+        assert_eq!(
+            repl.reload(),
+            Err(error::InitializationError::InvalidReplicaConfiguration(
+                String::from("yamabiko.replica.test.method")
+            ))
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_replica_remove(#[case] data_format: DataFormat) {
+        let (db, td) = create_db(data_format);
+        let repl = Replicator::initialize(td.path(), "test", "test", ReplicationMethod::All, None)
+            .unwrap();
+        repl.set_proxy(ReplicaProxy::Auto).unwrap();
+        repl.remove().unwrap();
+        assert!(Replicator::configured(td.path()).unwrap().is_empty());
+        assert_eq!(
+            Replicator::load(td.path(), "test", None).err(),
+            Some(error::InitializationError::ReplicaNotConfigured(
+                String::from("test")
+            ))
+        );
+        assert!(db.repository().find_remote("_repl_test").is_err());
+        let config = db.repository().config().unwrap().snapshot().unwrap();
+        assert!(config.get_string("yamabiko.replica.test.proxy").is_err());
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_replication_worker(#[case] data_format: DataFormat) {
+        let (db, td) = create_db(data_format);
+        let (db_backup, td_backup) = create_db(data_format);
+        let repl = Replicator::initialize(
+            td.path(),
+            "test",
+            td_backup.path().to_str().unwrap(),
+            ReplicationMethod::All,
+            None,
+        )
+        .unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let worker = repl.spawn_worker(Some(tx));
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("a value")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        assert!(worker.notify());
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(30)).unwrap(),
+            Ok(ReplicationOutcome::Replicated(1))
+        );
+        let repl = worker.shutdown();
+        assert_eq!(repl.name(), "test");
+        assert_eq!(
+            db_backup
+                .get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct::new(String::from("a value"))
+        );
+    }
+
+    #[test]
+    fn test_slow_push_does_not_block_reads() {
+        let (db, td) = create_db(DataFormat::Json);
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("a value")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        // a remote which accepts the connection and then never says anything
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("git://{}/slow", listener.local_addr().unwrap());
+        let repl =
+            Replicator::initialize(td.path(), "slow", &url, ReplicationMethod::All, None).unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let worker = repl.spawn_worker(Some(tx));
+        assert!(worker.notify());
+        let (connection, _) = listener.accept().unwrap();
+        // the first push is in flight, the next request waits and the one after it is merged
+        assert!(worker.notify());
+        assert!(!worker.notify());
+        db.set(
+            "b",
+            SampleDbStruct::new(String::from("b value")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct::new(String::from("a value"))
+        );
+        assert!(rx.try_recv().is_err());
+        drop(connection);
+        drop(listener);
+        assert!(rx.recv_timeout(Duration::from_secs(30)).unwrap().is_err());
+        assert!(rx.recv_timeout(Duration::from_secs(30)).unwrap().is_err());
+        worker.shutdown();
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_replica_status(#[case] data_format: DataFormat) {
+        let (db, td) = create_db(data_format);
+        let (_, td_backup) = create_db(data_format);
+        let backup_path = td_backup.path().join("backup");
+        let clock = Arc::new(MockClock::new(1_700_000_000));
+        let repl = Replicator::initialize(
+            td.path(),
+            "test",
+            backup_path.to_str().unwrap(),
+            ReplicationMethod::All,
+            None,
+        )
+        .unwrap()
+        .with_retry_policy(RetryPolicy::new(1, Duration::ZERO, Duration::ZERO))
+        .with_clock(clock.clone());
+        assert_eq!(repl.status().unwrap(), ReplicaStatus::default());
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("a value")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        // the remote doesn't exist yet
+        assert!(repl.replicate().is_err());
+        let failed = repl.status().unwrap();
+        assert_eq!(failed.last_success, None);
+        assert!(failed.last_error.is_some());
+        assert_eq!(failed.last_error_at, Some(1_700_000_000));
+
+        Collection::initialize(&backup_path, data_format).unwrap();
+        clock.advance(60);
+        repl.replicate().unwrap();
+        let status = repl.status().unwrap();
+        assert_eq!(status.last_success, Some(1_700_000_060));
+        assert_eq!(status.last_error, failed.last_error);
+
+        assert_eq!(
+            db.metadata().keys().unwrap(),
+            vec![String::from("replica.test.status")]
+        );
+        repl.remove().unwrap();
+        assert!(db.metadata().keys().unwrap().is_empty());
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_replica_push_metadata(#[case] data_format: DataFormat) {
+        let pair = create_linked_pair(data_format);
+        pair.primary.metadata().increment("counter", 1).unwrap();
+        assert!(!pair.replicator.push_metadata().unwrap());
+        pair.replicator.replicate().unwrap();
+        assert!(pair.backup.metadata().keys().unwrap().is_empty());
+
+        pair.replicator.set_push_metadata(true).unwrap();
+        pair.replicator.replicate().unwrap();
+        assert_eq!(
+            pair.backup.metadata().get("counter").unwrap(),
+            Some(b"1".to_vec())
+        );
+        // only the documents are on main
+        assert_eq!(pair.backup.iter(OperationTarget::Main).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_retry_policy_backoff() {
+        let policy = RetryPolicy::new(10, Duration::from_millis(100), Duration::from_millis(1000));
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(4), Duration::from_millis(800));
+        assert_eq!(policy.backoff(5), Duration::from_millis(1000));
+        assert_eq!(policy.backoff(100), Duration::from_millis(1000));
+    }
+
     #[rstest]
     #[case(DataFormat::Json)]
     #[case(DataFormat::Yaml)]
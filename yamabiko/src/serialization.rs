@@ -4,6 +4,7 @@ use std::{collections::HashMap, str::FromStr};
 
 use crate::error::InvalidDataFormatError;
 use crate::field::Field;
+use crate::index::{Index, IndexType};
 
 #[cfg(any(feature = "yaml", feature = "full"))]
 use serde_yml;
@@ -51,17 +52,32 @@ impl Display for DataFormat {
 }
 
 impl DataFormat {
+    /// Add the value to the ones indexed, unless the index can't hold it or has it already
+    fn push_index_value(index: &Index, value: Result<Field, ()>, values: &mut Vec<Field>) {
+        if let Ok(value) = value {
+            if index.indexes_given_field(&value) && !values.contains(&value) {
+                values.push(value);
+            }
+        }
+    }
+
+    /// Values of the document to put in each of the indexes - the value of the field,
+    /// or each distinct element of it for the `IndexType::Collection` indexes
     pub fn extract_indexes_json(
         data: &serde_json::Value,
-        indexes: &mut HashMap<&crate::index::Index, Option<Field>>,
+        indexes: &mut HashMap<&Index, Vec<Field>>,
     ) {
         for (k, v) in indexes.iter_mut() {
-            if let Some(index_value) = data.get(k.indexed_field()) {
-                if let Ok(field) = Field::try_from(index_value) {
-                    if k.indexes_given_field(&field) {
-                        *v = Some(field);
+            let Some(index_value) = data.get(k.indexed_field()) else {
+                continue;
+            };
+            match k.kind() {
+                IndexType::Collection => {
+                    for element in index_value.as_array().into_iter().flatten() {
+                        Self::push_index_value(k, Field::try_from(element), v);
                     }
                 }
+                _ => Self::push_index_value(k, Field::try_from(index_value), v),
             }
         }
     }
@@ -69,42 +85,77 @@ impl DataFormat {
     #[cfg(any(feature = "yaml", feature = "full"))]
     pub fn extract_indexes_yaml(
         data: &serde_yml::Value,
-        indexes: &mut HashMap<&crate::index::Index, Option<Field>>,
+        indexes: &mut HashMap<&Index, Vec<Field>>,
     ) {
         for (k, v) in indexes.iter_mut() {
-            if let Some(index_value) = data.get(k.indexed_field()) {
-                if let Ok(field) = Field::try_from(index_value) {
-                    if k.indexes_given_field(&field) {
-                        *v = Some(field);
+            let Some(index_value) = data.get(k.indexed_field()) else {
+                continue;
+            };
+            match k.kind() {
+                IndexType::Collection => {
+                    for element in index_value.as_sequence().into_iter().flatten() {
+                        Self::push_index_value(k, Field::try_from(element), v);
                     }
                 }
+                _ => Self::push_index_value(k, Field::try_from(index_value), v),
             }
         }
     }
 
     #[cfg(any(feature = "pot", feature = "full"))]
-    pub fn extract_indexes_pot(
-        data: &pot::Value,
-        indexes: &mut HashMap<&crate::index::Index, Option<Field>>,
-    ) {
+    pub fn extract_indexes_pot(data: &pot::Value, indexes: &mut HashMap<&Index, Vec<Field>>) {
         for (k, v) in indexes.iter_mut() {
-            if let Some(index_value) = data
+            let Some((_, index_value)) = data
                 .mappings()
                 .find(|m| m.0 == pot::Value::from(k.indexed_field()))
-            {
-                if let Ok(field) = Field::try_from(&index_value.1) {
-                    if k.indexes_given_field(&field) {
-                        *v = Some(field);
+            else {
+                continue;
+            };
+            match (k.kind(), index_value) {
+                (IndexType::Collection, pot::Value::Sequence(elements)) => {
+                    for element in elements {
+                        Self::push_index_value(k, Field::try_from(element), v);
                     }
                 }
+                (IndexType::Collection, _) => {}
+                _ => Self::push_index_value(k, Field::try_from(index_value), v),
+            }
+        }
+    }
+
+    /// Extract the values of the indexed fields from a stored document, like
+    /// `serialize_with_indexes_raw` but without writing the document again. Returns
+    /// the reason it can't be parsed if it's not a document in this format.
+    pub fn extract_indexes_raw(
+        &self,
+        data: &[u8],
+        indexes: &mut HashMap<&Index, Vec<Field>>,
+    ) -> Result<(), String> {
+        match self {
+            Self::Json => {
+                let v: serde_json::Value =
+                    serde_json::from_slice(data).map_err(|err| err.to_string())?;
+                DataFormat::extract_indexes_json(&v, indexes);
+            }
+            #[cfg(any(feature = "yaml", feature = "full"))]
+            Self::Yaml => {
+                let v: serde_yml::Value =
+                    serde_yml::from_slice(data).map_err(|err| err.to_string())?;
+                DataFormat::extract_indexes_yaml(&v, indexes);
+            }
+            #[cfg(any(feature = "pot", feature = "full"))]
+            Self::Pot => {
+                let v: pot::Value = pot::from_slice(data).map_err(|err| err.to_string())?;
+                DataFormat::extract_indexes_pot(&v, indexes);
             }
         }
+        Ok(())
     }
 
     pub fn serialize_with_indexes_raw(
         &self,
         data: &[u8],
-        indexes: &mut HashMap<&crate::index::Index, Option<Field>>,
+        indexes: &mut HashMap<&Index, Vec<Field>>,
     ) -> Vec<u8> {
         match self {
             Self::Json => {
@@ -130,7 +181,7 @@ impl DataFormat {
     pub fn serialize_with_indexes<T>(
         &self,
         data: T,
-        indexes: &mut HashMap<&crate::index::Index, Option<Field>>,
+        indexes: &mut HashMap<&Index, Vec<Field>>,
     ) -> Vec<u8>
     where
         T: Serialize,
@@ -191,6 +242,85 @@ impl DataFormat {
         }
     }
 
+    /// Whether the top-level field is an array with the value among its elements
+    pub fn match_element(&self, data: &[u8], field: &str, value: &Field) -> bool {
+        match self {
+            Self::Json => {
+                let v: serde_json::Value = serde_json::from_slice(data).unwrap();
+                v.get(field)
+                    .and_then(|res| res.as_array())
+                    .is_some_and(|elements| elements.iter().any(|element| value == element))
+            }
+            #[cfg(any(feature = "yaml", feature = "full"))]
+            Self::Yaml => {
+                let v: serde_yml::Value = serde_yml::from_slice(data).unwrap();
+                v.get(field)
+                    .and_then(|res| res.as_sequence())
+                    .is_some_and(|elements| elements.iter().any(|element| value == element))
+            }
+            #[cfg(any(feature = "pot", feature = "full"))]
+            Self::Pot => {
+                let v: pot::Value = pot::from_slice(data).unwrap();
+                match v.mappings().find(|m| m.0 == pot::Value::from(field)) {
+                    Some((_, pot::Value::Sequence(elements))) => {
+                        elements.iter().any(|element| value == element)
+                    }
+                    _ => false,
+                }
+            }
+        }
+    }
+
+    /// Extract the value of a top-level field, if it can be represented as a Field
+    pub fn extract_field(&self, data: &[u8], field: &str) -> Option<Field> {
+        match self {
+            Self::Json => {
+                let v: serde_json::Value = serde_json::from_slice(data).unwrap();
+                v.get(field).and_then(|res| Field::try_from(res).ok())
+            }
+            #[cfg(any(feature = "yaml", feature = "full"))]
+            Self::Yaml => {
+                let v: serde_yml::Value = serde_yml::from_slice(data).unwrap();
+                v.get(field).and_then(|res| Field::try_from(res).ok())
+            }
+            #[cfg(any(feature = "pot", feature = "full"))]
+            Self::Pot => {
+                let v: pot::Value = pot::from_slice(data).unwrap();
+                v.mappings()
+                    .find(|m| m.0 == pot::Value::from(field))
+                    .and_then(|res| Field::try_from(&res.1).ok())
+            }
+        }
+    }
+
+    /// Whether the format isn't text made of lines, so values can't be merged line by line
+    pub fn is_binary(&self) -> bool {
+        match self {
+            Self::Json => false,
+            #[cfg(any(feature = "yaml", feature = "full"))]
+            Self::Yaml => false,
+            #[cfg(any(feature = "pot", feature = "full"))]
+            Self::Pot => true,
+        }
+    }
+
+    /// Check that the data can be parsed in this format, without panicking if it can't
+    pub fn validate(&self, data: &[u8]) -> Result<(), String> {
+        match self {
+            Self::Json => serde_json::from_slice::<serde_json::Value>(data)
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+            #[cfg(any(feature = "yaml", feature = "full"))]
+            Self::Yaml => serde_yml::from_slice::<serde_yml::Value>(data)
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+            #[cfg(any(feature = "pot", feature = "full"))]
+            Self::Pot => pot::from_slice::<pot::Value>(data)
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+        }
+    }
+
     pub fn deserialize<'a, T>(&self, data: &'a [u8]) -> T
     where
         T: Deserialize<'a>,
@@ -0,0 +1,424 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use git2::{ErrorCode, Oid};
+
+use crate::{debug, error, record, Collection};
+
+/// Signs the commits written by a Collection and verifies the signatures afterwards.
+///
+/// The signature is stored in the `gpgsig` header of the commit, just like `git commit -S` does,
+/// so `git log --show-signature` understands it as well.
+pub trait CommitSigner: Send + Sync {
+    /// Returns an armored signature of the commit buffer.
+    fn sign(&self, commit: &str) -> Result<String, error::SigningError>;
+    /// Returns whether the signature is a valid signature of the commit buffer made by this signer.
+    fn verify(&self, commit: &str, signature: &str) -> Result<bool, error::SigningError>;
+}
+
+/// Signs with an SSH key using `ssh-keygen -Y sign`, in the `git` namespace.
+pub struct SshSigner {
+    key: PathBuf,
+}
+
+impl SshSigner {
+    /// `key` is the path to the private key. It must not be protected with a passphrase,
+    /// since there is no one to type it in.
+    pub fn new(key: impl Into<PathBuf>) -> Self {
+        Self { key: key.into() }
+    }
+
+    fn public_key(&self) -> Result<String, error::SigningError> {
+        let output = run(
+            Command::new("ssh-keygen")
+                .arg("-y")
+                .arg("-f")
+                .arg(&self.key),
+            None,
+        )?;
+        if !output.status.success() {
+            return Err(failed(&output));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+}
+
+impl CommitSigner for SshSigner {
+    fn sign(&self, commit: &str) -> Result<String, error::SigningError> {
+        let output = run(
+            Command::new("ssh-keygen")
+                .args(["-Y", "sign", "-n", "git", "-f"])
+                .arg(&self.key),
+            Some(commit),
+        )?;
+        if !output.status.success() {
+            return Err(failed(&output));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+
+    fn verify(&self, commit: &str, signature: &str) -> Result<bool, error::SigningError> {
+        let dir = tempfile::tempdir().map_err(io_failed)?;
+        let allowed_signers = dir.path().join("allowed_signers");
+        let signature_file = dir.path().join("signature");
+        std::fs::write(
+            &allowed_signers,
+            format!("yamabiko namespaces=\"git\" {}\n", self.public_key()?),
+        )
+        .map_err(io_failed)?;
+        std::fs::write(&signature_file, signature).map_err(io_failed)?;
+        let output = run(
+            Command::new("ssh-keygen")
+                .args(["-Y", "verify", "-I", "yamabiko", "-n", "git", "-f"])
+                .arg(&allowed_signers)
+                .arg("-s")
+                .arg(&signature_file),
+            Some(commit),
+        )?;
+        Ok(output.status.success())
+    }
+}
+
+/// Signs with a GPG key using `gpg --detach-sign`.
+pub struct GpgSigner {
+    key_id: String,
+    home: Option<PathBuf>,
+}
+
+impl GpgSigner {
+    /// `key_id` is anything gpg accepts as `--local-user`. The key must not be protected
+    /// with a passphrase, unless gpg-agent already has it cached.
+    pub fn new(key_id: impl Into<String>) -> Self {
+        Self {
+            key_id: key_id.into(),
+            home: None,
+        }
+    }
+
+    /// Use this directory as the gpg home instead of the default one (`GNUPGHOME`)
+    pub fn with_home(mut self, home: impl Into<PathBuf>) -> Self {
+        self.home = Some(home.into());
+        self
+    }
+
+    fn gpg(&self) -> Command {
+        let mut command = Command::new("gpg");
+        command.arg("--batch");
+        if let Some(home) = &self.home {
+            command.arg("--homedir").arg(home);
+        }
+        command
+    }
+}
+
+impl CommitSigner for GpgSigner {
+    fn sign(&self, commit: &str) -> Result<String, error::SigningError> {
+        let output = run(
+            self.gpg()
+                .args(["--armor", "--detach-sign", "--local-user"])
+                .arg(&self.key_id),
+            Some(commit),
+        )?;
+        if !output.status.success() {
+            return Err(failed(&output));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+
+    fn verify(&self, commit: &str, signature: &str) -> Result<bool, error::SigningError> {
+        let dir = tempfile::tempdir().map_err(io_failed)?;
+        let signature_file = dir.path().join("signature.asc");
+        std::fs::write(&signature_file, signature).map_err(io_failed)?;
+        let output = run(
+            self.gpg()
+                .arg("--status-fd=1")
+                .arg("--verify")
+                .arg(&signature_file)
+                .arg("-"),
+            Some(commit),
+        )?;
+        // any key in the keyring would pass `--verify`, so check the signature is made by ours
+        let status = String::from_utf8_lossy(&output.stdout);
+        let key_id = self.key_id.to_uppercase();
+        Ok(output.status.success()
+            && status.lines().any(|line| {
+                let line = line.to_uppercase();
+                // the key id can be a user id (matched by GOODSIG) or a (suffix of) a fingerprint
+                (line.starts_with("[GNUPG:] GOODSIG ") && line.contains(key_id.as_str()))
+                    || (line.starts_with("[GNUPG:] VALIDSIG ")
+                        && line
+                            .split(' ')
+                            .any(|field| field.ends_with(key_id.as_str())))
+            }))
+    }
+}
+
+fn run(
+    command: &mut Command,
+    stdin: Option<&str>,
+) -> Result<std::process::Output, error::SigningError> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(io_failed)?;
+    // dropping the handle closes stdin, so the program knows there is nothing more to read
+    if let Some(mut handle) = child.stdin.take() {
+        handle
+            .write_all(stdin.unwrap_or_default().as_bytes())
+            .map_err(io_failed)?;
+    }
+    child.wait_with_output().map_err(io_failed)
+}
+
+fn failed(output: &std::process::Output) -> error::SigningError {
+    error::SigningError::SignerFailed(String::from_utf8_lossy(&output.stderr).trim().to_string())
+}
+
+fn io_failed(err: std::io::Error) -> error::SigningError {
+    error::SigningError::SignerFailed(err.to_string())
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct SignatureReport {
+    /// Number of commits with a signature the signer accepted.
+    pub valid: usize,
+    /// Commits without a signature, like the initial commit, commits written before the signer
+    /// was configured and commits written by rebasing or squashing.
+    pub unsigned: Vec<Oid>,
+    /// Commits with a signature the signer rejected.
+    pub invalid: Vec<Oid>,
+}
+
+impl SignatureReport {
+    /// There are no invalid signatures. Unsigned commits are not considered a problem,
+    /// since every history starts with one.
+    pub fn is_ok(&self) -> bool {
+        self.invalid.is_empty()
+    }
+}
+
+pub(crate) fn verify_signatures(
+    collection: &Collection,
+    signer: &dyn CommitSigner,
+) -> Result<SignatureReport, error::SigningError> {
+    let repo = collection.repository();
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push_ref("refs/heads/main")?;
+    let mut report = SignatureReport::default();
+    for oid in revwalk {
+        let oid = oid?;
+        let (signature, content) = match repo.extract_signature(&oid, None) {
+            Ok(extracted) => extracted,
+            Err(err) if err.code() == ErrorCode::NotFound => {
+                report.unsigned.push(oid);
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let valid = match (signature.as_str(), content.as_str()) {
+            (Some(signature), Some(content)) => signer.verify(content, signature)?,
+            _ => false,
+        };
+        if valid {
+            report.valid += 1;
+        } else {
+            debug!("commit {} has an invalid signature", oid);
+            report.invalid.push(oid);
+        }
+    }
+    record!("valid", report.valid);
+    record!("invalid", report.invalid.len());
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::Path,
+        process::{Command, Stdio},
+        sync::Arc,
+    };
+
+    use git2::ObjectType;
+    use rstest::rstest;
+
+    use crate::{
+        error::SigningError,
+        serialization::DataFormat,
+        signing::{CommitSigner, GpgSigner, SshSigner},
+        test::*,
+        OperationTarget,
+    };
+
+    /// Whether the program can be run at all - the tests of the signer which needs it
+    /// are skipped otherwise
+    fn installed(program: &str) -> bool {
+        let found = Command::new(program)
+            .arg("--version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok();
+        if !found {
+            eprintln!("skipping: {} is not installed", program);
+        }
+        found
+    }
+
+    fn ssh_key(dir: &Path) -> std::path::PathBuf {
+        let key = dir.join("id_ed25519");
+        let status = Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-f"])
+            .arg(&key)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        key
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_unsigned_commits_have_no_signature(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let commit = db
+            .set(
+                "a",
+                SampleDbStruct::new(String::from("a")),
+                OperationTarget::Main,
+            )
+            .unwrap();
+        let repo = db.repository();
+        let odb = repo.odb().unwrap();
+        let raw = odb.read(commit).unwrap();
+        assert_eq!(raw.kind(), ObjectType::Commit);
+        assert!(!String::from_utf8_lossy(raw.data()).contains("gpgsig"));
+        assert!(repo.extract_signature(&commit, None).is_err());
+        assert_eq!(db.verify_signatures(), Err(SigningError::NoSigner));
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_ssh_signed_commits(#[case] data_format: DataFormat) {
+        if !installed("ssh-keygen") {
+            return;
+        }
+        let keys = tempfile::tempdir().unwrap();
+        let signer = Arc::new(SshSigner::new(ssh_key(keys.path())));
+        let (db, _td) = create_db(data_format);
+        let unsigned = db
+            .set(
+                "a",
+                SampleDbStruct::new(String::from("a")),
+                OperationTarget::Main,
+            )
+            .unwrap();
+        let db = db.with_signer(signer.clone());
+        let signed = db
+            .set(
+                "b",
+                SampleDbStruct::new(String::from("b")),
+                OperationTarget::Main,
+            )
+            .unwrap();
+        let t = db.new_transaction(None).unwrap();
+        db.set(
+            "c",
+            SampleDbStruct::new(String::from("c")),
+            OperationTarget::Transaction(&t),
+        )
+        .unwrap();
+        db.set(
+            "b",
+            SampleDbStruct::new(String::from("b2")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        db.apply_transaction(&t, crate::ConflictResolution::Overwrite)
+            .unwrap();
+        let (signature, content) = db.repository().extract_signature(&signed, None).unwrap();
+        assert!(signature
+            .as_str()
+            .unwrap()
+            .starts_with("-----BEGIN SSH SIGNATURE-----"));
+        assert!(signer
+            .verify(content.as_str().unwrap(), signature.as_str().unwrap())
+            .unwrap());
+        let report = db.verify_signatures().unwrap();
+        assert!(report.is_ok(), "{:?}", report);
+        assert!(report.unsigned.contains(&unsigned));
+        assert!(!report.unsigned.contains(&signed));
+        assert!(report.valid >= 2);
+    }
+
+    #[test]
+    fn test_ssh_signature_by_other_key_is_invalid() {
+        if !installed("ssh-keygen") {
+            return;
+        }
+        let keys = tempfile::tempdir().unwrap();
+        let other_keys = tempfile::tempdir().unwrap();
+        let (db, _td) = create_db(DataFormat::Json);
+        let db = db.with_signer(Arc::new(SshSigner::new(ssh_key(other_keys.path()))));
+        let commit = db
+            .set(
+                "a",
+                SampleDbStruct::new(String::from("a")),
+                OperationTarget::Main,
+            )
+            .unwrap();
+        let db = db.with_signer(Arc::new(SshSigner::new(ssh_key(keys.path()))));
+        let report = db.verify_signatures().unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.invalid, vec![commit]);
+    }
+
+    #[test]
+    fn test_gpg_signed_commits() {
+        if !installed("gpg") {
+            return;
+        }
+        let home = tempfile::tempdir().unwrap();
+        let status = Command::new("gpg")
+            .arg("--homedir")
+            .arg(home.path())
+            .args([
+                "--batch",
+                "--passphrase",
+                "",
+                "--quick-gen-key",
+                "yamabiko <yamabiko@localhost>",
+                "ed25519",
+                "sign",
+                "never",
+            ])
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let signer = Arc::new(GpgSigner::new("yamabiko@localhost").with_home(home.path()));
+        let (db, _td) = create_db(DataFormat::Json);
+        let db = db.with_signer(signer);
+        let commit = db
+            .set(
+                "a",
+                SampleDbStruct::new(String::from("a")),
+                OperationTarget::Main,
+            )
+            .unwrap();
+        let (signature, _) = db.repository().extract_signature(&commit, None).unwrap();
+        assert!(signature
+            .as_str()
+            .unwrap()
+            .starts_with("-----BEGIN PGP SIGNATURE-----"));
+        let report = db.verify_signatures().unwrap();
+        assert_eq!(report.valid, 1);
+        assert!(report.is_ok());
+    }
+}
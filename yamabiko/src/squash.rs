@@ -1,23 +1,38 @@
 use core::str;
 use std::path::Path;
+use std::sync::Arc;
 
 use chrono::DateTime;
 use git2::{
     build::CheckoutBuilder, BranchType, IndexEntry, MergeOptions, Oid, RebaseOptions, Repository,
 };
 
-use crate::{debug, error, RepositoryAbstraction};
+use crate::{
+    clock::{Clock, SystemClock},
+    debug, error, lock, record, RepositoryAbstraction,
+};
 
 pub struct Squasher {
     repository: Repository,
+    clock: Arc<dyn Clock>,
 }
 
 impl RepositoryAbstraction for Squasher {}
 
 impl Squasher {
     pub fn initialize(path: &Path) -> Result<Self, error::InitializationError> {
-        let repo = Self::load_or_create_repo(path)?;
-        Ok(Self { repository: repo })
+        let repo = Self::load_or_create_repo(path, SystemClock.now())?;
+        Ok(Self {
+            repository: repo,
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+    /// Take the timestamps of the commits written from now on from the given Clock,
+    /// see `Collection::with_clock`
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
     }
 
     pub fn cleanup_revert_history_tags(
@@ -51,6 +66,14 @@ impl Squasher {
         Ok(())
     }
 
+    #[cfg_attr(
+        any(feature = "tracing", feature = "full"),
+        tracing::instrument(
+            name = "squasher.squash",
+            skip_all,
+            fields(before = commit.to_string(), commit = tracing::field::Empty)
+        )
+    )]
     pub fn squash_before_commit(&self, commit: Oid) -> Result<(), git2::Error> {
         let annotated_commit = self.repository.find_annotated_commit(commit)?;
         let mut checkout_options = CheckoutBuilder::default();
@@ -63,15 +86,15 @@ impl Squasher {
         rebase_options.merge_options(merge_options);
         rebase_options.checkout_options(checkout_options);
 
-        let signature = &Self::signature();
+        let signature = &Self::signature_at(self.clock.now());
 
         let treebuilder = self.repository.treebuilder(None)?;
         let new_root_tree_id = treebuilder.write()?;
         let new_root_tree = self.repository.find_tree(new_root_tree_id)?;
         let new_root_commit_id = self.repository.commit(
             None,
-            &Self::signature(),
-            &Self::signature(),
+            signature,
+            signature,
             "squash old commits",
             &new_root_tree,
             &[],
@@ -80,6 +103,8 @@ impl Squasher {
         let new_root_commit_normal = self.repository.find_commit(new_root_commit_id)?;
         debug!("New orphan commit id is {}", new_root_tree.id());
 
+        // main can't move from under the rebase until it's replaced with the result
+        let _lock = lock::acquire(&self.repository, lock::DEFAULT_LOCK_TIMEOUT)?;
         let reference = self.repository.find_branch("main", BranchType::Local)?;
         let main_commit = self
             .repository
@@ -144,20 +169,57 @@ impl Squasher {
             &[&new_root_commit_normal],
         )?;
         debug!("New tip is {}", final_commit);
-        self.repository
-            .reference("refs/heads/main", final_commit, true, "")?;
+        record!("commit", final_commit.to_string());
+        self.repository.reference_matching(
+            "refs/heads/main",
+            final_commit,
+            true,
+            main_commit.id(),
+            "",
+        )?;
         Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+
     use git2::{BranchType, Repository};
 
-    use crate::{serialization::DataFormat, squash::Squasher, test::*, OperationTarget};
+    use crate::{
+        clock::MockClock, serialization::DataFormat, squash::Squasher, test::*, OperationTarget,
+    };
 
     use rstest::rstest;
 
+    #[test]
+    fn test_squash_with_clock() {
+        let (db, td) = create_db(DataFormat::Json);
+        let squasher = Squasher::initialize(td.path())
+            .unwrap()
+            .with_clock(Arc::new(MockClock::new(1_700_000_000)));
+        for value in ["a", "b"] {
+            db.set(
+                "a",
+                SampleDbStruct::new(String::from(value)),
+                OperationTarget::Main,
+            )
+            .unwrap();
+        }
+        let head = db.head(OperationTarget::Main).unwrap();
+        squasher.squash_before_commit(head).unwrap();
+        let repo = db.repository();
+        let tip = repo
+            .find_branch("main", BranchType::Local)
+            .unwrap()
+            .get()
+            .peel_to_commit()
+            .unwrap();
+        assert_eq!(tip.time().seconds(), 1_700_000_000);
+        assert_eq!(tip.parent(0).unwrap().time().seconds(), 1_700_000_000);
+    }
+
     #[rstest]
     #[case(DataFormat::Json)]
     #[case(DataFormat::Yaml)]
@@ -345,6 +407,56 @@ mod tests {
         assert_eq!(new_head_commit.parent(0).unwrap().parent_count(), 0);
     }
 
+    #[test]
+    fn test_restore_after_squash() {
+        let (db, td) = create_db(DataFormat::Json);
+        let squasher = Squasher::initialize(td.path()).unwrap();
+        let a = SampleDbStruct::new(String::from("a"));
+        db.set("a", a.clone(), OperationTarget::Main).unwrap();
+        db.soft_delete("a", OperationTarget::Main).unwrap();
+        db.set(
+            "b",
+            SampleDbStruct::new(String::from("b")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let repo = Repository::open(td.path()).unwrap();
+        let head_commit = repo
+            .find_branch("main", BranchType::Local)
+            .unwrap()
+            .into_reference()
+            .peel_to_commit()
+            .unwrap();
+        squasher
+            .squash_before_commit(head_commit.parent(0).unwrap().id())
+            .unwrap();
+        // the commit writing the value is gone, but the value is still reachable from main
+        let (_, meta) = db
+            .get_with_meta("a", OperationTarget::Main)
+            .unwrap()
+            .unwrap();
+        let blob = meta.document.unwrap().tombstone.unwrap().blob;
+        let mut reachable = false;
+        let mut revwalk = repo.revwalk().unwrap();
+        revwalk.push_ref("refs/heads/main").unwrap();
+        for commit in revwalk {
+            let tree = repo.find_commit(commit.unwrap()).unwrap().tree().unwrap();
+            tree.walk(git2::TreeWalkMode::PreOrder, |_, entry| {
+                reachable |= entry.id().to_string() == blob;
+                git2::TreeWalkResult::Ok
+            })
+            .unwrap();
+        }
+        assert!(reachable);
+        assert!(db.restore("a", OperationTarget::Main).unwrap());
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap(),
+            Some(a)
+        );
+        assert_eq!(db.iter(OperationTarget::Main).unwrap().count(), 2);
+    }
+
     #[tokio::test]
     async fn test_no_discarded_changes_while_squashing() {
         let (db, td) = create_db(DataFormat::Json);
@@ -0,0 +1,271 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use git2::{ObjectType, Oid, TreeWalkResult};
+
+use crate::index::Index;
+use crate::{error, namespace, Collection, RepositoryAbstraction};
+
+/// Number of documents checked against every index when looking for stale indexes
+const STALENESS_SAMPLE_SIZE: usize = 32;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct IndexStats {
+    pub index: Index,
+    /// Number of entries in the index.
+    pub entries: usize,
+    /// Some of the sampled documents on main have a value for the indexed field,
+    /// but no entry in the index.
+    pub stale: bool,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct CollectionStats {
+    /// Number of keys on main.
+    pub keys: usize,
+    /// Number of commits in the history of main.
+    pub commits: usize,
+    pub indexes: Vec<IndexStats>,
+    /// Size of the repository directory, including the index files, in bytes.
+    pub size_on_disk: u64,
+}
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct DedupStats {
+    /// Number of keys on main.
+    pub keys: usize,
+    /// Size of all the values on main, as if every key had its own copy, in bytes.
+    pub logical_bytes: u64,
+    /// Number of distinct blobs the values are stored in.
+    pub unique_blobs: usize,
+    /// Size of the distinct blobs, before compression, in bytes.
+    pub unique_bytes: u64,
+    /// Number of keys sharing each of the blobs.
+    pub keys_per_blob: HashMap<Oid, usize>,
+}
+
+impl DedupStats {
+    /// How many times more space the values would take without deduplication,
+    /// 1.0 for an empty collection
+    pub fn ratio(&self) -> f64 {
+        if self.unique_bytes == 0 {
+            return 1.0;
+        }
+        self.logical_bytes as f64 / self.unique_bytes as f64
+    }
+}
+
+fn dir_size(path: &Path) -> Result<u64, std::io::Error> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+pub(crate) fn stats(collection: &Collection) -> Result<CollectionStats, error::StatsError> {
+    let repo = collection.repository();
+    let head = Collection::current_commit(repo, "main")?;
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(head.id())?;
+    let commits = revwalk.count();
+
+    let mut documents = Vec::new();
+    head.tree()?
+        .walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+            let Some(name) = entry.name() else {
+                return TreeWalkResult::Skip;
+            };
+            if (root.is_empty() && name.ends_with(".index")) || namespace::is_reserved_tree(entry) {
+                return TreeWalkResult::Skip;
+            }
+            if entry.kind() == Some(ObjectType::Blob) {
+                documents.push((root.to_string(), name.to_string(), entry.id()));
+            }
+            TreeWalkResult::Ok
+        })?;
+    let keys = documents.len();
+    let step = keys.div_ceil(STALENESS_SAMPLE_SIZE).max(1);
+    let mut sample = Vec::new();
+    for (root, name, blob) in documents.into_iter().step_by(step) {
+        let key = Collection::key_from_path(&root, &name)?;
+        let hash = Oid::hash_object(ObjectType::Blob, key.as_bytes())?;
+        sample.push((hash, repo.find_blob(blob)?));
+    }
+
+    let mut indexes = Vec::new();
+    for index in collection.index_list() {
+        let git_index = index.git_index(repo);
+        let indexed: HashSet<Oid> = git_index.iter().map(|e| e.id).collect();
+        let stale = sample.iter().any(|(hash, blob)| {
+            let mut values = HashMap::from([(&index, Vec::new())]);
+            collection
+                .data_format
+                .serialize_with_indexes_raw(blob.content(), &mut values);
+            !values[&index].is_empty() && !indexed.contains(hash)
+        });
+        indexes.push(IndexStats {
+            entries: git_index.len(),
+            index,
+            stale,
+        });
+    }
+
+    Ok(CollectionStats {
+        keys,
+        commits,
+        indexes,
+        size_on_disk: dir_size(repo.path())?,
+    })
+}
+
+pub(crate) fn dedup_stats(collection: &Collection) -> Result<DedupStats, error::StatsError> {
+    let repo = collection.repository();
+    let odb = repo.odb()?;
+    let mut blobs = Vec::new();
+    Collection::current_commit(repo, "main")?.tree()?.walk(
+        git2::TreeWalkMode::PreOrder,
+        |root, entry| {
+            if (root.is_empty() && entry.name().is_some_and(|n| n.ends_with(".index")))
+                || namespace::is_reserved_tree(entry)
+            {
+                return TreeWalkResult::Skip;
+            }
+            if entry.kind() == Some(ObjectType::Blob) {
+                blobs.push(entry.id());
+            }
+            TreeWalkResult::Ok
+        },
+    )?;
+    let mut stats = DedupStats {
+        keys: blobs.len(),
+        ..Default::default()
+    };
+    let mut sizes = HashMap::new();
+    for blob in blobs {
+        let size = match sizes.get(&blob) {
+            Some(size) => *size,
+            None => {
+                let (size, _) = odb.read_header(blob)?;
+                sizes.insert(blob, size as u64);
+                stats.unique_bytes += size as u64;
+                size as u64
+            }
+        };
+        stats.logical_bytes += size;
+        *stats.keys_per_blob.entry(blob).or_default() += 1;
+    }
+    stats.unique_blobs = sizes.len();
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rstest::rstest;
+
+    use crate::{index::IndexType, serialization::DataFormat, test::*, OperationTarget};
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_stats(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let index = db.add_index("num_val", IndexType::Numeric);
+        db.set_batch(
+            (0..10).map(|i| (format!("key-{}", i), InterigentDbStruct { num_val: i })),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        db.set(
+            "nested/key",
+            InterigentDbStruct { num_val: 10 },
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.keys, 11);
+        // init, add index, two writes
+        assert_eq!(stats.commits, 4);
+        assert_eq!(stats.indexes.len(), 1);
+        assert_eq!(stats.indexes[0].index, index);
+        assert_eq!(stats.indexes[0].entries, 11);
+        assert!(!stats.indexes[0].stale);
+        assert!(stats.size_on_disk > 0);
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_stats_stale_index(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let index = db.add_index("num_val", IndexType::Numeric);
+        db.set(
+            "a",
+            InterigentDbStruct { num_val: 1 },
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let mut git_index = index.git_index(db.repository());
+        git_index.clear().unwrap();
+        git_index.write().unwrap();
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.indexes[0].entries, 0);
+        assert!(stats.indexes[0].stale);
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_dedup_stats(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        assert_eq!(db.dedup_stats().unwrap().ratio(), 1.0);
+        db.add_index("str_val", IndexType::Sequential);
+        db.set_batch(
+            (0..4).map(|i| {
+                (
+                    format!("same-{}", i),
+                    SampleDbStruct::new(String::from("same")),
+                )
+            }),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        db.set(
+            "nested/unique",
+            SampleDbStruct::new(String::from("unique")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let stats = db.dedup_stats().unwrap();
+        assert_eq!(stats.keys, 5);
+        assert_eq!(stats.unique_blobs, 2);
+        let same = data_format.serialize_with_indexes(
+            SampleDbStruct::new(String::from("same")),
+            &mut HashMap::new(),
+        );
+        let unique = data_format.serialize_with_indexes(
+            SampleDbStruct::new(String::from("unique")),
+            &mut HashMap::new(),
+        );
+        assert_eq!(
+            stats.logical_bytes,
+            4 * same.len() as u64 + unique.len() as u64
+        );
+        assert_eq!(stats.unique_bytes, same.len() as u64 + unique.len() as u64);
+        let mut sharing: Vec<_> = stats.keys_per_blob.values().copied().collect();
+        sharing.sort();
+        assert_eq!(sharing, vec![1, 4]);
+        assert!(stats.ratio() > 1.0);
+    }
+}
@@ -21,9 +21,31 @@ pub struct FloatyDbStruct {
     pub num_val: f64,
 }
 
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct TaggedDbStruct {
+    pub tags: Vec<String>,
+}
+
+impl TaggedDbStruct {
+    pub fn new(tags: &[&str]) -> Self {
+        Self {
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[cfg_attr(
+    any(feature = "derive", feature = "full"),
+    derive(crate::model::YamabikoModel)
+)]
 pub struct ComplexDbStruct {
+    #[cfg_attr(
+        any(feature = "derive", feature = "full"),
+        yamabiko(index = "sequential")
+    )]
     pub str_val: String,
+    #[cfg_attr(any(feature = "derive", feature = "full"), yamabiko(index = "numeric"))]
     pub usize_val: usize,
     pub float_val: f64,
 }
@@ -44,16 +66,169 @@ impl ComplexDbStruct {
     }
 }
 
-pub fn create_db(data_format: DataFormat) -> (Collection, TempDir) {
+/// Empty temporary directory for a collection, kept after the test if the
+/// `YAMABIKO_KEEP_TEST_DIR` environment variable is set
+fn test_dir() -> TempDir {
     #[cfg(test)]
     let _ = SimpleLogger::new().init();
     let keep_test_dir = !std::env::var("YAMABIKO_KEEP_TEST_DIR")
         .unwrap_or(String::from(""))
         .is_empty();
-    let tmpdir = Builder::new().keep(keep_test_dir).tempdir().unwrap();
+    let tmpdir = Builder::new()
+        .disable_cleanup(keep_test_dir)
+        .tempdir()
+        .unwrap();
     debug!("Using tmpdir {:?} for this test", tmpdir.path().to_str());
+    tmpdir
+}
+
+/// New collection in its own temporary directory, removed when the TempDir is dropped
+pub fn create_db(data_format: DataFormat) -> (Collection, TempDir) {
+    let tmpdir = test_dir();
     (
         Collection::initialize(tmpdir.path(), data_format).unwrap(),
         tmpdir,
     )
 }
+
+/// Like `create_db`, but the collection is created by the given builder
+/// (with the data format set on it)
+pub fn create_db_with(
+    data_format: DataFormat,
+    builder: builder::CollectionBuilder,
+) -> (Collection, TempDir) {
+    let tmpdir = test_dir();
+    (
+        builder
+            .data_format(data_format)
+            .create(tmpdir.path())
+            .unwrap(),
+        tmpdir,
+    )
+}
+
+/// `n` documents to store under the keys `key-0` to `key-{n-1}`. The values are random,
+/// but the same for every call with the same `n`.
+pub fn seed_records(n: usize) -> Vec<(String, ComplexDbStruct)> {
+    let mut rng = StdRng::seed_from_u64(0);
+    (0..n)
+        .map(|i| {
+            let str_val = (&mut rng)
+                .sample_iter(&Alphanumeric)
+                .take(8)
+                .map(char::from)
+                .collect();
+            // quarters survive the round trip through every data format exactly
+            let float_val = rng.gen_range(0..4000) as f64 / 4.0;
+            let record = ComplexDbStruct::new(str_val, rng.gen_range(0..1000), float_val);
+            (format!("key-{}", i), record)
+        })
+        .collect()
+}
+
+/// Store the documents of `seed_records(n)` on main in one commit and return them
+pub fn seed_db(db: &Collection, n: usize) -> Vec<(String, ComplexDbStruct)> {
+    let records = seed_records(n);
+    db.set_batch(records.iter().cloned(), OperationTarget::Main)
+        .unwrap();
+    records
+}
+
+/// Number of the commits reachable from the tip of the branch
+pub fn commit_count(db: &Collection, branch: &str) -> usize {
+    let repo = db.repository();
+    let mut revwalk = repo.revwalk().unwrap();
+    revwalk
+        .push(
+            repo.refname_to_id(&format!("refs/heads/{}", branch))
+                .unwrap(),
+        )
+        .unwrap();
+    revwalk.count()
+}
+
+/// Collection replicating to another one, see `create_linked_pair`
+pub struct LinkedPair {
+    pub primary: Collection,
+    pub primary_dir: TempDir,
+    pub backup: Collection,
+    pub backup_dir: TempDir,
+    /// Replicates everything from the primary to the backup, under the name `test`
+    pub replicator: replica::Replicator,
+}
+
+/// Two new collections, the second one a replica of the first
+pub fn create_linked_pair(data_format: DataFormat) -> LinkedPair {
+    let (primary, primary_dir) = create_db(data_format);
+    let (backup, backup_dir) = create_db(data_format);
+    let replicator = replica::Replicator::initialize(
+        primary_dir.path(),
+        "test",
+        backup_dir.path().to_str().unwrap(),
+        replica::ReplicationMethod::All,
+        None,
+    )
+    .unwrap();
+    LinkedPair {
+        primary,
+        primary_dir,
+        backup,
+        backup_dir,
+        replicator,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use crate::{replica::ReplicationOutcome, serialization::DataFormat, test::*, OperationTarget};
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_create_db_with(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db_with(data_format, Collection::builder().value_limit(64));
+        assert_eq!(db.data_format().to_string(), data_format.to_string());
+        assert_eq!(db.value_limit().unwrap(), Some(64));
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_seed_db(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let (other, _other_td) = create_db(data_format);
+        let records = seed_db(&db, 20);
+        assert_eq!(records.len(), 20);
+        assert_eq!(seed_db(&other, 20), records);
+        for (key, record) in records {
+            assert_eq!(
+                db.get::<ComplexDbStruct>(&key, OperationTarget::Main)
+                    .unwrap(),
+                Some(record)
+            );
+        }
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_create_linked_pair(#[case] data_format: DataFormat) {
+        let pair = create_linked_pair(data_format);
+        let records = seed_db(&pair.primary, 3);
+        assert_eq!(
+            pair.replicator.replicate().unwrap(),
+            ReplicationOutcome::Replicated(1)
+        );
+        assert_eq!(
+            pair.backup
+                .get::<ComplexDbStruct>(&records[2].0, OperationTarget::Main)
+                .unwrap(),
+            Some(records[2].1.clone())
+        );
+    }
+}
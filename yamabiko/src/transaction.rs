@@ -0,0 +1,819 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard};
+
+use git2::{BranchType, ErrorCode, Oid, Reference, Repository};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::serialization::DataFormat;
+use crate::{debug, error, Collection, OperationTarget};
+
+/// Set once the branches left from before transactions had markers are marked,
+/// see `mark_legacy_transactions`
+const LEGACY_MARKED_CONFIG: &str = "yamabiko.legacytransactionsmarked";
+
+fn read_set_ref(name: &str) -> String {
+    format!("refs/read_sets/{}", name)
+}
+
+/// Marks the branch as a transaction (as opposed to a branch written to with
+/// `OperationTarget::Branch`), points at the commit the transaction started from
+fn transaction_ref(name: &str) -> String {
+    format!("refs/transactions/{}", name)
+}
+
+/// Savepoints of a transaction are references to commits of its branch,
+/// scoped to the transaction by being stored under its name
+fn savepoint_ref(transaction: &str, savepoint: &str) -> String {
+    format!("refs/savepoints/{}/{}", transaction, savepoint)
+}
+
+/// Keys read with `Transaction::get_tracked`, along with the blobs they pointed at when read
+/// (None if the key didn't exist)
+type ReadSet = BTreeMap<String, Option<Oid>>;
+
+/// Read set of the transaction with the given name
+pub(crate) fn read_set(repo: &Repository, name: &str) -> Result<ReadSet, git2::Error> {
+    Ok(stored_read_set(repo, name)?.1)
+}
+
+/// The read set along with the blob it's stored in (None if nothing was read yet)
+fn stored_read_set(repo: &Repository, name: &str) -> Result<(Option<Oid>, ReadSet), git2::Error> {
+    let reference = match repo.find_reference(&read_set_ref(name)) {
+        Ok(reference) => reference,
+        Err(err) if err.code() == ErrorCode::NotFound => return Ok((None, BTreeMap::new())),
+        Err(err) => return Err(err),
+    };
+    let blob = reference.peel_to_blob()?;
+    let stored: BTreeMap<String, Option<String>> = serde_json::from_slice(blob.content())
+        .map_err(|err| git2::Error::from_str(&format!("invalid read set: {}", err)))?;
+    let read_set = stored
+        .into_iter()
+        .map(|(key, oid)| Ok((key, oid.map(|oid| Oid::from_str(&oid)).transpose()?)))
+        .collect::<Result<_, git2::Error>>()?;
+    Ok((Some(blob.id()), read_set))
+}
+
+/// Mark the branch of the same name as a transaction which started from the commit
+pub(crate) fn mark(repo: &Repository, name: &str, base: Oid) -> Result<(), git2::Error> {
+    repo.reference(&transaction_ref(name), base, true, "new transaction")?;
+    Ok(())
+}
+
+/// Transactions created before the markers were introduced have none, and back then every
+/// branch other than main was taken for a transaction - mark them all, so that they're still
+/// collected and reported as stale. Done once per repository, on opening it.
+pub(crate) fn mark_legacy_transactions(repo: &Repository) -> Result<(), git2::Error> {
+    let mut config = repo.config()?;
+    match config.get_bool(LEGACY_MARKED_CONFIG) {
+        Ok(true) => return Ok(()),
+        Ok(false) => {}
+        Err(err) if err.code() == ErrorCode::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    let main = repo.refname_to_id("refs/heads/main")?;
+    for branch in repo.branches(Some(BranchType::Local))? {
+        let (branch, _) = branch?;
+        let Some(name) = branch.name()? else {
+            continue;
+        };
+        if name == "main" || is_transaction(repo, name) {
+            continue;
+        }
+        let tip = branch.get().peel_to_commit()?.id();
+        let base = repo.merge_base(main, tip).unwrap_or(tip);
+        debug!(
+            "marking '{}' as a transaction created before the markers",
+            name
+        );
+        mark(repo, name, base)?;
+    }
+    config.set_bool(LEGACY_MARKED_CONFIG, true)
+}
+
+/// Whether the branch was created as a transaction, see `Collection::new_transaction`
+pub(crate) fn is_transaction(repo: &Repository, name: &str) -> bool {
+    repo.find_reference(&transaction_ref(name)).is_ok()
+}
+
+/// Delete the marker, the read set and the savepoints of the transaction,
+/// once it's applied or abandoned
+pub(crate) fn delete_refs(repo: &Repository, name: &str) -> Result<(), git2::Error> {
+    for reference in [transaction_ref(name), read_set_ref(name)] {
+        match repo.find_reference(&reference) {
+            Ok(mut reference) => reference.delete()?,
+            Err(err) if err.code() == ErrorCode::NotFound => {}
+            Err(err) => return Err(err),
+        }
+    }
+    for mut reference in savepoints(repo, name)? {
+        reference.delete()?;
+    }
+    Ok(())
+}
+
+fn savepoints<'r>(repo: &'r Repository, name: &str) -> Result<Vec<Reference<'r>>, git2::Error> {
+    repo.references_glob(&savepoint_ref(name, "*"))?.collect()
+}
+
+/// Whether reads through a Transaction see the changes staged in it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadYourWrites {
+    /// Staged values (and removals) take precedence over the transaction branch.
+    Enabled,
+    /// Only what was already committed to the transaction branch is visible.
+    Disabled,
+}
+
+/// Transaction branch which stages changes in memory and commits them all at once.
+///
+/// Every `Collection::set` on a transaction is a separate commit, which `apply_transaction`
+/// then has to rebase one by one. Changes staged here end up in a single commit created
+/// by `commit_staged`, so applying the transaction replays just that one.
+/// Staging the same key again replaces the previously staged change.
+pub struct Transaction {
+    name: String,
+    data_format: DataFormat,
+    staged: Mutex<BTreeMap<String, Option<Vec<u8>>>>,
+}
+
+impl Transaction {
+    /// Create a new transaction branch (see `Collection::new_transaction`)
+    pub fn begin(
+        collection: &Collection,
+        name: Option<&str>,
+    ) -> Result<Self, error::NewTransactionError> {
+        let name = collection.new_transaction(name)?;
+        Ok(Self::open(collection, &name))
+    }
+
+    /// Stage changes on an already existing transaction branch
+    pub fn open(collection: &Collection, name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            data_format: collection.data_format,
+            staged: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn target(&self) -> OperationTarget<'_> {
+        OperationTarget::Transaction(&self.name)
+    }
+
+    /// Number of staged changes waiting for `commit_staged`
+    pub fn staged(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn stage<S>(&self, key: &str, value: S) -> Result<(), error::KeyError>
+    where
+        S: Serialize,
+    {
+        let data = self
+            .data_format
+            .serialize_with_indexes(value, &mut HashMap::new());
+        self.stage_raw(key, data)
+    }
+
+    pub fn stage_raw(&self, key: &str, value: Vec<u8>) -> Result<(), error::KeyError> {
+        Collection::construct_path_to_key(key)?;
+        self.lock().insert(key.to_string(), Some(value));
+        Ok(())
+    }
+
+    /// Stage the removal of the key. Keys which turn out not to exist are ignored on commit.
+    pub fn stage_remove(&self, key: &str) -> Result<(), error::KeyError> {
+        Collection::construct_path_to_key(key)?;
+        self.lock().insert(key.to_string(), None);
+        Ok(())
+    }
+
+    /// Drop all of the staged changes
+    pub fn discard_staged(&self) {
+        self.lock().clear();
+    }
+
+    /// Write all of the staged changes to the transaction branch as a single commit.
+    /// Returns None if there was nothing to commit. The changes stay staged if committing fails.
+    pub fn commit_staged(
+        &self,
+        collection: &Collection,
+    ) -> Result<Option<Oid>, error::SetObjectError> {
+        let mut staged = self.lock();
+        let commit = collection.write_changes(
+            staged.iter().map(|(k, v)| (k.as_str(), v.as_deref())),
+            self.target(),
+        )?;
+        debug!("Committed {} staged changes on {}", staged.len(), self.name);
+        staged.clear();
+        Ok(commit)
+    }
+
+    pub fn get<D>(
+        &self,
+        collection: &Collection,
+        key: &str,
+        read_your_writes: ReadYourWrites,
+    ) -> Result<Option<D>, error::GetObjectError>
+    where
+        D: DeserializeOwned,
+    {
+        if read_your_writes == ReadYourWrites::Enabled {
+            if let Some(change) = self.lock().get(key) {
+                return Ok(change
+                    .as_ref()
+                    .map(|value| self.data_format.deserialize(value)));
+            }
+        }
+        collection.get(key, self.target())
+    }
+
+    /// Read the key like `get` (with ReadYourWrites::Enabled) and remember what it was.
+    /// If main changes any of the tracked keys before the transaction is applied,
+    /// applying fails with a ReadSetConflict. Values staged or committed by the transaction
+    /// itself don't count, since main had no say in them. The read set is stored
+    /// in the repository, so it also covers reads made through other Transaction handles.
+    pub fn get_tracked<D>(
+        &self,
+        collection: &Collection,
+        key: &str,
+    ) -> Result<Option<D>, error::GetObjectError>
+    where
+        D: DeserializeOwned,
+    {
+        if let Some(change) = self.lock().get(key) {
+            return Ok(change
+                .as_ref()
+                .map(|value| self.data_format.deserialize(value)));
+        }
+        let entry = collection.get_tree_key(key, self.target())?;
+        self.track(collection.repository(), key, entry.as_ref().map(|e| e.id()))?;
+        match entry {
+            Some(entry) => {
+                let blob = collection.repository().find_blob(entry.id())?;
+                let content = collection.open_value(blob.content())?;
+                Ok(Some(self.data_format.deserialize(&content)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Add the key to the read set, unless it's already there - the first read is what counts.
+    /// The reference is moved with a compare-and-swap (retried on a change), so reads through
+    /// other handles of the transaction don't drop each other's keys.
+    fn track(&self, repo: &Repository, key: &str, blob: Option<Oid>) -> Result<(), git2::Error> {
+        loop {
+            let (current, mut read_set) = stored_read_set(repo, &self.name)?;
+            if read_set.contains_key(key) {
+                return Ok(());
+            }
+            read_set.insert(key.to_string(), blob);
+            let stored: BTreeMap<&String, Option<String>> = read_set
+                .iter()
+                .map(|(key, oid)| (key, oid.map(|oid| oid.to_string())))
+                .collect();
+            // unwrap: a map of strings always serializes
+            let content = serde_json::to_vec(&stored).unwrap();
+            // a zero id only matches a missing reference
+            let moved = repo.reference_matching(
+                &read_set_ref(&self.name),
+                repo.blob(&content)?,
+                true,
+                current.unwrap_or(Oid::zero()),
+                &format!("track {}", key),
+            );
+            match moved {
+                Ok(_) => return Ok(()),
+                Err(err)
+                    if matches!(
+                        err.code(),
+                        ErrorCode::Modified | ErrorCode::Exists | ErrorCode::Locked
+                    ) =>
+                {
+                    debug!("the read set of {} changed, retrying", self.name);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Remember the commit the transaction branch is at under the name (replacing
+    /// a previous savepoint of the same name), so that `rollback_to` can go back to it.
+    /// Changes which are only staged are not part of the savepoint.
+    pub fn savepoint(
+        &self,
+        collection: &Collection,
+        name: &str,
+    ) -> Result<Oid, error::TransactionError> {
+        let reference = self.savepoint_ref(name)?;
+        let tip = self.tip(collection.repository())?;
+        collection
+            .repository()
+            .reference(&reference, tip, true, "savepoint")?;
+        debug!("savepoint {} of {} at {}", name, self.name, tip);
+        Ok(tip)
+    }
+
+    /// Move the transaction branch back to the commit of the savepoint, dropping the commits
+    /// written to it since (staged changes are kept). Savepoints created after this one are
+    /// released, this one stays so that it can be rolled back to again.
+    pub fn rollback_to(
+        &self,
+        collection: &Collection,
+        name: &str,
+    ) -> Result<Oid, error::TransactionError> {
+        let repo = collection.repository();
+        let reference = self.savepoint_ref(name)?;
+        let savepoint = match repo.refname_to_id(&reference) {
+            Ok(savepoint) => savepoint,
+            Err(err) if err.code() == ErrorCode::NotFound => {
+                return Err(error::TransactionError::SavepointNotFound(name.to_string()))
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let tip = self.tip(repo)?;
+        let in_history = |commit: Oid| -> Result<bool, git2::Error> {
+            Ok(commit == savepoint || repo.graph_descendant_of(savepoint, commit)?)
+        };
+        if savepoint != tip {
+            // the branch was moved some other way since
+            if !repo.graph_descendant_of(tip, savepoint)? {
+                return Err(error::TransactionError::SavepointNotFound(name.to_string()));
+            }
+            collection.advance_branch(
+                &self.name,
+                tip,
+                savepoint,
+                &format!("rollback to savepoint {}", name),
+            )?;
+        }
+        for mut later in savepoints(repo, &self.name)? {
+            if !later.target().map(in_history).transpose()?.unwrap_or(false) {
+                later.delete()?;
+            }
+        }
+        debug!("rolled {} back to savepoint {}", self.name, name);
+        Ok(savepoint)
+    }
+
+    /// Forget the savepoint, it can't be rolled back to anymore
+    pub fn release_savepoint(
+        &self,
+        collection: &Collection,
+        name: &str,
+    ) -> Result<(), error::TransactionError> {
+        let reference = self.savepoint_ref(name)?;
+        match collection.repository().find_reference(&reference) {
+            Ok(mut reference) => Ok(reference.delete()?),
+            Err(err) if err.code() == ErrorCode::NotFound => {
+                Err(error::TransactionError::SavepointNotFound(name.to_string()))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn savepoint_ref(&self, name: &str) -> Result<String, error::TransactionError> {
+        let reference = savepoint_ref(&self.name, name);
+        if name.is_empty() || name.contains('/') || !Reference::is_valid_name(&reference) {
+            return Err(error::TransactionError::InvalidSavepointName(
+                name.to_string(),
+            ));
+        }
+        Ok(reference)
+    }
+
+    fn tip(&self, repo: &Repository) -> Result<Oid, error::TransactionError> {
+        let branch = repo
+            .find_branch(&self.name, BranchType::Local)
+            .map_err(|err| match err.code() {
+                ErrorCode::NotFound => error::TransactionError::TransactionNotFound,
+                _ => err.into(),
+            })?;
+        branch
+            .get()
+            .target()
+            .ok_or(error::TransactionError::TransactionNotFound)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<String, Option<Vec<u8>>>> {
+        // unwrap: only poisoned if another thread panicked while holding the lock
+        self.staged.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use crate::{
+        error,
+        field::Field,
+        index::IndexType,
+        serialization::DataFormat,
+        test::*,
+        transaction::{read_set, ReadYourWrites, Transaction},
+        Collection, ConflictResolution, OperationTarget, Resolution,
+    };
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_commit_staged(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let index = db.add_index("str_val", IndexType::Sequential);
+        db.set(
+            "removed",
+            SampleDbStruct::new(String::from("old")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let t = Transaction::begin(&db, None).unwrap();
+        let commits_before = commit_count(&db, t.name());
+        for i in 0..100 {
+            t.stage(&format!("key-{}", i), SampleDbStruct::new(i.to_string()))
+                .unwrap();
+        }
+        t.stage("key-0", SampleDbStruct::new(String::from("replaced")))
+            .unwrap();
+        t.stage_remove("removed").unwrap();
+        t.stage_remove("never-existed").unwrap();
+        assert_eq!(t.staged(), 102);
+        assert!(t.commit_staged(&db).unwrap().is_some());
+        assert_eq!(t.staged(), 0);
+        assert_eq!(commit_count(&db, t.name()), commits_before + 1);
+        assert_eq!(t.commit_staged(&db).unwrap(), None);
+
+        let outcome = db
+            .apply_transaction(t.name(), ConflictResolution::Abort)
+            .unwrap();
+        assert_eq!(outcome.commits_applied, 1);
+        assert_eq!(
+            db.get::<SampleDbStruct>("key-0", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct::new(String::from("replaced"))
+        );
+        assert_eq!(
+            db.get::<SampleDbStruct>("key-99", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct::new(String::from("99"))
+        );
+        assert!(db
+            .get::<SampleDbStruct>("removed", OperationTarget::Main)
+            .unwrap()
+            .is_none());
+        let git_index = index.git_index(db.repository());
+        assert_eq!(git_index.len(), 100);
+        let old = Field::String(String::from("old")).to_index_value();
+        assert!(!git_index.iter().any(|e| e.path.starts_with(old.as_bytes())));
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_read_your_writes(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let t = Transaction::begin(&db, Some("staging")).unwrap();
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("committed")),
+            t.target(),
+        )
+        .unwrap();
+        db.set(
+            "b",
+            SampleDbStruct::new(String::from("committed")),
+            t.target(),
+        )
+        .unwrap();
+        t.stage("a", SampleDbStruct::new(String::from("staged")))
+            .unwrap();
+        t.stage_remove("b").unwrap();
+
+        let staged = t
+            .get::<SampleDbStruct>(&db, "a", ReadYourWrites::Enabled)
+            .unwrap();
+        assert_eq!(staged, Some(SampleDbStruct::new(String::from("staged"))));
+        assert!(t
+            .get::<SampleDbStruct>(&db, "b", ReadYourWrites::Enabled)
+            .unwrap()
+            .is_none());
+        let committed = t
+            .get::<SampleDbStruct>(&db, "a", ReadYourWrites::Disabled)
+            .unwrap();
+        assert_eq!(
+            committed,
+            Some(SampleDbStruct::new(String::from("committed")))
+        );
+        assert!(t
+            .get::<SampleDbStruct>(&db, "b", ReadYourWrites::Disabled)
+            .unwrap()
+            .is_some());
+
+        t.discard_staged();
+        assert_eq!(
+            t.get::<SampleDbStruct>(&db, "a", ReadYourWrites::Enabled)
+                .unwrap(),
+            Some(SampleDbStruct::new(String::from("committed")))
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_commit_staged_value_too_large(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.set_value_limit(8).unwrap();
+        let t = Transaction::begin(&db, None).unwrap();
+        t.stage("a", SampleDbStruct::new(String::from("too large to store")))
+            .unwrap();
+        assert!(matches!(
+            t.commit_staged(&db),
+            Err(crate::error::SetObjectError::ValueTooLarge { .. })
+        ));
+        assert_eq!(t.staged(), 1);
+        assert!(t
+            .get::<SampleDbStruct>(&db, "a", ReadYourWrites::Disabled)
+            .unwrap()
+            .is_none());
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_read_set_conflict(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.set_batch(
+            [
+                ("a", SampleDbStruct::new(String::from("a"))),
+                ("b", SampleDbStruct::new(String::from("b"))),
+            ],
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let t = Transaction::begin(&db, None).unwrap();
+        let a = t.get_tracked::<SampleDbStruct>(&db, "a").unwrap();
+        assert_eq!(a, Some(SampleDbStruct::new(String::from("a"))));
+        assert!(t
+            .get_tracked::<SampleDbStruct>(&db, "missing")
+            .unwrap()
+            .is_none());
+        t.stage("c", SampleDbStruct::new(String::from("from a")))
+            .unwrap();
+        t.commit_staged(&db).unwrap();
+        // changes to keys which weren't read don't matter
+        db.set(
+            "b",
+            SampleDbStruct::new(String::from("changed")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("changed")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        db.set(
+            "missing",
+            SampleDbStruct::new(String::from("created")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let conflict = || error::TransactionError::ReadSetConflict {
+            keys: vec![String::from("a"), String::from("missing")],
+        };
+        assert_eq!(
+            db.apply_transaction(t.name(), ConflictResolution::Overwrite),
+            Err(conflict())
+        );
+        // the read set is stored, so a new handle to the same transaction sees it as well
+        let reopened = Transaction::open(&db, t.name());
+        assert_eq!(
+            db.apply_transaction_with(reopened.name(), |_| Resolution::TakeTransaction),
+            Err(conflict())
+        );
+        assert!(db
+            .get::<SampleDbStruct>("c", OperationTarget::Main)
+            .unwrap()
+            .is_none());
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_read_set_applies(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("a")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let t = Transaction::begin(&db, None).unwrap();
+        t.get_tracked::<SampleDbStruct>(&db, "a").unwrap();
+        db.set(
+            "own",
+            SampleDbStruct::new(String::from("written first")),
+            t.target(),
+        )
+        .unwrap();
+        // reads of its own writes aren't checked against main
+        t.get_tracked::<SampleDbStruct>(&db, "own").unwrap();
+        db.set(
+            "own",
+            SampleDbStruct::new(String::from("main")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        assert_eq!(read_set(db.repository(), t.name()).unwrap().len(), 2);
+        db.apply_transaction_with(t.name(), |_| Resolution::TakeTransaction)
+            .unwrap();
+        assert!(read_set(db.repository(), t.name()).unwrap().is_empty());
+        assert_eq!(
+            db.get::<SampleDbStruct>("own", OperationTarget::Main)
+                .unwrap()
+                .unwrap(),
+            SampleDbStruct::new(String::from("written first"))
+        );
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_read_set_checked_under_lock(#[case] data_format: DataFormat) {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        use std::time::Duration;
+
+        let (db, td) = create_db(data_format);
+        let value = |s: &str| SampleDbStruct::new(String::from(s));
+        db.set_batch(
+            [("a", value("a")), ("b", value("b"))],
+            OperationTarget::Main,
+        )
+        .unwrap();
+        // another writer, which tries to change a tracked key while the transaction is applied
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        let write_a = {
+            let other = Collection::initialize(td.path(), data_format)
+                .unwrap()
+                .with_lock_timeout(Duration::from_millis(20));
+            let writes = writes.clone();
+            move || {
+                let result = other.set(
+                    "a",
+                    SampleDbStruct::new(String::from("other")),
+                    OperationTarget::Main,
+                );
+                writes.borrow_mut().push(result.is_ok());
+            }
+        };
+        let write_a = Rc::new(write_a);
+        for apply_with in [false, true] {
+            let t = Transaction::begin(&db, None).unwrap();
+            t.get_tracked::<SampleDbStruct>(&db, "a").unwrap();
+            // a conflict, so that the resolver runs in the middle of the merge
+            let suffix = if apply_with { "with" } else { "callback" };
+            db.set("b", value(&format!("transaction {}", suffix)), t.target())
+                .unwrap();
+            db.set(
+                "b",
+                value(&format!("main {}", suffix)),
+                OperationTarget::Main,
+            )
+            .unwrap();
+            let outcome = if apply_with {
+                let write_a = write_a.clone();
+                db.apply_transaction_with(t.name(), move |_| {
+                    write_a();
+                    Resolution::TakeTransaction
+                })
+            } else {
+                let write_a = write_a.clone();
+                db.apply_transaction(
+                    t.name(),
+                    ConflictResolution::Callback(Box::new(move |_| {
+                        write_a();
+                        Resolution::TakeTransaction
+                    })),
+                )
+            };
+            assert!(outcome.is_ok());
+        }
+        // the main couldn't be moved between checking the read set and the merge
+        assert_eq!(*writes.borrow(), vec![false, false]);
+        write_a();
+        assert_eq!(writes.borrow().last(), Some(&true));
+    }
+
+    #[test]
+    fn test_concurrent_tracked_reads() {
+        let (db, td) = create_db(DataFormat::Json);
+        let t = Transaction::begin(&db, None).unwrap();
+        let threads: Vec<_> = (0..8)
+            .map(|n| {
+                let path = td.path().to_path_buf();
+                let name = t.name().to_string();
+                std::thread::spawn(move || {
+                    let db = Collection::initialize(&path, DataFormat::Json).unwrap();
+                    let t = Transaction::open(&db, &name);
+                    t.get_tracked::<SampleDbStruct>(&db, &n.to_string())
+                        .unwrap();
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        // no read was lost to a concurrent one
+        assert_eq!(read_set(db.repository(), t.name()).unwrap().len(), 8);
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_savepoints(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let t = Transaction::begin(&db, None).unwrap();
+        let value = |s: &str| SampleDbStruct::new(String::from(s));
+        db.set("a", value("a"), t.target()).unwrap();
+        let after_a = t.savepoint(&db, "after-a").unwrap();
+        db.set("b", value("b"), t.target()).unwrap();
+        t.savepoint(&db, "after-b").unwrap();
+        db.set("c", value("c"), t.target()).unwrap();
+
+        assert_eq!(t.rollback_to(&db, "after-a"), Ok(after_a));
+        assert_eq!(db.get::<SampleDbStruct>("b", t.target()).unwrap(), None);
+        // released by rolling back past it
+        assert_eq!(
+            t.rollback_to(&db, "after-b"),
+            Err(error::TransactionError::SavepointNotFound(String::from(
+                "after-b"
+            )))
+        );
+        // the savepoint rolled back to is kept
+        db.set("d", value("d"), t.target()).unwrap();
+        assert_eq!(t.rollback_to(&db, "after-a"), Ok(after_a));
+
+        t.release_savepoint(&db, "after-a").unwrap();
+        assert_eq!(
+            t.rollback_to(&db, "after-a"),
+            Err(error::TransactionError::SavepointNotFound(String::from(
+                "after-a"
+            )))
+        );
+        for name in ["", "nested/name", "with space", "..", "a.lock"] {
+            assert_eq!(
+                t.savepoint(&db, name),
+                Err(error::TransactionError::InvalidSavepointName(
+                    name.to_string()
+                ))
+            );
+        }
+        // savepoints are scoped to their transaction
+        t.savepoint(&db, "shared").unwrap();
+        let other = Transaction::begin(&db, None).unwrap();
+        assert_eq!(
+            other.rollback_to(&db, "shared"),
+            Err(error::TransactionError::SavepointNotFound(String::from(
+                "shared"
+            )))
+        );
+
+        let savepoint_count = || {
+            db.repository()
+                .references_glob("refs/savepoints/*")
+                .unwrap()
+                .count()
+        };
+        assert_eq!(savepoint_count(), 1);
+        db.apply_transaction(t.name(), ConflictResolution::Abort)
+            .unwrap();
+        assert_eq!(savepoint_count(), 0);
+        assert_eq!(
+            db.get::<SampleDbStruct>("a", OperationTarget::Main)
+                .unwrap(),
+            Some(value("a"))
+        );
+        for key in ["b", "c", "d"] {
+            assert_eq!(
+                db.get::<SampleDbStruct>(key, OperationTarget::Main)
+                    .unwrap(),
+                None
+            );
+        }
+    }
+}
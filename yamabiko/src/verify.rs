@@ -0,0 +1,263 @@
+use std::collections::HashSet;
+
+use git2::{ErrorCode, ObjectType, Oid, Repository};
+
+use crate::check::branch_key_hashes;
+use crate::{debug, error, record, Collection};
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ObjectProblem {
+    /// The object is referenced, but it's not in the object database.
+    Missing,
+    /// The object cannot be read from the object database. Contains the error from git.
+    Unreadable(String),
+    /// The content of the object hashes to a different oid than the one it's stored under.
+    HashMismatch { actual: Oid },
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CorruptObject {
+    pub oid: Oid,
+    pub problem: ObjectProblem,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct DanglingIndexEntry {
+    /// Name of the index, like `age#numeric.index`.
+    pub index: String,
+    /// Path of the entry in the index file.
+    pub path: String,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct VerifyReport {
+    /// Number of objects reachable from the references of the repository which were rehashed.
+    pub objects_verified: usize,
+    pub corrupt_objects: Vec<CorruptObject>,
+    /// Number of index entries cross-checked against the keys on the branches.
+    pub index_entries_verified: usize,
+    /// Index entries pointing at keys which don't exist on any branch.
+    pub dangling_index_entries: Vec<DanglingIndexEntry>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.corrupt_objects.is_empty() && self.dangling_index_entries.is_empty()
+    }
+
+    fn corrupt(&mut self, oid: Oid, problem: ObjectProblem) {
+        debug!("verify found a corrupt object {}: {:?}", oid, problem);
+        self.corrupt_objects.push(CorruptObject { oid, problem });
+    }
+}
+
+pub(crate) fn verify(collection: &Collection) -> Result<VerifyReport, error::VerifyError> {
+    let repo = collection.repository();
+    let mut report = VerifyReport::default();
+    verify_objects(repo, &mut report)?;
+    verify_indexes(collection, &mut report)?;
+    record!("objects", report.objects_verified);
+    record!(
+        "problems",
+        report.corrupt_objects.len() + report.dangling_index_entries.len()
+    );
+    Ok(report)
+}
+
+/// Walk everything reachable from the references (the whole history, not just the tips)
+/// and rehash every object
+fn verify_objects(repo: &Repository, report: &mut VerifyReport) -> Result<(), git2::Error> {
+    let odb = repo.odb()?;
+    let mut pending = Vec::new();
+    for reference in repo.references()? {
+        if let Some(oid) = reference?.resolve().ok().and_then(|r| r.target()) {
+            pending.push(oid);
+        }
+    }
+    let mut visited = HashSet::new();
+    while let Some(oid) = pending.pop() {
+        if !visited.insert(oid) {
+            continue;
+        }
+        report.objects_verified += 1;
+        let object = match odb.read(oid) {
+            Ok(object) => object,
+            Err(err) if err.code() == ErrorCode::NotFound => {
+                report.corrupt(oid, ObjectProblem::Missing);
+                continue;
+            }
+            Err(err) => {
+                report.corrupt(oid, ObjectProblem::Unreadable(err.message().to_string()));
+                continue;
+            }
+        };
+        let actual = Oid::hash_object(object.kind(), object.data())?;
+        if actual != oid {
+            report.corrupt(oid, ObjectProblem::HashMismatch { actual });
+            continue;
+        }
+        match object.kind() {
+            ObjectType::Commit => {
+                let commit = repo.find_commit(oid)?;
+                pending.push(commit.tree_id());
+                pending.extend(commit.parent_ids());
+            }
+            ObjectType::Tree => {
+                let tree = repo.find_tree(oid)?;
+                pending.extend(
+                    tree.iter()
+                        // gitlinks point at commits of other repositories
+                        .filter(|entry| entry.kind() != Some(ObjectType::Commit))
+                        .map(|entry| entry.id()),
+                );
+            }
+            ObjectType::Tag => pending.push(repo.find_tag(oid)?.target_id()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn verify_indexes(collection: &Collection, report: &mut VerifyReport) -> Result<(), git2::Error> {
+    let repo = collection.repository();
+    let odb = repo.odb()?;
+    let key_hashes = branch_key_hashes(repo, None)?;
+    for index in collection.list_indexes() {
+        let index_path = repo.path().join(".index").join(index.name());
+        // the file doesn't exist until the first entry is written - nothing to verify
+        if !index_path.exists() {
+            continue;
+        }
+        for entry in git2::Index::open(&index_path)?.iter() {
+            report.index_entries_verified += 1;
+            if key_hashes.contains(&entry.id) || odb.exists(entry.id) {
+                continue;
+            }
+            let path = String::from_utf8_lossy(&entry.path).to_string();
+            debug!("verify found a dangling entry {} in {}", path, index.name());
+            report.dangling_index_entries.push(DanglingIndexEntry {
+                index: index.name().to_string(),
+                path,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use rstest::rstest;
+
+    use crate::{
+        field::Field,
+        index::IndexType,
+        serialization::DataFormat,
+        test::*,
+        verify::{DanglingIndexEntry, ObjectProblem},
+        Collection, OperationTarget,
+    };
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_verify_healthy(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        db.add_index("num_val", IndexType::Numeric);
+        db.set(
+            "a",
+            InterigentDbStruct { num_val: 1 },
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let t = db.new_transaction(None).unwrap();
+        db.set(
+            "nested/b",
+            InterigentDbStruct { num_val: 2 },
+            OperationTarget::Transaction(&t),
+        )
+        .unwrap();
+        let report = db.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report);
+        // the commits of both branches, their trees and the blobs of both keys
+        assert!(report.objects_verified > 10);
+        assert_eq!(report.index_entries_verified, 2);
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_verify_corrupt_history(#[case] data_format: DataFormat) {
+        let (db, td) = create_db(data_format);
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("old")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let old_blob = db
+            .repository()
+            .head()
+            .unwrap()
+            .peel_to_tree()
+            .unwrap()
+            .get_path(std::path::Path::new(
+                &Collection::construct_path_to_key("a").unwrap(),
+            ))
+            .unwrap()
+            .id();
+        // only reachable through the history of main
+        db.set(
+            "a",
+            SampleDbStruct::new(String::from("new")),
+            OperationTarget::Main,
+        )
+        .unwrap();
+        let hex = old_blob.to_string();
+        let path = td.path().join("objects").join(&hex[..2]).join(&hex[2..]);
+        let mut permissions = fs::metadata(&path).unwrap().permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        fs::set_permissions(&path, permissions).unwrap();
+        fs::remove_file(&path).unwrap();
+        drop(db);
+        let db = Collection::initialize(td.path(), data_format).unwrap();
+        let report = db.verify().unwrap();
+        assert_eq!(report.corrupt_objects.len(), 1);
+        assert_eq!(report.corrupt_objects[0].oid, old_blob);
+        assert_eq!(report.corrupt_objects[0].problem, ObjectProblem::Missing);
+    }
+
+    #[rstest]
+    #[case(DataFormat::Json)]
+    #[case(DataFormat::Yaml)]
+    #[case(DataFormat::Pot)]
+    fn test_verify_dangling_index_entry(#[case] data_format: DataFormat) {
+        let (db, _td) = create_db(data_format);
+        let index = db.add_index("num_val", IndexType::Numeric);
+        db.set(
+            "a",
+            InterigentDbStruct { num_val: 1 },
+            OperationTarget::Main,
+        )
+        .unwrap();
+        index
+            .create_entry(
+                db.repository(),
+                git2::Oid::hash_object(git2::ObjectType::Blob, b"gone").unwrap(),
+                &Field::Int(5),
+            )
+            .unwrap();
+        let report = db.verify().unwrap();
+        assert!(report.corrupt_objects.is_empty());
+        assert_eq!(report.index_entries_verified, 2);
+        assert_eq!(report.dangling_index_entries.len(), 1);
+        assert!(matches!(
+            &report.dangling_index_entries[0],
+            DanglingIndexEntry { index: name, .. } if name == index.name()
+        ));
+    }
+}
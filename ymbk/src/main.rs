@@ -54,7 +54,9 @@ enum Command {
         commit: String, 
         #[clap(long, action)]
         keep_history: bool
-    }
+    },
+    /// Rebuilds the log of the order the keys were inserted in from the history of main
+    RebuildInsertionLog
 }
 
 #[derive(Subcommand, Debug)]
@@ -118,5 +120,11 @@ fn main() {
                 Err(_err) => eprintln!("Invalid commit Oid format")
             }
         }, 
+        Command::RebuildInsertionLog => {
+            match collection.rebuild_insertion_log() {
+                Ok(keys) => println!("Successfully rebuilt the insertion log of {} keys", keys),
+                Err(err) => eprintln!("Error: {:?}", err),
+            }
+        },
     }
 }